@@ -41,7 +41,7 @@ fn bvfmt(bv: &BitSlice<usize, Msb0>) -> String {
 
 /// Generate an address where bits [45:32] are randomized
 fn gen_random_addr() -> usize { 
-    let r = thread_rng().gen_range(0x2000..=0x3fff);
+    let r = perfect_rng().gen_range(0x2000..=0x3fff);
     0x0000_0000_0000_0000usize | (r << 32)
 }
 
@@ -252,7 +252,7 @@ fn main() {
 
     // Test all N-bit patterns of branch outcomes
     let mut patterns = generate_patterns_exhaustive(10);
-    patterns.shuffle(&mut thread_rng());
+    patterns.shuffle(&mut perfect_rng());
 
     let res = PatternStimulus::run(&mut harness, patterns, None);
     PatternStimulus::print_results(res);
@@ -8,7 +8,7 @@ use std::collections::*;
 use bitvec::prelude::*;
 
 fn gen_random_addr() -> usize { 
-    let r = thread_rng().gen_range(0x2000..=0x4fff);
+    let r = perfect_rng().gen_range(0x2000..=0x4fff);
     0x0000_0000_0000_0000usize | (r << 32)
 }
 
@@ -314,7 +314,7 @@ impl ClearGhist {
             let func = f.as_fn();
 
             let mut inputs: Vec<(usize,usize)> = (0..Self::NUM_ITER)
-                .map(|i| (thread_rng().gen::<bool>() as usize, func as usize))
+                .map(|i| (perfect_rng().gen::<bool>() as usize, func as usize))
                 .collect();
 
             flush_btb::<8192>();
@@ -542,7 +542,7 @@ impl CorrelatedBranches {
 
         // NOTE: Why does using a random order change the results? 
         let mut paddings = (1..=96).collect_vec();
-        //paddings.shuffle(&mut thread_rng());
+        //paddings.shuffle(&mut perfect_rng());
 
         for abit in 5..=19 {
 
@@ -686,7 +686,7 @@ impl CorrelatedBranchesSimple {
             flush_btb::<8192>();
 
             let inputs: Vec<(usize,usize)> = (0..Self::NUM_ITER)
-                .map(|i| (thread_rng().gen::<bool>() as usize, num_padding))
+                .map(|i| (perfect_rng().gen::<bool>() as usize, num_padding))
                 .collect();
 
             let results = harness.measure(func,
@@ -724,7 +724,7 @@ impl CorrelatedBranchesSimple {
 
 
 fn main() {
-    let r = thread_rng().gen_range(0x1000..=0x1fff);
+    let r = perfect_rng().gen_range(0x1000..=0x1fff);
     let harness_addr = 0x0000_0000_0000_0000usize | (r << 32);
 
     let mut harness = HarnessConfig::default_zen2()
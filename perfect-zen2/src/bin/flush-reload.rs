@@ -1,12 +1,14 @@
 use perfect::*;
 use perfect::events::*;
+use perfect::sidechannel::{ self, ProbeResult, Access };
 use rand::prelude::*;
 use rand::distributions::Uniform;
 use std::collections::*;
 
 fn main() {
+    let args = ExperimentArgs::parse();
     let mut harness = HarnessConfig::default_zen2().emit();
-    FlushReload::run(&mut harness);
+    FlushReload::run(&mut harness, args.timer.unwrap_or(Timer::RDTSC));
 }
 
 /// Simple synthetic example of cache timing with FLUSH+RELOAD. 
@@ -50,34 +52,10 @@ fn main() {
 ///
 pub struct FlushReload;
 impl FlushReload {
-    const PROBE_ADDR:       usize = 0x0000_0000_4001_0000;
     const VICTIM_ADDR:      usize = 0x0000_0000_4002_0000;
     const ATTACKER_ADDR:    usize = 0x0000_1001_4002_0000;
     const ARR_ADDR:         usize = 0x0000_0000_0a00_0000;
 
-    /// Emit a gadget for timing a single load with RDTSC (which returns the 
-    /// observed value in RAX). 
-    fn emit_probe() -> X64AssemblerFixed {
-        let mut f = X64AssemblerFixed::new(Self::PROBE_ADDR, 0x4000);
-        dynasm!(f
-            ; xor r8, r8
-            ; lfence
-            ; rdtsc
-            ; sub r8, rax
-
-            ; mov r9, [rdi]
-
-            ; lfence
-            ; rdtsc
-            ; add rax, r8
-        );
-        dynasm!(f
-            ; ret
-        );
-        f.commit().unwrap();
-        f
-    }
-
     /// Variation 1. Two paths with different loads to different addresses
     fn emit_victim_v1(f: &mut X64AssemblerFixed) {
         dynasm!(f
@@ -199,6 +177,7 @@ impl FlushReload {
 
         // Pad until we reach address matching the victim's branch
         let brn_addr = victim_jz_addr | 0x0000_1001_0000_0000;
+        assert!(uarch::btb::BtbModel::aliasing(victim_jz_addr, brn_addr));
         f.pad_until(brn_addr);
         
         // Emit a branch which is aliasing with the victim's branch
@@ -222,22 +201,24 @@ impl FlushReload {
     /// the state of the cache, since we might expect the data prefetcher
     /// to access all of the lines we are trying to measure. 
     /// Instead, perform accesses on each element in a random order.
-    fn run_probe(harness: &mut PerfectHarness, probe_fn: MeasuredFn) 
-        -> [usize; 256]
+    fn run_probe(harness: &mut PerfectHarness, 
+        probe: &sidechannel::FlushReload) -> [ProbeResult; 256]
     {
-        let mut results = [0usize; 256];
+        let mut results = [ProbeResult { latency: 0, access: Access::Miss }; 256];
         let mut indexes = (0..=255).collect_vec();
         indexes.shuffle(&mut harness.rng);
         for idx in indexes {
             let addr = (Self::ARR_ADDR | (idx << 12)) as usize;
-            let res = harness.call(addr, 0, probe_fn);
-            results[idx] = res;
+            results[idx] = probe.probe(addr);
         }
         results
     }
 
-    fn run(harness: &mut PerfectHarness) {
-        let probe  = Self::emit_probe();
+    fn run(harness: &mut PerfectHarness, timer: Timer) {
+        let mut probe = sidechannel::FlushReload::new(timer);
+        let threshold = probe.calibrate(Self::ARR_ADDR, 1024).unwrap();
+        println!("{:?}", threshold);
+
         let (victim, victim_jz_addr) = Self::emit_victim();
         let attacker = Self::emit_attacker(victim_jz_addr);
 
@@ -256,14 +237,9 @@ impl FlushReload {
         let res = harness.call(0, 0, attacker.as_fn());
 
         // Flush lines from the cache
-        unsafe { 
-            for _ in 0..64 {
-                for idx in 0..=255 {
-                    let addr = (Self::ARR_ADDR | (idx << 12)) as *const u8;
-                    core::arch::x86_64::_mm_clflush(addr);
-                    core::arch::x86_64::_mm_mfence();
-                    core::arch::x86_64::_mm_lfence();
-                }
+        for _ in 0..64 {
+            for idx in 0..=255 {
+                probe.flush(Self::ARR_ADDR | (idx << 12));
             }
         }
 
@@ -274,10 +250,10 @@ impl FlushReload {
         // Probe the cache state. 
         // We expect that the time is low for both the architectural and 
         // speculative accesses in the victim function.
-        let results = Self::run_probe(harness, probe.as_fn());
+        let results = Self::run_probe(harness, &probe);
         for idx in 0..=255 {
-            if results[idx] < 300 {
-                println!("Test 2, {:02x}: {}", idx, results[idx]);
+            if results[idx].is_hit() {
+                println!("Test 2, {:02x}: {}", idx, results[idx].latency);
             }
         }
     }
@@ -0,0 +1,108 @@
+use perfect::*;
+use perfect::events::*;
+use perfect::ir::*;
+use perfect::experiments::fuzz::*;
+use clap::ValueEnum;
+
+/// Coverage-guided fuzzing: mutate inputs and keep the ones that produce
+/// new event signatures.
+#[derive(Parser)]
+pub struct Args {
+    /// Type of input to fuzz
+    #[arg(short, long, value_enum, default_value = "prog")]
+    input: FuzzInputKind,
+
+    /// Directory where the corpus is stored
+    #[arg(long, default_value = "fuzz-corpus")]
+    corpus: std::path::PathBuf,
+
+    /// Number of inputs to execute
+    #[arg(short, long, default_value = "10000")]
+    execs: usize,
+
+    /// Number of random seed inputs (when the corpus is empty)
+    #[arg(long, default_value = "16")]
+    seeds: usize,
+
+    /// Length of each random seed input
+    #[arg(long, default_value = "16")]
+    len: usize,
+
+    /// Target CPU core (#15 by default)
+    #[arg(short, long, default_value = "15")]
+    core: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum FuzzInputKind {
+    /// Random programs ([PerfectProg])
+    Prog,
+    /// Random instruction encodings ([EncodingSeq])
+    Enc,
+}
+
+const ARENA_BASE: usize = 0x0000_0020_0000_0000;
+
+fn main() {
+    let arg = Args::parse();
+    let layout = ArenaLayout::new(ARENA_BASE)
+        .buffer(perfect::experiments::fuzz::arena_buffer());
+    let mut harness = HarnessConfig::default_zen2()
+        .pinned_core(arg.core)
+        .no_arena_alloc()
+        .arena(layout)
+        .recover_faults(true)
+        .emit();
+
+    let events = EventSet::new_from_slice(&[
+        Zen2Event::LsNotHaltedCyc(0x00),
+        Zen2Event::ExRetCops(0x00),
+        Zen2Event::ExRetBrnMisp(0x00),
+        Zen2Event::LsBadStatus2(LsBadStatus2Mask::UnkWidthMismatch),
+        Zen2Event::LsSTLF(0x00),
+        Zen2Event::MemFileHit(0x00),
+    ]);
+    let opts = FuzzOptions::default();
+
+    match arg.input {
+        FuzzInputKind::Prog => {
+            let corpus = Corpus::open(arg.corpus.join("prog")).unwrap();
+            let mut fuzzer = Fuzzer::new(opts, events, corpus);
+            run(&arg, &mut harness, &mut fuzzer, |rng| PerfectProg::gen(rng, arg.len));
+        },
+        FuzzInputKind::Enc => {
+            let corpus = Corpus::open(arg.corpus.join("enc")).unwrap();
+            let mut fuzzer = Fuzzer::new(opts, events, corpus);
+            run(&arg, &mut harness, &mut fuzzer,
+                |rng| EncodingSeq::gen(rng, arg.len)
+            );
+        },
+    }
+}
+
+fn run<I: FuzzInput>(
+    arg: &Args,
+    harness: &mut PerfectHarness,
+    fuzzer: &mut Fuzzer<Zen2Event, I>,
+    gen: impl Fn(&mut PerfectRng) -> I,
+)
+{
+    let mut rng = perfect_rng();
+    if fuzzer.corpus.is_empty() {
+        for _ in 0..arg.seeds {
+            fuzzer.evaluate(harness, gen(&mut rng)).unwrap();
+        }
+    }
+    println!("[*] Corpus has {} entries ({} features)",
+        fuzzer.corpus.len(), fuzzer.coverage.len()
+    );
+
+    for _ in 0..arg.execs {
+        if let Some(id) = fuzzer.step(harness).unwrap() {
+            let entry = fuzzer.corpus.entries.last().unwrap();
+            println!("[*] exec {:8}: new entry {:06} ({} features): {}",
+                fuzzer.execs, id, fuzzer.coverage.len(), entry.signature
+            );
+        }
+    }
+}
@@ -6,10 +6,15 @@ use perfect::asm::*;
 use perfect::stats::*;
 use rand::prelude::*;
 
+const ARENA_BASE: usize = 0x0000_0020_0000_0000;
+
 fn main() {
+    let layout = ArenaLayout::new(ARENA_BASE)
+        .buffer(MispredictedReturnOptions::<usize>::arena_buffer());
     let mut harness = HarnessConfig::default_zen2()
         .zero_strategy(ZeroStrategy::MovFromZero)
         .arena_alloc(0, 0x2000_0000)
+        .arena(layout)
         .emit();
     IntPrfPressure::run(&mut harness);
 }
@@ -170,7 +175,7 @@ impl IntPrfPressure {
 
         EmitterDesc { desc: "mov r64, imm (random)", 
             func: |f, input| {
-            let mut rng = thread_rng();
+            let mut rng = perfect_rng();
             for i in 0..=input { 
                 let val: i64 = rng.gen_range(
                     0x1000_0000_0000_0000..=0x2000_0000_0000_0000
@@ -191,7 +196,7 @@ impl IntPrfPressure {
 
         EmitterDesc { desc: "cmp r64, imm (random)", 
             func: |f, input| {
-            let mut rng = thread_rng();
+            let mut rng = perfect_rng();
             for i in 0..=input { 
                 let val: i32 = rng.gen_range(
                     0x1000_0000..=0x2000_0000
@@ -213,7 +218,7 @@ impl IntPrfPressure {
 
         EmitterDesc { desc: "lea r64, [rip + imm] (random)", 
             func: |f, input| {
-            let mut rng = thread_rng();
+            let mut rng = perfect_rng();
             for i in 0..=input { 
                 let val: i32 = rng.gen_range(
                     0x1000_0000..=0x2000_0000
@@ -252,6 +257,7 @@ impl IntPrfPressure {
 
         let opts = MispredictedReturnOptions::zen2_defaults()
             .free_pregs(true)
+            .free_pregs_in(harness)
             .prologue_fn(Some(|f, input| { 
                 dynasm!(f; mov rax, 0xdeadbeef; vmovq xmm0, rax)
             }))
@@ -125,7 +125,7 @@ impl Experiment<usize> for MemfileWindow {
     fn emit(idx: usize) -> X64Assembler {
         let mut f = X64Assembler::new().unwrap();
 
-        let mut rng = thread_rng();
+        let mut rng = perfect_rng();
         let mut addrs: Vec<i32> = (0x0000_0008..=0x0000_03f8)
             .step_by(8).collect();
         addrs.shuffle(&mut rng);
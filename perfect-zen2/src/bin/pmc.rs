@@ -32,6 +32,15 @@ pub struct Args {
     #[arg(short, long)]
     all_groups: bool,
 
+    /// Generate test groups from instruction metadata for a 
+    /// comma-separated list of mnemonics (ie. 'add,imul,vpaddq')
+    #[arg(long, value_delimiter = ',')]
+    generate: Vec<String>,
+
+    /// Generate test groups for all instructions supported by this CPU
+    #[arg(long)]
+    generate_all: bool,
+
     /// Target CPU core (#15 by default)
     #[arg(short, long, default_value = "15")]
     core: Option<usize>,
@@ -379,8 +388,20 @@ fn main() {
         create_default_events()
     };
 
+    // The user wants to measure generated instruction groups
+    if arg.generate_all || !arg.generate.is_empty() {
+        let mut gen = InstrFormGenerator::new().host_cpuid();
+        if !arg.generate_all {
+            let mnemonics = Vec::from_iter(arg.generate.iter().map(|s| {
+                mnemonic_from_str(s)
+                    .unwrap_or_else(|| panic!("Unknown mnemonic '{}'", s))
+            }));
+            gen = gen.mnemonics(&mnemonics);
+        }
+        PmcHammer::run_dynamic_groups(&mut harness, event_set, gen.groups())
+    }
     // The user wants to measure all instruction groups
-    if arg.all_groups { 
+    else if arg.all_groups { 
         let all_groups = Vec::from_iter(
             TestGroupId::ALL_GROUPS.iter().map(|x| *x)
         );
@@ -415,6 +436,75 @@ impl PmcHammer {
         EventResults { floor_min, result_min, normalized_min }
     }
 
+    /// Emit and measure a single emitter from some group.
+    fn measure_emitter(harness: &mut PerfectHarness,
+        events: &EventSet<Zen2Event>,
+        floor_fn: MeasuredFn,
+        prologue: Option<fn(&mut X64Assembler)>, 
+        epilogue: Option<fn(&mut X64Assembler)>, 
+        common_measured: Option<fn(&mut X64Assembler)>, 
+        emitter: &dyn Fn(&mut X64Assembler),
+        desc: Option<String>,
+        single: bool,
+    ) -> EmitterResults
+    {
+        // Emit the test
+        let asm = Self::emit(prologue, epilogue, common_measured, emitter);
+        let asm_reader = asm.reader();
+        let asm_tgt_buf = asm_reader.lock();
+        let asm_tgt_ptr = asm_tgt_buf.ptr(AssemblyOffset(0));
+        let asm_fn: MeasuredFn = unsafe { 
+            std::mem::transmute(asm_tgt_ptr)
+        };
+
+        let inner = asm.labels()
+            .resolve_static(&StaticLabel::global("inner"))
+            .unwrap();
+        let inner_start = asm.labels()
+            .resolve_static(&StaticLabel::global("inner_start"))
+            .unwrap();
+        let inner_end = asm.labels()
+            .resolve_static(&StaticLabel::global("inner_end"))
+            .unwrap();
+
+        let disas = disas_chunk(&asm_tgt_buf, inner_start, inner_end);
+        let (istr, bstr) = disas_single(&asm_tgt_buf, inner);
+        let first_inst = disas[0].0.clone();
+
+        // FIXME: Account for presence of a prologue when using the 
+        // disassembly output as the name of an emitter for a single 
+        // instruction
+        let emitter_name = if let Some(d) = desc {
+            d
+        } else { 
+            if single { 
+                istr
+            } else { 
+                "unnamed".to_string()
+            }
+        };
+
+        let mut emitter_results = EmitterResults { 
+            name: emitter_name,
+            disas,
+            by_event: HashMap::new()
+        };
+
+        // For each event, take measurements and collect them
+        for event in events.iter() {
+            let desc = event.as_desc();
+            let event_result = Self::measure_event_for_case(
+                harness, &desc, floor_fn, asm_fn
+            );
+
+            // NOTE: Skip empty results for now
+            if event_result.normalized_min != 0 { 
+                emitter_results.record_for_event(*event, event_result);
+            }
+        }
+        emitter_results
+    }
+
     /// Measure an entire [`TestGroup`].
     fn measure_group(harness: &mut PerfectHarness,
         group: &TestGroup,
@@ -427,9 +517,9 @@ impl PmcHammer {
 
         // Emit a floor measurement for this group. 
         let floor_asm = if let Some(custom_floor) = group.floor {
-            Self::emit(None, None, group.common_measured, custom_floor)
+            Self::emit(None, None, group.common_measured, &custom_floor)
         } else {
-            Self::emit(None, None, group.common_measured, |mut f| {})
+            Self::emit(None, None, group.common_measured, &|mut f| {})
         };
         let rdr = floor_asm.reader();
         let buf = rdr.lock();
@@ -439,69 +529,80 @@ impl PmcHammer {
         };
 
         for emitter in group.emitters {
-            // Emit the test
-            let asm = Self::emit(
-                group.prologue, group.epilogue, group.common_measured, 
-                emitter.func,
+            let emitter_results = Self::measure_emitter(harness, events,
+                floor_fn, group.prologue, group.epilogue, 
+                group.common_measured, &emitter.func, 
+                emitter.desc.map(|d| d.to_string()), emitter.single
             );
-            let asm_reader = asm.reader();
-            let asm_tgt_buf = asm_reader.lock();
-            let asm_tgt_ptr = asm_tgt_buf.ptr(AssemblyOffset(0));
-            let asm_fn: MeasuredFn = unsafe { 
-                std::mem::transmute(asm_tgt_ptr)
-            };
-
-            let inner = asm.labels()
-                .resolve_static(&StaticLabel::global("inner"))
-                .unwrap();
-            let inner_start = asm.labels()
-                .resolve_static(&StaticLabel::global("inner_start"))
-                .unwrap();
-            let inner_end = asm.labels()
-                .resolve_static(&StaticLabel::global("inner_end"))
-                .unwrap();
-
-            let disas = disas_chunk(&asm_tgt_buf, inner_start, inner_end);
-            let (istr, bstr) = disas_single(&asm_tgt_buf, inner);
-            let first_inst = disas[0].0.clone();
-
-            // FIXME: Account for presence of a prologue when using the 
-            // disassembly output as the name of an emitter for a single 
-            // instruction
-            let emitter_name = if let Some(d) = emitter.desc {
-                d.to_string()
-            } else { 
-                if emitter.single { 
-                    istr
-                } else { 
-                    "unnamed".to_string()
-                }
-            };
-
-            let mut emitter_results = EmitterResults { 
-                name: emitter_name,
-                disas,
-                by_event: HashMap::new()
-            };
-
-            // For each event, take measurements and collect them
-            for event in events.iter() {
-                let desc = event.as_desc();
-                let event_result = Self::measure_event_for_case(
-                    harness, &desc, floor_fn, asm_fn
-                );
-
-                // NOTE: Skip empty results for now
-                if event_result.normalized_min != 0 { 
-                    emitter_results.record_for_event(*event, event_result);
-                }
-            }
             res.by_emitter.push(emitter_results);
+        }
+        res
+    }
+
+    /// Measure an entire [`DynamicTestGroup`].
+    fn measure_dynamic_group(harness: &mut PerfectHarness,
+        group: &DynamicTestGroup,
+        events: &EventSet<Zen2Event>,
+    ) -> GroupResults
+    {
+        let mut res = GroupResults { 
+            by_emitter: Vec::new()
+        };
+
+        // Emit a floor measurement for this group. 
+        let floor_asm = if let Some(custom_floor) = group.floor {
+            Self::emit(None, None, group.common_measured, &custom_floor)
+        } else {
+            Self::emit(None, None, group.common_measured, &|mut f| {})
+        };
+        let rdr = floor_asm.reader();
+        let buf = rdr.lock();
+        let ptr = buf.ptr(AssemblyOffset(0));
+        let floor_fn: MeasuredFn = unsafe { 
+            std::mem::transmute(ptr)
+        };
 
+        for emitter in group.emitters.iter() {
+            let emitter_results = Self::measure_emitter(harness, events,
+                floor_fn, group.prologue, group.epilogue, 
+                group.common_measured, &|f| emitter.emit(f), 
+                Some(emitter.desc.clone()), false
+            );
+            res.by_emitter.push(emitter_results);
         }
         res
     }
 
+    /// Print the results for a group.
+    fn print_group_results(group_results: GroupResults) {
+        for emitter_result in group_results.by_emitter {
+            println!("Emitter '{}'", emitter_result.name);
+            for line in emitter_result.disas {
+                println!("  {:<32} {}", line.1, line.0);
+            }
+
+            for (event, result) in emitter_result.by_event.iter()
+                .sorted_by(|x,y| { 
+                    x.0.as_desc().id().cmp(&y.0.as_desc().id())
+                    .then(x.0.as_desc().mask().cmp(&y.0.as_desc().mask())) 
+                }) 
+            {
+                if result.normalized_min == 0 { continue; }
+
+                let desc = event.as_desc();
+                println!("    min={:4} (flr={:4} obs={:4}) {:03x}:{:02x}:{}", 
+                    result.normalized_min,
+                    result.floor_min,
+                    result.result_min,
+                    desc.id(), desc.mask(),
+                    desc.name(),
+                );
+            }
+            println!();
+        }
+        println!();
+    }
+
     fn run_groups(harness: &mut PerfectHarness, 
         events: EventSet<Zen2Event>,
         groups: Vec<TestGroupId>,
@@ -514,33 +615,27 @@ impl PmcHammer {
             println!("=======================================================");
             println!("[*] Running test group: '{}'", group.name);
             let group_results = Self::measure_group(harness, group, &events);
-            for emitter_result in group_results.by_emitter {
-                println!("Emitter '{}'", emitter_result.name);
-                for line in emitter_result.disas {
-                    println!("  {:<32} {}", line.1, line.0);
-                }
-
-                for (event, result) in emitter_result.by_event.iter()
-                    .sorted_by(|x,y| { 
-                        x.0.as_desc().id().cmp(&y.0.as_desc().id())
-                        .then(x.0.as_desc().mask().cmp(&y.0.as_desc().mask())) 
-                    }) 
-                {
-                    if result.normalized_min == 0 { continue; }
-
-                    let desc = event.as_desc();
-                    println!("    min={:4} (flr={:4} obs={:4}) {:03x}:{:02x}:{}", 
-                        result.normalized_min,
-                        result.floor_min,
-                        result.result_min,
-                        desc.id(), desc.mask(),
-                        desc.name(),
-                    );
-                }
-                println!();
-            }
-            println!();
+            Self::print_group_results(group_results);
+        }
+    }
 
+    fn run_dynamic_groups(harness: &mut PerfectHarness, 
+        events: EventSet<Zen2Event>,
+        groups: Vec<DynamicTestGroup>,
+    ) 
+    {
+        for group in groups { 
+            if group.emitters.is_empty() { 
+                continue;
+            }
+            println!("=======================================================");
+            println!("[*] Running test group: '{}' ({} emitters)", 
+                group.name, group.emitters.len()
+            );
+            let group_results = Self::measure_dynamic_group(
+                harness, &group, &events
+            );
+            Self::print_group_results(group_results);
         }
     }
 }
@@ -550,7 +645,7 @@ impl PmcHammer {
         prologue: Option<fn(&mut X64Assembler)>, 
         epilogue: Option<fn(&mut X64Assembler)>, 
         common_measured: Option<fn(&mut X64Assembler)>, 
-        emitter: fn(&mut X64Assembler),
+        emitter: &dyn Fn(&mut X64Assembler),
     ) -> X64Assembler 
     {
         let mut f = X64Assembler::new().unwrap();
@@ -0,0 +1,51 @@
+use perfect::*;
+use perfect::events::*;
+use perfect::experiments::pmcdisc::*;
+use perfect::experiments::ports::*;
+
+/// Infer the execution pipes used by instructions in some `pmcdisc` groups.
+#[derive(Parser)]
+pub struct Args { 
+    /// A comma-separated list of test groups to run.
+    #[arg(long, value_enum, value_delimiter = ',')]
+    groups: Vec<TestGroupId>,
+
+    /// Target CPU core (#15 by default)
+    #[arg(short, long, default_value = "15")]
+    core: Option<usize>,
+}
+
+const ARENA_BASE: usize = 0x0000_0020_0000_0000;
+
+fn main() {
+    let arg = Args::parse();
+    let layout = ArenaLayout::new(ARENA_BASE)
+        .buffer(PortUsageExperiment::arena_buffer());
+    let mut harness = HarnessConfig::default_zen2()
+        .pinned_core(arg.core)
+        .arena(layout)
+        .emit();
+
+    let model = &ZEN2_PORT_MODEL;
+    for group_id in arg.groups {
+        let group = group_id.group();
+        println!("[*] Running test group: '{}'", group.name);
+        for emitter in group.emitters {
+            let res = PortUsageExperiment::run(&mut harness, model,
+                Zen2Event::LsNotHaltedCyc(0x00), group.prologue, emitter
+            );
+            println!("{}", res.to_string(&model.layout));
+            for ((pipes, score), (_, stalls)) in res.scores.iter()
+                .zip(res.stalls.iter())
+            {
+                if *score < PortUsage::THRESHOLD { continue; }
+                println!("  {:<24} {:.2}", pipes.to_string(&model.layout), 
+                    score);
+                for (e, n) in model.stall_events.iter().zip(stalls.iter()) {
+                    println!("    {:<40} {:.2}", (e.event)().name(), n);
+                }
+            }
+        }
+        println!();
+    }
+}
@@ -0,0 +1,50 @@
+use perfect::*;
+use perfect::experiments::ras::*;
+
+fn main() {
+    let args = ExperimentArgs::parse();
+    let platform = args.platform.unwrap_or(TargetPlatform::Zen2);
+    let mut harness = match platform {
+        TargetPlatform::Zen2 => HarnessConfig::default_zen2(),
+        TargetPlatform::Zen3 => HarnessConfig::default_zen3(),
+        TargetPlatform::Tremont => HarnessConfig::default_tremont(),
+    }.emit();
+    ReturnAddressStack::run(&mut harness, platform);
+}
+
+/// Characterize the return address stack (RAS).
+///
+/// Test
+/// ====
+///
+/// Measure mispredicted returns for each [`RasCase`], and find the policies
+/// for a [`perfect::uarch::ras::RasModel`] that agree with the results
+/// (see [`RasExperiment::characterize`]).
+///
+pub struct ReturnAddressStack;
+impl ReturnAddressStack {
+    /// Largest chain of calls that is measured
+    const MAX_DEPTH: usize = 64;
+
+    /// Largest number of entries that the harness might leave behind
+    const MAX_FRAMES: usize = 8;
+
+    fn run(harness: &mut PerfectHarness, platform: TargetPlatform) {
+        let report = RasExperiment::characterize(harness, platform,
+            Self::MAX_DEPTH, Self::MAX_FRAMES
+        );
+
+        println!("[*] {:?}: estimated depth {}", platform, report.depth);
+        for (case, misses) in report.results.iter() {
+            println!("  {:<16} misses={}", format!("{:?}", case), misses);
+        }
+        if report.consistent.is_empty() {
+            println!("[!] No consistent policies");
+        }
+        for (underflow, recovery, frames) in report.consistent.iter() {
+            println!("[*] Consistent: underflow={:?} recovery={:?} frames={}",
+                underflow, recovery, frames
+            );
+        }
+    }
+}
@@ -6,9 +6,14 @@ use rand::prelude::*;
 use std::fs::File;
 use std::io::Write;
 
+const ARENA_BASE: usize = 0x0000_0020_0000_0000;
+
 fn main() {
+    let layout = ArenaLayout::new(ARENA_BASE)
+        .buffer(MispredictedReturnOptions::<usize>::arena_buffer());
     let mut harness = HarnessConfig::default_zen2()
         .zero_strategy(ZeroStrategy::MovFromZero)
+        .arena(layout)
         .emit();
     RenameResources::run(&mut harness);
     MoveElimination::run(&mut harness);
@@ -213,7 +218,7 @@ impl RenameResources {
         EmitterDesc { 
             desc: "Zero idiom random zeroed register", 
             func: |f, input| {
-                let mut rand = thread_rng();
+                let mut rand = perfect_rng();
                 for _ in 0..=input { 
                     let r = Self::ZEROED_REGS.choose(&mut rand).unwrap();
                     dynasm!(f ; xor Rq(*r), Rq(*r));
@@ -245,7 +250,7 @@ impl RenameResources {
         EmitterDesc { 
             desc: "Move from random zeroed register",
             func: |f, input| {
-                let mut rand = thread_rng();
+                let mut rand = perfect_rng();
                 for _ in 0..=input { 
                     let r = Self::ZEROED_REGS.choose(&mut rand).unwrap();
                     dynasm!(f ; mov rax, Rq(*r)); 
@@ -263,7 +268,7 @@ impl RenameResources {
         EmitterDesc { 
             desc: "Move from immediate zero to random",
             func: |f, input| {
-                let mut rand = thread_rng();
+                let mut rand = perfect_rng();
                 for _ in 0..=input { 
                     let r = Self::ZEROED_REGS.choose(&mut rand).unwrap();
                     dynasm!(f ; mov Rq(*r), 0x0); 
@@ -274,7 +279,7 @@ impl RenameResources {
         EmitterDesc { 
             desc: "Move from nonzero register to random",
             func: |f, input| {
-                let mut rand = thread_rng();
+                let mut rand = perfect_rng();
                 for _ in 0..=input { 
                     let r = Self::ZEROED_REGS.choose(&mut rand).unwrap();
                     dynasm!(f ; mov Rq(*r), rsp); 
@@ -292,6 +297,7 @@ impl RenameResources {
         let opts = MispredictedReturnOptions::zen2_defaults()
             .explicit_lfence(true)
             .free_pregs(true)
+            .free_pregs_in(harness)
             .rdpmc_strat(RdpmcStrategy::MemStatic(0x0000_5670));
 
         let mut exp_results = ExperimentResults::new();
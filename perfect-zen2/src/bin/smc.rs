@@ -8,8 +8,8 @@ use perfect::asm::Emitter;
 fn main() {
     let args = ExperimentArgs::parse();
     let mut harness = match HarnessConfig::from_cmdline_args(&args) {
-        Some(cfg) => cfg.emit(),
-        None => HarnessConfig::default_zen2().emit()
+        Some(cfg) => cfg.recover_faults(true).emit(),
+        None => HarnessConfig::default_zen2().recover_faults(true).emit()
     };
 
     //SmcSimple::run(&mut harness);
@@ -73,7 +73,7 @@ pub struct SmcSimple;
 impl SmcSimple {
     fn emit(padding: usize) -> X64AssemblerFixed
     {
-        let mut rng = rand::thread_rng();
+        let mut rng = perfect_rng();
         let mut f = X64AssemblerFixed::new(
             0x0000_1000_0000_0000,
             0x0000_0000_0001_0000,
@@ -233,7 +233,7 @@ pub struct SmcSpeculative;
 impl SmcSpeculative {
     fn emit(padding: usize) -> X64AssemblerFixed
     {
-        let mut rng = rand::thread_rng();
+        let mut rng = perfect_rng();
         let mut f = X64AssemblerFixed::new(
             0x0000_1000_0000_0000,
             0x0000_0000_0001_0000,
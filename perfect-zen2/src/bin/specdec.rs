@@ -15,7 +15,9 @@ use iced_x86::{
 };
 
 fn main() {
-    let mut harness = HarnessConfig::default_zen2().emit();
+    let mut harness = HarnessConfig::default_zen2()
+        .recover_faults(true)
+        .emit();
     //SpeculativeDecodeFuzz::run(&mut harness);
     SpeculativeDecodeExhaustive::<3>::run(&mut harness);
 }
@@ -72,7 +74,7 @@ impl SpeculativeDecodeFuzz {
             .rdpmc_strat(RdpmcStrategy::Gpr(Gpr::R15));
 
         let mut cases = Vec::new();
-        let mut rng = thread_rng();
+        let mut rng = perfect_rng();
 
         // Generate some random-ish x86 instruction encodings
         for _ in 0..4096 { 
@@ -55,7 +55,7 @@ impl StlfStoreQueuePressure {
     const STLF_ADDR: i32 = 0x0100_0000;
 
     fn emit(num_stores: usize) -> X64AssemblerFixed {
-        let mut rng = rand::thread_rng();
+        let mut rng = perfect_rng();
         let mut f = X64AssemblerFixed::new(
             0x0000_1000_0000_0000,
             0x0000_0000_0001_0000,
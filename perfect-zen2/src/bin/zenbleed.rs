@@ -8,12 +8,25 @@ use perfect::stats::*;
 use perfect::*;
 use perfect::events::*;
 
+#[derive(Parser)]
+pub struct Args {
+    /// Compare register state with and without floating-point move 
+    /// elimination (requires access to MSRs)
+    #[arg(long)]
+    diff: bool,
+}
+
 fn main() {
-    let mut harness = HarnessConfig::default_zen2()
+    let arg = Args::parse();
+    let cfg = HarnessConfig::default_zen2()
         .dump_vgpr(true)
         .zero_strategy_fp(ZeroStrategyFp::Vzeroall)
-        .zero_strategy(ZeroStrategy::MovFromZero)
-        .emit();
+        .zero_strategy(ZeroStrategy::MovFromZero);
+    if arg.diff {
+        Zenbleed::run_diff(cfg);
+        return;
+    }
+    let mut harness = cfg.emit();
     Zenbleed::run(&mut harness);
 }
 
@@ -238,6 +251,59 @@ impl Zenbleed {
     }
 }
 
+impl Zenbleed {
+    /// Run the test with and without floating-point move elimination, and 
+    /// report which registers diverged. 
+    ///
+    /// The bug depends on move elimination, so we expect divergence in the 
+    /// upper half of `TGT_YMM` (and nowhere else). 
+    fn run_diff(cfg: HarnessConfig) {
+        let event = Zen2Event::DeDisOpsFromDecoder(DeDisOpsFromDecoderMask::Fp);
+        let core = cfg.pinned_core.unwrap_or(15);
+
+        let asm = Self::emit();
+        let asm_reader = asm.reader();
+        let asm_tgt_buf = asm_reader.lock();
+        let asm_tgt_ptr = asm_tgt_buf.ptr(AssemblyOffset(0));
+        let asm_fn: MeasuredFn = unsafe { 
+            std::mem::transmute(asm_tgt_ptr)
+        };
+
+        // Put the MSR back the way we found it after each side of the test
+        let orig = PerfectEnv::fp_mov_elim(core).unwrap();
+        let restore = move |_: &mut PerfectHarness| {
+            PerfectEnv::toggle_fp_mov_elim(core, orig)
+        };
+
+        let test = DiffTest::new(
+            DiffConfig::new("fp_mov_elim=1", cfg.clone(), asm_fn)
+                .setup(move |_| PerfectEnv::toggle_fp_mov_elim(core, true))
+                .teardown(restore),
+            DiffConfig::new("fp_mov_elim=0", cfg, asm_fn)
+                .setup(move |_| PerfectEnv::toggle_fp_mov_elim(core, false))
+                .teardown(restore),
+        ).diff(StateDiff::new()
+            // Used for reading the counters
+            .ignore_gpr(Gpr::Rax)
+            .ignore_gpr(Gpr::Rcx)
+            .ignore_gpr(Gpr::Rdx)
+            .ignore_gpr(Gpr::R15)
+        );
+
+        let report = match test.run(&event.as_desc(), 16384,
+            InputMethod::Fixed(0, 0))
+        {
+            Ok((_, _, report)) => report,
+            Err(e) => {
+                println!("[!] {}", e);
+                return;
+            },
+        };
+        println!("[*] {} vs. {}:", test.a.desc, test.b.desc);
+        print!("{}", report);
+    }
+}
+
 /// These are various strategies for preparing values that we expect to be 
 /// leaked with the gadget. 
 impl Zenbleed {
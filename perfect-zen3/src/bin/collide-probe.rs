@@ -5,7 +5,8 @@ use rand::distributions::Uniform;
 use std::collections::*;
 
 use perfect::stats::{ RawResults, ResultList };
-use perfect::uarch::l1d::ZEN2_L1D_UTAG_FN;
+use perfect::uarch::Zen3;
+use perfect::sidechannel::CollideProbe;
 use perfect_zen3::{ Victim, VictimMsg };
 
 pub struct TestResults { 
@@ -39,106 +40,8 @@ impl TestResults {
 }
 
 
-#[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq)]
-pub struct VirtualAddress(pub usize);
-impl VirtualAddress {
-    /// Bits [5:0] map to an offset within the cache line
-    const OFFSET_MASK: usize = 0x0000_0000_0000_003f;
-    /// Bits [11:6] map to a set in the L1 data cache
-    const SET_MASK: usize    = 0x0000_0000_0000_0fc0;
-    /// According to the "Take A Way" paper, bits [27:12] constitute input to the utag
-    const UTAG_MASK: usize   = 0x0000_0000_0fff_f000;
-    /// High bits
-    const HI_MASK : usize    = 0x0000_ffff_f000_0000;
-
-    pub fn with_offset(self, off: usize) -> Self { 
-        Self(
-            (self.0 & !Self::OFFSET_MASK) | ((off & 0b111111))
-        )
-    }
-    pub fn with_set(self, set: usize) -> Self { 
-        Self(
-            (self.0 & !Self::SET_MASK) | ((set & 0b111111) << 6)
-        )
-    }
-    pub fn with_utag_input(self, input: usize) -> Self { 
-        Self(
-            (self.0 & !Self::UTAG_MASK) | ((input & 0xffff) << 12)
-        )
-    }
-
-
-    /// Return the set index bits
-    pub fn set(&self) -> usize {
-        (self.0 & Self::SET_MASK) >> 6
-    }
-
-    /// Return the offset bits
-    pub fn offset(&self) -> usize {
-        self.0 & Self::OFFSET_MASK
-    }
-
-    /// Return the micro-tag input bits
-    pub fn utag_input(&self) -> usize {
-        (self.0 & Self::UTAG_MASK) >> 12
-    }
-
-    pub fn utag(&self) -> usize { 
-        ZEN2_L1D_UTAG_FN.evaluate(self.0)
-    }
-
-    /// Return the 64-bit virtual address as a [`usize`]
-    pub fn value(&self) -> usize { 
-        self.0
-    }
-
-    /// Create a new virtual address from the given offset, set index, 
-    /// micro-tag input bits, and high bits. 
-    pub fn new(offset: usize, set: usize, utag_input: usize, hi_bits: usize) -> Self {
-        let offset = offset & 0x3f;
-        let set = set & 0b0011_1111;
-        let utag_input = utag_input & 0xffff;
-        Self(offset | set << 6 | utag_input << 12 | hi_bits << 28)
-    }
-
-    // Generate the set of all 256 addresses whose micro-tags are colliding
-    // with the micro-tag for this address.
-    pub fn generate_collisions(&self) -> Vec<Self> {
-        let mut res = Vec::new();
-        let map = Self::compute_utag_map();
-        let inputs = map.get(&self.utag()).unwrap();
-        for input in inputs {
-            res.push(VirtualAddress::new(0b000000, 0b000000, *input, 0));
-        }
-        res
-    }
-
-    // Generate a random address whose micro-tag is colliding with the 
-    // micro-tag for this address. 
-    pub fn random_collision(&self) -> Self { 
-        let mut rng = thread_rng();
-        let colls = self.generate_collisions();
-        let x = rng.gen_range(0..256);
-        colls[x]
-    }
-
-    /// Build a map of all possible micro-tags and their associated input bits.
-    pub fn compute_utag_map() -> BTreeMap<usize, BTreeSet<usize>> {
-        let mut map: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
-
-        for input in (0x0000..=0x0ffffusize) {
-            let utag = ZEN2_L1D_UTAG_FN.evaluate(input << 12);
-            if let Some(inputs) = map.get_mut(&utag) {
-                inputs.insert(input);
-            } else { 
-                let mut s = BTreeSet::new();
-                s.insert(input);
-                map.insert(utag, s);
-            }
-        }
-        map
-    }
-}
+/// Addresses on Zen 3 (see [`perfect::uarch::Zen3`]).
+type VirtualAddress = perfect::uarch::vaddr::VirtualAddress<Zen3>;
 
 /// Demonstrate the Collide+Probe side-channel. 
 ///
@@ -225,27 +128,6 @@ impl CollideAndProbe {
     /// Number of test iterations for each candidate. 
     const ITERS: usize = 128;
 
-    /// Emit the function used to measure the colliding load. 
-    fn emit_measure() -> X64AssemblerFixed { 
-        let mut f = X64AssemblerFixed::new(0x4000_0000, 0x0001_0000);
-        f.emit_aperf_start(Gpr::Rsi as u8);
-        dynasm!(f ; mov rax, [rdi]);
-        f.emit_aperf_end(Gpr::Rsi as u8, Gpr::Rax as u8);
-        f.emit_ret();
-        f.commit().unwrap();
-        f
-    }
-
-    fn emit_measure_floor() -> X64AssemblerFixed { 
-        let mut f = X64AssemblerFixed::new(0x4100_0000, 0x0001_0000);
-        f.emit_aperf_start(Gpr::Rsi as u8);
-        f.emit_aperf_end(Gpr::Rsi as u8, Gpr::Rax as u8);
-        f.emit_ret();
-        f.commit().unwrap();
-        f
-    }
-
-
     /// Run the Collide+Probe test where: 
     ///
     /// - 'kernel_vaddr' is the address of the kernel load
@@ -254,19 +136,12 @@ impl CollideAndProbe {
     fn run_collide_and_probe(
         harness: &mut PerfectHarness,
         victim: &mut Victim,
-        probe_func: MeasuredFn,
-        floor_func: MeasuredFn,
+        probe: &mut CollideProbe,
         user_vaddr: VirtualAddress,
         kernel_vaddr: VirtualAddress,
     ) -> RawResults 
     { 
-        let mut floor_results = RawResults(vec![0; Self::ITERS]);
-        for i in 0..Self::ITERS { 
-            let t = (floor_func)(0, 0);
-            floor_results.0[i] = t;
-        }
-        let floor = floor_results.get_min();
-
+        probe.calibrate_floor();
         let user_ptr = user_vaddr.0 as *const u32;
         let kernel_offset = (kernel_vaddr.0 & 0xfff) as i32;
 
@@ -277,9 +152,7 @@ impl CollideAndProbe {
                 let _ = core::ptr::read_volatile(user_ptr);
                 core::arch::x86_64::_mm_mfence();
                 victim.ping(kernel_offset);
-                let t = (probe_func)(user_ptr as usize, 0);
-                //let t = harness.call(user_ptr as usize, 0, probe_func);
-                results.0[i] = (t - floor);
+                results.0[i] = probe.measure(user_ptr as usize);
             }
         }
         results
@@ -290,13 +163,12 @@ impl CollideAndProbe {
     fn run_aliasing_test(
         harness: &mut PerfectHarness,
         victim: &mut Victim,
-        probe_func: MeasuredFn,
-        floor_func: MeasuredFn,
+        probe: &mut CollideProbe,
         kernel_base_vaddr: VirtualAddress,
     ) -> TestResults
     {
         // Pick a random colliding address
-        let user_base_vaddr = kernel_base_vaddr.random_collision();
+        let user_base_vaddr = kernel_base_vaddr.random_collision(&mut harness.rng);
         // Pick a random cache set used by both loads
         let set = harness.rng.gen_range(0..64);
 
@@ -305,16 +177,15 @@ impl CollideAndProbe {
             user_base_vaddr.0, 0x4000
         );
 
-        let user_vaddr   = VirtualAddress(user_base_vaddr.0)
+        let user_vaddr   = VirtualAddress::from(user_base_vaddr.0)
             .with_set(set);
-        let kernel_vaddr = VirtualAddress(kernel_base_vaddr.0)
+        let kernel_vaddr = VirtualAddress::from(kernel_base_vaddr.0)
             .with_set(set);
 
         let results = Self::run_collide_and_probe(
             harness, 
             victim, 
-            probe_func,
-            floor_func,
+            probe,
             user_vaddr,
             kernel_vaddr,
         );
@@ -331,13 +202,12 @@ impl CollideAndProbe {
     fn run_aliasing_cross_set_test(
         harness: &mut PerfectHarness,
         victim: &mut Victim,
-        probe_func: MeasuredFn,
-        floor_func: MeasuredFn,
+        probe: &mut CollideProbe,
         kernel_base_vaddr: VirtualAddress,
     ) -> TestResults
     {
         // Pick a random colliding address
-        let user_base_vaddr = kernel_base_vaddr.random_collision();
+        let user_base_vaddr = kernel_base_vaddr.random_collision(&mut harness.rng);
 
         // Pick two random *distinct* cache sets
         let user_set = harness.rng.gen_range(0..64);
@@ -348,16 +218,15 @@ impl CollideAndProbe {
             user_base_vaddr.0, 0x4000
         );
 
-        let user_vaddr   = VirtualAddress(user_base_vaddr.0)
+        let user_vaddr   = VirtualAddress::from(user_base_vaddr.0)
             .with_set(user_set);
-        let kernel_vaddr = VirtualAddress(kernel_base_vaddr.0)
+        let kernel_vaddr = VirtualAddress::from(kernel_base_vaddr.0)
             .with_set(kernel_set);
 
         let results = Self::run_collide_and_probe(
             harness, 
             victim, 
-            probe_func,
-            floor_func,
+            probe,
             user_vaddr,
             kernel_vaddr,
         );
@@ -374,8 +243,7 @@ impl CollideAndProbe {
     fn run_nonaliasing_cross_set_test(
         harness: &mut PerfectHarness,
         victim: &mut Victim,
-        probe_func: MeasuredFn,
-        floor_func: MeasuredFn,
+        probe: &mut CollideProbe,
         kernel_base_vaddr: VirtualAddress,
     ) -> TestResults
     {
@@ -384,7 +252,7 @@ impl CollideAndProbe {
         let kernel_set = user_set ^ 0b111111; 
 
         let input_bits = harness.rng.gen_range(0x0000..=0xffff);
-        let user_base_vaddr = VirtualAddress(0x1dea_0000_0000)
+        let user_base_vaddr = VirtualAddress::from(0x1dea_0000_0000)
             .with_utag_input(input_bits);
 
         // Map the colliding address into our process' address space
@@ -392,16 +260,15 @@ impl CollideAndProbe {
             user_base_vaddr.0, 0x4000
         );
 
-        let user_vaddr   = VirtualAddress(user_base_vaddr.0)
+        let user_vaddr   = VirtualAddress::from(user_base_vaddr.0)
             .with_set(user_set);
-        let kernel_vaddr = VirtualAddress(kernel_base_vaddr.0)
+        let kernel_vaddr = VirtualAddress::from(kernel_base_vaddr.0)
             .with_set(kernel_set);
 
         let results = Self::run_collide_and_probe(
             harness, 
             victim, 
-            probe_func,
-            floor_func,
+            probe,
             user_vaddr,
             kernel_vaddr,
         );
@@ -417,8 +284,7 @@ impl CollideAndProbe {
     fn run_nonaliasing_test(
         harness: &mut PerfectHarness,
         victim: &mut Victim,
-        probe_func: MeasuredFn,
-        floor_func: MeasuredFn,
+        probe: &mut CollideProbe,
         kernel_base_vaddr: VirtualAddress,
     ) -> TestResults
     {
@@ -427,7 +293,7 @@ impl CollideAndProbe {
         let kernel_set = user_set;
 
         let input_bits = harness.rng.gen_range(0x0000..=0xffff);
-        let user_base_vaddr = VirtualAddress(0x1dea_0000_0000)
+        let user_base_vaddr = VirtualAddress::from(0x1dea_0000_0000)
             .with_utag_input(input_bits);
 
 
@@ -436,16 +302,15 @@ impl CollideAndProbe {
             user_base_vaddr.0, 0x4000
         );
 
-        let user_vaddr   = VirtualAddress(user_base_vaddr.0)
+        let user_vaddr   = VirtualAddress::from(user_base_vaddr.0)
             .with_set(user_set);
-        let kernel_vaddr = VirtualAddress(kernel_base_vaddr.0)
+        let kernel_vaddr = VirtualAddress::from(kernel_base_vaddr.0)
             .with_set(kernel_set);
 
         let results = Self::run_collide_and_probe(
             harness, 
             victim, 
-            probe_func,
-            floor_func,
+            probe,
             user_vaddr,
             kernel_vaddr,
         );
@@ -461,16 +326,15 @@ impl CollideAndProbe {
 
 
 
-    fn run(harness: &mut PerfectHarness) {
+    fn run(harness: &mut PerfectHarness, timer: Timer) {
         // Open a handle to the victim kernel module
         let mut victim = Victim::open();
 
         // Emit gadget for measuring our access
-        let probe_func = Self::emit_measure();
-        let floor_func = Self::emit_measure_floor();
+        let mut probe = CollideProbe::new(timer);
 
         // Find the virtual address of the page allocated by the kernel
-        let kernel_base_vaddr = VirtualAddress(victim.scratch_page());
+        let kernel_base_vaddr = VirtualAddress::from(victim.scratch_page());
         println!("Kernel page @ {:016x}, utag={:08b}", 
             kernel_base_vaddr.0,
             kernel_base_vaddr.utag(),
@@ -480,8 +344,7 @@ impl CollideAndProbe {
         let res = Self::run_aliasing_test(
             harness, 
             &mut victim, 
-            probe_func.as_fn(), 
-            floor_func.as_fn(),
+            &mut probe,
             kernel_base_vaddr
         );
         res.print();
@@ -489,8 +352,7 @@ impl CollideAndProbe {
         let res = Self::run_aliasing_cross_set_test(
             harness, 
             &mut victim, 
-            probe_func.as_fn(), 
-            floor_func.as_fn(),
+            &mut probe,
             kernel_base_vaddr
         );
         res.print();
@@ -498,8 +360,7 @@ impl CollideAndProbe {
         let res = Self::run_nonaliasing_test(
             harness, 
             &mut victim, 
-            probe_func.as_fn(), 
-            floor_func.as_fn(),
+            &mut probe,
             kernel_base_vaddr
         );
         res.print();
@@ -507,8 +368,7 @@ impl CollideAndProbe {
         let res = Self::run_nonaliasing_cross_set_test(
             harness, 
             &mut victim, 
-            probe_func.as_fn(), 
-            floor_func.as_fn(),
+            &mut probe,
             kernel_base_vaddr
         );
         res.print();
@@ -521,11 +381,12 @@ impl CollideAndProbe {
 
 
 fn main() {
+    let args = ExperimentArgs::parse();
     let mut harness = HarnessConfig::default_zen2()
         .pinned_core(Some(5))
         .emit();
 
-    CollideAndProbe::run(&mut harness);
+    CollideAndProbe::run(&mut harness, args.timer.unwrap_or(Timer::APERF));
 
 }
 
@@ -9,6 +9,8 @@ use perfect::stats::{ RawResults, ResultList };
 fn main() {
     let mut harness = HarnessConfig::default_zen3()
         .pinned_core(Some(5))
+        .recover_faults(true)
+        .init_state(InitState::new().gpr(Div::DIVISOR, 1))
         .emit();
     Div::run(&mut harness);
 }
@@ -46,14 +48,14 @@ impl Input {
 
 // Purely random inputs
 impl Input { 
-    fn new_random(rng: &mut ThreadRng) -> Self { 
+    fn new_random(rng: &mut PerfectRng) -> Self { 
         Self { 
             rdx: rng.gen(),
             rax: rng.gen(),
             div: rng.gen(),
         }
     }
-    fn new_random_valid(rng: &mut ThreadRng) -> Self { 
+    fn new_random_valid(rng: &mut PerfectRng) -> Self { 
         loop {
             let res = Self::new_random(rng);
             if res.is_valid() { return res; }
@@ -63,7 +65,7 @@ impl Input {
 
 // Random inputs constrained to a certain bit-width. 
 impl Input { 
-    fn new_random_bits(rng: &mut ThreadRng, 
+    fn new_random_bits(rng: &mut PerfectRng, 
         dividend_bits: usize,
         divisor_bits: usize,
     ) -> Self 
@@ -88,7 +90,7 @@ impl Input {
         Self { rdx, rax, div }
     }
 
-    fn new_random_bits_valid(rng: &mut ThreadRng, 
+    fn new_random_bits_valid(rng: &mut PerfectRng, 
         dividend_bits: usize,
         divisor_bits: usize,
     ) -> Self 
@@ -101,13 +103,13 @@ impl Input {
 }
 
 impl Input { 
-    fn new_random_nbits(rng: &mut ThreadRng,
+    fn new_random_nbits(rng: &mut PerfectRng,
         num_rdx_bits: usize,
         num_rax_bits: usize,
         num_div_bits: usize,
     ) -> Self 
     { 
-        fn gen_bits(rng: &mut ThreadRng, num: usize) -> HashSet<usize> {
+        fn gen_bits(rng: &mut PerfectRng, num: usize) -> HashSet<usize> {
             let mut set = HashSet::new();
             while set.len() < num {
                 set.insert(rng.gen_range(0..64));
@@ -130,7 +132,7 @@ impl Input {
         Self { rdx, rax, div }
     }
 
-    fn new_random_nbits_valid(rng: &mut ThreadRng, 
+    fn new_random_nbits_valid(rng: &mut PerfectRng, 
         num_rdx_bits: usize,
         num_rax_bits: usize,
         num_div_bits: usize,
@@ -165,6 +167,9 @@ impl Div {
     /// Number of tests to run on a single set of inputs
     const ITERS: usize = 64;
 
+    /// Register holding the divisor (set with [`InitState`]) 
+    const DIVISOR: Gpr = Gpr::R9;
+
     /// Run some tests
     fn run(harness: &mut PerfectHarness) {
         // Single-bit inputs
@@ -177,21 +182,18 @@ impl Div {
         //Self::run_nbit_inputs(harness);
     }
 
-    /// Emitter measuring a DIV instruction
-    fn emit_div(divisor: i64) -> X64AssemblerFixed {
+    /// Emitter measuring a DIV instruction (see [`Div::DIVISOR`])
+    fn emit_div() -> X64AssemblerFixed {
         let mut f = X64AssemblerFixed::new(0x4000_0000, 0x0001_0000);
-        dynasm!(f
-            ; mov r9, QWORD divisor
-        );
-        f.emit_aperf_start(Gpr::R8 as u8);
+        f.emit_timer_start(Timer::APERF, Gpr::R8 as u8);
         dynasm!(f
             ; mov rdx, rdi 
             ; mov rax, rsi
-            ; div r9
+            ; div Rq(Self::DIVISOR as u8)
             ; xor rax, rax
             ; xor rdx, rdx
         );
-        f.emit_aperf_end(Gpr::R8 as u8, Gpr::Rax as u8);
+        f.emit_timer_end(Timer::APERF, Gpr::R8 as u8, Gpr::Rax as u8);
 
         f.emit_ret();
         f.commit().unwrap();
@@ -201,27 +203,36 @@ impl Div {
     /// Emitter measuring the APERF floor
     fn emit_floor() -> X64AssemblerFixed {
         let mut f = X64AssemblerFixed::new(0x4000_0000, 0x0001_0000);
-        f.emit_aperf_start(Gpr::R8 as u8);
-        f.emit_aperf_end(Gpr::R8 as u8, Gpr::Rax as u8);
+        f.emit_timer_start(Timer::APERF, Gpr::R8 as u8);
+        f.emit_timer_end(Timer::APERF, Gpr::R8 as u8, Gpr::Rax as u8);
         f.emit_ret();
         f.commit().unwrap();
         f
     }
 
     /// Run a test with the given inputs. 
+    ///
+    /// Returns [`None`] if the inputs caused a fault. 
     fn run_test(
         harness: &mut PerfectHarness,
         floor: usize,
         inp: Input,
-    ) -> TestResult 
+    ) -> Option<TestResult>
     {
-        let div_func = Self::emit_div(inp.div);
-        let mut raw = RawResults(vec![0; Self::ITERS]);
-        for idx in 0..Self::ITERS { 
-            let t = harness.call(inp.rdx, inp.rax, div_func.as_fn());
-            raw.0[idx] = t - floor;
+        harness.set_init_gpr(Self::DIVISOR, inp.div as usize);
+        let div_func = Self::emit_div();
+        let mut raw = RawResults(Vec::new());
+        for _ in 0..Self::ITERS { 
+            // Inputs with an out-of-range quotient raise #DE
+            match harness.try_call(inp.rdx, inp.rax, div_func.as_fn()) {
+                Ok(t) => raw.0.push(t - floor),
+                Err(fault) => {
+                    println!("[!] {:016x?}: {}", inp, fault);
+                    return None;
+                },
+            }
         }
-        TestResult { inp, mode: raw.get_mode() }
+        Some(TestResult { inp, mode: raw.get_mode() })
     }
 
     /// Measure the floor (associated with the use of RDPRU/APERF). 
@@ -282,7 +293,9 @@ impl Div {
         let mut log: BTreeMap<usize, BTreeSet<Input>> = BTreeMap::new();
 
         for inp in inputs { 
-            let res = Self::run_test(harness, floor, inp);
+            let Some(res) = Self::run_test(harness, floor, inp) else {
+                continue;
+            };
             if let Some(cnt) = hist.get_mut(&res.mode) {
                 *cnt += 1;
             } else { 
@@ -343,7 +356,9 @@ impl Div {
         loop {
             //let inp = Input::new_random_valid(&mut harness.rng);
             let inp = Input::new_random_bits_valid(&mut harness.rng, 31, 30);
-            let res = Self::run_test(harness, floor, inp);
+            let Some(res) = Self::run_test(harness, floor, inp) else {
+                continue;
+            };
 
             if let Some(cnt) = hist.get_mut(&res.mode) {
                 *cnt += 1;
@@ -421,7 +436,9 @@ impl Div {
                 },
             }
 
-            let res = Self::run_test(harness, floor, inp);
+            let Some(res) = Self::run_test(harness, floor, inp) else {
+                continue;
+            };
 
             if let Some(cnt) = hist.get_mut(&res.mode) {
                 *cnt += 1;
@@ -1,3 +1,5 @@
+pub mod iced;
+pub use iced::*;
 
 use dynasmrt::{
     DynasmApi,
@@ -29,6 +31,7 @@ use rand::distributions::{ Distribution, Standard };
 
 use crate::MeasuredFn;
 use crate::HarnessFn;
+use crate::timer::{ Timer, TimerSource, FencePolicy };
 
 /// Fallback/default assembler from [dynasmrt]. 
 pub type X64Assembler = Assembler<X64Relocation>;
@@ -701,78 +704,85 @@ pub trait Emitter: DynasmLabelApi<Relocation=X64Relocation> {
         dynasm!(self ; nop ; fnop ; fnop ; fnop ; fnop);
     }
 
-    fn emit_rdtsc_start(&mut self, scratch: u8) {
-        dynasm!(self 
-            ; lfence
-            ; rdtsc
-            ; lfence
-            ; xor Rq(scratch), Rq(scratch)
-            ; sub Rq(scratch), rax
-            ; xor rax, rax
-            ; xor rdx, rdx
-            ; lfence
-        );
-    }
-
-    fn emit_rdtsc_end(&mut self, scratch: u8, result: u8) {
-        dynasm!(self 
-            ; lfence
-            ; rdtsc
-            ; lfence
-            ; add Rq(result), Rq(scratch)
-            ; lfence
+    /// Emit CPUID as a serializing barrier (clobbers RAX, RCX, and RDX).
+    fn emit_cpuid_fence(&mut self) {
+        dynasm!(self
+            ; push rbx
+            ; xor eax, eax
+            ; cpuid
+            ; pop rbx
         );
     }
 
-    fn emit_aperf_start(&mut self, scratch: u8) {
-        dynasm!(self
-            ; lfence
-            ; mov rcx, 1 
-            ; lfence
-            ; .bytes [0x0f, 0x01, 0xfd] // RDPRU
-            ; lfence
-            ; mov Rq(scratch), rax
-            ; lfence
-        );
+    /// Emit a barrier (clobbers RAX, RCX, and RDX when using CPUID).
+    fn emit_fence(&mut self, fence: FencePolicy) {
+        match fence {
+            FencePolicy::None => {},
+            FencePolicy::Lfence => self.emit_lfence(),
+            FencePolicy::Mfence => {
+                self.emit_mfence();
+                self.emit_lfence();
+            },
+            FencePolicy::Cpuid => self.emit_cpuid_fence(),
+        }
     }
 
-    fn emit_aperf_end(&mut self, scratch: u8, result: u8) {
+    /// Read a [`TimerSource`] into RAX (clobbers RCX and RDX).
+    ///
+    /// NOTE: 'dynasm-rs' doesn't support RDPRU yet.
+    fn emit_timer_read(&mut self, source: TimerSource) {
+        match source {
+            TimerSource::Rdtsc => dynasm!(self ; rdtsc),
+            TimerSource::Rdtscp => dynasm!(self ; rdtscp),
+            TimerSource::Aperf => dynasm!(self
+                ; mov ecx, 1
+                ; .bytes [0x0f, 0x01, 0xfd] // RDPRU
+            ),
+            TimerSource::Mperf => dynasm!(self
+                ; xor ecx, ecx
+                ; .bytes [0x0f, 0x01, 0xfd] // RDPRU
+            ),
+            TimerSource::Rdpmc(ctr) => dynasm!(self
+                ; mov ecx, ctr as i32
+                ; rdpmc
+            ),
+        }
         dynasm!(self
-            ; lfence
-            ; mov rcx, 1 
-            ; lfence
-            ; .bytes [0x0f, 0x01, 0xfd] // RDPRU
-            ; lfence
-            ; sub rax, Rq(scratch)
+            ; shl rdx, 32
+            ; or rax, rdx
         );
-        if result != Gpr::Rax as u8 {
-            dynasm!(self ; mov Rq(result), rax);
-        }
-        dynasm!(self ; lfence);
     }
 
-    fn emit_aperf_nofence_start(&mut self, scratch: u8) {
-        dynasm!(self
-            ; mov rcx, 1 
-            ; .bytes [0x0f, 0x01, 0xfd] // RDPRU
-            ; mov Rq(scratch), rax
+    /// Start a measurement with some [`Timer`], leaving the initial value
+    /// in a scratch register which must live until the matching call to
+    /// `emit_timer_end`.
+    ///
+    /// RAX, RCX, RDX, and the scratch register are clobbered (the scratch
+    /// register cannot be one of these, or RBX).
+    fn emit_timer_start(&mut self, timer: Timer, scratch: u8) {
+        assert!(![Gpr::Rax, Gpr::Rbx, Gpr::Rcx, Gpr::Rdx].iter()
+            .any(|r| *r as u8 == scratch),
+            "Invalid scratch register for timer"
         );
+        self.emit_fence(timer.fence);
+        self.emit_timer_read(timer.source);
+        dynasm!(self ; mov Rq(scratch), rax);
+        self.emit_fence(timer.fence);
     }
 
-    fn emit_aperf_nofence_end(&mut self, scratch: u8, result: u8) {
+    /// End a measurement started with `emit_timer_start`, placing the
+    /// elapsed count in the result register.
+    fn emit_timer_end(&mut self, timer: Timer, scratch: u8, result: u8) {
+        self.emit_fence(timer.fence);
+        self.emit_timer_read(timer.source);
         dynasm!(self
-            ; mov rcx, 1 
-            ; .bytes [0x0f, 0x01, 0xfd] // RDPRU
             ; sub rax, Rq(scratch)
+            ; mov Rq(scratch), rax
         );
-        if result != Gpr::Rax as u8 { 
-            dynasm!(self
-                ; mov Rq(result), rax
-            );
-        }
+        self.emit_fence(timer.fence);
+        dynasm!(self ; mov Rq(result), Rq(scratch));
     }
 
-
     /// Start a measurement by emitting RDPMC, then moving the result into 
     /// some scratch register which is expected to live at least until 
     /// the second measurement (which must be emitted with `emit_rdpmc_end`).
@@ -785,6 +795,8 @@ pub trait Emitter: DynasmLabelApi<Relocation=X64Relocation> {
     /// later if you want to measure with multiple counters). 
     ///
     /// NOTE: This block of code is 0x18 bytes. 
+    /// Use [`Emitter::emit_timer_start`] with [`TimerSource::Rdpmc`] instead
+    /// if you need the full 64-bit counter value or a different fence policy.
     ///
     /// NOTE: This [presumably] allocates two physical registers: one for RCX, 
     /// and one for the result of RDPMC in RAX.
@@ -828,33 +840,6 @@ pub trait Emitter: DynasmLabelApi<Relocation=X64Relocation> {
         );
     }
 
-    fn emit_rdpmc_start64(&mut self, counter: i32, scratch: u8) {
-        dynasm!(self
-            ; lfence
-            ; mov rcx, counter
-            ; lfence
-            ; rdpmc
-            ; lfence
-            ; shl rdx, 32
-            ; or rax, rdx
-            ; mov Rq(scratch), rax
-            ; lfence
-        )
-    }
-    fn emit_rdpmc_end64(&mut self, counter: i32, scratch: u8, result: u8) {
-        dynasm!(self 
-            ; lfence
-            ; mov rcx, counter
-            ; lfence
-            ; rdpmc
-            ; lfence
-            ; shl rdx, 32
-            ; or rax, rdx
-            ; sub rax, Rq(scratch)
-            ; mov Rq(result), rax
-            ; lfence
-        );
-    }
 
     fn emit_flush_btb(&mut self, iter: usize) {
         for _ in 0..iter { 
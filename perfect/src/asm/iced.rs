@@ -0,0 +1,128 @@
+//! Emitting code from [`iced_x86::Instruction`].
+//!
+//! Code emitted with `dynasm!` is fixed at compile-time: you can vary the
+//! registers and immediates, but not the instruction itself. Since an
+//! [`Instruction`] is just a value, this makes it possible to emit code that
+//! has been generated at runtime (ie. from a table of instruction forms).
+//!
+//! Instruction Pointers
+//! ====================
+//!
+//! The encoder needs to know the address of an instruction in order to
+//! compute relative branch targets and RIP-relative memory operands.
+//! See [`IcedEmitter::iced_ip`]:
+//!
+//! - [`X64AssemblerFixed`] uses the actual virtual address
+//! - [`X64Assembler`] uses the offset into the assembler, since the backing
+//!   memory may move before the code is finalized
+//!
+//! In the latter case, branch targets must *also* be expressed as offsets
+//! into the assembler.
+
+use iced_x86::{
+    Instruction, Encoder, BlockEncoder, BlockEncoderOptions,
+    InstructionBlock, IcedError,
+};
+use dynasmrt::DynasmApi;
+use crate::asm::{ Emitter, X64Assembler, X64AssemblerFixed };
+
+/// Utility functions for emitting [`Instruction`] on something implementing
+/// [`Emitter`].
+pub trait IcedEmitter: Emitter {
+    /// Return the instruction pointer used to encode the next instruction.
+    fn iced_ip(&self) -> u64;
+
+    /// Encode and emit a single instruction.
+    /// Returns the number of emitted bytes.
+    fn emit_iced(&mut self, instr: &Instruction) -> Result<usize, IcedError> {
+        let mut enc = Encoder::new(64);
+        let len = enc.encode(instr, self.iced_ip())?;
+        self.extend(enc.take_buffer());
+        Ok(len)
+    }
+
+    /// Encode and emit a single instruction `n` times.
+    /// Returns the number of emitted bytes.
+    fn emit_iced_repeat(&mut self, instr: &Instruction, n: usize)
+        -> Result<usize, IcedError>
+    {
+        let mut len = 0;
+        for _ in 0..n {
+            len += self.emit_iced(instr)?;
+        }
+        Ok(len)
+    }
+
+    /// Encode and emit a block of instructions.
+    /// Returns the number of emitted bytes.
+    ///
+    /// Unlike [`IcedEmitter::emit_iced`], branches between instructions in
+    /// the block are resolved by [`BlockEncoder`]: a branch whose target is
+    /// the [`Instruction::ip`] of another instruction in the block is
+    /// fixed up after all instructions have been placed.
+    fn emit_iced_block(&mut self, instrs: &[Instruction])
+        -> Result<usize, IcedError>
+    {
+        let block = InstructionBlock::new(instrs, self.iced_ip());
+        let res = BlockEncoder::encode(64, block, BlockEncoderOptions::NONE)?;
+        let len = res.code_buffer.len();
+        self.extend(res.code_buffer);
+        Ok(len)
+    }
+}
+
+impl IcedEmitter for X64Assembler {
+    fn iced_ip(&self) -> u64 { self.offset().0 as u64 }
+}
+impl IcedEmitter for X64AssemblerFixed {
+    fn iced_ip(&self) -> u64 { self.cur_addr() as u64 }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use iced_x86::{ Code, Register };
+    use dynasmrt::dynasm;
+
+    #[test]
+    fn iced_matches_dynasm() {
+        let instrs = [
+            Instruction::with2(Code::Add_rm64_r64, Register::RAX, Register::RCX)
+                .unwrap(),
+            Instruction::with2(Code::Imul_r64_rm64, Register::RDX, Register::R9)
+                .unwrap(),
+            Instruction::with3(Code::VEX_Vpxor_ymm_ymm_ymmm256,
+                Register::YMM0, Register::YMM0, Register::YMM1).unwrap(),
+        ];
+
+        let mut x = X64Assembler::new().unwrap();
+        for instr in instrs.iter() {
+            x.emit_iced(instr).unwrap();
+        }
+        let x = x.finalize().unwrap();
+
+        let mut y = X64Assembler::new().unwrap();
+        dynasm!(y
+            ; add rax, rcx
+            ; imul rdx, r9
+            ; vpxor ymm0, ymm0, ymm1
+        );
+        let y = y.finalize().unwrap();
+        assert_eq!(&x[..], &y[..]);
+    }
+
+    #[test]
+    fn iced_block_branch() {
+        // 'jne' back to the first instruction in the block
+        let mut dec = Instruction::with1(Code::Dec_rm64, Register::RCX).unwrap();
+        dec.set_ip(0);
+        let mut jne = Instruction::with_branch(Code::Jne_rel8_64, 0).unwrap();
+        jne.set_ip(1);
+
+        let mut x = X64Assembler::new().unwrap();
+        let len = x.emit_iced_block(&[dec, jne]).unwrap();
+        let x = x.finalize().unwrap();
+        assert_eq!(len, 5);
+        assert_eq!(&x[..], &[0x48, 0xff, 0xc9, 0x75, 0xfb]);
+    }
+}
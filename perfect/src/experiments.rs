@@ -3,9 +3,13 @@ pub mod template;
 pub mod branch;
 pub mod pmcdisc;
 pub mod decoder;
+pub mod ports;
+pub mod ras;
+pub mod fuzz;
 
 use crate::asm::*;
 use crate::harness::*;
+use crate::timer::Timer;
 use dynasmrt::*;
 
 use clap::Parser;
@@ -15,6 +19,10 @@ use clap::Parser;
 pub struct ExperimentArgs {
     #[arg(short, long)]
     pub platform: Option<TargetPlatform>,
+
+    /// Timer used for measurements (ie. 'rdtsc', 'aperf:mfence', 'rdpmc0')
+    #[arg(long)]
+    pub timer: Option<Timer>,
 }
 
 
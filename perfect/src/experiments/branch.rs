@@ -93,6 +93,13 @@ impl BranchOutcomes {
         }
     }
 
+    pub fn as_slice(&self) -> &[usize] {
+        &self.data
+    }
+
+    pub fn len(&self) -> usize { self.data.len() }
+    pub fn is_empty(&self) -> bool { self.data.is_empty() }
+
     pub fn to_rdi_inputs(&self, size: usize) -> Vec<(usize, usize)> {
         let mut res = vec![(0, 0); size];
         for (idx, x) in self.data.iter().enumerate() {
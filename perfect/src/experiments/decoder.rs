@@ -1,4 +1,9 @@
 
+pub mod oracle;
+pub mod enumerate;
+pub use oracle::*;
+pub use enumerate::*;
+
 use rand::prelude::*;
 use rand::Rng;
 use rand::distributions::{ Distribution, Standard };
@@ -0,0 +1,419 @@
+//! Structured enumeration of the x86_64 encoding space.
+//!
+//! Instead of sampling random bytes (see [`RandomEncoding`]), we walk the
+//! space of encodings one "structural" choice at a time:
+//!
+//! - Legacy prefixes (each segment override, and one representative from
+//!   each of the other groups)
+//! - REX/VEX/XOP/EVEX prefixes (and their L/W/pp fields)
+//! - Opcode maps
+//! - Opcode bytes
+//! - ModR/M and SIB classes (register operands, plain memory operands,
+//!   memory operands with SIB, disp8, disp32, and RIP-relative)
+//!
+//! The remaining bytes (the displacement and any immediate) are all filled
+//! with one of the patterns in [`FILL_BYTES`]. The whole space is walked
+//! once for each pattern. Since most points in this space decode to an
+//! instruction we've already seen, encodings are de-duplicated by their
+//! iced-x86 [`Code`] (separately for each pattern, and for each set of
+//! legacy and REX prefixes, which don't always change the [`Code`]).
+//!
+//! Checkpointing
+//! =============
+//!
+//! Every point in the space has a linear index. The state of an enumerator
+//! is just the next index and the set of [`Code`] values seen so far, which
+//! can be saved with [`EncodingEnumerator::save_checkpoint`] and restored
+//! with [`EncodingEnumerator::from_checkpoint`].
+
+use std::collections::BTreeSet;
+use std::io::{ BufRead, BufReader, Write };
+use std::path::Path;
+use iced_x86::{ Code, Decoder, DecoderOptions };
+
+/// Representative sets of legacy prefixes.
+pub const LEGACY_PREFIX_SETS: [&[u8]; 14] = [
+    &[], &[0x66], &[0x67], &[0xf2], &[0xf3], &[0xf0],
+    &[0x66, 0xf2], &[0x66, 0xf3],
+    &[0x2e], &[0x3e], &[0x26], &[0x64], &[0x65], &[0x36],
+];
+
+/// Patterns used to fill the displacement and immediate bytes.
+pub const FILL_BYTES: [u8; 4] = [ 0x00, 0x01, 0x80, 0xff ];
+
+/// Representative REX prefixes.
+pub const REX_PREFIXES: [Option<u8>; 3] = [ None, Some(0x40), Some(0x48) ];
+
+/// Escape bytes for the legacy opcode maps.
+pub const LEGACY_MAPS: [&[u8]; 4] = [
+    &[], &[0x0f], &[0x0f, 0x38], &[0x0f, 0x3a]
+];
+/// Opcode maps for VEX-encoded instructions.
+pub const VEX_MAPS: [u8; 3] = [ 1, 2, 3 ];
+/// Opcode maps for XOP-encoded instructions.
+pub const XOP_MAPS: [u8; 3] = [ 8, 9, 10 ];
+/// Opcode maps for EVEX-encoded instructions.
+pub const EVEX_MAPS: [u8; 5] = [ 1, 2, 3, 5, 6 ];
+
+/// A class of ModR/M (and SIB/displacement) bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ModRmClass {
+    /// Register operand (mod=11)
+    Reg { reg: u8, rm: u8 },
+    /// `[rax]`
+    Mem { reg: u8 },
+    /// `[rax + rax*1]`
+    MemSib { reg: u8 },
+    /// `[rax + disp8]`
+    MemDisp8 { reg: u8 },
+    /// `[rax + disp32]`
+    MemDisp32 { reg: u8 },
+    /// `[rip + disp32]`
+    RipRel { reg: u8 },
+}
+impl ModRmClass {
+    pub const NUM: usize = 64 + (5 * 8);
+
+    pub fn from_index(idx: usize) -> Self {
+        assert!(idx < Self::NUM);
+        if idx < 64 {
+            return Self::Reg { reg: (idx / 8) as u8, rm: (idx % 8) as u8 };
+        }
+        let (kind, reg) = ((idx - 64) / 8, ((idx - 64) % 8) as u8);
+        match kind {
+            0 => Self::Mem { reg },
+            1 => Self::MemSib { reg },
+            2 => Self::MemDisp8 { reg },
+            3 => Self::MemDisp32 { reg },
+            4 => Self::RipRel { reg },
+            _ => unreachable!(),
+        }
+    }
+
+    /// The ModR/M (and SIB) bytes, without the displacement.
+    pub fn as_bytes(&self) -> Vec<u8> {
+        match *self {
+            Self::Reg { reg, rm }  => vec![0xc0 | (reg << 3) | rm],
+            Self::Mem { reg }      => vec![reg << 3],
+            Self::MemSib { reg }   => vec![(reg << 3) | 0b100, 0x00],
+            Self::MemDisp8 { reg } => vec![0x40 | (reg << 3)],
+            Self::MemDisp32 { reg } => vec![0x80 | (reg << 3)],
+            Self::RipRel { reg }   => vec![(reg << 3) | 0b101],
+        }
+    }
+}
+
+/// The different kinds of encodings.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EncodingScheme { Legacy, Vex2, Vex3, Xop, Evex }
+impl EncodingScheme {
+    pub const ALL: [Self; 5] = [
+        Self::Legacy, Self::Vex2, Self::Vex3, Self::Xop, Self::Evex
+    ];
+
+    /// The size of each dimension for this scheme.
+    /// The last two dimensions are always the opcode and ModR/M class.
+    fn radix(&self) -> &'static [usize] {
+        const OP: usize = 256;
+        const M: usize = ModRmClass::NUM;
+        // prefixes, REX, map
+        const LEGACY: [usize; 5] = [LEGACY_PREFIX_SETS.len(), 
+            REX_PREFIXES.len(), LEGACY_MAPS.len(), OP, M];
+        // pp, L
+        const VEX2: [usize; 4] = [4, 2, OP, M];
+        // map, pp, L, W
+        const VEX3: [usize; 6] = [VEX_MAPS.len(), 4, 2, 2, OP, M];
+        // map, L, W
+        const XOP: [usize; 5] = [XOP_MAPS.len(), 2, 2, OP, M];
+        // map, pp, L'L, W
+        const EVEX: [usize; 6] = [EVEX_MAPS.len(), 4, 3, 2, OP, M];
+        match self {
+            Self::Legacy => &LEGACY,
+            Self::Vex2 => &VEX2,
+            Self::Vex3 => &VEX3,
+            Self::Xop => &XOP,
+            Self::Evex => &EVEX,
+        }
+    }
+
+    /// The number of points in the space for this scheme.
+    pub fn size(&self) -> u64 {
+        self.radix().iter().map(|r| *r as u64).product()
+    }
+
+    /// Split an index into digits (most-significant first).
+    fn digits(&self, mut idx: u64) -> Vec<usize> {
+        let radix = self.radix();
+        let mut res = vec![0; radix.len()];
+        for (digit, r) in res.iter_mut().zip(radix.iter()).rev() {
+            *digit = (idx % *r as u64) as usize;
+            idx /= *r as u64;
+        }
+        res
+    }
+
+    /// Return the encoding for some point in the space for this scheme.
+    /// The result is always padded to 15 bytes with `fill`.
+    pub fn encoding_at(&self, idx: u64, fill: u8) -> Vec<u8> {
+        assert!(idx < self.size());
+        let d = self.digits(idx);
+        let mut res = Vec::new();
+        let (opcode, modrm) = (d[d.len() - 2] as u8, d[d.len() - 1]);
+        match self {
+            Self::Legacy => {
+                res.extend_from_slice(LEGACY_PREFIX_SETS[d[0]]);
+                if let Some(rex) = REX_PREFIXES[d[1]] {
+                    res.push(rex);
+                }
+                res.extend_from_slice(LEGACY_MAPS[d[2]]);
+            },
+            Self::Vex2 => {
+                let (pp, l) = (d[0] as u8, d[1] as u8);
+                res.extend_from_slice(&[0xc5, 0xf8 | (l << 2) | pp]);
+            },
+            Self::Vex3 => {
+                let (map, pp, l, w) = (VEX_MAPS[d[0]], d[1] as u8,
+                    d[2] as u8, d[3] as u8);
+                res.extend_from_slice(&[0xc4, 0xe0 | map,
+                    (w << 7) | 0x78 | (l << 2) | pp]);
+            },
+            Self::Xop => {
+                let (map, l, w) = (XOP_MAPS[d[0]], d[1] as u8, d[2] as u8);
+                res.extend_from_slice(&[0x8f, 0xe0 | map,
+                    (w << 7) | 0x78 | (l << 2)]);
+            },
+            Self::Evex => {
+                let (map, pp, ll, w) = (EVEX_MAPS[d[0]], d[1] as u8,
+                    d[2] as u8, d[3] as u8);
+                res.extend_from_slice(&[0x62, 0xf0 | map,
+                    (w << 7) | 0x78 | 0x04 | pp, (ll << 5) | 0x08]);
+            },
+        }
+        res.push(opcode);
+        res.extend(ModRmClass::from_index(modrm).as_bytes());
+        res.resize(15, fill);
+        res
+    }
+}
+
+/// An encoding produced by [`EncodingEnumerator`].
+#[derive(Clone, Debug)]
+pub struct EnumeratedEncoding {
+    /// Linear index of this encoding in the space
+    pub index: u64,
+    /// The encoding (truncated to the length decoded by iced-x86 when the
+    /// encoding is valid)
+    pub bytes: Vec<u8>,
+    pub code: Code,
+}
+
+/// Iterator over the structured encoding space, yielding one encoding for
+/// each distinct [`Code`] (see [`EncodingEnumerator::seen_key`]).
+pub struct EncodingEnumerator {
+    /// The next index in the space
+    pub next: u64,
+    /// The set of [`Code`] values we've already seen (see
+    /// [`EncodingEnumerator::seen_key`])
+    pub seen: BTreeSet<u32>,
+    /// Also yield encodings that iced-x86 considers invalid
+    pub keep_invalid: bool,
+}
+impl EncodingEnumerator {
+    pub fn new() -> Self {
+        Self { next: 0, seen: BTreeSet::new(), keep_invalid: false }
+    }
+
+    pub fn keep_invalid(mut self, x: bool) -> Self {
+        self.keep_invalid = x;
+        self
+    }
+
+    /// The number of points in the space for each pattern in [`FILL_BYTES`].
+    pub fn fill_size() -> u64 {
+        EncodingScheme::ALL.iter().map(|s| s.size()).sum()
+    }
+
+    /// The total number of points in the space.
+    pub fn size() -> u64 {
+        FILL_BYTES.len() as u64 * Self::fill_size()
+    }
+
+    /// Return the encoding for some point in the space.
+    pub fn encoding_at(idx: u64) -> Vec<u8> {
+        assert!(idx < Self::size(), "index {} is out of bounds", idx);
+        let fill = FILL_BYTES[(idx / Self::fill_size()) as usize];
+        let mut idx = idx % Self::fill_size();
+        for scheme in EncodingScheme::ALL {
+            if idx < scheme.size() {
+                return scheme.encoding_at(idx, fill);
+            }
+            idx -= scheme.size();
+        }
+        unreachable!();
+    }
+
+    /// The key used to de-duplicate an encoding at `index` with `code`.
+    ///
+    /// This includes the fill pattern and (for legacy encodings) the
+    /// indexes into [`LEGACY_PREFIX_SETS`] and [`REX_PREFIXES`].
+    fn seen_key(index: u64, code: Code) -> u32 {
+        let fill = (index / Self::fill_size()) as u32;
+        let idx = index % Self::fill_size();
+        let (prefixes, rex) = if idx < EncodingScheme::Legacy.size() {
+            let d = EncodingScheme::Legacy.digits(idx);
+            (d[0] as u32, d[1] as u32)
+        } else {
+            (0, 0)
+        };
+        (fill << 24) | (rex << 22) | (prefixes << 16) | code as u32
+    }
+
+    /// Save the state of this enumerator to a file.
+    ///
+    /// The checkpoint is written to a temporary file first, so an
+    /// interrupted save never clobbers the previous checkpoint.
+    pub fn save_checkpoint(&self, path: impl AsRef<Path>) -> std::io::Result<()> {
+        let path = path.as_ref();
+        let tmp = path.with_extension("tmp");
+        let mut f = std::fs::File::create(&tmp)?;
+        writeln!(f, "next {}", self.next)?;
+        writeln!(f, "keep_invalid {}", self.keep_invalid)?;
+        let seen: Vec<String> = self.seen.iter().map(|c| c.to_string())
+            .collect();
+        writeln!(f, "seen {}", seen.join(" "))?;
+        f.sync_all()?;
+        std::fs::rename(&tmp, path)
+    }
+
+    /// Restore an enumerator from a file written with
+    /// [`EncodingEnumerator::save_checkpoint`].
+    pub fn from_checkpoint(path: impl AsRef<Path>) -> std::io::Result<Self> {
+        use std::io::{ Error, ErrorKind };
+        let bad = |msg: &str| Error::new(ErrorKind::InvalidData, msg.to_string());
+
+        let f = std::fs::File::open(path)?;
+        let mut res = Self::new();
+        for line in BufReader::new(f).lines() {
+            let line = line?;
+            let mut words = line.split_whitespace();
+            match words.next() {
+                Some("next") => {
+                    res.next = words.next().and_then(|w| w.parse().ok())
+                        .ok_or_else(|| bad("invalid 'next'"))?;
+                },
+                Some("keep_invalid") => {
+                    res.keep_invalid = words.next()
+                        .and_then(|w| w.parse().ok())
+                        .ok_or_else(|| bad("invalid 'keep_invalid'"))?;
+                },
+                Some("seen") => {
+                    for w in words {
+                        res.seen.insert(w.parse()
+                            .map_err(|_| bad("invalid 'seen'"))?);
+                    }
+                },
+                Some(_) => return Err(bad("unexpected line")),
+                None => {},
+            }
+        }
+        Ok(res)
+    }
+
+    /// Fraction of the space which has been visited.
+    pub fn progress(&self) -> f64 {
+        self.next as f64 / Self::size() as f64
+    }
+}
+impl Default for EncodingEnumerator {
+    fn default() -> Self { Self::new() }
+}
+impl Iterator for EncodingEnumerator {
+    type Item = EnumeratedEncoding;
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.next < Self::size() {
+            let index = self.next;
+            self.next += 1;
+
+            let bytes = Self::encoding_at(index);
+            let mut dec = Decoder::new(64, &bytes, DecoderOptions::NONE);
+            let instr = dec.decode();
+            let code = instr.code();
+            if instr.is_invalid() {
+                if !self.keep_invalid {
+                    continue;
+                }
+                return Some(EnumeratedEncoding { index, bytes, code });
+            }
+            if !self.seen.insert(Self::seen_key(index, code)) {
+                continue;
+            }
+            let bytes = bytes[..instr.len()].to_vec();
+            return Some(EnumeratedEncoding { index, bytes, code });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn enumerate_encoding_at() {
+        // Legacy, no prefixes, no REX, one-byte map, 'add ecx, eax'
+        let idx = (0x01 * ModRmClass::NUM + 1) as u64;
+        assert_eq!(&EncodingScheme::Legacy.encoding_at(idx, 0)[..2], &[0x01, 0xc1]);
+
+        // 'add [rax + 0x01010101], eax' (with the second fill pattern)
+        let idx = EncodingEnumerator::fill_size()
+            + (0x01 * ModRmClass::NUM + 64 + 3 * 8) as u64;
+        let bytes = EncodingEnumerator::encoding_at(idx);
+        assert_eq!(&bytes[..6], &[0x01, 0x80, 0x01, 0x01, 0x01, 0x01]);
+
+        // 'mov eax, fs:[rax]'
+        let idx = (11 * REX_PREFIXES.len() * LEGACY_MAPS.len() * 256
+            + 0x8b) as u64 * ModRmClass::NUM as u64 + 64;
+        let bytes = EncodingEnumerator::encoding_at(idx);
+        assert_eq!(&bytes[..3], &[0x64, 0x8b, 0x00]);
+
+        // Segment overrides don't change the Code, but they aren't
+        // de-duplicated with the unprefixed encoding
+        let plain = (0x8b * ModRmClass::NUM + 64) as u64;
+        let mut dec = Decoder::new(64, &bytes, DecoderOptions::NONE);
+        let code = dec.decode().code();
+        assert_eq!(code, Code::Mov_r32_rm32);
+        assert_ne!(EncodingEnumerator::seen_key(idx, code),
+            EncodingEnumerator::seen_key(plain, code)
+        );
+
+        // 'vpxor ymm0, ymm0, ymm0' (VEX.256.66.0F.WIG EF /r)
+        let idx = EncodingScheme::Legacy.size();
+        let (pp, l) = (1, 1);
+        let idx = idx + ((pp * 2 + l) * 256 + 0xef) as u64
+            * ModRmClass::NUM as u64;
+        let bytes = EncodingEnumerator::encoding_at(idx);
+        let mut dec = Decoder::new(64, &bytes, DecoderOptions::NONE);
+        assert_eq!(dec.decode().code(), Code::VEX_Vpxor_ymm_ymm_ymmm256);
+    }
+
+    #[test]
+    fn enumerate_checkpoint() {
+        let mut a = EncodingEnumerator::new();
+        let first: Vec<Code> = a.by_ref().take(64).map(|e| e.code).collect();
+        let unique: BTreeSet<u32> = first.iter().map(|c| *c as u32).collect();
+        assert_eq!(unique.len(), first.len());
+
+        let path = std::env::temp_dir().join(
+            format!("perfect-enumerate-{}.ckpt", std::process::id())
+        );
+        a.save_checkpoint(&path).unwrap();
+        let b = EncodingEnumerator::from_checkpoint(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(a.next, b.next);
+        assert_eq!(a.seen, b.seen);
+
+        let x: Vec<u64> = a.take(64).map(|e| e.index).collect();
+        let y: Vec<u64> = b.take(64).map(|e| e.index).collect();
+        assert_eq!(x, y);
+    }
+}
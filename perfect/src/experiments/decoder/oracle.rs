@@ -0,0 +1,329 @@
+//! Architectural oracle for instruction encodings.
+//!
+//! [`RandomEncoding`] and the speculative decode experiments only learn
+//! whether an encoding is valid *indirectly*. Instead, [`FaultOracle`]
+//! actually executes a single encoding (in an isolated page, with fault
+//! recovery enabled in the harness) and classifies the outcome.
+//!
+//! Test
+//! ====
+//!
+//! The code page looks like this:
+//!
+//! ```text
+//! pushfq
+//! or qword [rsp], 0x100   ; set RFLAGS.TF
+//! popfq
+//! <encoding>              ; single-step trap after this instruction
+//! int3 ...                ; the rest of the page
+//! ```
+//!
+//! When the encoding executes normally, the single-step trap (#DB) reports
+//! the address of the next instruction, which gives us the length of the
+//! encoding as decoded by the machine. Otherwise, the trap number tells us
+//! which exception occurred.
+//!
+//! The outcome is compared against the result of decoding the same bytes
+//! with iced-x86, see [`OracleDisagreement`].
+//!
+//! Caveats
+//! =======
+//!
+//! Executing arbitrary encodings is not entirely safe! A few instructions
+//! that would obviously clobber the state of the process (ie. system calls,
+//! writes to the FS/GS segment registers, and accesses to thread-local 
+//! storage via FS/GS) are never executed when iced-x86 recognizes them 
+//! (see [`FaultOracle::is_excluded`]), but random memory accesses can still 
+//! land in mapped memory.
+
+use iced_x86::{ Code, Decoder, DecoderOptions, FlowControl, OpKind, Register };
+use nix::sys::signal::Signal;
+
+use crate::asm::X64AssemblerFixed;
+use crate::harness::*;
+use crate::util::cpuid::HostCpuid;
+
+/// The architectural outcome of executing a single encoding.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchOutcome {
+    /// The instruction retired.
+    ///
+    /// The length is [`None`] when the next instruction is not within 15
+    /// bytes of the encoding (ie. when the instruction is a branch).
+    Executed { len: Option<usize> },
+    /// Invalid opcode (#UD)
+    InvalidOpcode,
+    /// General protection fault (#GP)
+    GeneralProtection,
+    /// Page fault (#PF) on some address
+    PageFault { addr: usize },
+    /// Divide error (#DE)
+    DivideError,
+    /// Breakpoint (#BP)
+    Breakpoint,
+    /// Alignment check (#AC)
+    AlignmentCheck,
+    /// x87 (#MF) or SIMD (#XM) floating-point exception
+    FloatingPoint,
+    /// Some other signal
+    Other { signal: Signal, trapno: u64 },
+}
+impl ArchOutcome {
+    /// Classify a fault, given the address of the tested encoding.
+    pub fn from_fault(fault: &FaultRecord, enc_addr: usize) -> Self {
+        match (fault.signal, fault.trapno()) {
+            (Signal::SIGTRAP, 1) => {
+                let len = fault.rip().wrapping_sub(enc_addr);
+                let len = if (1..=15).contains(&len) { Some(len) } else { None };
+                Self::Executed { len }
+            },
+            (Signal::SIGTRAP, 3) => Self::Breakpoint,
+            (_, 0)  => Self::DivideError,
+            (_, 6)  => Self::InvalidOpcode,
+            (_, 13) => Self::GeneralProtection,
+            (_, 14) => Self::PageFault { addr: fault.cr2() },
+            (_, 17) => Self::AlignmentCheck,
+            (_, 16) | (_, 19) => Self::FloatingPoint,
+            (signal, trapno) => Self::Other { signal, trapno },
+        }
+    }
+}
+
+/// The result of decoding an encoding with iced-x86.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct IcedOutcome {
+    pub code: Code,
+    pub len: usize,
+    pub flow: FlowControl,
+    /// Whether this instruction has a memory operand using the FS or GS
+    /// segment.
+    pub fsgs_mem: bool,
+    /// Whether the host supports all of the CPUID features required by
+    /// this instruction.
+    pub supported: bool,
+}
+impl IcedOutcome {
+    pub fn from_bytes(bytes: &[u8], cpuid: &HostCpuid) -> Self {
+        let mut dec = Decoder::new(64, bytes, DecoderOptions::NONE);
+        let instr = dec.decode();
+        let code = instr.code();
+        let supported = !instr.is_invalid()
+            && cpuid.supports_all(code.cpuid_features());
+        let has_mem = (0..instr.op_count())
+            .any(|i| instr.op_kind(i) == OpKind::Memory);
+        let fsgs_mem = has_mem && matches!(instr.memory_segment(),
+            Register::FS | Register::GS
+        );
+        Self {
+            code, len: instr.len(), flow: instr.flow_control(), supported,
+            fsgs_mem
+        }
+    }
+    pub fn is_invalid(&self) -> bool { self.code == Code::INVALID }
+
+    /// Returns true if this instruction is *supposed* to raise #UD.
+    pub fn is_ud(&self) -> bool {
+        use iced_x86::Mnemonic::*;
+        matches!(self.code.mnemonic(), Ud0 | Ud1 | Ud2)
+    }
+}
+
+/// A disagreement between iced-x86 and the machine.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OracleDisagreement {
+    /// The machine executes an encoding that iced-x86 rejects.
+    UnexpectedExecute,
+    /// The machine raises #UD for a [supported] encoding that iced-x86
+    /// accepts.
+    UnexpectedInvalid,
+    /// The machine and iced-x86 disagree about the instruction length.
+    LengthMismatch { iced: usize, arch: usize },
+}
+impl OracleDisagreement {
+    pub fn check(arch: &ArchOutcome, iced: &IcedOutcome) -> Option<Self> {
+        match arch {
+            ArchOutcome::Executed { .. } if iced.is_invalid() => {
+                Some(Self::UnexpectedExecute)
+            },
+            ArchOutcome::Executed { len: Some(len) } => {
+                // Only sequential instructions tell us anything about length
+                if iced.flow == FlowControl::Next && *len != iced.len {
+                    Some(Self::LengthMismatch { iced: iced.len, arch: *len })
+                } else {
+                    None
+                }
+            },
+            ArchOutcome::InvalidOpcode if iced.supported && !iced.is_ud() => {
+                Some(Self::UnexpectedInvalid)
+            },
+            _ => None,
+        }
+    }
+}
+
+/// The result of testing a single encoding with [`FaultOracle`].
+#[derive(Clone, Debug)]
+pub struct OracleResult {
+    pub bytes: Vec<u8>,
+    pub arch: ArchOutcome,
+    pub iced: IcedOutcome,
+    pub disagreement: Option<OracleDisagreement>,
+}
+impl std::fmt::Display for OracleResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for b in self.bytes.iter() {
+            write!(f, "{:02x}", b)?;
+        }
+        write!(f, " arch={:?} iced={:?}(len={})",
+            self.arch, self.iced.code, self.iced.len
+        )?;
+        if let Some(d) = self.disagreement {
+            write!(f, " [!] {:?}", d)?;
+        }
+        Ok(())
+    }
+}
+
+/// Executes single instruction encodings and classifies the outcome.
+pub struct FaultOracle {
+    page: X64AssemblerFixed,
+    cpuid: HostCpuid,
+}
+impl FaultOracle {
+    /// Default address of the isolated code page.
+    pub const DEFAULT_ADDR: usize = 0x0000_1400_0000_0000;
+
+    /// pushfq; or qword [rsp], 0x100; popfq
+    const PROLOGUE: [u8; 10] = [
+        0x9c,
+        0x48, 0x81, 0x0c, 0x24, 0x00, 0x01, 0x00, 0x00,
+        0x9d
+    ];
+
+    pub fn new(addr: usize) -> Self {
+        Self {
+            page: X64AssemblerFixed::new(addr, 0x1000),
+            cpuid: HostCpuid::read(),
+        }
+    }
+
+    /// Address of the tested encoding.
+    pub fn enc_addr(&self) -> usize {
+        self.page.base_addr() + Self::PROLOGUE.len()
+    }
+
+    /// Returns true if we never want to execute this instruction.
+    pub fn is_excluded(iced: &IcedOutcome) -> bool {
+        use iced_x86::Mnemonic::*;
+        let code = iced.code;
+        // Accesses relative to FS/GS can clobber thread-local storage
+        if iced.fsgs_mem {
+            return true;
+        }
+        match code.mnemonic() {
+            // System calls (including 'int 0x80')
+            Syscall | Sysenter | Int => true,
+            // Clobbering FS/GS breaks thread-local storage
+            Wrfsbase | Wrgsbase | Lfs | Lgs => true,
+            _ => matches!(code,
+                Code::Popq_FS | Code::Popw_FS |
+                Code::Popq_GS | Code::Popw_GS |
+                Code::Mov_Sreg_rm16 | Code::Mov_Sreg_r32m16 |
+                Code::Mov_Sreg_r64m16
+            ),
+        }
+    }
+
+    /// Execute an encoding, returning [`None`] if the encoding is excluded.
+    ///
+    /// Only the first 15 bytes of the encoding are used.
+    pub fn execute(&mut self, harness: &mut PerfectHarness, bytes: &[u8])
+        -> Option<ArchOutcome>
+    {
+        let bytes = &bytes[..bytes.len().min(15)];
+        let iced = IcedOutcome::from_bytes(bytes, &self.cpuid);
+        if Self::is_excluded(&iced) {
+            return None;
+        }
+
+        let page = unsafe {
+            std::slice::from_raw_parts_mut(self.page.base_addr() as *mut u8,
+                0x1000
+            )
+        };
+        page.fill(0xcc);
+        page[..Self::PROLOGUE.len()].copy_from_slice(&Self::PROLOGUE);
+        let off = Self::PROLOGUE.len();
+        page[off..off + bytes.len()].copy_from_slice(bytes);
+
+        harness.enable_handler();
+        let func: MeasuredFn = unsafe {
+            std::mem::transmute(self.page.base_addr())
+        };
+        let res = harness.try_call(0, 0, func);
+        Self::reset_fp_state();
+
+        let outcome = match res {
+            Err(fault) => ArchOutcome::from_fault(&fault, self.enc_addr()),
+            // The single-step trap should always occur
+            Ok(_) => ArchOutcome::Executed { len: None },
+        };
+        Some(outcome)
+    }
+
+    /// Execute an encoding and compare the outcome with iced-x86.
+    pub fn check(&mut self, harness: &mut PerfectHarness, bytes: &[u8])
+        -> Option<OracleResult>
+    {
+        let bytes = &bytes[..bytes.len().min(15)];
+        let arch = self.execute(harness, bytes)?;
+        let iced = IcedOutcome::from_bytes(bytes, &self.cpuid);
+        let disagreement = OracleDisagreement::check(&arch, &iced);
+        Some(OracleResult { bytes: bytes.to_vec(), arch, iced, disagreement })
+    }
+
+    /// The tested instruction may have clobbered the x87 control word or
+    /// MXCSR: put them back to their default values.
+    fn reset_fp_state() {
+        let mxcsr: u32 = 0x1f80;
+        unsafe {
+            core::arch::asm!(
+                "fninit",
+                "ldmxcsr [{}]",
+                in(reg) &mxcsr,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn oracle_classify() {
+        let mut harness = HarnessConfig::default_test(0x133a)
+            .recover_faults(true)
+            .emit();
+        let mut oracle = FaultOracle::new(0x0000_1401_0000_0000);
+
+        let cases: [(&[u8], ArchOutcome); 7] = [
+            (&[0x90], ArchOutcome::Executed { len: Some(1) }),
+            (&[0x48, 0x01, 0xc8], ArchOutcome::Executed { len: Some(3) }),
+            (&[0x0f, 0x0b], ArchOutcome::InvalidOpcode),
+            (&[0x48, 0x8b, 0x04, 0x25, 0x00, 0x00, 0x00, 0x00],
+                ArchOutcome::PageFault { addr: 0 }),
+            (&[0x48, 0xf7, 0xf1], ArchOutcome::DivideError),
+            (&[0xf4], ArchOutcome::GeneralProtection),
+            (&[0xcc], ArchOutcome::Breakpoint),
+        ];
+        for (bytes, expected) in cases {
+            let res = oracle.check(&mut harness, bytes).unwrap();
+            assert_eq!(res.arch, expected, "{}", res);
+            assert_eq!(res.disagreement, None, "{}", res);
+        }
+
+        // Never execute 'syscall'
+        assert!(oracle.check(&mut harness, &[0x0f, 0x05]).is_none());
+    }
+}
@@ -0,0 +1,383 @@
+//! Coverage-guided fuzzing of microarchitectural behavior.
+//!
+//! Instead of sweeping over a fixed set of experiments, a [`Fuzzer`]
+//! repeatedly picks an input from a [`Corpus`], mutates it, and measures a
+//! set of events while running it in the harness. The result is summarized
+//! as an [`EventSignature`]: the (minimum) observed count for each event,
+//! plus the fault which occurred (if any).
+//!
+//! Coverage
+//! ========
+//!
+//! A signature is split into a set of [`SignatureFeature`]s, where each
+//! count is reduced to a logarithmic bucket (similar to the "hit count"
+//! buckets used by AFL). An input is kept when it produces a feature which
+//! hasn't been observed before: for instance, the first program that causes
+//! a nonzero count for `LsBadStatus2`, or an unusually large number of
+//! speculatively-dispatched ops.
+//!
+//! Inputs
+//! ======
+//!
+//! Anything implementing [`FuzzInput`] can be fuzzed. This module provides
+//! implementations for [`PerfectProg`](crate::ir::PerfectProg) and for
+//! sequences of raw instruction encodings ([`EncodingSeq`]).
+//!
+//! Measured code runs with fault recovery enabled
+//! (see [`HarnessConfig::recover_faults`]), so inputs that fault are
+//! measured like any other input. Inputs may use the arena buffer
+//! [`ARENA_BUF`] (see [`FuzzInput::emit_setup`]).
+
+pub mod input;
+pub mod corpus;
+pub use input::*;
+pub use corpus::*;
+
+use crate::asm::*;
+use crate::harness::*;
+use crate::events::*;
+use crate::util::rng::*;
+use rand::prelude::*;
+use dynasmrt::{ dynasm, DynasmApi, DynasmLabelApi, AssemblyOffset };
+use std::collections::BTreeSet;
+
+/// The (minimum) observed count for a particular event.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EventCount {
+    pub id: u16,
+    pub mask: u8,
+    pub value: usize,
+}
+
+/// The set of events observed while running some input.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EventSignature {
+    pub counts: Vec<EventCount>,
+    /// The exception vector for the fault that occurred (if any)
+    pub fault: Option<u64>,
+}
+impl EventSignature {
+    /// Reduce a count to a logarithmic bucket.
+    pub fn bucket(value: usize) -> u8 {
+        (usize::BITS - value.leading_zeros()) as u8
+    }
+
+    /// Return the set of features in this signature.
+    pub fn features(&self) -> impl Iterator<Item=SignatureFeature> + '_ {
+        let events = self.counts.iter().map(|c| SignatureFeature::Event {
+            id: c.id, mask: c.mask, bucket: Self::bucket(c.value),
+        });
+        let fault = self.fault.map(|trapno| SignatureFeature::Fault { trapno });
+        events.chain(fault)
+    }
+}
+impl std::fmt::Display for EventSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, cnt) in self.counts.iter().enumerate() {
+            if idx != 0 {
+                write!(f, " ")?;
+            }
+            write!(f, "{:03x}:{:02x}={}", cnt.id, cnt.mask, cnt.value)?;
+        }
+        if let Some(trapno) = self.fault {
+            write!(f, " fault={}", trapno)?;
+        }
+        Ok(())
+    }
+}
+
+/// A single unit of coverage.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum SignatureFeature {
+    /// The count for an event fell into some bucket
+    Event { id: u16, mask: u8, bucket: u8 },
+    /// Some fault occurred
+    Fault { trapno: u64 },
+}
+
+/// Name of the arena buffer used by inputs (see [`arena_buffer`]).
+pub const ARENA_BUF: &str = "fuzz";
+
+/// Size of the arena buffer used by inputs.
+pub const ARENA_LEN: usize = 0x2000;
+
+/// The arena buffer used by inputs. This must be added to the
+/// [`ArenaLayout`] for the harness.
+pub fn arena_buffer() -> ArenaBufferDesc {
+    ArenaBufferDesc::new(ARENA_BUF, ARENA_LEN)
+}
+
+/// Options for a [`Fuzzer`].
+#[derive(Clone, Copy)]
+pub struct FuzzOptions {
+    /// Number of test iterations for each event
+    pub iters: usize,
+    /// Maximum number of mutations applied to an input
+    pub max_mutations: usize,
+    /// Counter index used by RDPMC
+    pub ctr_idx: i32,
+    /// Emitter for code run before the measured region
+    pub prologue_fn: Option<fn(&mut X64Assembler)>,
+    /// Seed for the RNG used to select and mutate inputs (otherwise, derived
+    /// from [`perfect_rng`])
+    pub seed: Option<u64>,
+}
+impl Default for FuzzOptions {
+    fn default() -> Self {
+        Self {
+            iters: 16,
+            max_mutations: 4,
+            ctr_idx: 0,
+            prologue_fn: None,
+            seed: None,
+        }
+    }
+}
+impl FuzzOptions {
+    pub fn iters(mut self, x: usize) -> Self {
+        self.iters = x;
+        self
+    }
+    pub fn max_mutations(mut self, x: usize) -> Self {
+        self.max_mutations = x;
+        self
+    }
+    pub fn ctr_idx(mut self, x: i32) -> Self {
+        self.ctr_idx = x;
+        self
+    }
+    pub fn prologue_fn(mut self, x: Option<fn(&mut X64Assembler)>) -> Self {
+        self.prologue_fn = x;
+        self
+    }
+    pub fn seed(mut self, x: u64) -> Self {
+        self.seed = Some(x);
+        self
+    }
+}
+
+/// A coverage-guided fuzzer for some type of [`FuzzInput`].
+pub struct Fuzzer<E: AsEventDesc, I: FuzzInput> {
+    pub opts: FuzzOptions,
+    pub events: EventSet<E>,
+    pub corpus: Corpus<I>,
+    /// The set of features covered by the corpus
+    pub coverage: BTreeSet<SignatureFeature>,
+    /// Total number of executed inputs
+    pub execs: usize,
+    rng: PerfectRng,
+}
+impl <E: AsEventDesc, I: FuzzInput> Fuzzer<E, I> {
+    pub fn new(opts: FuzzOptions, events: EventSet<E>, corpus: Corpus<I>)
+        -> Self
+    {
+        let coverage = corpus.features().collect();
+        let rng = opts.seed.map(seeded_rng).unwrap_or_else(perfect_rng);
+        Self { opts, events, corpus, coverage, execs: 0, rng }
+    }
+
+    /// Emit the measured function for some input (using the arena buffer
+    /// at `arena`).
+    fn emit(&self, input: &I, arena: usize) -> X64Assembler {
+        let mut f = X64Assembler::new().unwrap();
+        if let Some(prologue) = self.opts.prologue_fn {
+            prologue(&mut f);
+        }
+        I::emit_setup(&mut f, arena);
+        dynasm!(f
+            ; .align 64
+            ; lfence
+        );
+        f.emit_rdpmc_start(self.opts.ctr_idx, Gpr::R15 as u8);
+        input.emit(&mut f);
+        f.emit_rdpmc_end(self.opts.ctr_idx, Gpr::R15 as u8, Gpr::Rax as u8);
+
+        // Undo any changes to state that the caller expects to be preserved.
+        // When an input faults, this is skipped and the harness recovery
+        // routine resets the same state instead.
+        dynasm!(f
+            ; cld
+            ; fninit
+            ; mov DWORD [rsp - 8], InitState::DEFAULT_MXCSR as i32
+            ; ldmxcsr [rsp - 8]
+        );
+        f.emit_ret();
+        f.commit().unwrap();
+        f
+    }
+
+    /// Measure the [`EventSignature`] for some input.
+    pub fn measure(&mut self, harness: &mut PerfectHarness, input: &I)
+        -> EventSignature
+    {
+        let asm = self.emit(input, harness.arena_addr(ARENA_BUF));
+        let asm_reader = asm.reader();
+        let asm_tgt_buf = asm_reader.lock();
+        let asm_fn: MeasuredFn = unsafe {
+            std::mem::transmute(asm_tgt_buf.ptr(AssemblyOffset(0)))
+        };
+
+        harness.enable_handler();
+        let mut res = EventSignature::default();
+        for event in self.events.iter() {
+            let desc = event.as_desc();
+            let results = harness.measure(asm_fn, &desc, self.opts.iters,
+                InputMethod::Fixed(0, 0)
+            ).unwrap();
+
+            let value = results.data.0.iter().enumerate()
+                .filter(|(idx, _)| !results.is_faulted(*idx))
+                .map(|(_, v)| *v)
+                .min().unwrap_or(0);
+            res.counts.push(EventCount {
+                id: desc.id(), mask: desc.mask(), value
+            });
+            if res.fault.is_none() {
+                res.fault = results.faults.first().map(|(_, f)| f.trapno());
+            }
+        }
+        self.execs += 1;
+        res
+    }
+
+    /// Measure some input, adding it to the corpus if it produces any new
+    /// features. Returns the new corpus entry ID (if the input was added).
+    pub fn evaluate(&mut self, harness: &mut PerfectHarness, input: I)
+        -> std::io::Result<Option<usize>>
+    {
+        let signature = self.measure(harness, &input);
+        let new: Vec<SignatureFeature> = signature.features()
+            .filter(|f| !self.coverage.contains(f))
+            .collect();
+        if new.is_empty() {
+            return Ok(None);
+        }
+        self.coverage.extend(new);
+        self.corpus.add(input, signature).map(Some)
+    }
+
+    /// Pick an input from the corpus, mutate it, and evaluate the result.
+    ///
+    /// The corpus must not be empty (see [`Fuzzer::evaluate`]).
+    pub fn step(&mut self, harness: &mut PerfectHarness)
+        -> std::io::Result<Option<usize>>
+    {
+        assert!(!self.corpus.is_empty(), "Fuzzer corpus must be seeded");
+        let idx = self.rng.gen_range(0..self.corpus.len());
+        let mut input = self.corpus.entries[idx].input.clone();
+        for _ in 0..self.rng.gen_range(1..=self.opts.max_mutations) {
+            input.mutate(&mut self.rng);
+        }
+        self.evaluate(harness, input)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::*;
+    use crate::util::PerfectEnv;
+    use crate::util::cpuid::HostCpuid;
+
+    #[test]
+    fn fuzz_corpus_roundtrip() {
+        let dir = std::env::temp_dir()
+            .join(format!("perfect-fuzz-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let signature = EventSignature {
+            counts: vec![
+                EventCount { id: 0x0c0, mask: 0x00, value: 142 },
+                EventCount { id: 0x025, mask: 0x02, value: 0 },
+            ],
+            fault: Some(14),
+        };
+        let prog = PerfectProg::gen(&mut seeded_rng(0), 32);
+        let enc = EncodingSeq(vec![vec![0x01, 0xc8], vec![0x90]]);
+        {
+            let mut progs = Corpus::open(dir.join("prog")).unwrap();
+            progs.add(prog.clone(), signature.clone()).unwrap();
+            let mut encs = Corpus::open(dir.join("enc")).unwrap();
+            encs.add(enc.clone(), EventSignature::default()).unwrap();
+        }
+
+        let progs: Corpus<PerfectProg> = Corpus::open(dir.join("prog")).unwrap();
+        assert_eq!(progs.len(), 1);
+        assert_eq!(progs.entries[0].input.data, prog.data);
+        assert_eq!(progs.entries[0].signature, signature);
+        assert_eq!(progs.features().count(), 3);
+        let encs: Corpus<EncodingSeq> = Corpus::open(dir.join("enc")).unwrap();
+        assert_eq!(encs.entries[0].input, enc);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn fuzz_encoding_arena() {
+        let cpuid = HostCpuid::read();
+        // mov rax, [r10 + 0x10]
+        assert!(EncodingSeq::is_fuzzable(&[0x49, 0x8b, 0x42, 0x10], &cpuid));
+        // mov rax, [rbx]
+        assert!(!EncodingSeq::is_fuzzable(&[0x48, 0x8b, 0x03], &cpuid));
+        // mov rax, [r10 + 0x3000]
+        assert!(!EncodingSeq::is_fuzzable(
+            &[0x49, 0x8b, 0x82, 0x00, 0x30, 0x00, 0x00], &cpuid
+        ));
+        // mov rax, [r10 + rbx]
+        assert!(!EncodingSeq::is_fuzzable(&[0x49, 0x8b, 0x04, 0x1a], &cpuid));
+        // mov rax, fs:[r10]
+        assert!(!EncodingSeq::is_fuzzable(&[0x64, 0x49, 0x8b, 0x02], &cpuid));
+        // mov r10, rax
+        assert!(!EncodingSeq::is_fuzzable(&[0x49, 0x89, 0xc2], &cpuid));
+    }
+
+    /// Replay entries from a corpus on disk, and check that the state
+    /// changed by an input is reset even when the input faults.
+    #[test]
+    fn fuzz_corpus_replay() {
+        // Measured code uses RDPMC, which faults without a core PMU
+        if PerfectEnv::sysfs_rdpmc_enabled().is_err() {
+            return;
+        }
+        let dir = std::env::temp_dir()
+            .join(format!("perfect-fuzz-replay-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // mov rax, [r10 + 0x10]
+        std::fs::write(dir.join("000000.txt"), "---\n498b4210\n").unwrap();
+        // std; ldmxcsr [r10]; ud2
+        std::fs::write(dir.join("000001.txt"),
+            "fault 6\n---\nfd\n410fae12\n0f0b\n"
+        ).unwrap();
+
+        let layout = ArenaLayout::new(0x0000_0035_0000_0000)
+            .buffer(arena_buffer());
+        let mut harness = HarnessConfig::default_test(0x1340)
+            .arena(layout)
+            .recover_faults(true)
+            .emit();
+
+        let corpus: Corpus<EncodingSeq> = Corpus::open(&dir).unwrap();
+        let events = EventSet::new_from_slice(&[Zen2Event::ExRetCops(0x00)]);
+        let mut fuzzer = Fuzzer::new(FuzzOptions::default().iters(4),
+            events, corpus
+        );
+        assert_eq!(fuzzer.corpus.len(), 2);
+        for idx in 0..fuzzer.corpus.len() {
+            let entry = fuzzer.corpus.entries[idx].clone();
+            let signature = fuzzer.measure(&mut harness, &entry.input);
+            assert_eq!(signature.fault, entry.signature.fault);
+
+            let rflags: usize;
+            let mut mxcsr = 0u32;
+            unsafe {
+                core::arch::asm!("pushfq", "pop {}", out(reg) rflags);
+                core::arch::asm!("stmxcsr [{}]", in(reg) &mut mxcsr);
+            }
+            assert_eq!(rflags & (1 << 10), 0, "DF is set");
+            assert_eq!(mxcsr, InitState::DEFAULT_MXCSR);
+        }
+        assert_eq!(fuzzer.execs, 2);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
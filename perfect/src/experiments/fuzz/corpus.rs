@@ -0,0 +1,146 @@
+//! A corpus of interesting inputs (optionally stored on disk).
+//!
+//! Each entry is stored in its own file (`<id>.txt`) with the signature
+//! first, followed by a separator line and the input text:
+//!
+//! ```text
+//! event 0c0 00 142
+//! event 025 02 1
+//! fault 14
+//! ---
+//! <text from FuzzInput::to_text>
+//! ```
+
+use crate::experiments::fuzz::*;
+use std::path::{ Path, PathBuf };
+
+/// An input and the signature it produced when it was added.
+#[derive(Clone, Debug)]
+pub struct CorpusEntry<I: FuzzInput> {
+    pub id: usize,
+    pub input: I,
+    pub signature: EventSignature,
+}
+impl <I: FuzzInput> CorpusEntry<I> {
+    const SEPARATOR: &'static str = "---";
+
+    fn to_text(&self) -> String {
+        let mut res = String::new();
+        for cnt in self.signature.counts.iter() {
+            res.push_str(&format!("event {:03x} {:02x} {}\n",
+                cnt.id, cnt.mask, cnt.value
+            ));
+        }
+        if let Some(trapno) = self.signature.fault {
+            res.push_str(&format!("fault {}\n", trapno));
+        }
+        res.push_str(Self::SEPARATOR);
+        res.push('\n');
+        res.push_str(&self.input.to_text());
+        res
+    }
+
+    fn from_text(id: usize, s: &str) -> Result<Self, String> {
+        let Some((header, body)) = s.split_once(&format!("{}\n", Self::SEPARATOR))
+        else {
+            return Err("missing separator".to_string());
+        };
+        let mut signature = EventSignature::default();
+        for line in header.lines() {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            match words.as_slice() {
+                ["event", id, mask, value] => {
+                    signature.counts.push(EventCount {
+                        id: u16::from_str_radix(id, 16)
+                            .map_err(|_| format!("invalid event '{}'", line))?,
+                        mask: u8::from_str_radix(mask, 16)
+                            .map_err(|_| format!("invalid event '{}'", line))?,
+                        value: value.parse()
+                            .map_err(|_| format!("invalid event '{}'", line))?,
+                    });
+                },
+                ["fault", trapno] => {
+                    signature.fault = Some(trapno.parse()
+                        .map_err(|_| format!("invalid fault '{}'", line))?);
+                },
+                [] => {},
+                _ => return Err(format!("unexpected line '{}'", line)),
+            }
+        }
+        Ok(Self { id, input: I::from_text(body)?, signature })
+    }
+}
+
+/// A set of inputs that produced new [`SignatureFeature`]s.
+pub struct Corpus<I: FuzzInput> {
+    /// Directory where entries are stored (if any)
+    dir: Option<PathBuf>,
+    pub entries: Vec<CorpusEntry<I>>,
+    next_id: usize,
+}
+impl <I: FuzzInput> Corpus<I> {
+    /// Create a corpus which is only kept in memory.
+    pub fn new() -> Self {
+        Self { dir: None, entries: Vec::new(), next_id: 0 }
+    }
+
+    /// Open a corpus stored in some directory (creating the directory if it
+    /// doesn't exist), loading any existing entries.
+    pub fn open(dir: impl AsRef<Path>) -> std::io::Result<Self> {
+        use std::io::{ Error, ErrorKind };
+        let dir = dir.as_ref().to_path_buf();
+        std::fs::create_dir_all(&dir)?;
+
+        let mut res = Self { dir: Some(dir.clone()), entries: Vec::new(),
+            next_id: 0
+        };
+        for dirent in std::fs::read_dir(&dir)? {
+            let path = dirent?.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("txt") {
+                continue;
+            }
+            let Some(id) = path.file_stem().and_then(|s| s.to_str())
+                .and_then(|s| s.parse::<usize>().ok())
+            else {
+                continue;
+            };
+            let text = std::fs::read_to_string(&path)?;
+            let entry = CorpusEntry::from_text(id, &text).map_err(|e| {
+                Error::new(ErrorKind::InvalidData, format!("{:?}: {}", path, e))
+            })?;
+            res.next_id = res.next_id.max(id + 1);
+            res.entries.push(entry);
+        }
+        res.entries.sort_by_key(|e| e.id);
+        Ok(res)
+    }
+
+    pub fn len(&self) -> usize { self.entries.len() }
+    pub fn is_empty(&self) -> bool { self.entries.is_empty() }
+
+    /// The directory where entries are stored (if any).
+    pub fn dir(&self) -> Option<&Path> { self.dir.as_deref() }
+
+    /// Add an entry to the corpus (writing it to disk if necessary),
+    /// returning the new entry ID.
+    pub fn add(&mut self, input: I, signature: EventSignature)
+        -> std::io::Result<usize>
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+        let entry = CorpusEntry { id, input, signature };
+        if let Some(dir) = &self.dir {
+            std::fs::write(dir.join(format!("{:06}.txt", id)), entry.to_text())?;
+        }
+        self.entries.push(entry);
+        Ok(id)
+    }
+
+    /// Return an iterator over all of the features covered by this corpus.
+    pub fn features(&self) -> impl Iterator<Item=SignatureFeature> + '_ {
+        self.entries.iter().flat_map(|e| e.signature.features())
+    }
+}
+impl <I: FuzzInput> Default for Corpus<I> {
+    fn default() -> Self { Self::new() }
+}
@@ -0,0 +1,243 @@
+//! Inputs which can be mutated by a [`Fuzzer`](super::Fuzzer).
+
+use crate::asm::*;
+use crate::ir::*;
+use crate::experiments::decoder::*;
+use crate::experiments::fuzz::ARENA_LEN;
+use crate::util::cpuid::HostCpuid;
+use rand::prelude::*;
+use iced_x86::{
+    Decoder, DecoderOptions, FlowControl, InstructionInfoFactory, OpAccess,
+    Register,
+};
+
+/// Implemented on types which can be mutated and measured by a
+/// [`Fuzzer`](super::Fuzzer).
+pub trait FuzzInput: Clone + Sized {
+    /// Apply a single random mutation.
+    fn mutate(&mut self, rng: &mut impl Rng);
+
+    /// Emit code which runs before the measured region, given the address
+    /// of the arena buffer used by inputs (see
+    /// [`ARENA_BUF`](super::ARENA_BUF)).
+    fn emit_setup(_f: &mut X64Assembler, _arena: usize) {}
+
+    /// Emit this input into the measured region.
+    ///
+    /// Emitted code must not clobber RSP or R15 (which holds the counter
+    /// value at the start of the measurement).
+    fn emit(&self, f: &mut X64Assembler);
+
+    /// Text representation (used when storing an input in a
+    /// [`Corpus`](super::Corpus)).
+    fn to_text(&self) -> String;
+
+    /// Parse the text representation produced by [`FuzzInput::to_text`].
+    fn from_text(s: &str) -> Result<Self, String>;
+}
+
+/// Returns true for operations which a mutation is allowed to remove or
+/// reorder. Labels and branches are left in place, so that branches are
+/// always forward.
+fn is_movable(op: &PerfectOp) -> bool {
+    !matches!(op, PerfectOp::Label(_) | PerfectOp::Jmp(_) | PerfectOp::Jcc(..))
+}
+
+impl FuzzInput for PerfectProg {
+    fn mutate(&mut self, rng: &mut impl Rng) {
+        let movable: Vec<usize> = (0..self.data.len())
+            .filter(|idx| is_movable(&self.data[*idx]))
+            .collect();
+
+        match (rng.gen_range(0..=4), movable.choose(rng)) {
+            // Insert a new operation
+            (0, _) | (_, None) => {
+                let idx = rng.gen_range(0..=self.data.len());
+                self.data.insert(idx, rng.gen());
+            },
+            // Replace an operation
+            (1, Some(&idx)) => {
+                self.data[idx] = rng.gen();
+            },
+            // Remove an operation
+            (2, Some(&idx)) => {
+                self.data.remove(idx);
+            },
+            // Duplicate an operation
+            (3, Some(&idx)) => {
+                let op = self.data[idx];
+                self.data.insert(idx, op);
+            },
+            // Swap two operations
+            (4, Some(&idx)) => {
+                let other = *movable.choose(rng).unwrap();
+                self.data.swap(idx, other);
+            },
+            _ => unreachable!(),
+        }
+        self.fix_flags(rng);
+    }
+
+    fn emit_setup(f: &mut X64Assembler, arena: usize) {
+        PerfectProg::emit_arena_ptr(f, arena);
+    }
+
+    fn emit(&self, f: &mut X64Assembler) {
+        PerfectProg::emit(self, f);
+    }
+
+    fn to_text(&self) -> String {
+        self.to_string()
+    }
+
+    fn from_text(s: &str) -> Result<Self, String> {
+        s.parse()
+    }
+}
+
+/// A sequence of raw instruction encodings.
+///
+/// Every encoding in the sequence is checked with [`EncodingSeq::is_fuzzable`]
+/// before being added. Memory operands are only allowed relative to the
+/// arena buffer (see [`EncodingSeq::ARENA_REG`]).
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct EncodingSeq(pub Vec<Vec<u8>>);
+impl EncodingSeq {
+    /// Register holding the address of the arena buffer
+    /// (the same as [`PerfectOp::ARENA_REG`]).
+    pub const ARENA_REG: Register = Register::R10;
+
+    /// Returns true if an encoding is a single valid instruction that we
+    /// can safely run in the measured region.
+    ///
+    /// This excludes instructions that:
+    ///
+    /// - Aren't supported by the host
+    /// - Are excluded by [`FaultOracle::is_excluded`]
+    /// - Change control flow
+    /// - Write to RSP, R15, or [`EncodingSeq::ARENA_REG`]
+    /// - Access memory outside of the arena buffer (ie. with a different
+    ///   base register, an index register, a segment override, or a
+    ///   displacement that goes past the end of the buffer)
+    ///
+    pub fn is_fuzzable(bytes: &[u8], cpuid: &HostCpuid) -> bool {
+        let iced = IcedOutcome::from_bytes(bytes, cpuid);
+        if iced.is_invalid() || !iced.supported || iced.len != bytes.len()
+            || iced.flow != FlowControl::Next
+            || FaultOracle::is_excluded(&iced)
+        {
+            return false;
+        }
+
+        let mut dec = Decoder::new(64, bytes, DecoderOptions::NONE);
+        let instr = dec.decode();
+        let mut factory = InstructionInfoFactory::new();
+        let info = factory.info(&instr);
+        let clobbers = info.used_registers().iter().any(|r| {
+            let full = r.register().full_register();
+            [Register::RSP, Register::R15, Self::ARENA_REG].contains(&full)
+                && r.access() != OpAccess::Read
+        });
+        let outside_arena = info.used_memory().iter().any(|m| {
+            let size = m.memory_size().size() as u64;
+            m.base() != Self::ARENA_REG || m.index() != Register::None
+                || !matches!(m.segment(), Register::DS | Register::ES | Register::SS)
+                || size == 0
+                || m.displacement().saturating_add(size) > ARENA_LEN as u64
+        });
+        !(clobbers || outside_arena)
+    }
+
+    /// Sample a random fuzzable encoding.
+    pub fn gen_encoding(rng: &mut impl Rng, cpuid: &HostCpuid) -> Vec<u8> {
+        loop {
+            let enc: RandomEncoding<15> = rng.gen();
+            let bytes = enc.as_bytes();
+            let mut dec = Decoder::new(64, &bytes, DecoderOptions::NONE);
+            let len = dec.decode().len();
+            if len != 0 && Self::is_fuzzable(&bytes[..len], cpuid) {
+                return bytes[..len].to_vec();
+            }
+        }
+    }
+
+    /// Generate a random sequence with `len` encodings.
+    pub fn gen(rng: &mut impl Rng, len: usize) -> Self {
+        let cpuid = HostCpuid::read();
+        Self((0..len).map(|_| Self::gen_encoding(rng, &cpuid)).collect())
+    }
+}
+
+impl FuzzInput for EncodingSeq {
+    fn emit_setup(f: &mut X64Assembler, arena: usize) {
+        PerfectProg::emit_arena_ptr(f, arena);
+    }
+
+    fn mutate(&mut self, rng: &mut impl Rng) {
+        let cpuid = HostCpuid::read();
+        let len = self.0.len();
+        match (rng.gen_range(0..=3), len) {
+            // Insert a new encoding
+            (0, _) | (_, 0) => {
+                let idx = rng.gen_range(0..=len);
+                self.0.insert(idx, Self::gen_encoding(rng, &cpuid));
+            },
+            // Remove an encoding
+            (1, _) => {
+                self.0.remove(rng.gen_range(0..len));
+            },
+            // Duplicate an encoding
+            (2, _) => {
+                let idx = rng.gen_range(0..len);
+                let enc = self.0[idx].clone();
+                self.0.insert(idx, enc);
+            },
+            // Flip a bit in an encoding (falling back to a new encoding if
+            // the result isn't fuzzable)
+            (3, _) => {
+                let idx = rng.gen_range(0..len);
+                let mut enc = self.0[idx].clone();
+                let byte = rng.gen_range(0..enc.len());
+                enc[byte] ^= 1 << rng.gen_range(0..8);
+                self.0[idx] = if Self::is_fuzzable(&enc, &cpuid) {
+                    enc
+                } else {
+                    Self::gen_encoding(rng, &cpuid)
+                };
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn emit(&self, f: &mut X64Assembler) {
+        for enc in self.0.iter() {
+            f.extend(enc.iter().copied());
+        }
+    }
+
+    fn to_text(&self) -> String {
+        let mut res = String::new();
+        for enc in self.0.iter() {
+            for b in enc.iter() {
+                res.push_str(&format!("{:02x}", b));
+            }
+            res.push('\n');
+        }
+        res
+    }
+
+    fn from_text(s: &str) -> Result<Self, String> {
+        let mut res = Vec::new();
+        for line in s.lines().map(|l| l.trim()).filter(|l| !l.is_empty()) {
+            if line.len() % 2 != 0 {
+                return Err(format!("invalid encoding '{}'", line));
+            }
+            let enc = (0..line.len()).step_by(2)
+                .map(|i| u8::from_str_radix(&line[i..i + 2], 16))
+                .collect::<Result<Vec<u8>, _>>()
+                .map_err(|_| format!("invalid encoding '{}'", line))?;
+            res.push(enc);
+        }
+        Ok(Self(res))
+    }
+}
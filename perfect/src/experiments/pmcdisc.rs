@@ -1,7 +1,9 @@
 
 pub mod group; 
+pub mod gen;
 
 pub use group::*;
+pub use gen::*;
 
 use crate::asm::*;
 use crate::experiments::*;
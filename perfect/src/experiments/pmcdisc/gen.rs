@@ -0,0 +1,593 @@
+//! Generating `pmcdisc` tests from instruction metadata.
+//!
+//! The groups in [`crate::experiments::pmcdisc::group`] are written by hand.
+//! Instead, this module enumerates instruction forms (a mnemonic with some
+//! set of operand kinds and widths) from the tables in [`iced_x86`], and
+//! builds a [`DynamicTestGroup`] for them.
+//!
+//! Each form can be emitted in two variants (see [`GenVariant`]):
+//!
+//! - A *latency* variant, where each instance depends on the result of the
+//!   previous instance
+//! - A *throughput* variant, where each instance writes a different
+//!   destination register and reads from a set of source registers which
+//!   are never written
+//!
+//! Limitations
+//! ===========
+//!
+//! - Only register and immediate operands are supported. Forms that
+//!   require a memory operand are skipped (forms with "r/m" operands are
+//!   emitted with a register).
+//! - Forms that change control-flow, use the stack, are privileged, or
+//!   are otherwise likely to fault or disturb the state of the process
+//!   are skipped.
+//! - RAX, RCX and RDX are clobbered by RDPMC in the measured region, and
+//!   RSP, RBP and R15 are reserved: none of these are used as operands
+//!   (unless an operand is fixed to one of them).
+
+use iced_x86::{
+    Code, EncodingKind, FlowControl, Instruction,
+    InstructionInfoFactory, Mnemonic, OpAccess, OpCodeOperandKind, OpKind,
+    Register,
+};
+use crate::asm::*;
+use crate::util::cpuid::HostCpuid;
+use std::collections::BTreeSet;
+
+/// Mnemonics that are never generated.
+///
+/// These are allowed in 64-bit user mode, but they either fault with
+/// arbitrary register inputs, or they change some part of the process
+/// state that we expect to remain constant. Instructions that implicitly
+/// write RAX/RCX/RDX (which are used by RDPMC in the measured region) or
+/// that are serializing are also excluded.
+const EXCLUDED_MNEMONICS: &[Mnemonic] = &[
+    Mnemonic::Div, Mnemonic::Idiv,
+    Mnemonic::Rdpmc, Mnemonic::Rdpru,
+    Mnemonic::Cpuid, Mnemonic::Rdtscp, Mnemonic::Rdpkru, Mnemonic::Xgetbv,
+    Mnemonic::Monitor, Mnemonic::Mwait, Mnemonic::Monitorx, Mnemonic::Mwaitx,
+    Mnemonic::Umonitor, Mnemonic::Umwait, Mnemonic::Tpause,
+    Mnemonic::Clzero,
+    Mnemonic::Wrfsbase, Mnemonic::Wrgsbase,
+    Mnemonic::Cli, Mnemonic::Sti, Mnemonic::Std,
+    Mnemonic::Sgdt, Mnemonic::Sidt, Mnemonic::Sldt, Mnemonic::Smsw,
+    Mnemonic::Str,
+    Mnemonic::Syscall, Mnemonic::Sysenter, Mnemonic::Sysexit,
+    Mnemonic::Sysret,
+    Mnemonic::Xabort, Mnemonic::Xend, Mnemonic::Xbegin,
+    Mnemonic::Ldmxcsr, Mnemonic::Vldmxcsr, Mnemonic::Fldcw,
+    Mnemonic::Wrpkru,
+];
+
+/// Look up a [`Mnemonic`] by name (ie. "add" or "vpaddq").
+pub fn mnemonic_from_str(s: &str) -> Option<Mnemonic> {
+    Mnemonic::values().find(|m| {
+        format!("{:?}", m).eq_ignore_ascii_case(s)
+    })
+}
+
+/// Register operand classes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum RegClass {
+    Gpr8, Gpr16, Gpr32, Gpr64, Mm, Xmm, Ymm, Zmm,
+}
+impl RegClass {
+    /// Registers used for destination operands.
+    const GPR_DST: [Gpr; 8] = [
+        Gpr::Rbx, Gpr::Rsi, Gpr::Rdi, Gpr::R8,
+        Gpr::R9, Gpr::R10, Gpr::R11, Gpr::R12,
+    ];
+    /// Registers used for source operands.
+    const GPR_SRC: [Gpr; 2] = [ Gpr::R13, Gpr::R14 ];
+
+    /// Registers used for destination operands.
+    const VEC_DST: [VectorGpr; 12] = [
+        VectorGpr::YMM0, VectorGpr::YMM1, VectorGpr::YMM2, VectorGpr::YMM3,
+        VectorGpr::YMM4, VectorGpr::YMM5, VectorGpr::YMM6, VectorGpr::YMM7,
+        VectorGpr::YMM8, VectorGpr::YMM9, VectorGpr::YMM10, VectorGpr::YMM11,
+    ];
+    /// Registers used for source operands.
+    const VEC_SRC: [VectorGpr; 2] = [ VectorGpr::YMM14, VectorGpr::YMM15 ];
+
+    /// Number of available destination registers.
+    pub fn num_dst(&self) -> usize {
+        match self {
+            Self::Gpr8 | Self::Gpr16 | Self::Gpr32 | Self::Gpr64 => {
+                Self::GPR_DST.len()
+            },
+            Self::Mm => 6,
+            Self::Xmm | Self::Ymm | Self::Zmm => Self::VEC_DST.len(),
+        }
+    }
+
+    /// Return the `idx`-th destination register in this class.
+    pub fn dst(&self, idx: usize) -> Option<Register> {
+        match self {
+            Self::Gpr8 | Self::Gpr16 | Self::Gpr32 | Self::Gpr64 => {
+                self.gpr(Self::GPR_DST[idx % Self::GPR_DST.len()])
+            },
+            Self::Mm => Some(Register::MM0 + (idx % 6) as u32),
+            Self::Xmm | Self::Ymm | Self::Zmm => {
+                self.vgpr(Self::VEC_DST[idx % Self::VEC_DST.len()])
+            },
+        }
+    }
+
+    /// Return the `idx`-th source register in this class.
+    pub fn src(&self, idx: usize) -> Option<Register> {
+        match self {
+            Self::Gpr8 | Self::Gpr16 | Self::Gpr32 | Self::Gpr64 => {
+                self.gpr(Self::GPR_SRC[idx % Self::GPR_SRC.len()])
+            },
+            Self::Mm => Some(Register::MM6 + (idx % 2) as u32),
+            Self::Xmm | Self::Ymm | Self::Zmm => {
+                self.vgpr(Self::VEC_SRC[idx % Self::VEC_SRC.len()])
+            },
+        }
+    }
+
+    /// Return the register in this class for some [`Gpr`]
+    /// (or `None` if this isn't a GPR class).
+    pub fn gpr(&self, gpr: Gpr) -> Option<Register> {
+        let idx = gpr as u32;
+        match self {
+            // NOTE: With a REX prefix, 4-7 select SPL, BPL, SIL, DIL
+            Self::Gpr8 if idx >= 4 => Some(Register::AL + (idx + 4)),
+            Self::Gpr8 => Some(Register::AL + idx),
+            Self::Gpr16 => Some(Register::AX + idx),
+            Self::Gpr32 => Some(Register::EAX + idx),
+            Self::Gpr64 => Some(Register::RAX + idx),
+            _ => None,
+        }
+    }
+
+    /// Return the register in this class for some [`VectorGpr`]
+    /// (or `None` if this isn't a vector register class).
+    pub fn vgpr(&self, vgpr: VectorGpr) -> Option<Register> {
+        let idx = vgpr as u32;
+        match self {
+            Self::Xmm => Some(Register::XMM0 + idx),
+            Self::Ymm => Some(Register::YMM0 + idx),
+            Self::Zmm => Some(Register::ZMM0 + idx),
+            _ => None,
+        }
+    }
+}
+
+/// The role of a single operand in some [`InstrForm`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GenOperand {
+    /// A register operand from some [`RegClass`]
+    Reg(RegClass),
+    /// A register operand that is fixed by the encoding (ie. 'al' or 'cl')
+    Fixed(Register),
+    /// An immediate operand
+    Imm(OpKind),
+}
+impl GenOperand {
+    /// Convert from an [`OpCodeOperandKind`].
+    /// Returns `None` for operand kinds that aren't supported.
+    pub fn from_kind(kind: OpCodeOperandKind) -> Option<Self> {
+        use OpCodeOperandKind as K;
+        Some(match kind {
+            K::r8_or_mem | K::r8_reg | K::r8_opcode => {
+                Self::Reg(RegClass::Gpr8)
+            },
+            K::r16_or_mem | K::r16_reg | K::r16_reg_mem | K::r16_rm |
+            K::r16_opcode => {
+                Self::Reg(RegClass::Gpr16)
+            },
+            K::r32_or_mem | K::r32_reg | K::r32_reg_mem | K::r32_rm |
+            K::r32_opcode | K::r32_vvvv => {
+                Self::Reg(RegClass::Gpr32)
+            },
+            K::r64_or_mem | K::r64_reg | K::r64_reg_mem | K::r64_rm |
+            K::r64_opcode | K::r64_vvvv => {
+                Self::Reg(RegClass::Gpr64)
+            },
+            K::mm_or_mem | K::mm_reg | K::mm_rm => Self::Reg(RegClass::Mm),
+            K::xmm_or_mem | K::xmm_reg | K::xmm_rm | K::xmm_vvvv |
+            K::xmm_is4 => {
+                Self::Reg(RegClass::Xmm)
+            },
+            K::ymm_or_mem | K::ymm_reg | K::ymm_rm | K::ymm_vvvv |
+            K::ymm_is4 => {
+                Self::Reg(RegClass::Ymm)
+            },
+            K::zmm_or_mem | K::zmm_reg | K::zmm_rm | K::zmm_vvvv => {
+                Self::Reg(RegClass::Zmm)
+            },
+
+            K::al => Self::Fixed(Register::AL),
+            K::cl => Self::Fixed(Register::CL),
+            K::ax => Self::Fixed(Register::AX),
+            K::eax => Self::Fixed(Register::EAX),
+            K::rax => Self::Fixed(Register::RAX),
+
+            K::imm8 | K::imm8_const_1 => Self::Imm(OpKind::Immediate8),
+            K::imm8sex16 => Self::Imm(OpKind::Immediate8to16),
+            K::imm8sex32 => Self::Imm(OpKind::Immediate8to32),
+            K::imm8sex64 => Self::Imm(OpKind::Immediate8to64),
+            K::imm16 => Self::Imm(OpKind::Immediate16),
+            K::imm32 => Self::Imm(OpKind::Immediate32),
+            K::imm32sex64 => Self::Imm(OpKind::Immediate32to64),
+            K::imm64 => Self::Imm(OpKind::Immediate64),
+            _ => return None,
+        })
+    }
+}
+
+/// Variants of a generated test.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum GenVariant {
+    /// Each instance depends on the previous instance
+    Latency,
+    /// Instances are independent of one another
+    Throughput,
+}
+
+/// A single instruction form.
+#[derive(Clone, Debug)]
+pub struct InstrForm {
+    pub code: Code,
+    pub operands: Vec<GenOperand>,
+}
+impl InstrForm {
+    /// Try to create a form from some [`Code`].
+    /// Returns `None` if any of the operands are unsupported.
+    pub fn new(code: Code) -> Option<Self> {
+        let operands = code.op_code().op_kinds().iter()
+            .map(|k| GenOperand::from_kind(*k))
+            .collect::<Option<Vec<GenOperand>>>()?;
+        Some(Self { code, operands })
+    }
+
+    /// Return a description of this form (ie. "ADD r/m64, r64").
+    pub fn desc(&self) -> &'static str {
+        self.code.op_code().instruction_string()
+    }
+
+    /// Build the `idx`-th instance of this form for the given variant.
+    ///
+    /// - In the latency variant, the first operand of each [`RegClass`]
+    ///   alternates between two destination registers, and the second
+    ///   operand of the same class reads the previous destination.
+    ///   If only one operand has a particular class, it always uses the
+    ///   same register.
+    /// - In the throughput variant, the first operand of each class
+    ///   rotates through all destination registers, and the remaining
+    ///   operands rotate through all source registers.
+    ///
+    /// Returns `None` if some operand has no register for its class.
+    pub fn build(&self, variant: GenVariant, idx: usize) -> Option<Instruction> {
+        let mut instr = Instruction::new();
+        instr.set_code(self.code);
+
+        let mut seen: Vec<(RegClass, usize)> = Vec::new();
+        let mut imm8_seen = false;
+        for (op, operand) in self.operands.iter().enumerate() {
+            let op = op as u32;
+            match operand {
+                GenOperand::Reg(class) => {
+                    let count = self.operands.iter()
+                        .filter(|o| **o == GenOperand::Reg(*class))
+                        .count();
+                    let nth = match seen.iter_mut().find(|x| x.0 == *class) {
+                        Some(ent) => { ent.1 += 1; ent.1 },
+                        None => { seen.push((*class, 0)); 0 },
+                    };
+                    let reg = match (variant, nth) {
+                        (GenVariant::Latency, 0) if count == 1 => {
+                            class.dst(0)
+                        },
+                        (GenVariant::Latency, 0) => class.dst(idx & 1),
+                        (GenVariant::Latency, 1) => class.dst((idx + 1) & 1),
+                        (GenVariant::Latency, n) => class.src(n),
+                        (GenVariant::Throughput, 0) => class.dst(idx),
+                        (GenVariant::Throughput, n) => class.src(idx + n - 1),
+                    }?;
+                    instr.set_op_kind(op, OpKind::Register);
+                    instr.set_op_register(op, reg);
+                },
+                GenOperand::Fixed(reg) => {
+                    instr.set_op_kind(op, OpKind::Register);
+                    instr.set_op_register(op, *reg);
+                },
+                // NOTE: Only 'extrq' and 'insertq' have a second imm8
+                GenOperand::Imm(OpKind::Immediate8) if imm8_seen => {
+                    instr.set_op_kind(op, OpKind::Immediate8_2nd);
+                    instr.set_immediate8_2nd(1);
+                },
+                GenOperand::Imm(kind) => {
+                    imm8_seen |= *kind == OpKind::Immediate8;
+                    instr.set_op_kind(op, *kind);
+                    instr.set_immediate_u64(op, 1);
+                },
+            }
+        }
+        Some(instr)
+    }
+
+    /// Build `n` instances of this form for the given variant.
+    pub fn build_unrolled(&self, variant: GenVariant, n: usize)
+        -> Option<Vec<Instruction>>
+    {
+        (0..n).map(|idx| self.build(variant, idx)).collect()
+    }
+
+    /// Returns true if the second instance of this form in the latency
+    /// variant reads a register written by the first instance.
+    ///
+    /// When this isn't the case, the latency variant doesn't actually
+    /// measure a dependency chain (ie. for 'cmp', which only writes flags).
+    pub fn has_dependency_chain(&self) -> bool {
+        let mut factory = InstructionInfoFactory::new();
+        let (Some(first), Some(second)) = (
+            self.build(GenVariant::Latency, 0),
+            self.build(GenVariant::Latency, 1),
+        ) else {
+            return false;
+        };
+
+        let written: BTreeSet<Register> = factory.info(&first)
+            .used_registers().iter()
+            .filter(|r| matches!(r.access(),
+                OpAccess::Write | OpAccess::CondWrite |
+                OpAccess::ReadWrite | OpAccess::ReadCondWrite
+            ))
+            .map(|r| r.register().full_register())
+            .collect();
+        let read: BTreeSet<Register> = factory.info(&second)
+            .used_registers().iter()
+            .filter(|r| matches!(r.access(),
+                OpAccess::Read | OpAccess::CondRead |
+                OpAccess::ReadWrite | OpAccess::ReadCondWrite
+            ))
+            .map(|r| r.register().full_register())
+            .collect();
+        written.intersection(&read).next().is_some()
+    }
+}
+
+/// A test emitter whose instructions are determined at runtime.
+#[derive(Clone)]
+pub struct DynamicTestEmitter {
+    /// Description of this emitter
+    pub desc: String,
+
+    /// Instructions emitted by this emitter
+    pub instrs: Vec<Instruction>,
+}
+impl DynamicTestEmitter {
+    pub fn new(desc: impl Into<String>, instrs: Vec<Instruction>) -> Self {
+        Self { desc: desc.into(), instrs }
+    }
+
+    /// Emit all instructions.
+    pub fn emit(&self, f: &mut X64Assembler) {
+        f.emit_iced_block(&self.instrs).unwrap();
+    }
+}
+
+/// A group of one or more [`DynamicTestEmitter`].
+///
+/// This is the runtime counterpart of [`crate::experiments::pmcdisc::TestGroup`].
+pub struct DynamicTestGroup {
+    pub name: String,
+
+    /// A prologue common to all emitters in this group, executed before
+    /// the start of the measurement.
+    pub prologue: Option<fn(&mut X64Assembler)>,
+
+    /// An epilogue common to all emitters in this group, executed after
+    /// the end of the measurement.
+    pub epilogue: Option<fn(&mut X64Assembler)>,
+
+    /// A common block of code emitted *after* the start of the measurement,
+    /// for all emitters in this group.
+    pub common_measured: Option<fn(&mut X64Assembler)>,
+
+    /// Common block of code used to measure the "floor" for this test
+    pub floor: Option<fn(&mut X64Assembler)>,
+
+    pub emitters: Vec<DynamicTestEmitter>,
+}
+
+/// Generates [`DynamicTestGroup`] from instruction metadata.
+#[derive(Clone)]
+pub struct InstrFormGenerator {
+    /// Only generate forms supported by this CPU
+    cpuid: Option<HostCpuid>,
+    /// Only generate forms with these mnemonics
+    mnemonics: Option<Vec<Mnemonic>>,
+    /// Only generate forms with these encodings
+    encodings: Vec<EncodingKind>,
+    /// Number of instances of each form in a test
+    unroll: usize,
+}
+impl Default for InstrFormGenerator {
+    fn default() -> Self { Self::new() }
+}
+impl InstrFormGenerator {
+    pub fn new() -> Self {
+        Self {
+            cpuid: None,
+            mnemonics: None,
+            encodings: vec![
+                EncodingKind::Legacy, EncodingKind::VEX, EncodingKind::XOP,
+            ],
+            unroll: 64,
+        }
+    }
+
+    /// Only generate forms supported by the host.
+    pub fn host_cpuid(mut self) -> Self {
+        self.cpuid = Some(HostCpuid::read());
+        self
+    }
+
+    /// Only generate forms supported by the given CPUID flags.
+    pub fn cpuid(mut self, cpuid: HostCpuid) -> Self {
+        self.cpuid = Some(cpuid);
+        self
+    }
+
+    /// Only generate forms with the given mnemonics.
+    pub fn mnemonics(mut self, mnemonics: &[Mnemonic]) -> Self {
+        self.mnemonics = Some(mnemonics.to_vec());
+        self
+    }
+
+    /// Only generate forms with the given encodings.
+    pub fn encodings(mut self, encodings: &[EncodingKind]) -> Self {
+        self.encodings = encodings.to_vec();
+        self
+    }
+
+    /// Set the number of instances of each form in a test.
+    pub fn unroll(mut self, unroll: usize) -> Self {
+        self.unroll = unroll;
+        self
+    }
+
+    /// Returns true if the form for this [`Code`] should be generated.
+    fn filter(&self, code: Code) -> bool {
+        let op = code.op_code();
+        if !op.is_instruction() || !op.mode64() {
+            return false;
+        }
+        // These are encoded with a leading 'wait'
+        if op.fwait() {
+            return false;
+        }
+        if !self.encodings.contains(&op.encoding()) {
+            return false;
+        }
+        if code.flow_control() != FlowControl::Next {
+            return false;
+        }
+        if op.is_privileged() || op.must_be_cpl0() || op.is_stack_instruction()
+            || op.is_save_restore() || op.is_input_output()
+            || op.require_op_mask_register()
+        {
+            return false;
+        }
+        if EXCLUDED_MNEMONICS.contains(&code.mnemonic()) {
+            return false;
+        }
+        if let Some(mnemonics) = &self.mnemonics {
+            if !mnemonics.contains(&code.mnemonic()) {
+                return false;
+            }
+        }
+        if let Some(cpuid) = &self.cpuid {
+            if !cpuid.supports_all(code.cpuid_features()) {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Enumerate all supported instruction forms.
+    pub fn forms(&self) -> Vec<InstrForm> {
+        Code::values()
+            .filter(|code| self.filter(*code))
+            .filter_map(InstrForm::new)
+            .collect()
+    }
+
+    /// Build a [`DynamicTestGroup`] for some variant of all supported
+    /// instruction forms with a particular encoding.
+    pub fn group(&self, encoding: EncodingKind, variant: GenVariant)
+        -> DynamicTestGroup
+    {
+        let emitters = self.forms().iter()
+            .filter(|form| form.code.op_code().encoding() == encoding)
+            .filter(|form| {
+                variant != GenVariant::Latency || form.has_dependency_chain()
+            })
+            .filter_map(|form| Some(DynamicTestEmitter::new(
+                format!("{} ({:?})", form.desc(), variant),
+                form.build_unrolled(variant, self.unroll)?,
+            )))
+            .collect();
+
+        DynamicTestGroup {
+            name: format!("Generated {:?} instructions ({:?})",
+                encoding, variant),
+            prologue: None,
+            epilogue: None,
+            common_measured: None,
+            floor: None,
+            emitters,
+        }
+    }
+
+    /// Build groups for both variants of all supported instruction forms
+    /// (for each encoding).
+    pub fn groups(&self) -> Vec<DynamicTestGroup> {
+        let mut res = Vec::new();
+        for encoding in self.encodings.iter() {
+            for variant in [GenVariant::Latency, GenVariant::Throughput] {
+                res.push(self.group(*encoding, variant));
+            }
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use iced_x86::{ Decoder, DecoderOptions };
+
+    #[test]
+    fn gen_add_r64_r64() {
+        let form = InstrForm::new(Code::Add_rm64_r64).unwrap();
+        assert!(form.has_dependency_chain());
+
+        let lat = form.build_unrolled(GenVariant::Latency, 2).unwrap();
+        assert_eq!(lat[0].op0_register(), Register::RBX);
+        assert_eq!(lat[0].op1_register(), Register::RSI);
+        assert_eq!(lat[1].op0_register(), Register::RSI);
+        assert_eq!(lat[1].op1_register(), Register::RBX);
+
+        let tput = form.build_unrolled(GenVariant::Throughput, 2).unwrap();
+        assert_eq!(tput[0].op0_register(), Register::RBX);
+        assert_eq!(tput[1].op0_register(), Register::RSI);
+        assert_eq!(tput[0].op1_register(), Register::R13);
+        assert_eq!(tput[1].op1_register(), Register::R14);
+
+        assert_eq!(RegClass::Gpr8.gpr(Gpr::Rsi), Some(Register::SIL));
+        assert_eq!(RegClass::Xmm.gpr(Gpr::Rsi), None);
+        assert_eq!(RegClass::Gpr64.vgpr(VectorGpr::YMM0), None);
+
+        let cmp = InstrForm::new(Code::Cmp_rm64_r64).unwrap();
+        assert!(!cmp.has_dependency_chain());
+
+        let gen = InstrFormGenerator::new();
+        assert!(!gen.filter(Code::Cpuid));
+        assert!(!gen.filter(Code::Rdtscp));
+        assert!(!gen.filter(Code::Xgetbv));
+    }
+
+    #[test]
+    fn gen_forms_encodable() {
+        let gen = InstrFormGenerator::new().host_cpuid().unroll(4);
+        for group in gen.groups() {
+            for emitter in group.emitters.iter() {
+                let mut f = X64Assembler::new().unwrap();
+                emitter.emit(&mut f);
+                let buf = f.finalize().unwrap();
+
+                // The decoder should agree about the encoded instructions
+                let mut dec = Decoder::new(64, &buf, DecoderOptions::NONE);
+                for instr in emitter.instrs.iter() {
+                    let d = dec.decode();
+                    assert_eq!(d.code(), instr.code(), "{}", emitter.desc);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,486 @@
+//! Inferring the execution pipes used by an instruction.
+//!
+//! Strategy
+//! ========
+//!
+//! A "blocker" is an instruction that is assumed to only execute on a
+//! particular set of pipes. When many independent blockers are emitted, the
+//! pipes they use are saturated: mixing in a tested instruction whose uops
+//! must also execute on those pipes will make the whole block take longer,
+//! while a tested instruction that can use some other pipe doesn't add any
+//! extra cycles.
+//!
+//! For each set of blocked pipes `S`, we measure:
+//!
+//! - A block containing only the blockers (taking `c_b` cycles)
+//! - A block containing only the tested instruction (taking `c_t` cycles)
+//! - The blockers interleaved with `n` instances of the tested instruction
+//!   (taking `c_m` cycles)
+//!
+//! and compute a *contention score* `(c_m - max(c_b, c_t)) * |S| / n`,
+//! which is roughly the number of uops per instance that could *only* be
+//! issued to the pipes in `S`. The smallest set of pipes with a score
+//! close to 1 is the set of pipes used by the instruction.
+//!
+//! This is basically the same approach used by [uops.info](https://uops.info).
+//!
+//! Dispatch Stalls
+//! ===============
+//!
+//! The score above only uses cycles. Some platforms also count dispatch
+//! stalls caused by running out of tokens for a particular scheduler (see
+//! [`StallEvent`]). For each set of blocked pipes, we also measure these
+//! events for both blocks and report the number of extra stalls per
+//! instance of the tested instruction.
+//!
+//! Blocker Register Use
+//! ====================
+//!
+//! Tests in [`crate::experiments::pmcdisc`] mostly use RAX and the lower
+//! vector registers. Blockers only use R8-R12, YMM8-YMM13, and the arena
+//! buffer [`PortUsageExperiment::ARENA_BUF`] (whose address is held in R12).
+//! R12 and YMM13 are only ever read by blockers, and blockers never read
+//! their destination register, so that blockers never depend on one another.
+
+use crate::experiments::*;
+use crate::experiments::pmcdisc::TestEmitter;
+use crate::events::*;
+use crate::stats::*;
+use itertools::Itertools;
+
+/// Describes the execution pipes on some platform.
+pub struct PipeLayout {
+    /// The name of each pipe
+    pub names: &'static [&'static str],
+}
+
+/// A set of execution pipes (bit `n` is the `n`-th pipe in some
+/// [`PipeLayout`]).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PipeSet(pub u32);
+impl PipeSet {
+    pub const EMPTY: Self = Self(0);
+
+    /// Create a set from a list of pipe indexes.
+    pub const fn from_slice(pipes: &[usize]) -> Self {
+        let mut res = 0;
+        let mut idx = 0;
+        while idx < pipes.len() {
+            res |= 1 << pipes[idx];
+            idx += 1;
+        }
+        Self(res)
+    }
+
+    pub fn len(&self) -> usize { self.0.count_ones() as usize }
+    pub fn is_empty(&self) -> bool { self.0 == 0 }
+    pub fn contains(&self, pipe: usize) -> bool { (self.0 & (1 << pipe)) != 0 }
+    pub fn union(&self, other: Self) -> Self { Self(self.0 | other.0) }
+    pub fn is_disjoint(&self, other: Self) -> bool { (self.0 & other.0) == 0 }
+
+    /// Return an iterator over pipe indexes in this set.
+    pub fn iter(&self) -> impl Iterator<Item=usize> + '_ {
+        (0..32).filter(|idx| self.contains(*idx))
+    }
+
+    /// Format this set with the names from some [`PipeLayout`]
+    /// (ie. "ALU0|ALU1").
+    pub fn to_string(&self, layout: &PipeLayout) -> String {
+        if self.is_empty() {
+            return "?".to_string();
+        }
+        self.iter().map(|idx| layout.names[idx]).join("|")
+    }
+}
+
+/// An instruction that is assumed to only execute on some set of pipes.
+pub struct Blocker {
+    /// The set of pipes used by this instruction
+    pub pipes: PipeSet,
+
+    /// Emit the `idx`-th instance of this instruction.
+    /// Different instances are expected to be independent of one another.
+    pub func: fn(&mut X64Assembler, usize),
+}
+
+/// An event counting dispatch stalls for the scheduler(s) feeding some set
+/// of pipes.
+pub struct StallEvent {
+    /// The set of pipes fed by the scheduler(s)
+    pub pipes: PipeSet,
+    pub event: fn() -> EventDesc,
+}
+
+/// A set of blockers and the layout of pipes for some platform.
+pub struct PortModel {
+    pub layout: PipeLayout,
+    pub blockers: &'static [Blocker],
+    pub stall_events: &'static [StallEvent],
+}
+
+/// Execution pipes on Zen 2.
+///
+/// NOTE: The pipes assumed for each of these blockers are taken from the
+/// Family 17h Software Optimization Guide and the measurements on
+/// [uops.info](https://uops.info). If these turn out to be wrong, the
+/// results will also be wrong!
+pub static ZEN2_PORT_MODEL: PortModel = PortModel {
+    layout: PipeLayout {
+        names: &[
+            "ALU0", "ALU1", "ALU2", "ALU3",
+            "AGU0", "AGU1", "AGU2",
+            "FP0", "FP1", "FP2", "FP3",
+        ],
+    },
+    blockers: &[
+        // Integer multiply
+        Blocker { pipes: PipeSet::from_slice(&[1]),
+            func: |f, idx| {
+                let dst = 8 + (idx % 4) as u8;
+                dynasm!(f ; imul Rq(dst), r12, 3);
+            },
+        },
+        // Simple integer operations
+        Blocker { pipes: PipeSet::from_slice(&[0, 1, 2, 3]),
+            func: |f, idx| {
+                let dst = 8 + (idx % 4) as u8;
+                dynasm!(f ; mov Rq(dst), 1);
+            },
+        },
+        // Loads
+        Blocker { pipes: PipeSet::from_slice(&[4, 5]),
+            func: |f, idx| {
+                let dst = 8 + (idx % 4) as u8;
+                dynasm!(f ; mov Rq(dst), [r12]);
+            },
+        },
+        // Floating-point multiply
+        Blocker { pipes: PipeSet::from_slice(&[7, 8]),
+            func: |f, idx| {
+                let dst = 8 + (idx % 5) as u8;
+                dynasm!(f ; vmulpd Ry(dst), Ry(13), Ry(13));
+            },
+        },
+        // Floating-point add
+        Blocker { pipes: PipeSet::from_slice(&[9, 10]),
+            func: |f, idx| {
+                let dst = 8 + (idx % 5) as u8;
+                dynasm!(f ; vaddpd Ry(dst), Ry(13), Ry(13));
+            },
+        },
+        // Vector bitwise operations
+        Blocker { pipes: PipeSet::from_slice(&[7, 8, 9, 10]),
+            func: |f, idx| {
+                let dst = 8 + (idx % 5) as u8;
+                dynasm!(f ; vpor Ry(dst), Ry(13), Ry(13));
+            },
+        },
+    ],
+    // NOTE: The schedulers associated with each of these events are a
+    // guess based on the names in the PPR.
+    stall_events: &[
+        StallEvent { pipes: PipeSet::from_slice(&[0]),
+            event: || Zen2Event::DeDisDispatchTokenStalls0(
+                DeDisDispatchTokenStalls0Mask::ALSQ1RsrcStall
+            ).as_desc(),
+        },
+        StallEvent { pipes: PipeSet::from_slice(&[1]),
+            event: || Zen2Event::DeDisDispatchTokenStalls0(
+                DeDisDispatchTokenStalls0Mask::ALSQ2RsrcStall
+            ).as_desc(),
+        },
+        StallEvent { pipes: PipeSet::from_slice(&[2, 3]),
+            event: || Zen2Event::DeDisDispatchTokenStalls0(
+                DeDisDispatchTokenStalls0Mask::ALSQ3_0_TokenStall
+            ).as_desc(),
+        },
+        StallEvent { pipes: PipeSet::from_slice(&[0, 1, 2, 3]),
+            event: || Zen2Event::DeDisDispatchTokenStalls0(
+                DeDisDispatchTokenStalls0Mask::ALUTokenStall
+            ).as_desc(),
+        },
+        StallEvent { pipes: PipeSet::from_slice(&[4, 5, 6]),
+            event: || Zen2Event::DeDisDispatchTokenStalls0(
+                DeDisDispatchTokenStalls0Mask::AGSQTokenStall
+            ).as_desc(),
+        },
+        StallEvent { pipes: PipeSet::from_slice(&[7, 8, 9, 10]),
+            event: || Zen2Event::DeDisDispatchTokenStalls1(
+                DeDisDispatchTokenStalls1Mask::FpSchRsrcStall
+            ).as_desc(),
+        },
+    ],
+};
+
+/// The result of inferring the pipes used by some instruction.
+#[derive(Clone, Debug)]
+pub struct PortUsage {
+    /// Description of the instruction
+    pub desc: String,
+    /// The inferred set of pipes
+    pub pipes: PipeSet,
+    /// Contention scores for each set of blocked pipes
+    pub scores: Vec<(PipeSet, f32)>,
+    /// For each set of blocked pipes, the number of extra dispatch stalls
+    /// per instance for each of the [`PortModel::stall_events`]
+    pub stalls: Vec<(PipeSet, Vec<f32>)>,
+}
+impl PortUsage {
+    /// Contention scores above this value indicate that the instruction
+    /// cannot avoid the blocked pipes.
+    pub const THRESHOLD: f32 = 0.5;
+
+    /// Infer the set of pipes from a list of contention scores.
+    ///
+    /// The result is the union of the *smallest* sets of blocked pipes
+    /// with a score above [`PortUsage::THRESHOLD`].
+    pub fn infer(scores: &[(PipeSet, f32)]) -> PipeSet {
+        let candidates: Vec<PipeSet> = scores.iter()
+            .filter(|(_, score)| *score >= Self::THRESHOLD)
+            .map(|(pipes, _)| *pipes)
+            .collect();
+        let min_len = match candidates.iter().map(|p| p.len()).min() {
+            Some(len) => len,
+            None => return PipeSet::EMPTY,
+        };
+        candidates.iter()
+            .filter(|p| p.len() == min_len)
+            .fold(PipeSet::EMPTY, |acc, p| acc.union(*p))
+    }
+
+    /// Format this result (ie. "add r64,r64 -> ALU0|ALU1|ALU2|ALU3").
+    pub fn to_string(&self, layout: &PipeLayout) -> String {
+        format!("{} -> {}", self.desc, self.pipes.to_string(layout))
+    }
+}
+
+/// Experiment for inferring the pipes used by a [`TestEmitter`].
+pub struct PortUsageExperiment;
+impl PortUsageExperiment {
+    /// Name of the arena buffer used by blockers (see
+    /// [`PortUsageExperiment::arena_buffer`]).
+    pub const ARENA_BUF: &'static str = "ports.blocker";
+
+    /// The arena buffer used by blockers. This must be added to the 
+    /// [`ArenaLayout`] for the harness. 
+    pub fn arena_buffer() -> ArenaBufferDesc {
+        ArenaBufferDesc::new(Self::ARENA_BUF, 0x1000)
+    }
+
+    /// Number of cycles that each set of blockers is expected to occupy.
+    const BLOCKER_CYCLES: usize = 128;
+
+    /// Number of instances of the tested instruction.
+    const TEST_INSTANCES: usize = 32;
+
+    /// Return all combinations of blockers that use disjoint sets of pipes.
+    pub fn blocker_sets(model: &PortModel) -> Vec<Vec<&Blocker>> {
+        let mut res = Vec::new();
+        for len in 1..=model.blockers.len() {
+            for set in model.blockers.iter().combinations(len) {
+                let disjoint = set.iter().tuple_combinations()
+                    .all(|(x, y): (&&Blocker, &&Blocker)| {
+                        x.pipes.is_disjoint(y.pipes)
+                    });
+                if disjoint {
+                    res.push(set);
+                }
+            }
+        }
+        res
+    }
+
+    /// Emit a block of `num_blockers` instances of blockers (chosen from
+    /// `blockers` in round-robin order), interleaved with `num_tests`
+    /// instances of the tested instruction.
+    ///
+    /// Blockers which access memory use the buffer at `blocker_addr`.
+    pub fn emit(
+        prologue: Option<fn(&mut X64Assembler)>,
+        blocker_addr: usize,
+        blockers: &[&Blocker],
+        num_blockers: usize,
+        test: Option<fn(&mut X64Assembler)>,
+        num_tests: usize,
+    ) -> X64Assembler
+    {
+        let mut f = X64Assembler::new().unwrap();
+        if let Some(prologue) = prologue {
+            prologue(&mut f);
+        }
+        dynasm!(f
+            ; mov r12, QWORD blocker_addr as _
+            ; vpxor ymm13, ymm13, ymm13
+            ; .align 64
+            ; lfence
+        );
+
+        f.emit_rdpmc_start(0, Gpr::R15 as u8);
+
+        let total = num_blockers + num_tests;
+        let mut emitted_tests = 0;
+        let mut emitted_blockers = 0;
+        for idx in 0..total {
+            // Spread the tested instructions evenly across the block
+            let want_tests = ((idx + 1) * num_tests) / total;
+            if emitted_tests < want_tests {
+                if let Some(test) = test {
+                    test(&mut f);
+                }
+                emitted_tests += 1;
+            } else {
+                let blocker = blockers[emitted_blockers % blockers.len()];
+                (blocker.func)(&mut f, emitted_blockers / blockers.len());
+                emitted_blockers += 1;
+            }
+        }
+
+        f.emit_rdpmc_end(0, Gpr::R15 as u8, Gpr::Rax as u8);
+        f.emit_ret();
+        f.commit().unwrap();
+        f
+    }
+
+    fn measure_min(harness: &mut PerfectHarness, desc: &EventDesc,
+        asm: &X64Assembler) -> usize
+    {
+        let asm_reader = asm.reader();
+        let asm_tgt_buf = asm_reader.lock();
+        let asm_tgt_ptr = asm_tgt_buf.ptr(AssemblyOffset(0));
+        let asm_fn: MeasuredFn = unsafe {
+            std::mem::transmute(asm_tgt_ptr)
+        };
+        let results = harness.measure(asm_fn, desc, 64,
+            InputMethod::Fixed(0, 0)
+        ).unwrap();
+        results.get_min()
+    }
+
+    /// Infer the set of pipes used by a [`TestEmitter`].
+    ///
+    /// The event `event` should count cycles. Each of the
+    /// [`PortModel::stall_events`] is also measured for each set of blocked
+    /// pipes.
+    pub fn run<E: AsEventDesc>(
+        harness: &mut PerfectHarness,
+        model: &PortModel,
+        event: E,
+        prologue: Option<fn(&mut X64Assembler)>,
+        emitter: &TestEmitter,
+    ) -> PortUsage
+    {
+        let desc = event.as_desc();
+        let n = Self::TEST_INSTANCES;
+        let addr = harness.arena_addr(Self::ARENA_BUF);
+
+        let test_only = Self::emit(prologue, addr, &[], 0, 
+            Some(emitter.func), n
+        );
+        let c_t = Self::measure_min(harness, &desc, &test_only);
+
+        let stall_descs: Vec<EventDesc> = model.stall_events.iter()
+            .map(|e| (e.event)())
+            .collect();
+
+        let mut scores = Vec::new();
+        let mut stalls = Vec::new();
+        for blockers in Self::blocker_sets(model) {
+            let pipes = blockers.iter()
+                .fold(PipeSet::EMPTY, |acc, b| acc.union(b.pipes));
+            let num_blockers = Self::BLOCKER_CYCLES * pipes.len();
+
+            let blockers_only = Self::emit(prologue, addr,
+                &blockers, num_blockers, None, 0
+            );
+            let mixed = Self::emit(prologue, addr,
+                &blockers, num_blockers, Some(emitter.func), n
+            );
+            let c_b = Self::measure_min(harness, &desc, &blockers_only);
+            let c_m = Self::measure_min(harness, &desc, &mixed);
+
+            let extra = c_m as f32 - usize::max(c_b, c_t) as f32;
+            scores.push((pipes, extra * pipes.len() as f32 / n as f32));
+
+            let extra_stalls = stall_descs.iter().map(|desc| {
+                let s_b = Self::measure_min(harness, desc, &blockers_only);
+                let s_m = Self::measure_min(harness, desc, &mixed);
+                (s_m as f32 - s_b as f32) / n as f32
+            }).collect();
+            stalls.push((pipes, extra_stalls));
+        }
+
+        // Use the disassembly as a description for anonymous emitters
+        let desc = if let Some(d) = emitter.desc {
+            d.to_string()
+        } else {
+            let mut f = X64Assembler::new().unwrap();
+            (emitter.func)(&mut f);
+            let buf = f.finalize().unwrap();
+            crate::util::disas_single(&buf, AssemblyOffset(0)).0
+        };
+
+        PortUsage {
+            desc,
+            pipes: PortUsage::infer(&scores),
+            scores,
+            stalls,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ports_infer() {
+        let layout = &ZEN2_PORT_MODEL.layout;
+        let alu1 = PipeSet::from_slice(&[1]);
+        let alu = PipeSet::from_slice(&[0, 1, 2, 3]);
+        let fmul = PipeSet::from_slice(&[7, 8]);
+
+        // Only contends with blockers on all ALUs
+        let scores = [(alu1, 0.1), (alu, 0.9), (fmul, 0.0)];
+        let pipes = PortUsage::infer(&scores);
+        assert_eq!(pipes, alu);
+        assert_eq!(pipes.to_string(layout), "ALU0|ALU1|ALU2|ALU3");
+
+        // Contends with the multiplier
+        let scores = [(alu1, 1.0), (alu, 1.0), (fmul, 0.0)];
+        assert_eq!(PortUsage::infer(&scores).to_string(layout), "ALU1");
+
+        let scores = [(alu1, 0.0), (alu, 0.0)];
+        assert_eq!(PortUsage::infer(&scores).to_string(layout), "?");
+    }
+
+    #[test]
+    fn ports_blocker_sets() {
+        // Sets of blockers never overlap
+        for set in PortUsageExperiment::blocker_sets(&ZEN2_PORT_MODEL) {
+            let total: usize = set.iter().map(|b| b.pipes.len()).sum();
+            let union = set.iter()
+                .fold(PipeSet::EMPTY, |acc, b| acc.union(b.pipes));
+            assert_eq!(total, union.len());
+        }
+    }
+
+    #[test]
+    fn ports_blocker_sources() {
+        // Blockers never write to the registers used as sources, and never
+        // read their own destination
+        for blocker in ZEN2_PORT_MODEL.blockers {
+            for idx in 0..16 {
+                let mut f = X64Assembler::new().unwrap();
+                (blocker.func)(&mut f, idx);
+                let buf = f.finalize().unwrap();
+                let (istr, _) = crate::util::disas_single(&buf, AssemblyOffset(0));
+                let ops: Vec<&str> = istr.split_once(' ').unwrap().1
+                    .split(',').map(|op| op.trim()).collect();
+                let dst = ops[0];
+                assert!(!dst.starts_with("r12") && !dst.starts_with("ymm13"),
+                    "{}", istr);
+                assert!(!ops[1..].iter().any(|op| op.contains(dst)), "{}", istr);
+            }
+        }
+    }
+}
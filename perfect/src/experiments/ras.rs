@@ -0,0 +1,423 @@
+//! Characterizing the return address stack (RAS).
+//!
+//! Each [`RasCase`] is a sequence of calls and returns emitted as measured
+//! code, along with the same sequence as a list of [`RasOp`] which can be
+//! replayed on a [`RasModel`]. Mispredicted returns are counted with a
+//! PMC event for the target platform (see [`RasExperiment::event`]).
+//!
+//! Measured code is entered with `call r15` from the harness, which is
+//! itself called from Rust code. The entries for these frames are still on
+//! the stack when measured code runs, and they are modeled by pushing
+//! `frames` placeholder entries before replaying a case. Only returns that
+//! pop past the measured code (ie. in [`RasCase::Underflow`]) can tell
+//! these apart.
+
+use crate::experiments::*;
+use crate::events::*;
+use crate::stats::*;
+use crate::uarch::ras::*;
+
+/// A sequence of calls and returns.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RasCase {
+    /// `n` nested calls followed by `n` returns.
+    Depth(usize),
+
+    /// `n` nested calls and returns, followed by `n` unmatched returns
+    /// through the same return sites (in the same order they were pushed,
+    /// from the deepest).
+    Underflow(usize),
+
+    /// A mispredicted return whose wrong path executes `n` calls.
+    SpecCall(usize),
+
+    /// A mispredicted return whose wrong path executes a return (popping
+    /// an entry) and a call (overwriting the same entry).
+    SpecOverwrite,
+}
+
+impl RasCase {
+    /// Symbol for the return at the top-level of measured code.
+    const TOP: usize = 0x1000;
+    /// Symbol for the end of measured code.
+    const FIN: usize = 0x1001;
+    /// Symbol for the return in the deepest call of a chain.
+    const LEAF: usize = 0x1002;
+    /// Symbols for the functions in [`RasCase::SpecCall`] and
+    /// [`RasCase::SpecOverwrite`].
+    const OUTER_SITE: usize = 0x1003;
+    const INNER_SITE: usize = 0x1004;
+    const INNER_RET: usize = 0x1005;
+    const REAL: usize = 0x1006;
+    const GADGET: usize = 0x1007;
+    /// Symbols for the return sites in a chain of calls.
+    const SITE: usize = 0x2000;
+    /// Symbols for calls on a mispredicted path.
+    const SPEC: usize = 0x3000;
+    /// Symbols for the entries pushed before entering measured code.
+    const FRAME: usize = 0xffff_0000;
+
+    /// Number of returns that are measured.
+    pub fn returns(&self) -> usize {
+        self.ops().iter().fold((0, false), |(n, spec), op| match op {
+            RasOp::Speculate => (n, true),
+            RasOp::Resolve => (n, false),
+            RasOp::Ret { .. } if !spec => (n + 1, spec),
+            _ => (n, spec),
+        }).0
+    }
+
+    fn chain_ops(n: usize) -> Vec<RasOp> {
+        let mut ops: Vec<RasOp> = (0..n)
+            .map(|i| RasOp::Call { site: Self::SITE + i })
+            .collect();
+        if n != 0 {
+            ops.push(RasOp::Ret { addr: Self::LEAF, target: Self::SITE + n - 1 });
+        }
+        for j in (1..n).rev() {
+            ops.push(RasOp::Ret { addr: Self::SITE + j, target: Self::SITE + j - 1 });
+        }
+        ops
+    }
+
+    /// Return the operations performed by measured code.
+    pub fn ops(&self) -> Vec<RasOp> {
+        match *self {
+            Self::Depth(n) => Self::chain_ops(n),
+            Self::Underflow(n) => {
+                let mut ops = Self::chain_ops(n);
+                if n == 0 {
+                    return ops;
+                }
+                let mut addr = Self::TOP;
+                for j in (1..n).rev() {
+                    ops.push(RasOp::Ret { addr, target: Self::SITE + j });
+                    addr = Self::SITE + j;
+                }
+                ops.push(RasOp::Ret { addr, target: Self::FIN });
+                ops
+            },
+            Self::SpecCall(n) => {
+                let mut ops = vec![
+                    RasOp::Call { site: Self::OUTER_SITE },
+                    RasOp::Call { site: Self::INNER_SITE },
+                    RasOp::Ret { addr: Self::INNER_RET, target: Self::REAL },
+                    RasOp::Speculate,
+                ];
+                ops.extend((0..n).map(|i| RasOp::Call { site: Self::SPEC + i }));
+                ops.extend([
+                    RasOp::Resolve,
+                    RasOp::Ret { addr: Self::REAL, target: Self::OUTER_SITE },
+                ]);
+                ops
+            },
+            Self::SpecOverwrite => vec![
+                RasOp::Call { site: Self::OUTER_SITE },
+                RasOp::Call { site: Self::INNER_SITE },
+                RasOp::Ret { addr: Self::INNER_RET, target: Self::REAL },
+                RasOp::Speculate,
+                RasOp::Ret { addr: Self::INNER_SITE, target: Self::OUTER_SITE },
+                RasOp::Call { site: Self::GADGET },
+                RasOp::Resolve,
+                RasOp::Ret { addr: Self::REAL, target: Self::OUTER_SITE },
+                RasOp::Call { site: Self::GADGET },
+            ],
+        }
+    }
+
+    /// Emit a chain of `n` nested calls and returns. The return site for
+    /// the call to the `j`-th function (for `j > 0`) is `sites[j]`, which
+    /// is placed in function `j - 1` and is always a return.
+    fn emit_chain(f: &mut X64Assembler, n: usize) -> Vec<DynamicLabel> {
+        let funcs: Vec<DynamicLabel> = (0..n).map(|_| f.new_dynamic_label()).collect();
+        let sites: Vec<DynamicLabel> = (0..n).map(|_| f.new_dynamic_label()).collect();
+        if n == 0 {
+            return sites;
+        }
+        let skip = f.new_dynamic_label();
+        dynasm!(f
+            ; call =>funcs[0]
+            ; jmp =>skip
+        );
+        for i in 0..n {
+            f.place_dynamic_label(funcs[i]);
+            if i + 1 < n {
+                dynasm!(f
+                    ; call =>funcs[i + 1]
+                    ; =>sites[i + 1]
+                );
+            }
+            dynasm!(f ; ret);
+        }
+        f.place_dynamic_label(skip);
+        sites
+    }
+
+    /// Emit a function which returns to `real` instead of its caller.
+    /// The return address is flushed so that the wrong path is as long
+    /// as possible.
+    fn emit_redirect(f: &mut X64Assembler, real: DynamicLabel) {
+        dynasm!(f
+            ; lea rax, [=>real]
+            ; mov [rsp], rax
+            ; mfence
+            ; clflush [rsp]
+            ; mfence
+            ; ret
+        );
+    }
+
+    /// Emit measured code for this case.
+    pub fn emit(&self) -> X64Assembler {
+        let mut f = X64Assembler::new().unwrap();
+        let fin = f.new_dynamic_label();
+        dynasm!(f
+            ; .align 64
+            ; lfence
+        );
+        f.emit_rdpmc_start(0, Gpr::R15 as u8);
+
+        match *self {
+            Self::Depth(n) => {
+                Self::emit_chain(&mut f, n);
+            },
+            Self::Underflow(n) => {
+                let sites = Self::emit_chain(&mut f, n);
+                if n != 0 {
+                    dynasm!(f
+                        ; lea rax, [=>fin]
+                        ; push rax
+                    );
+                    for site in sites.iter().skip(1).copied() {
+                        dynasm!(f
+                            ; lea rax, [=>site]
+                            ; push rax
+                        );
+                    }
+                    dynasm!(f ; ret);
+                }
+            },
+            Self::SpecCall(n) => {
+                let outer = f.new_dynamic_label();
+                let inner = f.new_dynamic_label();
+                let real = f.new_dynamic_label();
+                dynasm!(f
+                    ; call =>outer
+                    ; jmp =>fin
+                    ; =>outer
+                    ; call =>inner
+                );
+                // Calls on the wrong path
+                for _ in 0..n {
+                    let next = f.new_dynamic_label();
+                    dynasm!(f
+                        ; call =>next
+                        ; =>next
+                    );
+                }
+                let spin = f.new_dynamic_label();
+                dynasm!(f
+                    ; =>spin
+                    ; pause
+                    ; jmp =>spin
+                    ; =>real
+                    ; ret
+                    ; =>inner
+                );
+                Self::emit_redirect(&mut f, real);
+            },
+            Self::SpecOverwrite => {
+                let outer = f.new_dynamic_label();
+                let inner = f.new_dynamic_label();
+                let real = f.new_dynamic_label();
+                let gadget = f.new_dynamic_label();
+                // The wrong path returns here, and then calls a gadget
+                // which discards the new return address
+                dynasm!(f
+                    ; call =>outer
+                    ; call =>gadget
+                    ; =>gadget
+                    ; add rsp, 8
+                    ; jmp =>fin
+                    ; =>outer
+                    ; call =>inner
+                    ; ret
+                    ; =>real
+                    ; ret
+                    ; =>inner
+                );
+                Self::emit_redirect(&mut f, real);
+            },
+        }
+
+        f.place_dynamic_label(fin);
+        f.emit_rdpmc_end(0, Gpr::R15 as u8, Gpr::Rax as u8);
+        f.emit_ret();
+        f.commit().unwrap();
+        f
+    }
+}
+
+/// Results from [`RasExperiment::characterize`].
+#[derive(Clone, Debug)]
+pub struct RasReport {
+    /// The estimated number of entries
+    pub depth: usize,
+    /// The minimum number of mispredicted returns measured for each case
+    pub results: Vec<(RasCase, usize)>,
+    /// Policies (and the number of entries valid before entering measured
+    /// code) where [`RasModel`] agrees with all measurements
+    pub consistent: Vec<(RasUnderflow, RasRecovery, usize)>,
+}
+
+/// Experiment for characterizing the return address stack.
+pub struct RasExperiment;
+impl RasExperiment {
+    /// Number of times each case is measured.
+    const ITERS: usize = 64;
+
+    /// Event counting mispredicted returns on the target platform.
+    pub fn event(platform: TargetPlatform) -> EventDesc {
+        match platform {
+            TargetPlatform::Zen2 |
+            TargetPlatform::Zen3 => Zen2Event::ExRetNearRetMisp(0x00).as_desc(),
+            TargetPlatform::Tremont => {
+                TremontEvent::BrMisp(BrMispMask::Return).as_desc()
+            },
+        }
+    }
+
+    /// Measure the minimum number of mispredicted returns for some case.
+    pub fn measure(harness: &mut PerfectHarness, platform: TargetPlatform,
+        case: RasCase) -> usize
+    {
+        let asm = case.emit();
+        let asm_reader = asm.reader();
+        let asm_tgt_buf = asm_reader.lock();
+        let asm_tgt_ptr = asm_tgt_buf.ptr(AssemblyOffset(0));
+        let asm_fn: MeasuredFn = unsafe {
+            std::mem::transmute(asm_tgt_ptr)
+        };
+        let results = harness.measure(asm_fn, &Self::event(platform),
+            Self::ITERS, InputMethod::Fixed(0, 0)
+        ).unwrap();
+        results.get_min()
+    }
+
+    /// Predict the number of mispredicted returns for some case, assuming
+    /// that `frames` entries are valid before entering measured code.
+    ///
+    /// The case is replayed twice, so that any state left behind by the
+    /// first run (ie. stale entries) is used by the second run.
+    pub fn predict(model: &RasModel, case: RasCase, frames: usize) -> usize {
+        let mut model = model.clone();
+        let mut res = 0;
+        for _ in 0..2 {
+            model.clear();
+            for i in 0..frames {
+                model.call(RasCase::FRAME + i);
+            }
+            res = model.mispredicts(&case.ops());
+        }
+        res
+    }
+
+    /// Measure all cases up to `max_depth` entries, and find the policies
+    /// for a [`RasModel`] that agree with the measurements (with up to
+    /// `max_frames` entries left behind by the harness).
+    pub fn characterize(harness: &mut PerfectHarness,
+        platform: TargetPlatform, max_depth: usize, max_frames: usize,
+    ) -> RasReport
+    {
+        let mut results = Vec::new();
+
+        // The depth is the longest chain without any mispredictions
+        let mut depth = 0;
+        for n in 1..=max_depth {
+            let misses = Self::measure(harness, platform, RasCase::Depth(n));
+            results.push((RasCase::Depth(n), misses));
+            if misses == 0 {
+                depth = n;
+            }
+        }
+
+        let mut cases = vec![RasCase::SpecOverwrite];
+        for n in [1, 2, 4, 8] {
+            cases.push(RasCase::SpecCall(n));
+        }
+        for n in [2, depth / 2, depth, depth + 1] {
+            cases.push(RasCase::Underflow(n.max(2)));
+        }
+        for case in cases {
+            results.push((case, Self::measure(harness, platform, case)));
+        }
+
+        let mut consistent = Vec::new();
+        for underflow in [RasUnderflow::Empty, RasUnderflow::Wrap, RasUnderflow::Fallback] {
+            for recovery in [RasRecovery::None, RasRecovery::Pointer, RasRecovery::Full] {
+                let model = RasModel::new(depth.max(1))
+                    .underflow(underflow)
+                    .recovery(recovery);
+                for frames in 0..=max_frames {
+                    if results.iter().all(|(case, misses)| {
+                        Self::predict(&model, *case, frames) == *misses
+                    }) {
+                        consistent.push((underflow, recovery, frames));
+                    }
+                }
+            }
+        }
+
+        RasReport { depth, results, consistent }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ras_cases() {
+        let model = RasModel::new(16);
+        for n in 0..=24 {
+            assert_eq!(RasCase::Depth(n).returns(), n);
+            assert_eq!(RasExperiment::predict(&model, RasCase::Depth(n), 4),
+                n.saturating_sub(16));
+        }
+
+        // Unmatched returns only predict correctly when the pointer wraps
+        // around to the stale entries from the chain
+        let case = RasCase::Underflow(16);
+        assert_eq!(case.returns(), 32);
+        let wrap = model.clone().underflow(RasUnderflow::Wrap);
+        assert_eq!(RasExperiment::predict(&model, case, 0), 16);
+        assert_eq!(RasExperiment::predict(&wrap, case, 0), 1);
+        assert_eq!(RasExperiment::predict(&model, case, 4), 16);
+
+        // The chain overwrites the entries for the harness, and only the
+        // last return has a different target than the chain
+        let fallback = model.clone().underflow(RasUnderflow::Fallback);
+        assert_eq!(RasExperiment::predict(&fallback, case, 4), 1);
+
+        for (recovery, expected) in [
+            (RasRecovery::None, (2, 2)),
+            (RasRecovery::Pointer, (1, 2)),
+            (RasRecovery::Full, (1, 1)),
+        ] {
+            let model = RasModel::new(16).recovery(recovery);
+            let res = (
+                RasExperiment::predict(&model, RasCase::SpecCall(4), 4),
+                RasExperiment::predict(&model, RasCase::SpecOverwrite, 4),
+            );
+            assert_eq!(res, expected, "{:?}", recovery);
+        }
+        assert_eq!(RasCase::SpecOverwrite.returns(), 2);
+
+        // Make sure we can actually emit all of these
+        for case in [RasCase::Depth(8), RasCase::Underflow(8),
+            RasCase::SpecCall(4), RasCase::SpecOverwrite]
+        {
+            case.emit();
+        }
+    }
+}
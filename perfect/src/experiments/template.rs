@@ -3,6 +3,9 @@
 use crate::experiments::*;
 use crate::harness::TargetPlatform;
 
+pub mod timing;
+pub use timing::*;
+
 #[derive(Clone, Copy, Debug)]
 pub enum RdpmcStrategy { 
     /// Save initial RDPMC results in a general-purpose register
@@ -42,6 +45,10 @@ pub struct MispredictedReturnOptions<I> {
     /// Try to release any hanging physical registers after the initial RDPMC
     pub free_pregs: bool,
 
+    /// Address of the buffer written when releasing physical registers
+    /// (see [`MispredictedReturnOptions::ARENA_BUF`])
+    pub free_pregs_addr: Option<usize>,
+
     /// Mark the end of the body with a speculative 'PREFETCH' instruction
     pub prefetch_marker: Option<Gpr>,
 
@@ -63,6 +70,16 @@ pub struct MispredictedReturnOptions<I> {
 
 }
 impl <I> MispredictedReturnOptions<I> {
+    /// Name of the arena buffer used when releasing physical registers (see
+    /// [`MispredictedReturnOptions::arena_buffer`]).
+    pub const ARENA_BUF: &'static str = "template.free_pregs";
+
+    /// The arena buffer used when releasing physical registers. This must be
+    /// added to the [`ArenaLayout`] for the harness when `free_pregs` is set.
+    pub fn arena_buffer() -> ArenaBufferDesc {
+        ArenaBufferDesc::new(Self::ARENA_BUF, 0x1000)
+    }
+
     pub fn zen2_defaults() -> Self { 
         Self { 
             ctr_idx: 0,
@@ -73,6 +90,7 @@ impl <I> MispredictedReturnOptions<I> {
             prefetch_marker: None,
             fnop_marker: false,
             free_pregs: false,
+            free_pregs_addr: None,
             prologue_fn: None,
             post_prologue_fn: None,
             speculative_epilogue_fn: None,
@@ -90,6 +108,7 @@ impl <I> MispredictedReturnOptions<I> {
             prefetch_marker: None,
             fnop_marker: false,
             free_pregs: false,
+            free_pregs_addr: None,
             prologue_fn: None,
             post_prologue_fn: None,
             speculative_epilogue_fn: None,
@@ -137,6 +156,13 @@ impl <I> MispredictedReturnOptions<I> {
         self
     }
 
+    /// Use the arena buffer [`MispredictedReturnOptions::ARENA_BUF`] in some
+    /// harness when releasing physical registers.
+    pub fn free_pregs_in(mut self, harness: &PerfectHarness) -> Self {
+        self.free_pregs_addr = Some(harness.arena_addr(Self::ARENA_BUF));
+        self
+    }
+
     pub fn prologue_fn(mut self, x: Option<fn(&mut X64Assembler, I)>) -> Self { 
         self.prologue_fn = x;
         self
@@ -534,23 +560,21 @@ pub trait MispredictedReturnTemplate<I: Copy> {
                 // Try to free any hanging references to physical registers 
                 // *in the store queue* by filling the store queue with writes 
                 // that depend on a known-zero register.
-                dynasm!(f
-                    ; mov [0x0000_0200], rax
-                    ; mov [0x0000_0200], rbx
-                    ; mov [0x0000_0200], rcx
-                    ; mov [0x0000_0200], rdx
-                    ; mov [0x0000_0200], rdi
-                    ; mov [0x0000_0200], rsi
-                    ; mov [0x0000_0200], rbp
-                    ; mov [0x0000_0200], r8
-                    ; mov [0x0000_0200], r9
-                    ; mov [0x0000_0200], r10
-                    ; mov [0x0000_0200], r11
-                    ; mov [0x0000_0200], r12
-                    ; mov [0x0000_0200], r13
-                    ; mov [0x0000_0200], r14
-                    ; mov [0x0000_0200], r15
+                //
+                // RDI holds the address (so R8 is stored in its place), and
+                // is renamed to R8 again afterwards. 
+                let addr = opts.free_pregs_addr.expect(
+                    "MispredictedReturnOptions::free_pregs_addr is not set"
                 );
+                dynasm!(f ; mov rdi, QWORD addr as i64);
+                for reg in [
+                    Gpr::Rax, Gpr::Rbx, Gpr::Rcx, Gpr::Rdx, Gpr::R8,
+                    Gpr::Rsi, Gpr::Rbp, Gpr::R8, Gpr::R9, Gpr::R10, Gpr::R11,
+                    Gpr::R12, Gpr::R13, Gpr::R14, Gpr::R15,
+                ] {
+                    dynasm!(f ; mov [rdi], Rq(reg as u8));
+                }
+                dynasm!(f ; mov rdi, r8);
             }
         }
 
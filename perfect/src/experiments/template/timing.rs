@@ -0,0 +1,519 @@
+//! Templates for measuring instruction latency and throughput.
+
+use crate::experiments::*;
+use crate::events::*;
+use crate::stats::*;
+use std::fmt;
+
+/// Describes how a dependency chain is formed between instances of an
+/// instruction in the latency variant of a [`LatencyThroughputTemplate`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChainStrategy {
+    /// Each instance uses the same register for the source and destination
+    /// operand (ie. `imul rbx, rbx`).
+    ///
+    /// NOTE: Be careful with instructions that are recognized as zeroing
+    /// idioms when both operands are the same (ie. `xor rbx, rbx`).
+    Register,
+
+    /// Instances alternate between two registers, where the destination of
+    /// each instance is the source of the next (ie. `add rbx, rsi` followed
+    /// by `add rsi, rbx`).
+    RegisterPair,
+
+    /// Each instance uses a different destination register, and the chain
+    /// is formed through the flags (ie. `adc` or `rcl`).
+    ///
+    /// In the throughput variant, each instance is preceded by an
+    /// instruction that overwrites the flags (see
+    /// [`LatencyThroughputTemplate::emit_flags_break`]).
+    Flags,
+
+    /// Each instance uses a different destination register, and the chain
+    /// is formed through a memory operand at [`InstrOperands::mem`]
+    /// (ie. `add [r14], rbx`).
+    Memory,
+}
+
+/// Operands passed to an instruction emitter.
+///
+/// An emitter is expected to use these operands instead of hardcoding them,
+/// so that the template can decide how (or whether) different instances of
+/// the instruction depend on one another.
+#[derive(Clone, Copy, Debug)]
+pub struct InstrOperands {
+    /// The index of this instance
+    pub idx: usize,
+    /// Destination register
+    pub dst: Gpr,
+    /// Source register
+    pub src: Gpr,
+    /// Register holding the base address of memory operands
+    pub base: Gpr,
+    /// Offset of a memory operand (ie. `[base + mem]`)
+    pub mem: i32,
+}
+
+/// Different variants measured by a [`LatencyThroughputTemplate`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimingVariant {
+    /// Instances form a dependency chain (followed by the link emitter)
+    #[default]
+    Latency,
+    /// Only the link emitter
+    Link,
+    /// Instances are independent
+    Throughput,
+    /// Only the instruction used to break dependencies through the flags
+    /// (with [`ChainStrategy::Flags`])
+    FlagsBreak,
+}
+
+/// The input associated with a measurement taken by some
+/// [`LatencyThroughputTemplate`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TimingInput {
+    pub variant: TimingVariant,
+    /// Number of unrolled instances
+    pub unroll: usize,
+}
+impl fmt::Display for TimingInput {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let variant = match self.variant {
+            TimingVariant::Latency => "latency",
+            TimingVariant::Link => "link",
+            TimingVariant::Throughput => "throughput",
+            TimingVariant::FlagsBreak => "flags_break",
+        };
+        write!(f, "{}:{}", variant, self.unroll)
+    }
+}
+
+/// Latency and reciprocal throughput for some instruction.
+#[derive(Clone, Copy, Debug)]
+pub struct InstrTiming {
+    /// Cycles per instruction in a dependency chain
+    /// (with the cost of the link emitter removed)
+    pub latency: f32,
+    /// Cycles per instruction when all instances are independent
+    /// (with the cost of breaking dependencies through the flags removed)
+    pub throughput: f32,
+}
+impl InstrTiming {
+    /// Compute the latency and throughput from a set of results produced by
+    /// [`LatencyThroughputTemplate::run`].
+    ///
+    /// For each variant, the cost per instruction is the difference between
+    /// the minimum observed values for the smallest and largest number of
+    /// unrolled instances, divided by the difference in the number of
+    /// instances. This removes any fixed overhead (ie. from RDPMC).
+    pub fn from_results<E: AsEventDesc>(
+        results: &ExperimentCaseResults<E, TimingInput>,
+        event: E,
+    ) -> Option<Self>
+    {
+        let event_results = results.data.get(&event)?;
+        let slope = |variant: TimingVariant| -> Option<f32> {
+            let points: Vec<(usize, usize)> = event_results.inputs.iter()
+                .zip(event_results.local_min())
+                .filter(|(input, _)| input.variant == variant)
+                .map(|(input, min)| (input.unroll, min))
+                .collect();
+            let lo = points.iter().min_by_key(|(n, _)| *n)?;
+            let hi = points.iter().max_by_key(|(n, _)| *n)?;
+            if hi.0 == lo.0 {
+                return None;
+            }
+            Some((hi.1 as f32 - lo.1 as f32) / (hi.0 - lo.0) as f32)
+        };
+
+        let link = slope(TimingVariant::Link).unwrap_or(0.0);
+        let flags_break = slope(TimingVariant::FlagsBreak).unwrap_or(0.0);
+        Some(Self {
+            latency: slope(TimingVariant::Latency)? - link,
+            throughput: slope(TimingVariant::Throughput)? - flags_break,
+        })
+    }
+}
+
+/// Options passed to some [`LatencyThroughputTemplate`].
+#[derive(Clone, Copy, Debug)]
+pub struct LatencyThroughputOptions {
+    /// RDPMC counter index
+    pub ctr_idx: i32,
+
+    /// Strategy for forming a dependency chain
+    pub chain: ChainStrategy,
+
+    /// The number of unrolled instances used for each measurement
+    pub unroll: [usize; 2],
+
+    /// Number of measurements taken for each case
+    pub iters: usize,
+
+    /// Base address of memory operands (by default, the arena buffer
+    /// [`LatencyThroughputOptions::ARENA_BUF`])
+    pub mem_addr: Option<usize>,
+
+    /// Optional prologue emitter [emitted before measurement]
+    pub prologue_fn: Option<fn(&mut X64Assembler)>,
+
+    /// Optional "link" emitter [emitted after each instance in the latency
+    /// variant]. This is used to complete a dependency chain when the
+    /// instruction doesn't read its own result (ie. connecting a flags
+    /// result back to a register with 'setc'). The cost of the link is
+    /// measured separately and removed from the latency.
+    pub link_fn: Option<fn(&mut X64Assembler, InstrOperands)>,
+}
+impl LatencyThroughputOptions {
+    /// Name of the arena buffer used for memory operands (see
+    /// [`LatencyThroughputOptions::arena_buffer`]).
+    pub const ARENA_BUF: &'static str = "timing";
+
+    /// The arena buffer used for memory operands. This must be added to the
+    /// [`ArenaLayout`] for the harness (unless `mem_addr` is set).
+    pub fn arena_buffer() -> ArenaBufferDesc {
+        ArenaBufferDesc::new(Self::ARENA_BUF, 0x1000)
+    }
+
+    pub fn new() -> Self {
+        Self {
+            ctr_idx: 0,
+            chain: ChainStrategy::Register,
+            unroll: [64, 128],
+            iters: 256,
+            mem_addr: None,
+            prologue_fn: None,
+            link_fn: None,
+        }
+    }
+
+    pub fn ctr_idx(mut self, x: i32) -> Self {
+        self.ctr_idx = x;
+        self
+    }
+
+    pub fn chain(mut self, x: ChainStrategy) -> Self {
+        self.chain = x;
+        self
+    }
+
+    pub fn unroll(mut self, lo: usize, hi: usize) -> Self {
+        assert!(lo < hi);
+        self.unroll = [lo, hi];
+        self
+    }
+
+    pub fn iters(mut self, x: usize) -> Self {
+        self.iters = x;
+        self
+    }
+
+    pub fn mem_addr(mut self, x: usize) -> Self {
+        self.mem_addr = Some(x);
+        self
+    }
+
+    /// Return the base address of memory operands used with some harness.
+    pub fn resolve_mem_addr(&self, harness: &PerfectHarness) -> usize {
+        self.mem_addr.unwrap_or_else(|| harness.arena_addr(Self::ARENA_BUF))
+    }
+
+    pub fn prologue_fn(mut self, x: Option<fn(&mut X64Assembler)>) -> Self {
+        self.prologue_fn = x;
+        self
+    }
+
+    pub fn link_fn(mut self,
+        x: Option<fn(&mut X64Assembler, InstrOperands)>) -> Self
+    {
+        self.link_fn = x;
+        self
+    }
+}
+impl Default for LatencyThroughputOptions {
+    fn default() -> Self { Self::new() }
+}
+
+/// Template for measuring the latency and reciprocal throughput of an
+/// instruction (in the style of uops.info).
+///
+/// The user provides an emitter for a single instance of the instruction,
+/// which is passed a set of [`InstrOperands`]. The template measures:
+///
+/// - A dependency chain of instances (see [`ChainStrategy`]), each followed
+///   by an optional link emitter
+/// - The link emitter on its own
+/// - Independent instances that write different destination registers
+/// - The instruction used to break dependencies through the flags on its
+///   own (only with [`ChainStrategy::Flags`])
+///
+/// Each variant is measured with two different numbers of unrolled
+/// instances, and [`InstrTiming::from_results`] uses the difference between
+/// them to compute the number of cycles per instruction.
+///
+/// Register Use
+/// ============
+///
+/// RAX, RCX, and RDX are clobbered by RDPMC, and R15 holds the initial
+/// counter value. Emitters should only use the registers given to them in
+/// [`InstrOperands`]:
+///
+/// - Destination registers are taken from RBX, RSI, RDI, and R8-R12
+/// - Source registers in the throughput variant are R13 (or R14)
+/// - R14 holds the base address of memory operands
+/// - RBP is written when breaking dependencies through the flags
+///
+pub trait LatencyThroughputTemplate {
+    /// Registers used as destination operands
+    const DST_REGS: [Gpr; 8] = [
+        Gpr::Rbx, Gpr::Rsi, Gpr::Rdi, Gpr::R8,
+        Gpr::R9, Gpr::R10, Gpr::R11, Gpr::R12,
+    ];
+    /// Register used as a source operand in the throughput variant
+    const SRC_REG: Gpr = Gpr::R13;
+    /// Register holding the base address of memory operands
+    const MEM_REG: Gpr = Gpr::R14;
+    /// Scratch register used to break dependencies through the flags
+    const FLAGS_BREAK_REG: Gpr = Gpr::Rbp;
+
+    /// Emit an instruction which overwrites the flags (without depending on
+    /// any other instruction).
+    fn emit_flags_break(f: &mut X64Assembler) {
+        let reg = Self::FLAGS_BREAK_REG as u8;
+        dynasm!(f ; xor Rd(reg), Rd(reg));
+    }
+
+    /// Return the operands for the `idx`-th instance in some variant.
+    fn operands(
+        opts: &LatencyThroughputOptions,
+        variant: TimingVariant,
+        idx: usize
+    ) -> InstrOperands
+    {
+        let rotate = Self::DST_REGS[idx % Self::DST_REGS.len()];
+        let base = Self::MEM_REG;
+        // Use a different cacheline for independent memory operands
+        let mem_indep = (idx % 8) as i32 * 64;
+        match (variant, opts.chain) {
+            (TimingVariant::Throughput | TimingVariant::FlagsBreak, _) => {
+                InstrOperands {
+                    idx, dst: rotate, src: Self::SRC_REG, base, mem: mem_indep,
+                }
+            },
+            (_, ChainStrategy::Register) => InstrOperands {
+                idx, dst: Gpr::Rbx, src: Gpr::Rbx, base, mem: mem_indep,
+            },
+            (_, ChainStrategy::RegisterPair) => {
+                let (dst, src) = if idx & 1 == 0 {
+                    (Gpr::Rbx, Gpr::Rsi)
+                } else {
+                    (Gpr::Rsi, Gpr::Rbx)
+                };
+                InstrOperands { idx, dst, src, base, mem: mem_indep }
+            },
+            (_, ChainStrategy::Flags) => InstrOperands {
+                idx, dst: rotate, src: Self::SRC_REG, base, mem: mem_indep,
+            },
+            (_, ChainStrategy::Memory) => InstrOperands {
+                idx, dst: rotate, src: Self::SRC_REG, base, mem: 0,
+            },
+        }
+    }
+
+    /// Emit a measurement for some variant with `n` unrolled instances
+    /// (with memory operands relative to `mem_addr`).
+    fn emit(
+        opts: &LatencyThroughputOptions,
+        variant: TimingVariant,
+        n: usize,
+        mem_addr: usize,
+        user_fn: fn(&mut X64Assembler, InstrOperands),
+    ) -> X64Assembler
+    {
+        let mut f = X64Assembler::new().unwrap();
+
+        // Give all operands a known value before the measurement
+        for reg in Self::DST_REGS.iter().chain([Self::SRC_REG].iter()) {
+            dynasm!(f ; mov Rq(*reg as u8), 1);
+        }
+        dynasm!(f
+            ; mov Rq(Self::MEM_REG as u8), QWORD mem_addr as i64
+            ; xor eax, eax
+            ; mov [Rq(Self::MEM_REG as u8)], rax
+        );
+        if let Some(prologue) = opts.prologue_fn {
+            prologue(&mut f);
+        }
+
+        dynasm!(f
+            ; .align 64
+            ; lfence
+        );
+        f.emit_rdpmc_start(opts.ctr_idx, Gpr::R15 as u8);
+        for idx in 0..n {
+            let ops = Self::operands(opts, variant, idx);
+            match variant {
+                TimingVariant::Latency => {
+                    user_fn(&mut f, ops);
+                    if let Some(link) = opts.link_fn { link(&mut f, ops); }
+                },
+                TimingVariant::Link => {
+                    if let Some(link) = opts.link_fn { link(&mut f, ops); }
+                },
+                TimingVariant::Throughput => {
+                    if opts.chain == ChainStrategy::Flags {
+                        Self::emit_flags_break(&mut f);
+                    }
+                    user_fn(&mut f, ops);
+                },
+                TimingVariant::FlagsBreak => {
+                    Self::emit_flags_break(&mut f);
+                },
+            }
+        }
+        f.emit_rdpmc_end(opts.ctr_idx, Gpr::R15 as u8, Gpr::Rax as u8);
+        f.emit_ret();
+        f.commit().unwrap();
+        f
+    }
+
+    /// Measure all variants for some instruction emitter.
+    fn run<E: AsEventDesc>(
+        harness: &mut PerfectHarness,
+        opts: &LatencyThroughputOptions,
+        event: E,
+        desc: &'static str,
+        user_fn: fn(&mut X64Assembler, InstrOperands),
+    ) -> ExperimentCaseResults<E, TimingInput>
+    {
+        let mut case_res = ExperimentCaseResults::new(desc);
+        let edesc = event.as_desc();
+        let mem_addr = opts.resolve_mem_addr(harness);
+
+        let mut variants = vec![TimingVariant::Latency];
+        if opts.link_fn.is_some() {
+            variants.push(TimingVariant::Link);
+        }
+        variants.push(TimingVariant::Throughput);
+        if opts.chain == ChainStrategy::Flags {
+            variants.push(TimingVariant::FlagsBreak);
+        }
+
+        for variant in variants {
+            for unroll in opts.unroll {
+                let asm = Self::emit(opts, variant, unroll, mem_addr, user_fn);
+                let asm_reader = asm.reader();
+                let asm_tgt_buf = asm_reader.lock();
+                let asm_tgt_ptr = asm_tgt_buf.ptr(AssemblyOffset(0));
+                let asm_fn: MeasuredFn = unsafe {
+                    std::mem::transmute(asm_tgt_ptr)
+                };
+                let results = harness.measure(asm_fn,
+                    &edesc, opts.iters, InputMethod::Fixed(0, 0)
+                ).unwrap();
+                case_res.record(event, TimingInput { variant, unroll },
+                    results.data
+                );
+            }
+        }
+        case_res
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timing_from_results() {
+        let event = Zen2Event::LsNotHaltedCyc(0x00);
+        let mut res = ExperimentCaseResults::new("imul r64, r64");
+        let mut record = |variant, unroll, min: usize| {
+            res.record(event, TimingInput { variant, unroll },
+                RawResults(vec![min + 10, min, min + 3])
+            );
+        };
+        // 3 cycles/instr with a 1-cycle link, and 1 cycle/instr throughput
+        record(TimingVariant::Latency, 64, 100 + 64 * 4);
+        record(TimingVariant::Latency, 128, 100 + 128 * 4);
+        record(TimingVariant::Link, 64, 100 + 64);
+        record(TimingVariant::Link, 128, 100 + 128);
+        record(TimingVariant::Throughput, 64, 100 + 64);
+        record(TimingVariant::Throughput, 128, 100 + 128);
+
+        let timing = InstrTiming::from_results(&res, event).unwrap();
+        assert_eq!(timing.latency, 3.0);
+        assert_eq!(timing.throughput, 1.0);
+
+        // The cost of breaking dependencies through the flags is removed
+        res.record(event, TimingInput {
+            variant: TimingVariant::FlagsBreak, unroll: 64
+        }, RawResults(vec![100 + 16]));
+        res.record(event, TimingInput {
+            variant: TimingVariant::FlagsBreak, unroll: 128
+        }, RawResults(vec![100 + 32]));
+        let timing = InstrTiming::from_results(&res, event).unwrap();
+        assert_eq!(timing.throughput, 0.75);
+    }
+
+    #[test]
+    fn timing_flags_break() {
+        use iced_x86::{ Decoder, DecoderOptions, Code, Register };
+        struct Adc;
+        impl LatencyThroughputTemplate for Adc {}
+
+        // Each independent instance is preceded by a write to the flags
+        let opts = LatencyThroughputOptions::new().chain(ChainStrategy::Flags);
+        let asm = Adc::emit(&opts, TimingVariant::Throughput, 4, 0,
+            |f, ops| dynasm!(f ; adc Rq(ops.dst as u8), Rq(ops.src as u8))
+        );
+        let buf = asm.finalize().unwrap();
+        let codes: Vec<Code> = Decoder::new(64, &buf, DecoderOptions::NONE)
+            .iter()
+            .filter(|i| i.code() == Code::Adc_rm64_r64
+                || (i.code() == Code::Xor_rm32_r32
+                    && i.op0_register() == Register::EBP))
+            .map(|i| i.code())
+            .collect();
+        assert_eq!(codes, [Code::Xor_rm32_r32, Code::Adc_rm64_r64].repeat(4));
+    }
+
+    #[test]
+    fn timing_mem_addr() {
+        use iced_x86::{ Decoder, DecoderOptions, Code, Register };
+        struct AddMem;
+        impl LatencyThroughputTemplate for AddMem {}
+
+        let layout = ArenaLayout::new(0x0000_0036_0000_0000)
+            .buffer(LatencyThroughputOptions::arena_buffer());
+        let harness = HarnessConfig::default_test(0x1341)
+            .arena(layout)
+            .emit();
+        let arena = harness.arena_addr(LatencyThroughputOptions::ARENA_BUF);
+
+        let opts = LatencyThroughputOptions::new().chain(ChainStrategy::Memory);
+        assert_eq!(opts.resolve_mem_addr(&harness), arena);
+        assert_eq!(opts.mem_addr(0x1234_0000).resolve_mem_addr(&harness),
+            0x1234_0000
+        );
+
+        // The memory operand is addressed relative to the arena buffer
+        let asm = AddMem::emit(&opts, TimingVariant::Latency, 4, arena,
+            |f, ops| dynasm!(f ; add [Rq(ops.base as u8) + ops.mem], Rq(ops.dst as u8))
+        );
+        let buf = asm.finalize().unwrap();
+        let mut decoder = Decoder::new(64, &buf, DecoderOptions::NONE);
+        let setup = decoder.iter().find(|i| {
+            i.code() == Code::Mov_r64_imm64 && i.op0_register() == Register::R14
+        }).unwrap();
+        assert_eq!(setup.immediate64(), arena as u64);
+        let adds: Vec<_> = Decoder::new(64, &buf, DecoderOptions::NONE).iter()
+            .filter(|i| i.code() == Code::Add_rm64_r64)
+            .collect();
+        assert_eq!(adds.len(), 4);
+        assert!(adds.iter().all(|i| i.memory_base() == Register::R14
+            && i.memory_displacement64() == 0
+        ));
+    }
+}
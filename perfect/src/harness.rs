@@ -3,14 +3,24 @@ pub mod state;
 pub mod config;
 pub mod input;
 pub mod signal;
+pub mod diff;
+pub mod init;
+pub mod xstate;
+pub mod arena;
 pub use config::*;
 pub use state::*;
 pub use input::*;
+pub use signal::FaultRecord;
+pub use diff::*;
+pub use init::*;
+pub use xstate::*;
+pub use arena::*;
 
 use std::collections::*;
 use std::pin;
 use std::os::fd::{ AsRawFd, FromRawFd };
-use rand::rngs::ThreadRng;
+use crate::util::rng::*;
+use rand::Rng;
 use perf_event::{ Builder, Group, Counter };
 use perf_event::events::*;
 use perf_event::hooks::sys::bindings::perf_event_mmap_page;
@@ -25,6 +35,7 @@ use dynasmrt::{
 };
 
 use crate::util;
+use crate::util::cpuid::HostCpuid;
 use crate::stats::*;
 use crate::asm::{ X64Assembler, X64AssemblerFixed, Emitter, Gpr, VectorGpr, };
 use crate::asm::{ NOP6, NOP8 };
@@ -77,8 +88,12 @@ pub struct PerfectHarness {
     /// Fixed backing allocation for emitted code implementing the harness. 
     assembler: X64AssemblerFixed,
 
+    /// Fixed backing allocation for the fault recovery routine.
     handler_asm: X64AssemblerFixed,
 
+    /// Address of the fault recovery routine (see [`signal`]).
+    recovery_addr: usize,
+
     /// Saved stack pointer (for exiting the harness). 
     pub harness_state: Box<[u64; 16]>,
 
@@ -89,10 +104,37 @@ pub struct PerfectHarness {
     pub gpr_state: Box<GprState>,
     pub vgpr_state: Box<VectorGprState>,
 
+    /// Scratchpad memory for loading GPR state before entering JIT'ed code
+    /// (see [`HarnessConfig::preload_gpr`]).
+    pub preload_state: Box<GprState>,
+
+    /// Scratchpad memory for loading [`InitState`] before entering JIT'ed 
+    /// code (see [`HarnessConfig::init_state`]). 
+    pub init_block: Box<InitStateBlock>,
+
+    /// Memory blocks copied into the arena before entering JIT'ed code 
+    /// (see [`InitState::mem`]), and their target addresses.
+    init_mem: Vec<(usize, Box<[u8]>)>,
+
+    /// Scratchpad memory for saving extended state when JIT'ed code exits 
+    /// (see [`HarnessConfig::dump_xstate`]). 
+    pub xsave_area: Box<XSaveArea>,
+
+    /// Layout of the XSAVE area on this machine (when 
+    /// [`HarnessConfig::dump_xstate`] is set).
+    pub xsave_layout: Option<XSaveLayout>,
+
+    /// Named buffers used by measured code (see [`HarnessConfig::arena`]).
+    pub arena: Option<Arena>,
+
     /// Harness configuration.
     pub cfg: HarnessConfig,
 
-    pub rng: ThreadRng,
+    /// Seed for the harness RNG (see [`HarnessConfig::seed`]).
+    pub seed: u64,
+
+    /// RNG used to generate inputs (see [`InputMethod::Random`]).
+    pub rng: PerfectRng,
 }
 
 impl PerfectHarness {
@@ -108,17 +150,46 @@ impl PerfectHarness {
             cfg.handler_addr, 0x1000
         );
 
+        let seed = cfg.seed.unwrap_or_else(|| perfect_rng().gen());
+        let init = cfg.init_state.clone().unwrap_or_default();
+        let init_block = Box::new(InitStateBlock::new(&init));
+        let init_mem = init.resolve_mem(cfg.arena_alloc, cfg.arena.as_ref())
+            .unwrap_or_else(|e| panic!("[!] Invalid InitState::mem: {}", e))
+            .into_iter()
+            .map(|(addr, data)| (addr, data.into()))
+            .collect();
+        let xsave_layout = if cfg.dump_xstate {
+            let layout = XSaveLayout::new(&HostCpuid::read())
+                .expect("HarnessConfig::dump_xstate requires XSAVE");
+            assert!(layout.size <= XSaveArea::SIZE);
+            Some(layout)
+        } else {
+            None
+        };
+        assert!(!(init.rflags.is_some() && cfg.cmp_rdi.is_some()),
+            "InitState::rflags cannot be used with HarnessConfig::cmp_rdi"
+        );
+
         let mut res = Self {
             assembler,
+            recovery_addr: cfg.handler_addr,
             handler_asm,
             cfg,
-            rng: rand::thread_rng(),
+            seed,
+            rng: seeded_rng(seed),
             harness_state: Box::new([0; 16]),
             harness_stack: Box::new(HarnessStack::new()),
             gpr_state: Box::new(GprState::new()),
             vgpr_state: Box::new(VectorGprState::new()),
+            preload_state: Box::new(GprState::new()),
+            init_block,
+            init_mem,
+            xsave_area: Box::new(XSaveArea::new()),
+            xsave_layout,
+            arena: None,
         };
         res.emit();
+        res.emit_recovery();
         res
     }
 
@@ -127,31 +198,85 @@ impl PerfectHarness {
         self.assembler.disas(AssemblyOffset(0), None);
     }
 
-    /// Emit a signal handler (used for catching exceptions). 
-    pub fn emit_handler(&mut self, f: &dyn Fn(&mut X64AssemblerFixed)) {
+    /// Change the initial value of a GPR (see [`HarnessConfig::init_state`]) 
+    /// without emitting a new harness. 
+    ///
+    /// Panics if the register doesn't have an initial value. 
+    pub fn set_init_gpr(&mut self, gpr: Gpr, val: usize) {
+        assert!(self.cfg.init_state.as_ref()
+            .is_some_and(|init| init.gpr[gpr as usize].is_some()),
+            "{:?} doesn't have an initial value in InitState", gpr
+        );
+        self.init_block.gpr[gpr as usize] = val;
+    }
+
+    /// Return the base address of a named arena buffer (see 
+    /// [`HarnessConfig::arena`]).
+    ///
+    /// Panics if the buffer doesn't exist. 
+    pub fn arena_addr(&self, name: &str) -> usize {
+        self.arena.as_ref()
+            .unwrap_or_else(|| panic!("HarnessConfig::arena is not set"))
+            .addr(name)
+    }
+
+    /// Emit the default fault recovery routine.
+    ///
+    /// This restores the stack pointer saved by the harness and the original
+    /// nonvolatile registers, and then returns to the caller of the harness
+    /// with zero in RAX. 
+    ///
+    /// Measured code may have faulted with arbitrary x87 and SSE state, so
+    /// this also reinitializes the x87 FPU and MXCSR (and then restores the
+    /// control registers saved by [`InitState`], if any).
+    fn emit_recovery(&mut self) {
         let state_ptr = self.harness_state.as_ptr();
+        let init = self.cfg.init_state.clone().unwrap_or_default();
+        let init_ptr = self.init_block.as_ptr();
+        self.emit_handler(&|f| {
+            dynasm!(f
+                // Restore the original stack pointer saved by the harness
+                ; mov rcx, QWORD state_ptr as _
+                ; mov rsp, [rcx]
+
+                // Reset the direction flag, x87 FPU and MXCSR to their
+                // default state
+                ; cld
+                ; fninit
+                ; mov DWORD [rsp - 8], InitState::DEFAULT_MXCSR as i32
+                ; ldmxcsr [rsp - 8]
+            );
+            Self::emit_restore_fp_control(f, &init, init_ptr);
+            dynasm!(f
+                // Restore original nonvolatile registers from the stack
+                ; pop r15
+                ; pop r14
+                ; pop r13
+                ; pop r12
+                ; pop rsi
+                ; pop rdi
+                ; pop rbx
+                ; pop rbp
+                ; xor eax, eax
+                ; ret
+            );
+        });
+    }
+
+    /// Emit a custom fault recovery routine (used for catching exceptions).
+    ///
+    /// Subsequent faults in measured code resume execution at the start of
+    /// the emitted code. The routine is expected to eventually return to
+    /// the caller of the harness (see [`PerfectHarness::emit_recovery`]). 
+    pub fn emit_handler(&mut self, f: &dyn Fn(&mut X64AssemblerFixed)) {
+        self.recovery_addr = self.handler_asm.cur_addr();
         (f)(&mut self.handler_asm);
-        //dynasm!(self.handler_asm
-        //    // Restore the original stack pointer saved by the harness
-        //    ; mov rcx, QWORD state_ptr as _
-        //    ; mov rsp, [rcx]
-
-        //    // Restore original nonvolatile registers from the stack
-        //    ; pop r15
-        //    ; pop r14
-        //    ; pop r13
-        //    ; pop r12
-        //    ; pop rsi
-        //    ; pop rdi
-        //    ; pop rbx
-        //    ; pop rbp
-        //    ; ret
-        //);
         self.handler_asm.commit().unwrap();
     }
 
+    /// Register the fault handlers (see [`signal`]).
     pub fn enable_handler(&mut self) {
-        signal::register_sigsegv_handler();
+        signal::register_fault_handlers();
     }
 
     /// Emit the actual harness function during runtime.
@@ -163,7 +288,9 @@ impl PerfectHarness {
     /// - RDI and RSI are passed thru from the harness
     /// - R15 clobbered with the address of the measured function
     /// - RSP set to the address of `harness_state`
-    /// - All other integer GPRs are zeroed
+    /// - All other integer GPRs are zeroed (or loaded from `preload_state`
+    ///   when [`HarnessConfig::preload_gpr`] is set)
+    /// - Any state from [`HarnessConfig::init_state`] has been loaded
     ///
     /// - Measured functions are expected to end with a return instruction.
     /// - Measured functions are expected to return a result in RAX.
@@ -186,6 +313,31 @@ impl PerfectHarness {
 
             // Pointer to measured code
             ; mov r15, rdx
+        );
+
+        // Optionally copy the initial state of memory into the arena.
+        // RDI and RSI are saved in R8 and R9 for the duration. 
+        if !self.init_mem.is_empty() {
+            dynasm!(self.assembler
+                ; mov r8, rdi
+                ; mov r9, rsi
+                ; cld
+            );
+            for (addr, data) in self.init_mem.iter() {
+                dynasm!(self.assembler
+                    ; mov rsi, QWORD data.as_ptr() as _
+                    ; mov rdi, QWORD *addr as _
+                    ; mov rcx, QWORD data.len() as _
+                    ; rep movsb
+                );
+            }
+            dynasm!(self.assembler
+                ; mov rdi, r8
+                ; mov rsi, r9
+            );
+        }
+
+        dynasm!(self.assembler
 
             // Save the stack pointer.
             // NOTE: Allocates for RAX. 
@@ -323,6 +475,32 @@ impl PerfectHarness {
             }
         }
 
+        // Optionally load the initial state. 
+        // Clobbers RAX (unless it's also part of the initial state). 
+        if let Some(init) = &self.cfg.init_state {
+            self.emit_init_state(&init.clone());
+        }
+
+        // Optionally load GPRs from the preload state. RAX is loaded last
+        // since we're using it as the base address. 
+        if self.cfg.preload_gpr {
+            dynasm!(self.assembler
+                ; mov rax, QWORD self.preload_state.0.as_ptr() as _
+                ; mov rcx, [rax + 0x08]
+                ; mov rdx, [rax + 0x10]
+                ; mov rbx, [rax + 0x18]
+                ; mov rbp, [rax + 0x28]
+                ; mov r8,  [rax + 0x40]
+                ; mov r9,  [rax + 0x48]
+                ; mov r10, [rax + 0x50]
+                ; mov r11, [rax + 0x58]
+                ; mov r12, [rax + 0x60]
+                ; mov r13, [rax + 0x68]
+                ; mov r14, [rax + 0x70]
+                ; mov rax, [rax + 0x00]
+            );
+        }
+
         // Optionally use RDI to prepare the initial state of the flags
         // before entering measured code.
         if let Some(val) = self.cfg.cmp_rdi {
@@ -419,6 +597,32 @@ impl PerfectHarness {
             );
         }
 
+        // Optionally dump RFLAGS and extended state after exiting measured
+        // code. The result in RAX is preserved in R14. 
+        if let Some(layout) = self.xsave_layout {
+            dynasm!(self.assembler
+                ; mov r14, rax
+                ; pushfq
+                ; pop rdx
+                ; mov rcx, QWORD self.xsave_area.as_ptr() as _
+                ; mov [rcx + XSaveArea::OFFSET_RFLAGS], rdx
+                ; mov eax, (layout.rfbm & 0xffff_ffff) as i32
+                ; mov edx, (layout.rfbm >> 32) as i32
+                ; xsave64 [rcx]
+                ; mov rax, r14
+            );
+        }
+
+        // Undo any changes to the control registers from the initial state
+        if let Some(init) = &self.cfg.init_state {
+            Self::emit_restore_fp_control(&mut self.assembler, init,
+                self.init_block.as_ptr()
+            );
+            if init.rflags.is_some_and(|x| x & (1 << 10) != 0) {
+                dynasm!(self.assembler ; cld);
+            }
+        }
+
         dynasm!(self.assembler
             // Restore the stack pointer
             ; mov rcx, QWORD state_ptr as _
@@ -439,6 +643,71 @@ impl PerfectHarness {
     }
 }
 
+impl PerfectHarness {
+    /// Emit code for loading [`InitState`] (see [`HarnessConfig::init_state`]). 
+    ///
+    /// The original MXCSR and x87 control word are saved in `init_block` 
+    /// (see [`PerfectHarness::emit_restore_fp_control`]). 
+    fn emit_init_state(&mut self, init: &InitState) {
+        dynasm!(self.assembler
+            ; mov rax, QWORD self.init_block.as_ptr() as _
+        );
+        if init.mxcsr.is_some() {
+            dynasm!(self.assembler
+                ; stmxcsr [rax + InitStateBlock::OFFSET_SAVED_MXCSR]
+                ; ldmxcsr [rax + InitStateBlock::OFFSET_MXCSR]
+            );
+        }
+        if init.fcw.is_some() {
+            dynasm!(self.assembler
+                ; fnstcw [rax + InitStateBlock::OFFSET_SAVED_FCW]
+                ; fldcw [rax + InitStateBlock::OFFSET_FCW]
+            );
+        }
+        for (idx, val) in init.vgpr.iter().enumerate() {
+            if val.is_some() {
+                dynasm!(self.assembler
+                    ; vmovdqu Ry(idx as u8), [rax + (idx * 0x20) as i32]
+                );
+            }
+        }
+        if let Some(rflags) = init.rflags {
+            dynasm!(self.assembler
+                ; push (rflags | 0b10) as i32
+                ; popfq
+            );
+        }
+
+        // RAX is loaded last since we're using it as a scratch register
+        for (idx, val) in init.gpr.iter().enumerate().rev() {
+            if val.is_some() {
+                let off = InitStateBlock::OFFSET_GPR + (idx * 8) as i32;
+                dynasm!(self.assembler
+                    ; mov Rq(idx as u8), [rax + off]
+                );
+            }
+        }
+    }
+
+    /// Emit code for restoring the original MXCSR and/or x87 control word 
+    /// (saved by [`PerfectHarness::emit_init_state`]). Only the registers 
+    /// set by `init` are restored. Clobbers RCX. 
+    fn emit_restore_fp_control(f: &mut X64AssemblerFixed, init: &InitState,
+        init_ptr: *const InitStateBlock) 
+    {
+        if !init.has_fp_control() {
+            return;
+        }
+        dynasm!(f ; mov rcx, QWORD init_ptr as _);
+        if init.mxcsr.is_some() {
+            dynasm!(f ; ldmxcsr [rcx + InitStateBlock::OFFSET_SAVED_MXCSR]);
+        }
+        if init.fcw.is_some() {
+            dynasm!(f ; fldcw [rcx + InitStateBlock::OFFSET_SAVED_FCW]);
+        }
+    }
+}
+
 
 impl PerfectHarness {
     /// Generate the config bits for the raw perf_event (Intel).
@@ -479,44 +748,63 @@ impl PerfectHarness {
 }
 
 impl PerfectHarness {
-    /// Generate a list of inputs to measured code.
-    fn generate_inputs(rng: &mut ThreadRng, iters: usize, input: InputMethod) 
-        -> Vec<(usize, usize)>
+    /// Generate a list of inputs to measured code (and any additional 
+    /// state loaded before each iteration), along with the seed used for 
+    /// the RNG passed to the [`InputMethod`].
+    fn generate_inputs(&mut self, iters: usize, input: InputMethod) 
+        -> (Vec<(usize, usize)>, Vec<Preload>, u64)
     {
-        let mut inputs: Vec<(usize, usize)> = vec![(0, 0); iters];
+        let seed = self.rng.gen();
+        let inputs = input.args(&mut seeded_rng(seed), iters);
+        let preloads = input.preloads(iters);
+        assert!(self.cfg.preload_gpr || preloads.iter().all(|p| p.gpr.is_none()),
+            "Preloading GPRs requires HarnessConfig::preload_gpr"
+        );
+        (inputs, preloads, seed)
+    }
 
-        match input {
-            InputMethod::Fixed(rdi, rsi) => {
-                for val in inputs.iter_mut() {
-                    *val = (rdi, rsi);
-                }
-            },
-            InputMethod::Random(input_fn) => {
-                for (idx, val) in inputs.iter_mut().enumerate() {
-                    *val = input_fn(rng, idx);
-                }
-            }
-            InputMethod::List(data) => {
-                assert!(data.len() >= iters,
-                    "InputMethod::List must provide at least {} elements",
-                    iters
-                );
-                inputs.copy_from_slice(&data);
-            },
+    /// Load the [`Preload`] state for some iteration (if any). 
+    #[inline(always)]
+    fn apply_preload(preload_state: &mut GprState, preloads: &[Preload], 
+        idx: usize) 
+    {
+        if let Some(preload) = preloads.get(idx) {
+            *preload_state = preload.gpr.unwrap_or(GprState::new());
+            preload.write_mem();
         }
-        inputs
     }
 }
 
 impl PerfectHarness {
     /// Run the provided function a single time with the harness *without* 
     /// configuring performance counters. 
+    ///
+    /// Panics if the measured function faults. 
     pub fn call(&mut self, rdi: usize, rsi: usize, measured_fn: MeasuredFn) 
         -> usize
+    { 
+        match self.try_call(rdi, rsi, measured_fn) {
+            Ok(res) => res,
+            Err(fault) => panic!("Measured code faulted: {}", fault),
+        }
+    }
+
+    /// Run the provided function a single time with the harness *without* 
+    /// configuring performance counters. 
+    ///
+    /// Returns a [`FaultRecord`] if the measured function faulted (only 
+    /// when fault recovery has been enabled). 
+    pub fn try_call(&mut self, rdi: usize, rsi: usize, measured_fn: MeasuredFn) 
+        -> Result<usize, FaultRecord>
     { 
         let harness_fn = self.assembler.as_harness_fn();
+        signal::arm(self.recovery_addr, measured_fn as usize);
         let res = harness_fn(rdi, rsi, measured_fn as usize);
-        return res
+        signal::disarm();
+        match signal::take_fault() {
+            Some(fault) => Err(fault),
+            None => Ok(res),
+        }
     }
 
     /// Run and measure the provided function using a single PMC event. 
@@ -562,7 +850,7 @@ impl PerfectHarness {
     // machine that might be imparted by the body of this loop. 
     // 
     // For each requested iteration, we need to:
-    // - Load the inputs for this iteration
+    // - Load the inputs (and any preloaded state) for this iteration
     // - Call the harness with the requested function and inputs
     // - Save the result from this iteration
     //
@@ -570,14 +858,20 @@ impl PerfectHarness {
     fn measure_inner_loop(
         harness_fn: HarnessFn,
         measured_fn: MeasuredFn,
-        iters: usize,
         inputs: &[(usize, usize)],
+        preloads: &[Preload],
+        preload_state: &mut GprState,
         results: &mut [usize],
+        faults: &mut Vec<(usize, FaultRecord)>,
     ) {
-        for i in 0..iters { 
+        for i in 0..inputs.len() { 
+            Self::apply_preload(preload_state, preloads, i);
             let (rdi, rsi) = inputs[i];
             let res = harness_fn(rdi, rsi, measured_fn as usize);
             results[i] = res;
+            if let Some(fault) = signal::take_fault() {
+                faults.push((i, fault));
+            }
         }
     }
 
@@ -590,23 +884,27 @@ impl PerfectHarness {
         input: InputMethod,
    ) -> Result<MeasureResults, &str>
     {
-        let inputs = Self::generate_inputs(&mut self.rng, iters, input);
+        let (inputs, preloads, seed) = self.generate_inputs(iters, input);
         let harness_fn = self.assembler.as_harness_fn();
 
         // Allocate for output data produced while running the harness
         let mut results = vec![0; iters];
+        let mut faults = Vec::new();
 
         // Configure the appropriate counter with the requested event
         let mut ctr = Self::make_perf_cfg(self.cfg.platform, &event);
 
         ctr.reset().unwrap();
         ctr.enable().unwrap();
+        signal::arm(self.recovery_addr, measured_fn as usize);
 
         Self::measure_inner_loop(
             harness_fn, measured_fn, 
-            iters, &inputs, &mut results
+            &inputs, &preloads, &mut self.preload_state,
+            &mut results, &mut faults
         );
 
+        signal::disarm();
         ctr.disable().unwrap();
 
         self.gpr_state.clear();
@@ -617,7 +915,10 @@ impl PerfectHarness {
             event: event.clone(),
             gpr_dumps: None,
             vgpr_dumps: None,
+            xstate_dumps: None,
             inputs: Some(inputs),
+            seed,
+            faults,
         })
     }
 
@@ -628,12 +929,13 @@ impl PerfectHarness {
         input: InputMethod,
    ) -> Result<MeasureResults, &str>
     {
-        assert!(self.cfg.dump_gpr || self.cfg.dump_vgpr);
+        assert!(self.cfg.dump_gpr || self.cfg.dump_vgpr || self.cfg.dump_xstate);
 
-        let inputs = Self::generate_inputs(&mut self.rng, iters, input);
+        let (inputs, preloads, seed) = self.generate_inputs(iters, input);
         let harness_fn = self.assembler.as_harness_fn();
 
         let mut results = vec![0; iters];
+        let mut faults = Vec::new();
         let mut gpr_dumps = if self.cfg.dump_gpr {
             Some(Vec::new()) 
         } else { 
@@ -644,23 +946,39 @@ impl PerfectHarness {
         } else { 
             None 
         };
+        let mut xstate_dumps = self.xsave_layout.map(|_| Vec::new());
 
         let mut ctr = Self::make_perf_cfg(self.cfg.platform, &event);
         ctr.reset().unwrap();
         ctr.enable().unwrap();
+        signal::arm(self.recovery_addr, measured_fn as usize);
 
         for i in 0..iters {
+            Self::apply_preload(&mut self.preload_state, &preloads, i);
             let (rdi, rsi) = inputs[i];
             let res = harness_fn(rdi, rsi, measured_fn as usize);
             results[i] = res;
+
+            // Nothing is dumped when measured code faults: clear the state
+            // so that we don't report values from the previous iteration
+            if let Some(fault) = signal::take_fault() {
+                faults.push((i, fault));
+                self.gpr_state.clear();
+                self.vgpr_state.clear();
+                *self.xsave_area = XSaveArea::new();
+            }
             if let Some(data) = &mut gpr_dumps {
                 data.push(*self.gpr_state);
             }
             if let Some(data) = &mut vgpr_dumps {
                 data.push(*self.vgpr_state);
             }
+            if let (Some(data), Some(layout)) = (&mut xstate_dumps, &self.xsave_layout) {
+                data.push(ExtState::from_xsave(layout, &self.xsave_area));
+            }
         }
 
+        signal::disarm();
         ctr.disable().unwrap();
 
         self.gpr_state.clear();
@@ -670,7 +988,10 @@ impl PerfectHarness {
             event: event.clone(),
             gpr_dumps,
             vgpr_dumps,
+            xstate_dumps,
             inputs: Some(inputs),
+            seed,
+            faults,
         })
     }
 
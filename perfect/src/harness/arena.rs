@@ -0,0 +1,479 @@
+//! Typed memory arena for measured code.
+//!
+//! [`HarnessConfig::arena_alloc`](crate::harness::HarnessConfig) only maps
+//! a single anonymous region, and measured code is left to pick addresses
+//! inside of it. An [`ArenaLayout`] instead describes a set of *named*
+//! buffers, each with its own:
+//!
+//! - Address (either fixed, or placed automatically after the previous
+//!   buffer in the layout)
+//! - Page size (see [`PageSize`]) and alignment
+//! - Initial contents (see [`Fill`])
+//! - Optional guard pages (mapped with no permissions) on either side
+//!
+//! Addresses are resolved when a buffer is added to the layout, so code can
+//! be emitted against [`ArenaLayout::addr`] before anything is mapped (or
+//! against [`PerfectHarness::arena_addr`] afterwards). The initial contents
+//! of a buffer can also be restored before each call to measured code with
+//! [`InitState::mem_in`].
+//! Buffers are mapped with `MAP_FIXED_NOREPLACE`: a layout that collides
+//! with an existing mapping fails instead of silently clobbering it.
+//!
+//! ```ignore
+//! let layout = ArenaLayout::new(0x0000_0030_0000_0000)
+//!     .buffer(ArenaBufferDesc::new("probe", 256 * 4096)
+//!         .fill(Fill::Byte(0x5a))
+//!         .guard(true))
+//!     .buffer(ArenaBufferDesc::new("victim", 0x20_0000)
+//!         .page_size(PageSize::Transparent2M));
+//! let probe = layout.addr("probe");
+//! let mut harness = HarnessConfig::default_zen2().arena(layout).emit();
+//! let paddr = harness.arena.as_ref().unwrap().paddr("victim", 0);
+//! ```
+//!
+//! [`PerfectHarness::arena_addr`]: crate::harness::PerfectHarness::arena_addr
+//! [`InitState::mem_in`]: crate::harness::InitState::mem_in
+
+use crate::util::Align;
+use crate::util::pagemap::PageMap;
+use crate::util::rng::seeded_rng;
+use rand::RngCore;
+use nix::sys::mman::{ ProtFlags, MapFlags, MmapAdvise };
+use std::num::NonZeroUsize;
+
+/// The page size used to back an arena buffer.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PageSize {
+    /// Base (4KiB) pages
+    Base4K,
+    /// Explicit 2MiB pages from hugetlbfs (`MAP_HUGETLB`).
+    ///
+    /// This requires reserved huge pages (see `vm.nr_hugepages`).
+    Huge2M,
+    /// 2MiB-aligned base pages with `MADV_HUGEPAGE` (transparent huge
+    /// pages). The kernel is not obligated to actually use huge pages.
+    Transparent2M,
+}
+impl PageSize {
+    /// Return the size of a page in bytes.
+    pub fn bytes(&self) -> usize {
+        match self {
+            Self::Base4K => 0x1000,
+            Self::Huge2M | Self::Transparent2M => 0x20_0000,
+        }
+    }
+}
+
+/// Initial contents of an arena buffer.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Fill {
+    /// Leave the buffer zeroed
+    Zero,
+    /// Fill the buffer with a single byte
+    Byte(u8),
+    /// Fill the buffer with a repeating pattern
+    Pattern(Vec<u8>),
+    /// Fill the buffer with random bytes from a seeded RNG
+    Random(u64),
+    /// Fill each aligned quadword with its own address
+    SelfAddr,
+}
+impl Fill {
+    /// Write this pattern into a buffer at `addr`.
+    pub fn apply(&self, addr: usize, buf: &mut [u8]) {
+        match self {
+            Self::Zero => buf.fill(0),
+            Self::Byte(x) => buf.fill(*x),
+            Self::Pattern(pat) => {
+                assert!(!pat.is_empty(), "Fill::Pattern cannot be empty");
+                for (dst, src) in buf.iter_mut().zip(pat.iter().cycle()) {
+                    *dst = *src;
+                }
+            },
+            Self::Random(seed) => seeded_rng(*seed).fill_bytes(buf),
+            Self::SelfAddr => {
+                for (idx, chunk) in buf.chunks_mut(8).enumerate() {
+                    let val = (addr + idx * 8).to_le_bytes();
+                    chunk.copy_from_slice(&val[..chunk.len()]);
+                }
+            },
+        }
+    }
+}
+
+/// Description of a named buffer in an [`ArenaLayout`].
+#[derive(Clone, Debug)]
+pub struct ArenaBufferDesc {
+    pub name: String,
+    /// Fixed address for the buffer (otherwise, placed automatically)
+    pub addr: Option<usize>,
+    /// Length of the buffer (rounded up to the page size when mapped)
+    pub len: usize,
+    pub page_size: PageSize,
+    /// Minimum alignment of the buffer (at least the page size)
+    pub align: Align,
+    pub fill: Fill,
+    /// Surround the buffer with inaccessible guard pages
+    pub guard: bool,
+}
+impl ArenaBufferDesc {
+    pub fn new(name: &str, len: usize) -> Self {
+        assert!(len != 0, "Arena buffer '{}' cannot be empty", name);
+        Self {
+            name: name.to_string(),
+            addr: None,
+            len,
+            page_size: PageSize::Base4K,
+            align: Align::from_value(0x1000),
+            fill: Fill::Zero,
+            guard: false,
+        }
+    }
+    pub fn at(mut self, addr: usize) -> Self {
+        self.addr = Some(addr);
+        self
+    }
+    pub fn page_size(mut self, x: PageSize) -> Self {
+        self.page_size = x;
+        self
+    }
+    pub fn align(mut self, x: Align) -> Self {
+        self.align = x;
+        self
+    }
+    pub fn fill(mut self, x: Fill) -> Self {
+        self.fill = x;
+        self
+    }
+    pub fn guard(mut self, x: bool) -> Self {
+        self.guard = x;
+        self
+    }
+}
+
+/// A buffer in an [`ArenaLayout`] (with a resolved address).
+#[derive(Clone, Debug)]
+pub struct ArenaBuffer {
+    pub desc: ArenaBufferDesc,
+    /// Base address of the buffer
+    pub addr: usize,
+}
+impl ArenaBuffer {
+    pub fn name(&self) -> &str { &self.desc.name }
+    pub fn len(&self) -> usize { self.desc.len }
+    pub fn is_empty(&self) -> bool { self.desc.len == 0 }
+
+    /// Return the address at some offset into the buffer.
+    pub fn addr_at(&self, offset: usize) -> usize {
+        assert!(offset < self.desc.len,
+            "Offset {:#x} is outside of arena buffer '{}' (len {:#x})",
+            offset, self.desc.name, self.desc.len
+        );
+        self.addr + offset
+    }
+
+    /// Returns true if the address is inside this buffer.
+    pub fn contains(&self, addr: usize) -> bool {
+        (self.addr..self.addr + self.desc.len).contains(&addr)
+    }
+
+    /// Length of the mapping (rounded up to the page size).
+    pub fn map_len(&self) -> usize {
+        let page = self.desc.page_size.bytes();
+        self.desc.len.div_ceil(page) * page
+    }
+
+    /// Length of each guard region (if any).
+    pub fn guard_len(&self) -> usize {
+        if self.desc.guard { self.desc.page_size.bytes() } else { 0 }
+    }
+
+    /// The range of virtual addresses used by this buffer (including any
+    /// guard pages).
+    pub fn footprint(&self) -> std::ops::Range<usize> {
+        (self.addr - self.guard_len())..(self.addr + self.map_len() + self.guard_len())
+    }
+}
+
+/// A set of named buffers used by measured code.
+#[derive(Clone, Debug)]
+pub struct ArenaLayout {
+    /// Address where automatically-placed buffers begin
+    pub base: usize,
+    /// Address where the next automatically-placed buffer begins
+    cursor: usize,
+    pub buffers: Vec<ArenaBuffer>,
+}
+impl ArenaLayout {
+    pub fn new(base: usize) -> Self {
+        Self { base, cursor: base, buffers: Vec::new() }
+    }
+
+    /// Add a buffer to the layout.
+    ///
+    /// Panics if the name is already used, or if the buffer overlaps with
+    /// another buffer in the layout.
+    pub fn buffer(mut self, desc: ArenaBufferDesc) -> Self {
+        assert!(self.get(&desc.name).is_none(),
+            "Arena buffer '{}' already exists", desc.name
+        );
+        let page = desc.page_size.bytes();
+        let align = desc.align.value().max(page);
+        let guard = if desc.guard { page } else { 0 };
+        let addr = match desc.addr {
+            Some(addr) => {
+                assert!(addr & (align - 1) == 0,
+                    "Arena buffer '{}' at {:#x} must be aligned to {:#x}",
+                    desc.name, addr, align
+                );
+                addr
+            },
+            None => (self.cursor + guard).next_multiple_of(align),
+        };
+        let buf = ArenaBuffer { desc, addr };
+        let fp = buf.footprint();
+        for other in self.buffers.iter() {
+            let ofp = other.footprint();
+            assert!(fp.end <= ofp.start || ofp.end <= fp.start,
+                "Arena buffer '{}' ({:x?}) overlaps with '{}' ({:x?})",
+                buf.name(), fp, other.name(), ofp
+            );
+        }
+        self.cursor = self.cursor.max(fp.end);
+        self.buffers.push(buf);
+        self
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ArenaBuffer> {
+        self.buffers.iter().find(|b| b.desc.name == name)
+    }
+
+    /// Return the base address of a buffer (panics if it doesn't exist).
+    pub fn addr(&self, name: &str) -> usize {
+        self.get(name)
+            .unwrap_or_else(|| panic!("No arena buffer named '{}'", name))
+            .addr
+    }
+
+    /// Map all buffers into the current virtual address space.
+    pub fn map(&self) -> Result<Arena, String> {
+        let mut arena = Arena { layout: self.clone(), mappings: Vec::new() };
+        for buf in self.buffers.iter() {
+            arena.map_buffer(buf)?;
+        }
+        for buf in self.buffers.iter() {
+            let slice = arena.slice_mut(buf.name());
+            buf.desc.fill.apply(buf.addr, slice);
+        }
+        Ok(arena)
+    }
+}
+
+/// An [`ArenaLayout`] which has been mapped into memory.
+///
+/// All buffers are unmapped when this is dropped.
+pub struct Arena {
+    pub layout: ArenaLayout,
+    /// The set of mapped regions (address and length)
+    mappings: Vec<(usize, usize)>,
+}
+impl Arena {
+    fn mmap(&mut self, addr: usize, len: usize, prot: ProtFlags,
+        flags: MapFlags) -> Result<(), String>
+    {
+        let flags = flags | MapFlags::MAP_ANONYMOUS | MapFlags::MAP_PRIVATE
+            | MapFlags::MAP_FIXED_NOREPLACE;
+        let ptr = unsafe {
+            nix::sys::mman::mmap(NonZeroUsize::new(addr),
+                NonZeroUsize::new(len).unwrap(), prot, flags, 0, 0
+            )
+        }.map_err(|e| format!("mmap({:#x}, {:#x}) failed: {}", addr, len, e))?;
+        if ptr as usize != addr {
+            // Older kernels ignore MAP_FIXED_NOREPLACE
+            unsafe { nix::sys::mman::munmap(ptr, len).unwrap() };
+            return Err(format!("mmap({:#x}, {:#x}) returned {:p}",
+                addr, len, ptr
+            ));
+        }
+        self.mappings.push((addr, len));
+        Ok(())
+    }
+
+    fn map_buffer(&mut self, buf: &ArenaBuffer) -> Result<(), String> {
+        let rw = ProtFlags::PROT_READ | ProtFlags::PROT_WRITE;
+        let guard = buf.guard_len();
+        if guard != 0 {
+            self.mmap(buf.addr - guard, guard, ProtFlags::PROT_NONE,
+                MapFlags::MAP_NORESERVE
+            )?;
+            self.mmap(buf.addr + buf.map_len(), guard, ProtFlags::PROT_NONE,
+                MapFlags::MAP_NORESERVE
+            )?;
+        }
+        match buf.desc.page_size {
+            PageSize::Base4K => {
+                self.mmap(buf.addr, buf.map_len(), rw, MapFlags::MAP_POPULATE)?;
+            },
+            PageSize::Huge2M => {
+                self.mmap(buf.addr, buf.map_len(), rw,
+                    MapFlags::MAP_HUGETLB | MapFlags::MAP_HUGE_2MB
+                    | MapFlags::MAP_POPULATE
+                ).map_err(|e| format!("{} (are huge pages reserved?)", e))?;
+            },
+            PageSize::Transparent2M => {
+                self.mmap(buf.addr, buf.map_len(), rw, MapFlags::empty())?;
+                unsafe {
+                    nix::sys::mman::madvise(buf.addr as _, buf.map_len(),
+                        MmapAdvise::MADV_HUGEPAGE
+                    )
+                }.map_err(|e| format!("madvise() failed: {}", e))?;
+            },
+        }
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<&ArenaBuffer> {
+        self.layout.get(name)
+    }
+
+    /// Return the base address of a buffer (panics if it doesn't exist).
+    pub fn addr(&self, name: &str) -> usize {
+        self.layout.addr(name)
+    }
+
+    fn buffer(&self, name: &str) -> &ArenaBuffer {
+        self.get(name)
+            .unwrap_or_else(|| panic!("No arena buffer named '{}'", name))
+    }
+
+    /// Return the contents of a buffer.
+    pub fn slice(&self, name: &str) -> &[u8] {
+        let buf = self.buffer(name);
+        unsafe { std::slice::from_raw_parts(buf.addr as *const u8, buf.len()) }
+    }
+
+    /// Return the contents of a buffer.
+    pub fn slice_mut(&mut self, name: &str) -> &mut [u8] {
+        let buf = self.buffer(name);
+        unsafe { std::slice::from_raw_parts_mut(buf.addr as *mut u8, buf.len()) }
+    }
+
+    /// Restore the initial contents of a buffer (see [`Fill`]).
+    pub fn refill(&mut self, name: &str) {
+        let buf = self.buffer(name).clone();
+        buf.desc.fill.apply(buf.addr, self.slice_mut(name));
+    }
+
+    /// Resolve the physical address at some offset into a buffer
+    /// (see [`PageMap`]).
+    pub fn paddr(&self, name: &str, offset: usize) -> Result<usize, &'static str> {
+        PageMap::resolve_paddr(self.buffer(name).addr_at(offset))
+    }
+
+    /// Resolve the physical address of each page in a buffer.
+    pub fn paddrs(&self, name: &str) -> Result<Vec<usize>, &'static str> {
+        let buf = self.buffer(name);
+        let page = buf.desc.page_size.bytes();
+        (0..buf.len()).step_by(page)
+            .map(|off| PageMap::resolve_paddr(buf.addr + off))
+            .collect()
+    }
+}
+impl Drop for Arena {
+    fn drop(&mut self) {
+        for (addr, len) in self.mappings.drain(..) {
+            unsafe {
+                let _ = nix::sys::mman::munmap(addr as *mut _, len);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::maps::Maps;
+
+    #[test]
+    fn arena_layout_map() {
+        const BASE: usize = 0x0000_0031_0000_0000;
+        let layout = ArenaLayout::new(BASE)
+            .buffer(ArenaBufferDesc::new("a", 0x1800)
+                .fill(Fill::SelfAddr)
+                .guard(true))
+            .buffer(ArenaBufferDesc::new("b", 0x100)
+                .align(Align::from_value(0x10000))
+                .fill(Fill::Pattern(vec![1, 2, 3])))
+            .buffer(ArenaBufferDesc::new("c", 0x1000)
+                .at(BASE + 0x10_0000)
+                .fill(Fill::Random(1234)));
+
+        // The first buffer follows a guard page
+        assert_eq!(layout.addr("a"), BASE + 0x1000);
+        assert_eq!(layout.get("a").unwrap().footprint(), BASE..BASE + 0x4000);
+        assert_eq!(layout.addr("b"), BASE + 0x10000);
+
+        {
+            let mut arena = layout.map().unwrap();
+            assert_eq!(arena.slice("a")[0x18..0x20], (BASE + 0x1018).to_le_bytes());
+            assert_eq!(arena.slice("b")[..5], [1, 2, 3, 1, 2]);
+            let random = arena.slice("c").to_vec();
+            arena.slice_mut("c").fill(0);
+            arena.refill("c");
+            assert_eq!(arena.slice("c"), &random[..]);
+
+            // Guard pages are mapped, but inaccessible
+            let ranges = Maps::ranges().unwrap();
+            assert!(ranges.iter().any(|r| r.contains(&BASE)));
+
+            // Mapping the same layout twice fails instead of clobbering
+            assert!(layout.map().is_err());
+        }
+
+        // Everything is unmapped when the arena is dropped
+        let ranges = Maps::ranges().unwrap();
+        assert!(!ranges.iter().any(|r| r.contains(&(BASE + 0x1000))));
+        drop(layout.map().unwrap());
+    }
+
+    #[test]
+    fn arena_guard_pages() {
+        use crate::harness::*;
+        use crate::asm::{ X64Assembler, Emitter };
+        use dynasmrt::{ dynasm, DynasmApi, AssemblyOffset };
+        use nix::sys::signal::Signal;
+
+        let layout = ArenaLayout::new(0x0000_0034_0000_0000)
+            .buffer(ArenaBufferDesc::new("a", 0x1000).guard(true));
+        let buf = layout.get("a").unwrap().clone();
+        let mut harness = HarnessConfig::default_test(0x133f)
+            .arena(layout)
+            .recover_faults(true)
+            .emit();
+
+        // Load from the address in RDI
+        let mut f = X64Assembler::new().unwrap();
+        dynasm!(f ; mov rax, [rdi] ; ret);
+        f.commit().unwrap();
+        let code = f.finalize().unwrap();
+        let func: MeasuredFn = unsafe {
+            std::mem::transmute(code.ptr(AssemblyOffset(0)))
+        };
+
+        assert!(harness.try_call(buf.addr, 0, func).is_ok());
+        assert!(harness.try_call(buf.addr + buf.len() - 8, 0, func).is_ok());
+        let fp = buf.footprint();
+        for addr in [fp.start, fp.end - 8] {
+            let fault = harness.try_call(addr, 0, func).unwrap_err();
+            assert_eq!(fault.signal, Signal::SIGSEGV, "{:#x}", addr);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn arena_layout_overlap() {
+        let _ = ArenaLayout::new(0x0000_0032_0000_0000)
+            .buffer(ArenaBufferDesc::new("a", 0x2000))
+            .buffer(ArenaBufferDesc::new("b", 0x1000).at(0x0000_0032_0000_1000));
+    }
+}
@@ -1,7 +1,7 @@
 //! Harness configuration.
 
 use crate::experiments::ExperimentArgs;
-use crate::harness::PerfectHarness;
+use crate::harness::{ PerfectHarness, InitState, ArenaLayout };
 use crate::util::*;
 
 /// The target platform for generated code. 
@@ -32,7 +32,7 @@ pub enum ZeroStrategyFp {
 }
 
 /// Configuration passed to [PerfectHarness::emit].
-#[derive(Clone, Copy)]
+#[derive(Clone)]
 pub struct HarnessConfig {
     /// The target platform
     pub platform: TargetPlatform,
@@ -50,6 +50,10 @@ pub struct HarnessConfig {
     /// after running measured code.
     pub dump_vgpr: bool,
 
+    /// Optionally dump RFLAGS and extended state (x87, MXCSR, AVX-512) 
+    /// after running measured code (see [`crate::harness::xstate`]).
+    pub dump_xstate: bool,
+
     /// Optionally allow the harness to automatically execute RDPMC
     /// immediately before/after calling into measured code. 
     pub auto_rdpmc: Option<usize>,
@@ -64,6 +68,10 @@ pub struct HarnessConfig {
     /// Optionally allocate a fixed memory region for use by measured code.
     pub arena_alloc: Option<(usize, usize)>, 
 
+    /// Optionally map a set of named buffers for use by measured code 
+    /// (see [`crate::harness::arena`]).
+    pub arena: Option<ArenaLayout>,
+
     /// Optionally [try to] flush the BTB. 
     pub flush_btb: Option<usize>,
 
@@ -74,6 +82,23 @@ pub struct HarnessConfig {
     /// The strategy for zeroing vector general-purpose registers before 
     /// entering measured code.
     pub zero_strat_fp: ZeroStrategyFp,
+
+    /// Optionally recover from faults in measured code (see 
+    /// [`crate::harness::signal`]).
+    pub recover_faults: bool,
+
+    /// Optionally load the integer general-purpose registers from
+    /// [`Preload`](crate::harness::Preload) state before entering measured 
+    /// code (see [`InputMethod::WithPreload`](crate::harness::InputMethod)).
+    pub preload_gpr: bool,
+
+    /// Seed for the RNG used to generate inputs. When this is [`None`], 
+    /// the seed is derived from [`perfect_rng`](crate::util::perfect_rng).
+    pub seed: Option<u64>,
+
+    /// Optionally load some initial architectural state before entering 
+    /// measured code (see [`InitState`]). 
+    pub init_state: Option<InitState>,
 }
 
 impl HarnessConfig {
@@ -106,14 +131,20 @@ impl HarnessConfig {
             harness_size: Self::DEFAULT_SIZE,
             handler_addr: Self::DEFAULT_HANDLER_ADDR,
             arena_alloc: Some((0x0000_0000, 0x1000_0000)),
+            arena: None,
             dump_gpr: false,
             dump_vgpr: false,
+            dump_xstate: false,
             auto_rdpmc: None,
             cmp_rdi: None,
             flush_btb: None,
             platform: TargetPlatform::Zen2,
             zero_strat: ZeroStrategy::MovFromZero,
             zero_strat_fp: ZeroStrategyFp::None,
+            recover_faults: false,
+            preload_gpr: false,
+            seed: None,
+            init_state: None,
         }
     }
 
@@ -124,14 +155,20 @@ impl HarnessConfig {
             harness_size: Self::DEFAULT_SIZE,
             handler_addr: Self::DEFAULT_HANDLER_ADDR,
             arena_alloc: Some((0x0000_0000, 0x1000_0000)),
+            arena: None,
             dump_gpr: false,
             dump_vgpr: false,
+            dump_xstate: false,
             auto_rdpmc: None,
             cmp_rdi: None,
             flush_btb: None,
             platform: TargetPlatform::Zen3,
             zero_strat: ZeroStrategy::MovFromZero,
             zero_strat_fp: ZeroStrategyFp::None,
+            recover_faults: false,
+            preload_gpr: false,
+            seed: None,
+            init_state: None,
         }
     }
 
@@ -143,16 +180,34 @@ impl HarnessConfig {
             harness_size: Self::DEFAULT_SIZE,
             handler_addr: Self::DEFAULT_HANDLER_ADDR,
             arena_alloc: Some((0x0000_0000, 0x1000_0000)),
+            arena: None,
             dump_gpr: false,
             dump_vgpr: false,
+            dump_xstate: false,
             auto_rdpmc: None,
             cmp_rdi: None,
             flush_btb: None,
             platform: TargetPlatform::Tremont,
             zero_strat: ZeroStrategy::MovFromZero,
             zero_strat_fp: ZeroStrategyFp::None,
+            recover_faults: false,
+            preload_gpr: false,
+            seed: None,
+            init_state: None,
         }
     }
+
+    /// Configuration for unit tests. Tests run concurrently, so each one
+    /// must use a distinct `id` (selecting the harness and handler address).
+    #[cfg(test)]
+    pub(crate) fn default_test(id: usize) -> Self {
+        Self::default_zen2()
+            .pinned_core(None)
+            .harness_addr(id << 32)
+            .harness_size(0x0010_0000)
+            .handler_addr((id << 32) | 0xdead_0000)
+            .no_arena_alloc()
+    }
 }
 
 impl HarnessConfig {
@@ -181,6 +236,11 @@ impl HarnessConfig {
         self
     }
 
+    pub fn dump_xstate(mut self, x: bool) -> Self {
+        self.dump_xstate = x;
+        self
+    }
+
     pub fn auto_rdpmc(mut self, x: Option<usize>) -> Self {
         self.auto_rdpmc = x;
         self
@@ -201,6 +261,11 @@ impl HarnessConfig {
         self
     }
 
+    pub fn arena(mut self, x: ArenaLayout) -> Self {
+        self.arena = Some(x);
+        self
+    }
+
     pub fn cmp_rdi(mut self, x: i32) -> Self {
         self.cmp_rdi = Some(x);
         self
@@ -226,6 +291,26 @@ impl HarnessConfig {
         self
     }
 
+    pub fn recover_faults(mut self, x: bool) -> Self { 
+        self.recover_faults = x;
+        self
+    }
+
+    pub fn preload_gpr(mut self, x: bool) -> Self { 
+        self.preload_gpr = x;
+        self
+    }
+
+    pub fn seed(mut self, x: u64) -> Self { 
+        self.seed = Some(x);
+        self
+    }
+
+    pub fn init_state(mut self, x: InitState) -> Self { 
+        self.init_state = Some(x);
+        self
+    }
+
 }
 
 impl HarnessConfig {
@@ -245,8 +330,18 @@ impl HarnessConfig {
             }
             let _ = PerfectEnv::mmap_fixed(base, len);
         }
+        let arena = self.arena.as_ref().map(|layout| {
+            layout.map().unwrap_or_else(|e| {
+                panic!("[!] Cannot map arena layout: {}", e)
+            })
+        });
 
+        let recover_faults = self.recover_faults;
         let mut res = PerfectHarness::new(self);
+        res.arena = arena;
+        if recover_faults {
+            res.enable_handler();
+        }
         res
     }
 }
@@ -0,0 +1,342 @@
+//! Differential testing of architectural state.
+//!
+//! Some bugs (ie. Zenbleed) are only visible in architectural state: the
+//! values in registers after running some code are different depending on
+//! how the machine was configured. A [`DiffTest`] runs a measured function
+//! under two different [`DiffConfig`]s and compares the saved register
+//! state for each test iteration.
+//!
+//! The two configurations may differ in any way, for instance:
+//!
+//! - The [`HarnessConfig`] (ie. with/without [`ZeroStrategyFp::Vzeroall`])
+//! - The measured function (ie. with/without a mispredicted branch)
+//! - Setup performed before the measurement (ie. with/without
+//!   [`PerfectEnv::toggle_fp_mov_elim`](crate::util::PerfectEnv))
+//!
+//! Harnesses are emitted one at a time (both configurations usually use the
+//! same fixed addresses), so the two configurations are never measured
+//! concurrently.
+
+use crate::harness::*;
+use crate::asm::{ Gpr, VectorGpr };
+use crate::events::EventDesc;
+use crate::stats::MeasureResults;
+
+/// A function called with the harness for one side of a [`DiffTest`].
+pub type DiffHook = Box<dyn Fn(&mut PerfectHarness) -> Result<(), String>>;
+
+/// One side of a [`DiffTest`].
+pub struct DiffConfig {
+    pub desc: &'static str,
+    pub harness: HarnessConfig,
+    pub measured_fn: MeasuredFn,
+    /// Called with the new harness before taking measurements
+    pub setup: Option<DiffHook>,
+    /// Called with the harness after taking measurements
+    pub teardown: Option<DiffHook>,
+}
+impl DiffConfig {
+    /// Create a new configuration. Register dumps are always enabled.
+    pub fn new(desc: &'static str, harness: HarnessConfig,
+        measured_fn: MeasuredFn) -> Self
+    {
+        Self {
+            desc,
+            harness: harness.dump_gpr(true).dump_vgpr(true),
+            measured_fn,
+            setup: None,
+            teardown: None,
+        }
+    }
+    pub fn setup(mut self,
+        f: impl Fn(&mut PerfectHarness) -> Result<(), String> + 'static) -> Self
+    {
+        self.setup = Some(Box::new(f));
+        self
+    }
+    pub fn teardown(mut self,
+        f: impl Fn(&mut PerfectHarness) -> Result<(), String> + 'static) -> Self
+    {
+        self.teardown = Some(Box::new(f));
+        self
+    }
+
+    /// Emit the harness and collect register dumps.
+    ///
+    /// The teardown hook is always called after the setup hook, even when
+    /// setup or the measurement fails.
+    fn run(&self, event: &EventDesc, iters: usize, input: InputMethod)
+        -> Result<MeasureResults, String>
+    {
+        let mut harness = self.harness.clone().emit();
+        let res = match &self.setup {
+            Some(setup) => setup(&mut harness),
+            None => Ok(()),
+        }.and_then(|_| {
+            harness.measure_and_dump(self.measured_fn, event, iters, input)
+                .map_err(|e| e.to_string())
+        });
+        let teardown = match &self.teardown {
+            Some(teardown) => teardown(&mut harness),
+            None => Ok(()),
+        };
+        let res = res.map_err(|e| format!("{}: {}", self.desc, e))?;
+        teardown.map_err(|e| format!("{}: teardown: {}", self.desc, e))?;
+        Ok(res)
+    }
+}
+
+/// A location in architectural state.
+#[derive(Clone, Copy, Debug)]
+pub enum StateLoc {
+    Gpr(Gpr),
+    /// A 64-bit lane in some vector register
+    Vgpr(VectorGpr, usize),
+}
+impl std::fmt::Display for StateLoc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Gpr(gpr) => write!(f, "{}", gpr),
+            Self::Vgpr(vgpr, lane) => write!(f, "ymm{}[{}]", *vgpr as usize, lane),
+        }
+    }
+}
+
+/// A single location that diverged during some test iteration.
+#[derive(Clone, Copy, Debug)]
+pub struct StateDivergence {
+    pub iter: usize,
+    pub loc: StateLoc,
+    pub a: u64,
+    pub b: u64,
+}
+
+/// The result of comparing register dumps from two configurations.
+#[derive(Clone, Debug)]
+pub struct DiffReport {
+    /// Number of iterations that were compared (iterations that faulted
+    /// in either configuration are skipped)
+    pub iters: usize,
+    /// Number of iterations where each GPR diverged
+    pub gpr_counts: [usize; 16],
+    /// Number of iterations where each vector register lane diverged
+    pub vgpr_counts: [[usize; 4]; 16],
+    /// The first divergences (up to [`DiffReport::MAX_EXAMPLES`])
+    pub examples: Vec<StateDivergence>,
+}
+impl DiffReport {
+    pub const MAX_EXAMPLES: usize = 32;
+
+    fn new() -> Self {
+        Self {
+            iters: 0,
+            gpr_counts: [0; 16],
+            vgpr_counts: [[0; 4]; 16],
+            examples: Vec::new(),
+        }
+    }
+
+    fn record(&mut self, div: StateDivergence) {
+        match div.loc {
+            StateLoc::Gpr(gpr) => self.gpr_counts[gpr as usize] += 1,
+            StateLoc::Vgpr(vgpr, lane) => {
+                self.vgpr_counts[vgpr as usize][lane] += 1
+            },
+        }
+        if self.examples.len() < Self::MAX_EXAMPLES {
+            self.examples.push(div);
+        }
+    }
+
+    /// Returns true if no state diverged.
+    pub fn is_clean(&self) -> bool {
+        self.gpr_counts.iter().all(|c| *c == 0)
+            && self.vgpr_counts.iter().flatten().all(|c| *c == 0)
+    }
+
+    /// Return each location that diverged (and the number of iterations
+    /// where it diverged).
+    pub fn diverged(&self) -> Vec<(StateLoc, usize)> {
+        let gprs = self.gpr_counts.iter().enumerate()
+            .filter(|(_, cnt)| **cnt != 0)
+            .map(|(idx, cnt)| (StateLoc::Gpr(Gpr::from(idx as u8)), *cnt));
+        let vgprs = self.vgpr_counts.iter().enumerate()
+            .flat_map(|(idx, lanes)| lanes.iter().enumerate()
+                .filter(|(_, cnt)| **cnt != 0)
+                .map(move |(lane, cnt)| {
+                    (StateLoc::Vgpr(VectorGpr::from(idx as u8), lane), *cnt)
+                })
+            );
+        gprs.chain(vgprs).collect()
+    }
+}
+impl std::fmt::Display for DiffReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if self.is_clean() {
+            return writeln!(f, "no divergence in {} iterations", self.iters);
+        }
+        for (loc, cnt) in self.diverged() {
+            writeln!(f, "  {:<10} diverged in {}/{} iterations",
+                loc.to_string(), cnt, self.iters
+            )?;
+        }
+        for div in self.examples.iter() {
+            writeln!(f, "  iter {:6} {:<10} {:016x} != {:016x}",
+                div.iter, div.loc.to_string(), div.a, div.b
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Compare register dumps from two configurations.
+#[derive(Clone, Copy)]
+pub struct StateDiff {
+    ignore_gpr: [bool; 16],
+    ignore_vgpr: [bool; 16],
+}
+impl StateDiff {
+    /// Create a new comparison. RSP is always ignored, since each harness
+    /// uses a different stack.
+    pub fn new() -> Self {
+        let res = Self { ignore_gpr: [false; 16], ignore_vgpr: [false; 16] };
+        res.ignore_gpr(Gpr::Rsp)
+    }
+
+    /// Ignore a GPR which is expected to differ (ie. a register used to
+    /// hold a timestamp or counter value).
+    pub fn ignore_gpr(mut self, gpr: Gpr) -> Self {
+        self.ignore_gpr[gpr as usize] = true;
+        self
+    }
+
+    /// Ignore all lanes in a vector register which is expected to differ.
+    pub fn ignore_vgpr(mut self, vgpr: VectorGpr) -> Self {
+        self.ignore_vgpr[vgpr as usize] = true;
+        self
+    }
+
+    /// Compare the state for each iteration.
+    pub fn compare(&self,
+        a_gpr: &[GprState], b_gpr: &[GprState],
+        a_vgpr: &[VectorGprState], b_vgpr: &[VectorGprState],
+        skip: impl Fn(usize) -> bool,
+    ) -> DiffReport
+    {
+        let mut res = DiffReport::new();
+        let iters = a_gpr.len().max(a_vgpr.len())
+            .min(b_gpr.len().max(b_vgpr.len()));
+
+        for iter in (0..iters).filter(|i| !skip(*i)) {
+            res.iters += 1;
+            if let (Some(a), Some(b)) = (a_gpr.get(iter), b_gpr.get(iter)) {
+                for idx in (0..16).filter(|idx| !self.ignore_gpr[*idx]) {
+                    if a.0[idx] != b.0[idx] {
+                        res.record(StateDivergence { iter,
+                            loc: StateLoc::Gpr(Gpr::from(idx as u8)),
+                            a: a.0[idx] as u64, b: b.0[idx] as u64,
+                        });
+                    }
+                }
+            }
+            if let (Some(a), Some(b)) = (a_vgpr.get(iter), b_vgpr.get(iter)) {
+                for idx in (0..16).filter(|idx| !self.ignore_vgpr[*idx]) {
+                    for lane in 0..4 {
+                        if a.0[idx][lane] != b.0[idx][lane] {
+                            res.record(StateDivergence { iter,
+                                loc: StateLoc::Vgpr(VectorGpr::from(idx as u8), lane),
+                                a: a.0[idx][lane], b: b.0[idx][lane],
+                            });
+                        }
+                    }
+                }
+            }
+        }
+        res
+    }
+
+    /// Compare the register dumps in two sets of results.
+    ///
+    /// Iterations that faulted in either set of results are skipped.
+    pub fn compare_results(&self, a: &MeasureResults, b: &MeasureResults)
+        -> DiffReport
+    {
+        let empty_gpr = Vec::new();
+        let empty_vgpr = Vec::new();
+        self.compare(
+            a.gpr_dumps.as_ref().unwrap_or(&empty_gpr),
+            b.gpr_dumps.as_ref().unwrap_or(&empty_gpr),
+            a.vgpr_dumps.as_ref().unwrap_or(&empty_vgpr),
+            b.vgpr_dumps.as_ref().unwrap_or(&empty_vgpr),
+            |iter| a.is_faulted(iter) || b.is_faulted(iter),
+        )
+    }
+}
+impl Default for StateDiff {
+    fn default() -> Self { Self::new() }
+}
+
+/// Run a measured function under two configurations and compare the
+/// resulting architectural state.
+pub struct DiffTest {
+    pub a: DiffConfig,
+    pub b: DiffConfig,
+    pub diff: StateDiff,
+}
+impl DiffTest {
+    pub fn new(a: DiffConfig, b: DiffConfig) -> Self {
+        Self { a, b, diff: StateDiff::new() }
+    }
+    pub fn diff(mut self, diff: StateDiff) -> Self {
+        self.diff = diff;
+        self
+    }
+
+    /// Run both configurations and compare the results.
+    ///
+    /// NOTE: Both configurations are given the same [`InputMethod`]. If you
+    /// want to compare iterations with random inputs, use
+    /// [`InputMethod::Seeded`] or [`InputMethod::List`] so that both sides 
+    /// see the same inputs.
+    pub fn run(&self, event: &EventDesc, iters: usize, input: InputMethod)
+        -> Result<(MeasureResults, MeasureResults, DiffReport), String>
+    {
+        let a = self.a.run(event, iters, input.clone())?;
+        let b = self.b.run(event, iters, input)?;
+        let report = self.diff.compare_results(&a, &b);
+        Ok((a, b, report))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn diff_state_dumps() {
+        let mut a_gpr = vec![GprState::new(); 8];
+        let mut b_gpr = a_gpr.clone();
+        let a_vgpr = vec![VectorGprState::new(); 8];
+        let mut b_vgpr = a_vgpr.clone();
+
+        a_gpr[1].0[Gpr::Rcx as usize] = 1;
+        b_gpr[2].0[Gpr::R15 as usize] = 1;
+        b_vgpr[3].0[15][2] = 0xdead_c0de;
+        b_vgpr[4].0[15][2] = 0xdead_c0de;
+        b_vgpr[5].0[15][2] = 0xdead_c0de;
+
+        let diff = StateDiff::new().ignore_gpr(Gpr::R15);
+        let report = diff.compare(&a_gpr, &b_gpr, &a_vgpr, &b_vgpr, |_| false);
+        assert_eq!(report.iters, 8);
+        assert_eq!(report.gpr_counts[Gpr::Rcx as usize], 1);
+        assert_eq!(report.gpr_counts[Gpr::R15 as usize], 0);
+        assert_eq!(report.vgpr_counts[15], [0, 0, 3, 0]);
+        assert_eq!(report.diverged().len(), 2);
+
+        // Skipping iterations (ie. because they faulted)
+        let report = diff.compare(&a_gpr, &b_gpr, &a_vgpr, &b_vgpr, |i| i != 1);
+        assert_eq!(report.iters, 1);
+        assert_eq!(report.vgpr_counts[15], [0; 4]);
+        assert!(!report.is_clean());
+    }
+}
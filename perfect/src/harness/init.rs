@@ -0,0 +1,290 @@
+//! Declarative initial state for measured code.
+
+use crate::asm::{ Gpr, VectorGpr };
+use crate::harness::ArenaLayout;
+use std::mem::offset_of;
+
+/// The location of a block of memory in [`InitState::mem`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum InitMemTarget {
+    /// Offset into the harness arena (see
+    /// [`HarnessConfig::arena_alloc`](crate::harness::HarnessConfig))
+    Arena(usize),
+    /// Offset into a named buffer (see
+    /// [`HarnessConfig::arena`](crate::harness::HarnessConfig))
+    Buffer(String, usize),
+}
+
+/// Architectural state loaded by the harness before entering measured code
+/// (see [`HarnessConfig::init_state`](crate::harness::HarnessConfig)).
+///
+/// Any state which isn't specified here is left alone: GPRs and vector
+/// registers are still cleared according to [`ZeroStrategy`] and
+/// [`ZeroStrategyFp`] before the initial state is loaded.
+///
+/// - Values for RSP, RDI, RSI, and R15 cannot be set (see
+///   [`PerfectHarness::emit`] for the binary interface)
+/// - GPR values are overridden by [`Preload`] state when
+///   [`HarnessConfig::preload_gpr`] is set
+/// - RFLAGS cannot be combined with [`HarnessConfig::cmp_rdi`]
+/// - The original MXCSR and x87 control word are restored after exiting
+///   measured code (and by the default fault recovery routine)
+///
+/// [`ZeroStrategy`]: crate::harness::ZeroStrategy
+/// [`ZeroStrategyFp`]: crate::harness::ZeroStrategyFp
+/// [`PerfectHarness::emit`]: crate::harness::PerfectHarness
+/// [`Preload`]: crate::harness::Preload
+/// [`HarnessConfig::preload_gpr`]: crate::harness::HarnessConfig
+/// [`HarnessConfig::cmp_rdi`]: crate::harness::HarnessConfig
+#[derive(Clone, Debug, Default)]
+pub struct InitState {
+    /// Initial values for the integer GPRs.
+    pub gpr: [Option<usize>; 16],
+
+    /// Initial values for the vector GPRs.
+    pub vgpr: [Option<[u64; 4]>; 16],
+
+    /// Initial value for RFLAGS (only the bits in [`InitState::RFLAGS_MASK`]).
+    pub rflags: Option<u64>,
+
+    /// Initial value for MXCSR.
+    pub mxcsr: Option<u32>,
+
+    /// Initial value for the x87 FPU control word.
+    pub fcw: Option<u16>,
+
+    /// Blocks of memory (and their locations) copied into the arena before
+    /// each call to measured code.
+    pub mem: Vec<(InitMemTarget, Vec<u8>)>,
+}
+impl InitState {
+    /// RFLAGS bits which may be set (CF, PF, AF, ZF, SF, DF, and OF).
+    pub const RFLAGS_MASK: u64 = 0x0cd5;
+
+    /// Default value of MXCSR (all exceptions masked, round-to-nearest).
+    pub const DEFAULT_MXCSR: u32 = 0x1f80;
+
+    /// Default value of the x87 control word (after FNINIT).
+    pub const DEFAULT_FCW: u16 = 0x037f;
+
+    pub fn new() -> Self { Self::default() }
+
+    pub fn gpr(mut self, gpr: Gpr, x: usize) -> Self {
+        assert!(!matches!(gpr, Gpr::Rsp | Gpr::Rdi | Gpr::Rsi | Gpr::R15),
+            "Initial value for {:?} cannot be set", gpr
+        );
+        self.gpr[gpr as usize] = Some(x);
+        self
+    }
+
+    pub fn vgpr(mut self, vgpr: VectorGpr, x: [u64; 4]) -> Self {
+        self.vgpr[vgpr as usize] = Some(x);
+        self
+    }
+
+    pub fn rflags(mut self, x: u64) -> Self {
+        assert!(x & !(Self::RFLAGS_MASK | 0b10) == 0,
+            "Unsupported RFLAGS bits {:#x}", x & !Self::RFLAGS_MASK
+        );
+        self.rflags = Some(x & Self::RFLAGS_MASK);
+        self
+    }
+
+    pub fn mxcsr(mut self, x: u32) -> Self {
+        self.mxcsr = Some(x);
+        self
+    }
+
+    pub fn fcw(mut self, x: u16) -> Self {
+        self.fcw = Some(x);
+        self
+    }
+
+    pub fn mem(mut self, offset: usize, data: impl Into<Vec<u8>>) -> Self {
+        self.mem.push((InitMemTarget::Arena(offset), data.into()));
+        self
+    }
+
+    /// Like [`InitState::mem`], but for a named arena buffer.
+    pub fn mem_in(mut self, name: &str, offset: usize,
+        data: impl Into<Vec<u8>>) -> Self
+    {
+        let target = InitMemTarget::Buffer(name.to_string(), offset);
+        self.mem.push((target, data.into()));
+        self
+    }
+
+    /// Returns true if loading this state changes the MXCSR or x87 control
+    /// word (which must be restored afterwards).
+    pub fn has_fp_control(&self) -> bool {
+        self.mxcsr.is_some() || self.fcw.is_some()
+    }
+
+    /// Resolve the address of each memory block, given the harness arena
+    /// (`arena_alloc`, as a base address and length) and the arena layout.
+    ///
+    /// Fails if a block doesn't fit in its buffer.
+    pub fn resolve_mem(&self, arena_alloc: Option<(usize, usize)>,
+        layout: Option<&ArenaLayout>) -> Result<Vec<(usize, &[u8])>, String>
+    {
+        self.mem.iter().map(|(target, data)| {
+            let (name, base, len, off) = match target {
+                InitMemTarget::Arena(off) => {
+                    let (base, len) = arena_alloc.ok_or_else(|| {
+                        "InitState::mem requires HarnessConfig::arena_alloc"
+                            .to_string()
+                    })?;
+                    ("arena", base, len, *off)
+                },
+                InitMemTarget::Buffer(name, off) => {
+                    let buf = layout.and_then(|l| l.get(name))
+                        .ok_or_else(|| format!("No arena buffer named '{}'",
+                            name
+                        ))?;
+                    (name.as_str(), buf.addr, buf.len(), *off)
+                },
+            };
+            if off.checked_add(data.len()).is_none_or(|end| end > len) {
+                return Err(format!(
+                    "Block at offset {:#x} (len {:#x}) is outside of '{}' \
+                    (len {:#x})", off, data.len(), name, len
+                ));
+            }
+            Ok((base + off, data.as_slice()))
+        }).collect()
+    }
+}
+
+/// Scratchpad memory used by the harness for loading [`InitState`], and for
+/// saving/restoring the original floating-point control registers.
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
+pub struct InitStateBlock {
+    /// Initial values for the vector GPRs
+    pub vgpr: [[u64; 4]; 16],
+    /// Initial values for the integer GPRs
+    pub gpr: [usize; 16],
+    /// Initial value for MXCSR
+    pub mxcsr: u32,
+    /// Original value of MXCSR
+    pub saved_mxcsr: u32,
+    /// Initial value for the x87 control word
+    pub fcw: u16,
+    /// Original value of the x87 control word
+    pub saved_fcw: u16,
+}
+impl InitStateBlock {
+    pub const OFFSET_GPR: i32 = offset_of!(Self, gpr) as i32;
+    pub const OFFSET_MXCSR: i32 = offset_of!(Self, mxcsr) as i32;
+    pub const OFFSET_SAVED_MXCSR: i32 = offset_of!(Self, saved_mxcsr) as i32;
+    pub const OFFSET_FCW: i32 = offset_of!(Self, fcw) as i32;
+    pub const OFFSET_SAVED_FCW: i32 = offset_of!(Self, saved_fcw) as i32;
+
+    pub fn new(init: &InitState) -> Self {
+        let mut vgpr = [[0; 4]; 16];
+        for (idx, val) in init.vgpr.iter().enumerate() {
+            vgpr[idx] = val.unwrap_or([0; 4]);
+        }
+        let mut gpr = [0; 16];
+        for (idx, val) in init.gpr.iter().enumerate() {
+            gpr[idx] = val.unwrap_or(0);
+        }
+        Self {
+            vgpr,
+            gpr,
+            mxcsr: init.mxcsr.unwrap_or(InitState::DEFAULT_MXCSR),
+            saved_mxcsr: InitState::DEFAULT_MXCSR,
+            fcw: init.fcw.unwrap_or(InitState::DEFAULT_FCW),
+            saved_fcw: InitState::DEFAULT_FCW,
+        }
+    }
+    pub fn as_ptr(&self) -> *const Self { self as *const Self }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm::*;
+    use crate::harness::*;
+    use crate::events::{ EventDesc, MaskDesc };
+    use dynasmrt::{ dynasm, DynasmApi, AssemblyOffset };
+
+    #[test]
+    fn init_state_loaded() {
+        const ARENA_BASE: usize = 0x0000_0021_0000_0000;
+        let init = InitState::new()
+            .gpr(Gpr::Rax, 0x1234)
+            .gpr(Gpr::Rbx, 0x1111)
+            .gpr(Gpr::R12, 0x2222)
+            .vgpr(VectorGpr::YMM3, [1, 2, 3, 4])
+            .rflags(0x0041)
+            .mxcsr(0x9f80)
+            .fcw(0x027f)
+            .mem(0x100, 0xdead_beef_u64.to_le_bytes())
+            .mem_in("init", 0x10, 0xcafe_f00d_u64.to_le_bytes());
+        let layout = ArenaLayout::new(0x0000_0022_0000_0000)
+            .buffer(ArenaBufferDesc::new("init", 0x1000));
+        let buf_addr = layout.addr("init");
+        let mut harness = HarnessConfig::default_test(0x133c)
+            .arena_alloc(ARENA_BASE, 0x1000)
+            .arena(layout)
+            .zero_strategy_fp(ZeroStrategyFp::Vzeroall)
+            .dump_gpr(true)
+            .dump_vgpr(true)
+            .init_state(init)
+            .emit();
+
+        // Capture RFLAGS, MXCSR, the x87 control word, and the arena
+        let mut f = X64Assembler::new().unwrap();
+        dynasm!(f
+            ; mov r11, rax
+            ; pushfq
+            ; pop rcx
+            ; sub rsp, 8
+            ; stmxcsr [rsp]
+            ; mov edx, [rsp]
+            ; fnstcw [rsp]
+            ; movzx r8d, WORD [rsp]
+            ; add rsp, 8
+            ; mov r10, QWORD (ARENA_BASE + 0x100) as _
+            ; mov r9, [r10]
+            // Clobber the arena (it should be restored on the next call)
+            ; mov QWORD [r10], 0
+            ; mov r10, QWORD (buf_addr + 0x10) as _
+            ; mov r13, [r10]
+            ; mov QWORD [r10], 0
+            ; ret
+        );
+        f.commit().unwrap();
+        let buf = f.finalize().unwrap();
+        let func: MeasuredFn = unsafe {
+            std::mem::transmute(buf.ptr(AssemblyOffset(0)))
+        };
+
+        let event = EventDesc::new_unk(0x0c0, MaskDesc::new_unk(0x00));
+        let res = harness.measure_and_dump(func, &event, 4,
+            InputMethod::Fixed(0, 0)
+        ).unwrap();
+        for (gpr, vgpr) in res.gpr_dumps.unwrap().iter()
+            .zip(res.vgpr_dumps.unwrap().iter())
+        {
+            assert_eq!(gpr.r11(), 0x1234);
+            assert_eq!(gpr.rbx(), 0x1111);
+            assert_eq!(gpr.r12(), 0x2222);
+            assert_eq!(gpr.rcx() as u64 & InitState::RFLAGS_MASK, 0x0041);
+            assert_eq!(gpr.rdx(), 0x9f80);
+            assert_eq!(gpr.r8(), 0x027f);
+            assert_eq!(gpr.r9(), 0xdead_beef);
+            assert_eq!(gpr.r13(), 0xcafe_f00d);
+            assert_eq!(vgpr.ymm3(), [1, 2, 3, 4]);
+            assert_eq!(vgpr.ymm4(), [0; 4]);
+        }
+
+        // Initial values can be changed without emitting a new harness
+        harness.set_init_gpr(Gpr::Rbx, 0x3333);
+        let res = harness.measure_and_dump(func, &event, 1,
+            InputMethod::Fixed(0, 0)
+        ).unwrap();
+        assert_eq!(res.gpr_dumps.unwrap()[0].rbx(), 0x3333);
+    }
+}
@@ -1,6 +1,8 @@
 //! Module with types for handling input arguments to measured code.
 
-use rand::rngs::ThreadRng;
+use crate::util::rng::{ PerfectRng, seeded_rng };
+use std::rc::Rc;
+use crate::harness::GprState;
 
 /// Auto-implemented on function types that are suitable for generating
 /// input to a measured function.
@@ -11,14 +13,47 @@ use rand::rngs::ThreadRng;
 ///
 /// The arguments to this function are:
 ///
-/// - A mutable reference to the harness' [`ThreadRng`]
+/// - A mutable reference to a [`PerfectRng`] owned by the harness
 /// - The current iteration/test index for the associated input
 ///
 pub trait InputGenerator:
-    Fn(&mut ThreadRng, usize) -> (usize, usize) {}
-impl <F: Fn(&mut ThreadRng, usize) -> (usize, usize)>
+    Fn(&mut PerfectRng, usize) -> (usize, usize) {}
+impl <F: Fn(&mut PerfectRng, usize) -> (usize, usize)>
     InputGenerator for F {}
 
+/// State loaded before a single test iteration (in addition to the
+/// arguments passed in RDI and RSI).
+#[derive(Clone, Debug, Default)]
+pub struct Preload {
+    /// Initial values for the GPRs.
+    ///
+    /// This requires [`HarnessConfig::preload_gpr`](crate::harness::HarnessConfig).
+    /// Values for RSP, RDI, RSI, and R15 are ignored. When this is [`None`],
+    /// the GPRs are all zero.
+    pub gpr: Option<GprState>,
+
+    /// Blocks of memory (and their addresses) copied before the iteration.
+    ///
+    /// NOTE: These are written with a raw pointer: you're responsible for
+    /// making sure that the target memory is mapped and writable.
+    pub mem: Vec<(usize, Vec<u8>)>,
+}
+impl Preload {
+    /// Copy all memory blocks to their target addresses.
+    pub(crate) fn write_mem(&self) {
+        for (addr, data) in self.mem.iter() {
+            unsafe {
+                std::ptr::copy_nonoverlapping(data.as_ptr(), *addr as *mut u8,
+                    data.len()
+                );
+            }
+        }
+    }
+}
+
+/// A closure used with [`InputMethod::Seeded`].
+pub type SeededInputFn<'a> = Rc<dyn Fn(&mut PerfectRng, usize) -> (usize, usize) + 'a>;
+
 /// Strategy used by [PerfectHarness] to compute the set of inputs to the
 /// measured function across all test runs.
 #[derive(Clone)]
@@ -28,11 +63,146 @@ pub enum InputMethod<'a> {
 
     /// Provide a function/closure which computes the arguments (RDI and RSI)
     /// by using:
-    /// - A mutable reference to the [`ThreadRng`] owned by the harness
+    /// - A mutable reference to a [`PerfectRng`] owned by the harness
     /// - The index of the current test run
-    Random(&'static dyn Fn(&mut ThreadRng, usize) -> (usize, usize)),
+    ///
+    /// The RNG is seeded from the harness RNG, and the seed is recorded in 
+    /// [`MeasureResults::seed`](crate::stats::MeasureResults).
+    Random(&'static dyn Fn(&mut PerfectRng, usize) -> (usize, usize)),
 
     /// Provide a precomputed list of arguments (RDI and RSI).
+    /// The list must have at least one element for each test run.
     List(&'a Vec<(usize, usize)>),
+
+    /// Provide a closure which computes the arguments (RDI and RSI) from the
+    /// index of the current test run.
+    ///
+    /// Unlike [`InputMethod::Random`], the closure may capture borrowed
+    /// values.
+    Func(Rc<dyn Fn(usize) -> (usize, usize) + 'a>),
+
+    /// Like [`InputMethod::Random`], but with an explicit seed.
+    ///
+    /// Each test run uses a new RNG (see [`InputMethod::seeded_rng`])
+    /// derived from the seed and the index of the test run, so the input
+    /// for any single test run can be recomputed later.
+    Seeded(u64, SeededInputFn<'a>),
+
+    /// Use every combination of values for RDI and RSI (where RSI varies
+    /// fastest), starting over when the number of test runs is larger than
+    /// the number of combinations.
+    Cartesian(&'a [usize], &'a [usize]),
+
+    /// Cycle through a list of arguments (RDI and RSI).
+    Cycle(&'a [(usize, usize)]),
+
+    /// Use some other method for RDI and RSI, and provide a closure which
+    /// computes additional [`Preload`] state from the index of the current
+    /// test run.
+    WithPreload(Box<InputMethod<'a>>, Rc<dyn Fn(usize) -> Preload + 'a>),
 }
+impl <'a> InputMethod<'a> {
+    /// Return the RNG used by [`InputMethod::Seeded`] for a particular
+    /// test run.
+    pub fn seeded_rng(seed: u64, idx: usize) -> PerfectRng {
+        seeded_rng(seed ^ (idx as u64).wrapping_mul(0x9e37_79b9_7f4a_7c15))
+    }
 
+    /// Return the arguments (RDI and RSI) for each of `iters` test runs.
+    pub fn args(&self, rng: &mut PerfectRng, iters: usize)
+        -> Vec<(usize, usize)>
+    {
+        match self {
+            Self::Fixed(rdi, rsi) => vec![(*rdi, *rsi); iters],
+            Self::Random(input_fn) => {
+                (0..iters).map(|idx| input_fn(rng, idx)).collect()
+            },
+            Self::List(data) => {
+                assert!(data.len() >= iters,
+                    "InputMethod::List must provide at least {} elements",
+                    iters
+                );
+                data[..iters].to_vec()
+            },
+            Self::Func(input_fn) => (0..iters).map(|idx| input_fn(idx)).collect(),
+            Self::Seeded(seed, input_fn) => {
+                (0..iters).map(|idx| {
+                    input_fn(&mut Self::seeded_rng(*seed, idx), idx)
+                }).collect()
+            },
+            Self::Cartesian(rdi, rsi) => {
+                assert!(!rdi.is_empty() && !rsi.is_empty(),
+                    "InputMethod::Cartesian requires at least one value"
+                );
+                (0..iters).map(|idx| {
+                    let idx = idx % (rdi.len() * rsi.len());
+                    (rdi[idx / rsi.len()], rsi[idx % rsi.len()])
+                }).collect()
+            },
+            Self::Cycle(data) => {
+                assert!(!data.is_empty(),
+                    "InputMethod::Cycle requires at least one element"
+                );
+                data.iter().copied().cycle().take(iters).collect()
+            },
+            Self::WithPreload(inner, _) => inner.args(rng, iters),
+        }
+    }
+
+    /// Return the [`Preload`] state for each of `iters` test runs (or an
+    /// empty list if this method doesn't use any).
+    pub fn preloads(&self, iters: usize) -> Vec<Preload> {
+        match self {
+            Self::WithPreload(_, preload_fn) => {
+                (0..iters).map(|idx| preload_fn(idx)).collect()
+            },
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use rand::Rng;
+
+    #[test]
+    fn input_methods() {
+        let mut rng = seeded_rng(0);
+
+        let rdi = [1, 2];
+        let rsi = [10, 20, 30];
+        let args = InputMethod::Cartesian(&rdi, &rsi).args(&mut rng, 7);
+        assert_eq!(args, vec![
+            (1, 10), (1, 20), (1, 30), (2, 10), (2, 20), (2, 30), (1, 10)
+        ]);
+
+        let list = [(1, 2), (3, 4)];
+        let args = InputMethod::Cycle(&list).args(&mut rng, 3);
+        assert_eq!(args, vec![(1, 2), (3, 4), (1, 2)]);
+
+        // Closures can capture borrowed values
+        let base = vec![0x1000usize];
+        let args = InputMethod::Func(Rc::new(|idx| (base[0] + idx, 0)))
+            .args(&mut rng, 2);
+        assert_eq!(args, vec![(0x1000, 0), (0x1001, 0)]);
+
+        // The same seed always produces the same inputs, and any single
+        // input can be recomputed with the seed and index
+        let method = InputMethod::Seeded(1234, Rc::new(|rng, _| {
+            (rng.gen(), rng.gen())
+        }));
+        let a = method.args(&mut rng, 16);
+        assert_eq!(a, method.args(&mut rng, 16));
+        let mut sample_rng = InputMethod::seeded_rng(1234, 5);
+        assert_eq!(a[5], (sample_rng.gen(), sample_rng.gen()));
+
+        let method = InputMethod::WithPreload(
+            Box::new(InputMethod::Fixed(1, 2)),
+            Rc::new(|idx| Preload { gpr: None, mem: vec![(0x1000, vec![idx as u8])] })
+        );
+        assert_eq!(method.args(&mut rng, 2), vec![(1, 2), (1, 2)]);
+        assert_eq!(method.preloads(2)[1].mem[0].1, vec![1]);
+        assert!(InputMethod::Fixed(0, 0).preloads(2).is_empty());
+    }
+}
@@ -1,32 +1,87 @@
 //! Module for catching signals.
+//!
+//! Recovering from Faults
+//! ======================
+//!
+//! Measured code may (intentionally or not) cause an exception. Instead of
+//! killing the process, we'd like to record what happened and return to the
+//! harness as if the measured function had returned normally.
+//!
+//! When a synchronous fault signal (SIGSEGV, SIGILL, SIGFPE, SIGBUS, or
+//! SIGTRAP) is delivered while the harness is "armed" (see [`arm`]), the
+//! handler:
+//!
+//! 1. Saves the faulting context in a [`FaultRecord`]
+//! 2. Rewrites the saved RIP in the signal context to point at the recovery
+//!    routine emitted by the harness (at
+//!    [`HarnessConfig::handler_addr`](crate::harness::HarnessConfig))
+//! 3. Returns from the signal handler
+//!
+//! Since we return from the handler normally, the kernel restores the signal
+//! mask for us and the handler stays registered for subsequent faults.
+//! The recovery routine restores the stack pointer saved by the harness and
+//! pops the nonvolatile registers, returning directly to the caller.
+//!
+//! Signals are delivered on an alternate stack (see [`arm`]), so we can 
+//! still recover when measured code has clobbered the stack pointer.
+//!
+//! When a fault occurs while the harness is *not* armed, the fault is passed
+//! along to the handler that was registered before ours (ie. the handler
+//! used by the Rust runtime to report stack overflows). If there was no
+//! previous handler, the default disposition is restored and the signal is
+//! raised again.
 
+use std::cell::Cell;
 use std::ffi::{c_int, c_void};
-use std::sync::{Arc,Mutex};
-use std::sync::atomic::AtomicBool;
+use std::sync::{ Once, OnceLock };
 use nix::{
-    libc::{siginfo_t, ucontext_t},
+    libc::{self, siginfo_t, ucontext_t},
     sys::signal::{
         sigaction, SaFlags, SigAction, SigHandler, SigSet, Signal,
-        sigprocmask, SigmaskHow, 
+        sigprocmask, SigmaskHow,
     },
 };
 
-use crate::harness::config::*;
+/// The set of synchronous signals handled by [`fault_handler`].
+pub const FAULT_SIGNALS: [Signal; 5] = [
+    Signal::SIGSEGV,
+    Signal::SIGILL,
+    Signal::SIGFPE,
+    Signal::SIGBUS,
+    Signal::SIGTRAP,
+];
 
-thread_local! { 
-    pub static GREGS: Arc<Mutex<Gregs>> = Arc::new(Mutex::new(Gregs::new()));
-    pub static HANDLED: Arc<AtomicBool> = Arc::new(AtomicBool::new(false));
+/// State used by [`fault_handler`] to recover from a fault.
+#[derive(Clone, Copy, Debug)]
+struct Armed {
+    /// Address of the recovery routine
+    recovery_addr: usize,
+    /// Address of the measured function
+    measured_fn: usize,
 }
 
-/// Type mirroring the layout of 'gregset_t' in libc. 
+thread_local! {
+    static ARMED: Cell<Option<Armed>> = const { Cell::new(None) };
+    static FAULT: Cell<Option<FaultRecord>> = const { Cell::new(None) };
+    static ALTSTACK: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Handlers that were registered before [`register_fault_handlers`]
+/// (in the same order as [`FAULT_SIGNALS`]).
+static PREV_ACTIONS: OnceLock<[SigAction; FAULT_SIGNALS.len()]> = OnceLock::new();
+
+/// Size of the alternate signal stack.
+const ALTSTACK_SIZE: usize = 0x0001_0000;
+
+/// Type mirroring the layout of 'gregset_t' in libc.
 /// A signal handler stores saved general-purpose registers in this format.
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug)]
 #[repr(C)]
-pub struct Gregs { 
+pub struct Gregs {
     data: [u64; 23]
 }
-impl Gregs { 
-    pub fn new() -> Self { 
+impl Gregs {
+    pub fn new() -> Self {
         Self { data: [0; 23] }
     }
 
@@ -57,59 +112,237 @@ impl Gregs {
     pub fn cr2(&self)     -> u64 { self.data[22] }
 }
 
+/// Record of a fault that occurred while running measured code.
+#[derive(Clone, Copy, Debug)]
+pub struct FaultRecord {
+    /// The signal delivered for this fault
+    pub signal: Signal,
+    /// The saved GPR state at the faulting instruction
+    pub gregs: Gregs,
+    /// The faulting address reported in 'siginfo_t'
+    pub addr: usize,
+    /// The offset of the faulting instruction from the start of the
+    /// measured function
+    pub offset: usize,
+}
+impl FaultRecord {
+    pub fn rip(&self) -> usize { self.gregs.rip() as usize }
+    pub fn cr2(&self) -> usize { self.gregs.cr2() as usize }
+    pub fn trapno(&self) -> u64 { self.gregs.trapno() }
+}
+impl std::fmt::Display for FaultRecord {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} at rip={:016x} (offset {:x}), addr={:016x}, trapno={}",
+            self.signal, self.rip(), self.offset, self.addr, self.trapno()
+        )
+    }
+}
 
-/// Handler for SIGSEGV.
+/// Arm the fault handler for the current thread.
 ///
-/// FIXME: I'm not sure this is actually finished and working correctly
-extern "C" 
-fn sigsegv_handler(_sig: c_int, _si: *mut siginfo_t, ctx: *mut c_void) {
-
-    //let uctx = ctx.cast::<ucontext_t>();
-    //let gregs: &[u64] = unsafe { 
-    //    std::slice::from_raw_parts(
-    //        (*uctx).uc_mcontext.gregs.as_ptr() as *const u64,
-    //        23
-    //    )
-    //};
-    //let rip: u64 = gregs[16] + 9;
-
-    // Unblock SIGSEGV so we can handle another signal later
-    //let mut sigset = SigSet::empty();
-    //sigset.add(Signal::SIGSEGV);
-    //sigprocmask(SigmaskHow::SIG_UNBLOCK, Some(&sigset), None).unwrap();
-
-    // FIXME: Automatically re-register the handler? 
-    //register_sigsegv_handler();
-
-    // Recover from this segfault by restoring the saved stack pointer 
-    // and volatile registers. Instead of doing this *here*, we jump into the 
-    // handler emitted by the harness. 
-    unsafe { 
-        core::arch::asm!(r#"
-            mov r15, {foo}
-            jmp r15
-        "#,
-        foo = const HarnessConfig::DEFAULT_HANDLER_ADDR,
-        );
+/// Until [`disarm`] is called, faults are recorded and recovered by
+/// jumping to `recovery_addr`.
+pub fn arm(recovery_addr: usize, measured_fn: usize) {
+    enable_altstack();
+    ARMED.with(|a| a.set(Some(Armed { recovery_addr, measured_fn })));
+}
+
+/// Allocate an alternate signal stack for the current thread (if we 
+/// haven't already done this). 
+///
+/// The allocation is leaked, since the kernel may still refer to it when 
+/// the thread exits.
+fn enable_altstack() {
+    if ALTSTACK.with(|a| a.get()) {
+        return;
     }
+    let stack = Box::leak(vec![0u8; ALTSTACK_SIZE].into_boxed_slice());
+    let ss = libc::stack_t {
+        ss_sp: stack.as_mut_ptr().cast::<c_void>(),
+        ss_flags: 0,
+        ss_size: ALTSTACK_SIZE,
+    };
+    let res = unsafe { libc::sigaltstack(&ss, std::ptr::null_mut()) };
+    assert!(res == 0, "sigaltstack() failed");
+    ALTSTACK.with(|a| a.set(true));
+}
 
+/// Disarm the fault handler for the current thread.
+pub fn disarm() {
+    ARMED.with(|a| a.set(None));
 }
 
-/// Register the SIGSEGV handler. 
-///
-/// After calling, subsequent segfaults are handled with [`sigsegv_handler`].
-pub fn register_sigsegv_handler() {
-    let mut sigset = SigSet::empty();
-    sigset.add(Signal::SIGSEGV);
-    sigprocmask(SigmaskHow::SIG_UNBLOCK, Some(&sigset), None).unwrap();
-
-
-    let handler = SigHandler::SigAction(sigsegv_handler);
-    let flags = SaFlags::SA_SIGINFO | SaFlags::SA_RESETHAND;
-    let action = SigAction::new(handler, flags, SigSet::empty());
-    unsafe { 
-        let _ = sigaction(Signal::SIGSEGV, &action);
+/// Take the most-recent [`FaultRecord`] (if any) for the current thread.
+pub fn take_fault() -> Option<FaultRecord> {
+    FAULT.with(|f| f.take())
+}
+
+/// Handler for synchronous fault signals.
+extern "C"
+fn fault_handler(sig: c_int, si: *mut siginfo_t, ctx: *mut c_void) {
+    let signal = Signal::try_from(sig).unwrap();
+
+    // If this didn't happen in measured code, there's nothing we can do
+    let Some(armed) = ARMED.with(|a| a.get()) else {
+        chain_fault(signal, si, ctx);
+        return;
+    };
+
+    let uctx = ctx.cast::<ucontext_t>();
+    let gregs = unsafe { &mut (*uctx).uc_mcontext.gregs };
+    let mut saved = Gregs::new();
+    for (dst, src) in saved.data.iter_mut().zip(gregs.iter()) {
+        *dst = *src as u64;
+    }
+    let addr = unsafe { (*si).si_addr() as usize };
+    FAULT.with(|f| f.set(Some(FaultRecord {
+        signal,
+        gregs: saved,
+        addr,
+        offset: (saved.rip() as usize).wrapping_sub(armed.measured_fn),
+    })));
+
+    // Resume in the recovery routine. Clear the trap flag so we don't
+    // single-step through the harness, and clear the direction and
+    // alignment check flags (which the caller expects to be clear).
+    gregs[libc::REG_RIP as usize] = armed.recovery_addr as i64;
+    gregs[libc::REG_EFL as usize] &= !((1 << 8) | (1 << 10) | (1 << 18));
+}
+
+/// Pass a fault that didn't happen in measured code to the handler that
+/// was registered before [`fault_handler`].
+fn chain_fault(signal: Signal, si: *mut siginfo_t, ctx: *mut c_void) {
+    let prev = PREV_ACTIONS.get().and_then(|actions| {
+        FAULT_SIGNALS.iter().position(|s| *s == signal).map(|i| actions[i])
+    });
+    match prev.map(|action| action.handler()) {
+        Some(SigHandler::Handler(f)) => f(signal as c_int),
+        Some(SigHandler::SigAction(f)) => f(signal as c_int, si, ctx),
+        Some(SigHandler::SigIgn) => {},
+        Some(SigHandler::SigDfl) | None => {
+            unsafe {
+                let _ = nix::sys::signal::signal(signal, SigHandler::SigDfl);
+            }
+            let _ = nix::sys::signal::raise(signal);
+        },
     }
 }
 
+/// Register [`fault_handler`] for all of the signals in [`FAULT_SIGNALS`].
+///
+/// This only needs to happen once per process: subsequent calls do nothing.
+pub fn register_fault_handlers() {
+    static REGISTER: Once = Once::new();
+    REGISTER.call_once(|| {
+        let mut sigset = SigSet::empty();
+        for sig in FAULT_SIGNALS {
+            sigset.add(sig);
+        }
+        sigprocmask(SigmaskHow::SIG_UNBLOCK, Some(&sigset), None).unwrap();
+
+        let handler = SigHandler::SigAction(fault_handler);
+        let action = SigAction::new(handler, 
+            SaFlags::SA_SIGINFO | SaFlags::SA_ONSTACK,
+            SigSet::empty()
+        );
+        let prev = FAULT_SIGNALS.map(|sig| unsafe {
+            sigaction(sig, &action).unwrap()
+        });
+        let _ = PREV_ACTIONS.set(prev);
+    });
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::harness::*;
+    use crate::asm::{ X64Assembler, Emitter };
+    use crate::events::{ EventDesc, MaskDesc };
+    use crate::util::PerfectEnv;
+    use dynasmrt::{ dynasm, DynasmApi, AssemblyOffset };
+
+    #[test]
+    fn signal_recover_faults() {
+        let mut harness = HarnessConfig::default_test(0x1339)
+            .recover_faults(true)
+            .emit();
+
+        // (emitter, signal, trapno, offset of the faulting instruction)
+        let cases: [(&dyn Fn(&mut X64Assembler), Signal, u64, usize); 4] = [
+            (&|f| { dynasm!(f ; nop ; ud2); }, Signal::SIGILL, 6, 1),
+            (&|f| { dynasm!(f ; nop ; mov rax, [0]); }, Signal::SIGSEGV, 14, 1),
+            (&|f| { dynasm!(f ; nop ; xor ecx, ecx ; div rcx); },
+                Signal::SIGFPE, 0, 3),
+            // NOTE: RIP points *after* the breakpoint
+            (&|f| { dynasm!(f ; nop ; int3); }, Signal::SIGTRAP, 3, 2),
+        ];
 
+        // Run each case twice to make sure the handlers stay registered
+        for (emit, signal, trapno, offset) in cases.iter().chain(cases.iter()) {
+            let mut f = X64Assembler::new().unwrap();
+            emit(&mut f);
+            f.emit_ret();
+            f.commit().unwrap();
+            let buf = f.finalize().unwrap();
+            let func: MeasuredFn = unsafe {
+                std::mem::transmute(buf.ptr(AssemblyOffset(0)))
+            };
+
+            let fault = harness.try_call(0, 0, func).unwrap_err();
+            assert_eq!(fault.signal, *signal);
+            assert_eq!(fault.trapno(), *trapno);
+            assert_eq!(fault.offset, *offset);
+        }
+
+        // Make sure that normal calls still work afterwards
+        let mut f = X64Assembler::new().unwrap();
+        dynasm!(f ; mov rax, 0x1234 ; ret);
+        f.commit().unwrap();
+        let buf = f.finalize().unwrap();
+        let func: MeasuredFn = unsafe {
+            std::mem::transmute(buf.ptr(AssemblyOffset(0)))
+        };
+        assert_eq!(harness.try_call(0, 0, func).unwrap(), 0x1234);
+    }
+
+    #[test]
+    fn signal_fault_dumps() {
+        // Measured code uses RDPMC, which faults without a core PMU
+        if PerfectEnv::sysfs_rdpmc_enabled().is_err() {
+            return;
+        }
+        let mut harness = HarnessConfig::default_test(0x133e)
+            .recover_faults(true)
+            .dump_gpr(true)
+            .emit();
+
+        // Fault when RDI is zero
+        let mut f = X64Assembler::new().unwrap();
+        let ok = f.new_dynamic_label();
+        dynasm!(f
+            ; mov r11, rdi
+            ; test rdi, rdi
+            ; jnz =>ok
+            ; ud2
+            ; =>ok
+            ; ret
+        );
+        f.commit().unwrap();
+        let buf = f.finalize().unwrap();
+        let func: MeasuredFn = unsafe {
+            std::mem::transmute(buf.ptr(AssemblyOffset(0)))
+        };
+
+        let inputs = vec![(1, 0), (0, 0), (2, 0)];
+        let event = EventDesc::new_unk(0x0c0, MaskDesc::new_unk(0x00));
+        let res = harness.measure_and_dump(func, &event, 3,
+            InputMethod::List(&inputs)
+        ).unwrap();
+        assert_eq!(res.faults.len(), 1);
+        assert!(res.is_faulted(1));
+        let dumps = res.gpr_dumps.unwrap();
+        assert_eq!(dumps[0].r11(), 1);
+        assert_eq!(dumps[1].r11(), 0);
+        assert_eq!(dumps[2].r11(), 2);
+    }
+}
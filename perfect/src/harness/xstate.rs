@@ -0,0 +1,322 @@
+//! Capturing extended state (x87, MXCSR, AVX-512, RFLAGS) with XSAVE.
+//!
+//! When [`HarnessConfig::dump_xstate`](crate::harness::HarnessConfig) is
+//! set, the harness captures RFLAGS and executes XSAVE after exiting
+//! measured code. The set of requested state components is limited to the
+//! components supported by the host (according to CPUID and XCR0), so this
+//! is safe to use on machines without AVX-512 (ie. Zen 2 and Tremont):
+//! unsupported components are simply reported as zero.
+//!
+//! XSAVE uses the "standard" (non-compacted) format here, where the offset
+//! of each component is reported by CPUID leaf 0x0000_000d.
+
+use core::arch::x86_64::__cpuid_count;
+use crate::util::cpuid::HostCpuid;
+use iced_x86::CpuidFeature;
+
+/// The layout of the XSAVE area on the host, and the set of state
+/// components requested by the harness.
+#[derive(Clone, Copy, Debug)]
+pub struct XSaveLayout {
+    /// Requested-feature bitmap (passed to XSAVE in EDX:EAX)
+    pub rfbm: u64,
+    /// Size of the XSAVE area for the requested components
+    pub size: usize,
+    /// Offset of the upper halves of YMM0-15
+    pub avx: usize,
+    /// Offset of the opmask registers
+    pub opmask: usize,
+    /// Offset of the upper halves of ZMM0-15
+    pub zmm_hi256: usize,
+    /// Offset of ZMM16-31
+    pub hi16_zmm: usize,
+}
+impl XSaveLayout {
+    /// x87 state (FPU control/status/tag words and ST0-ST7)
+    pub const X87: u64 = 1 << 0;
+    /// SSE state (MXCSR and XMM0-15)
+    pub const SSE: u64 = 1 << 1;
+    /// AVX state (upper halves of YMM0-15)
+    pub const AVX: u64 = 1 << 2;
+    /// AVX-512 opmask registers (k0-k7)
+    pub const OPMASK: u64 = 1 << 5;
+    /// AVX-512 upper halves of ZMM0-15
+    pub const ZMM_HI256: u64 = 1 << 6;
+    /// AVX-512 ZMM16-31
+    pub const HI16_ZMM: u64 = 1 << 7;
+
+    /// All of the components that we know how to decode.
+    pub const ALL: u64 = Self::X87 | Self::SSE | Self::AVX
+        | Self::OPMASK | Self::ZMM_HI256 | Self::HI16_ZMM;
+
+    /// Offset of the XSAVE header (in the standard format).
+    pub const HEADER: usize = 512;
+
+    /// Determine the layout of the XSAVE area on the host.
+    ///
+    /// Returns [`None`] if XSAVE is not supported or not enabled by the OS.
+    pub fn new(cpuid: &HostCpuid) -> Option<Self> {
+        if !cpuid.supports(CpuidFeature::XSAVE) || cpuid.xcr0 == 0 {
+            return None;
+        }
+        let rfbm = cpuid.xcr0 & Self::ALL;
+        let offset = |idx: u32| -> usize {
+            if rfbm & (1 << idx) != 0 {
+                __cpuid_count(0x0000_000d, idx).ebx as usize
+            } else {
+                0
+            }
+        };
+        let mut res = Self {
+            rfbm,
+            size: Self::HEADER + 64,
+            avx: offset(2),
+            opmask: offset(5),
+            zmm_hi256: offset(6),
+            hi16_zmm: offset(7),
+        };
+        for idx in 2..8 {
+            if rfbm & (1 << idx) != 0 {
+                let leaf = __cpuid_count(0x0000_000d, idx);
+                res.size = res.size.max((leaf.ebx + leaf.eax) as usize);
+            }
+        }
+        Some(res)
+    }
+
+    /// Returns true if the AVX-512 state is captured.
+    pub fn has_avx512(&self) -> bool {
+        let mask = Self::OPMASK | Self::ZMM_HI256 | Self::HI16_ZMM;
+        self.rfbm & mask == mask
+    }
+}
+
+/// Scratchpad memory for the XSAVE area (and RFLAGS) used by the harness.
+#[repr(C, align(64))]
+#[derive(Clone, Copy)]
+pub struct XSaveArea {
+    pub data: [u8; Self::SIZE],
+    pub rflags: u64,
+}
+impl XSaveArea {
+    /// Size of the XSAVE area (enough for all components up to Hi16_ZMM).
+    pub const SIZE: usize = 0xc00;
+    /// Offset of the saved value of RFLAGS.
+    pub const OFFSET_RFLAGS: i32 = Self::SIZE as i32;
+
+    pub fn new() -> Self { Self { data: [0; Self::SIZE], rflags: 0 } }
+    pub fn as_ptr(&self) -> *const Self { self as *const Self }
+}
+impl Default for XSaveArea {
+    fn default() -> Self { Self::new() }
+}
+
+/// Extended state captured after running measured code.
+///
+/// Components which were not captured (or which were in their initial
+/// configuration) are reported with their initial values.
+#[derive(Clone, Copy)]
+pub struct ExtState {
+    /// The set of captured state components (see [`XSaveLayout::rfbm`])
+    pub components: u64,
+    pub rflags: u64,
+    /// x87 control word
+    pub fcw: u16,
+    /// x87 status word
+    pub fsw: u16,
+    /// x87 tag word (in the abridged format used by FXSAVE)
+    pub ftw: u8,
+    /// x87 last opcode
+    pub fop: u16,
+    /// x87 registers ST0-ST7 (80-bit values)
+    pub st: [[u8; 10]; 8],
+    pub mxcsr: u32,
+    /// Vector registers (only the lower 256 bits of ZMM0-15 without
+    /// AVX-512, and only the lower 128 bits without AVX)
+    pub zmm: [[u64; 8]; 32],
+    /// AVX-512 opmask registers
+    pub k: [u64; 8],
+}
+impl ExtState {
+    /// Decode the contents of the XSAVE area.
+    pub fn from_xsave(layout: &XSaveLayout, area: &XSaveArea) -> Self {
+        let data = &area.data;
+        let u16_at = |off: usize| u16::from_le_bytes([data[off], data[off+1]]);
+        let u32_at = |off: usize| {
+            u32::from_le_bytes(data[off..off+4].try_into().unwrap())
+        };
+        let u64_at = |off: usize| {
+            u64::from_le_bytes(data[off..off+8].try_into().unwrap())
+        };
+
+        // Components which are not set in XSTATE_BV are in their initial
+        // configuration (and were not written by XSAVE).
+        let xstate_bv = u64_at(XSaveLayout::HEADER) & layout.rfbm;
+        let has = |c: u64| xstate_bv & c != 0;
+
+        let mut res = Self {
+            components: layout.rfbm,
+            rflags: area.rflags,
+            fcw: 0x037f,
+            fsw: 0,
+            ftw: 0,
+            fop: 0,
+            st: [[0; 10]; 8],
+            mxcsr: 0x1f80,
+            zmm: [[0; 8]; 32],
+            k: [0; 8],
+        };
+        if has(XSaveLayout::X87) {
+            res.fcw = u16_at(0);
+            res.fsw = u16_at(2);
+            res.ftw = data[4];
+            res.fop = u16_at(6);
+            for (idx, st) in res.st.iter_mut().enumerate() {
+                st.copy_from_slice(&data[32 + idx * 16..][..10]);
+            }
+        }
+        // MXCSR is always written when SSE or AVX state is requested
+        if layout.rfbm & (XSaveLayout::SSE | XSaveLayout::AVX) != 0 {
+            res.mxcsr = u32_at(24);
+        }
+        if has(XSaveLayout::SSE) {
+            for idx in 0..16 {
+                res.zmm[idx][0] = u64_at(160 + idx * 16);
+                res.zmm[idx][1] = u64_at(160 + idx * 16 + 8);
+            }
+        }
+        if has(XSaveLayout::AVX) {
+            for idx in 0..16 {
+                res.zmm[idx][2] = u64_at(layout.avx + idx * 16);
+                res.zmm[idx][3] = u64_at(layout.avx + idx * 16 + 8);
+            }
+        }
+        if has(XSaveLayout::OPMASK) {
+            for (idx, k) in res.k.iter_mut().enumerate() {
+                *k = u64_at(layout.opmask + idx * 8);
+            }
+        }
+        if has(XSaveLayout::ZMM_HI256) {
+            for idx in 0..16 {
+                for lane in 0..4 {
+                    res.zmm[idx][4 + lane] =
+                        u64_at(layout.zmm_hi256 + idx * 32 + lane * 8);
+                }
+            }
+        }
+        if has(XSaveLayout::HI16_ZMM) {
+            for idx in 0..16 {
+                for lane in 0..8 {
+                    res.zmm[16 + idx][lane] =
+                        u64_at(layout.hi16_zmm + idx * 64 + lane * 8);
+                }
+            }
+        }
+        res
+    }
+
+    pub fn xmm(&self, idx: usize) -> [u64; 2] {
+        [self.zmm[idx][0], self.zmm[idx][1]]
+    }
+    pub fn ymm(&self, idx: usize) -> [u64; 4] {
+        [self.zmm[idx][0], self.zmm[idx][1], self.zmm[idx][2], self.zmm[idx][3]]
+    }
+    pub fn zmm(&self, idx: usize) -> [u64; 8] { self.zmm[idx] }
+    pub fn k(&self, idx: usize) -> u64 { self.k[idx] }
+
+    /// Return the x87 register ST(idx).
+    pub fn st(&self, idx: usize) -> [u8; 10] { self.st[idx] }
+
+    /// Return the top-of-stack pointer from the x87 status word.
+    pub fn x87_top(&self) -> u8 { ((self.fsw >> 11) & 0b111) as u8 }
+}
+impl std::fmt::Debug for ExtState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let num_zmm = if self.components & XSaveLayout::HI16_ZMM != 0 {
+            32
+        } else {
+            16
+        };
+        f.debug_struct("ExtState")
+         .field("components", &format_args!("{:#x}", self.components))
+         .field("rflags", &format_args!("{:#x}", self.rflags))
+         .field("fcw", &format_args!("{:#06x}", self.fcw))
+         .field("fsw", &format_args!("{:#06x}", self.fsw))
+         .field("ftw", &format_args!("{:#04x}", self.ftw))
+         .field("mxcsr", &format_args!("{:#x}", self.mxcsr))
+         .field("st", &self.st)
+         .field("zmm", &&self.zmm[..num_zmm])
+         .field("k", &self.k)
+         .finish()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::asm::*;
+    use crate::harness::*;
+    use crate::events::{ EventDesc, MaskDesc };
+    use dynasmrt::{ dynasm, DynasmApi, AssemblyOffset };
+
+    #[test]
+    fn xstate_dump() {
+        let mut harness = HarnessConfig::default_test(0x133d)
+            .zero_strategy_fp(ZeroStrategyFp::Vzeroall)
+            .init_state(InitState::new().mxcsr(0x9f80))
+            .dump_xstate(true)
+            .emit();
+        let layout = harness.xsave_layout.unwrap();
+        let cpuid = HostCpuid::read();
+        let avx2 = cpuid.supports(CpuidFeature::AVX2);
+        let avx512 = layout.has_avx512();
+
+        let mut f = X64Assembler::new().unwrap();
+        dynasm!(f
+            ; fninit
+            ; fld1
+        );
+        if avx2 {
+            dynasm!(f ; vpcmpeqd ymm2, ymm2, ymm2);
+        }
+        if avx512 {
+            dynasm!(f
+                // vpternlogd zmm20, zmm20, zmm20, 0xff
+                ; .bytes [0x62, 0xa3, 0x5d, 0x40, 0x25, 0xe4, 0xff]
+                // kxnorw k3, k0, k0
+                ; .bytes [0xc5, 0xfc, 0x46, 0xd8]
+            );
+        }
+        dynasm!(f
+            ; stc
+            ; ret
+        );
+        f.commit().unwrap();
+        let buf = f.finalize().unwrap();
+        let func: MeasuredFn = unsafe {
+            std::mem::transmute(buf.ptr(AssemblyOffset(0)))
+        };
+
+        let event = EventDesc::new_unk(0x0c0, MaskDesc::new_unk(0x00));
+        let res = harness.measure_and_dump(func, &event, 4,
+            InputMethod::Fixed(0, 0)
+        ).unwrap();
+        unsafe { core::arch::asm!("fninit") };
+
+        for ext in res.xstate_dumps.unwrap().iter() {
+            assert_eq!(ext.rflags & 1, 1);
+            assert_eq!(ext.mxcsr, 0x9f80);
+            assert_eq!(ext.fcw, 0x037f);
+            assert_eq!(ext.x87_top(), 7);
+            assert_eq!(ext.ftw, 0x80);
+            assert_eq!(ext.st(0), [0, 0, 0, 0, 0, 0, 0, 0x80, 0xff, 0x3f]);
+            if avx2 {
+                assert_eq!(ext.ymm(2), [u64::MAX; 4]);
+            }
+            if avx512 {
+                assert_eq!(ext.zmm(20), [u64::MAX; 8]);
+                assert_eq!(ext.k(3), 0xffff);
+            }
+        }
+    }
+}
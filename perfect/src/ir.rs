@@ -1,5 +1,6 @@
 
 pub mod branch;
+pub mod parse;
 pub mod bf; 
 
 use crate::asm::*;
@@ -7,6 +8,7 @@ use crate::asm::*;
 use rand::prelude::*;
 use rand::Rng;
 use rand::distributions::{Distribution, Standard};
+use std::collections::BTreeMap;
 pub use dynasmrt::{
     dynasm, 
     DynasmApi, 
@@ -85,7 +87,7 @@ impl Distribution<IRMemOperand> for Standard {
                 IRMemOperand::BaseImm32(imm)
             },
             02 => {
-                let imm = rng.gen_range(8..=0x3f8) & 0b111;
+                let imm = rng.gen_range(8..=0x3f8) & !0b111;
                 IRMemOperand::MemImm32(imm)
             },
             _ => unreachable!(),
@@ -112,8 +114,167 @@ impl Distribution<IRAnyOperand> for Standard {
     }
 }
 
+
+/// A vector register operand (`ymm0`-`ymm3`).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum IRVecOperand {
+    Ymm0 = 0,
+    Ymm1 = 1,
+    Ymm2 = 2,
+    Ymm3 = 3,
+}
+impl IRVecOperand {
+    pub const ALL: [Self; 4] = [Self::Ymm0, Self::Ymm1, Self::Ymm2, Self::Ymm3];
+}
+impl Distribution<IRVecOperand> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> IRVecOperand {
+        *IRVecOperand::ALL.choose(rng).unwrap()
+    }
+}
+
+/// A label (the target of a branch).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct IRLabel(pub usize);
+
+/// A condition code (as used by Jcc, SETcc, and CMOVcc).
+///
+/// The discriminant is the 4-bit condition code used in the encoding.
+/// Parity is not modeled by [`PerfectVm`], so P/NP are omitted.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum IRCond {
+    O  = 0x0,
+    No = 0x1,
+    B  = 0x2,
+    Ae = 0x3,
+    E  = 0x4,
+    Ne = 0x5,
+    Be = 0x6,
+    A  = 0x7,
+    S  = 0x8,
+    Ns = 0x9,
+    L  = 0xc,
+    Ge = 0xd,
+    Le = 0xe,
+    G  = 0xf,
+}
+impl IRCond {
+    pub const ALL: [Self; 14] = [
+        Self::O, Self::No, Self::B, Self::Ae, Self::E, Self::Ne, Self::Be,
+        Self::A, Self::S, Self::Ns, Self::L, Self::Ge, Self::Le, Self::G,
+    ];
+
+    /// The set of flags read when evaluating this condition.
+    pub fn flags_read(&self) -> IRFlagSet {
+        match self {
+            Self::O | Self::No => IRFlagSet::OF,
+            Self::B | Self::Ae => IRFlagSet::CF,
+            Self::E | Self::Ne => IRFlagSet::ZF,
+            Self::Be | Self::A => IRFlagSet::CF.union(IRFlagSet::ZF),
+            Self::S | Self::Ns => IRFlagSet::SF,
+            Self::L | Self::Ge => IRFlagSet::SF.union(IRFlagSet::OF),
+            Self::Le | Self::G => IRFlagSet::ZF
+                .union(IRFlagSet::SF).union(IRFlagSet::OF),
+        }
+    }
+
+    /// Evaluate this condition. Returns [`None`] if any of the flags read
+    /// by this condition are undefined.
+    pub fn eval(&self, flags: &IRFlags) -> Option<bool> {
+        let (cf, zf, sf, of) = (flags.cf, flags.zf, flags.sf, flags.of);
+        let res = match self {
+            Self::O  => of?,
+            Self::No => !of?,
+            Self::B  => cf?,
+            Self::Ae => !cf?,
+            Self::E  => zf?,
+            Self::Ne => !zf?,
+            Self::Be => cf? || zf?,
+            Self::A  => !cf? && !zf?,
+            Self::S  => sf?,
+            Self::Ns => !sf?,
+            Self::L  => sf? != of?,
+            Self::Ge => sf? == of?,
+            Self::Le => zf? || (sf? != of?),
+            Self::G  => !zf? && (sf? == of?),
+        };
+        Some(res)
+    }
+}
+impl Distribution<IRCond> for Standard {
+    fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> IRCond {
+        *IRCond::ALL.choose(rng).unwrap()
+    }
+}
+
+/// A set of status flags (CF, ZF, SF, OF).
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub struct IRFlagSet(pub u8);
+impl IRFlagSet {
+    pub const NONE: Self = Self(0);
+    pub const CF: Self   = Self(1 << 0);
+    pub const ZF: Self   = Self(1 << 1);
+    pub const SF: Self   = Self(1 << 2);
+    pub const OF: Self   = Self(1 << 3);
+    pub const ALL: Self  = Self(0b1111);
+
+    pub const fn union(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+    pub const fn difference(self, other: Self) -> Self {
+        Self(self.0 & !other.0)
+    }
+    pub const fn contains(self, other: Self) -> bool {
+        (self.0 & other.0) == other.0
+    }
+}
+
+/// The state of the status flags in [`PerfectVm`].
+///
+/// A flag is [`None`] when the last instruction that wrote it left it
+/// architecturally undefined.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct IRFlags {
+    pub cf: Option<bool>,
+    pub zf: Option<bool>,
+    pub sf: Option<bool>,
+    pub of: Option<bool>,
+}
+impl IRFlags {
+    /// Set ZF and SF from some result.
+    fn set_zs(&mut self, res: u64, width: PerfectOpWidth) {
+        self.zf = Some(res == 0);
+        self.sf = Some(res & width.msb() != 0);
+    }
+}
+
 #[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
 pub enum PerfectOpWidth { Qword, Dword, Word }
+impl PerfectOpWidth {
+    pub fn bits(&self) -> u32 {
+        match self {
+            Self::Qword => 64,
+            Self::Dword => 32,
+            Self::Word => 16,
+        }
+    }
+    pub fn mask(&self) -> u64 {
+        match self {
+            Self::Qword => u64::MAX,
+            Self::Dword => 0xffff_ffff,
+            Self::Word => 0xffff,
+        }
+    }
+    pub fn msb(&self) -> u64 {
+        1 << (self.bits() - 1)
+    }
+    /// Sign-extend a value with this width.
+    pub fn sext(&self, val: u64) -> i64 {
+        let shift = 64 - self.bits();
+        ((val << shift) as i64) >> shift
+    }
+}
 impl Distribution<PerfectOpWidth> for Standard {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> PerfectOpWidth {
         let r = rng.gen_range(0..=2);
@@ -148,14 +309,67 @@ pub enum PerfectOp {
     Movzx32_16(IRRegOperand, IRRegOperand),
     Movzx32_8(IRRegOperand, IRRegOperand),
     Movzx16_8(IRRegOperand, IRRegOperand),
+
+    /// A branch target.
+    Label(IRLabel),
+    /// Conditional branch to a label.
+    Jcc(IRCond, IRLabel),
+    /// Unconditional branch to a label.
+    Jmp(IRLabel),
+
+    Cmp(IRRegOperand, IRRegOperand, PerfectOpWidth),
+    Adc(IRRegOperand, IRRegOperand, PerfectOpWidth),
+    /// Set the low byte of a register.
+    Setcc(IRCond, IRRegOperand),
+    Cmovcc(IRCond, IRRegOperand, IRRegOperand, PerfectOpWidth),
+
+    /// Shift left by an immediate count (in `1..width`).
+    Shl(IRRegOperand, u8, PerfectOpWidth),
+    /// Logical shift right by an immediate count (in `1..width`).
+    Shr(IRRegOperand, u8, PerfectOpWidth),
+    /// Arithmetic shift right by an immediate count (in `1..width`).
+    Sar(IRRegOperand, u8, PerfectOpWidth),
+
+    /// Two-operand signed multiply.
+    Imul(IRRegOperand, IRRegOperand, PerfectOpWidth),
+    /// Unsigned multiply of RAX, writing the result to RDX:RAX.
+    Mul(IRRegOperand, PerfectOpWidth),
+    /// Unsigned divide of RDX:RAX.
+    ///
+    /// This is emitted as `xor edx, edx; or src, 1; div src` so that
+    /// the divide never faults. The source register cannot be RDX.
+    Div(IRRegOperand, PerfectOpWidth),
+
+    /// `vmovq xmm, r64` (zeroes the rest of the vector register)
+    VMovqFromGpr(IRVecOperand, IRRegOperand),
+    /// `vmovq r64, xmm`
+    VMovqToGpr(IRRegOperand, IRVecOperand),
+    Vpaddq(IRVecOperand, IRVecOperand, IRVecOperand),
+    Vpxor(IRVecOperand, IRVecOperand, IRVecOperand),
+    Vpand(IRVecOperand, IRVecOperand, IRVecOperand),
+    /// Legacy SSE `paddq` (preserves the upper half of the YMM register)
+    Paddq(IRVecOperand, IRVecOperand),
+    /// Legacy SSE `pxor` (preserves the upper half of the YMM register)
+    Pxor(IRVecOperand, IRVecOperand),
 }
+
+/// Sample a random [`PerfectOp`].
+///
+/// This never produces labels or branches: see [`PerfectProg::gen`].
+/// Operations that consume flags may be sampled in a context where the
+/// flags are undefined; [`PerfectProg::fix_flags`] deals with this.
 impl Distribution<PerfectOp> for Standard {
     fn sample<R: Rng + ?Sized>(&self, rng: &mut R) -> PerfectOp {
-        let r = rng.gen_range(0..=8);
+        let r = rng.gen_range(0..=31);
         let dst_reg: IRRegOperand = rng.gen();
         let src_reg: IRRegOperand = rng.gen();
         let mem: IRMemOperand = rng.gen();
         let width: PerfectOpWidth = rng.gen();
+        let imm: IRImmOperand = rng.gen();
+        let cond: IRCond = rng.gen();
+        let count = rng.gen_range(1..width.bits()) as u8;
+        let (vdst, vsrc1, vsrc2): (IRVecOperand, IRVecOperand, IRVecOperand)
+            = (rng.gen(), rng.gen(), rng.gen());
         match r {
             00 => PerfectOp::Nop,
             01 => PerfectOp::Mov(dst_reg, src_reg, width),
@@ -171,18 +385,97 @@ impl Distribution<PerfectOp> for Standard {
             11 => PerfectOp::Movzx32_16(dst_reg, src_reg),
             12 => PerfectOp::Movzx32_8(dst_reg, src_reg),
             13 => PerfectOp::Movzx16_8(dst_reg, src_reg),
+            14 => PerfectOp::MovImm(dst_reg, imm, width),
+            15 => PerfectOp::Cmp(dst_reg, src_reg, width),
+            16 => PerfectOp::Adc(dst_reg, src_reg, width),
+            17 => PerfectOp::Setcc(cond, dst_reg),
+            18 => PerfectOp::Cmovcc(cond, dst_reg, src_reg, width),
+            19 => PerfectOp::Shl(dst_reg, count, width),
+            20 => PerfectOp::Shr(dst_reg, count, width),
+            21 => PerfectOp::Sar(dst_reg, count, width),
+            22 => PerfectOp::Imul(dst_reg, src_reg, width),
+            23 => PerfectOp::Mul(src_reg, width),
+            24 => {
+                let src = if src_reg == IRRegOperand::Rdx {
+                    IRRegOperand::Rbx
+                } else {
+                    src_reg
+                };
+                PerfectOp::Div(src, width)
+            },
+            25 => PerfectOp::VMovqFromGpr(vdst, src_reg),
+            26 => PerfectOp::VMovqToGpr(dst_reg, vsrc1),
+            27 => PerfectOp::Vpaddq(vdst, vsrc1, vsrc2),
+            28 => PerfectOp::Vpxor(vdst, vsrc1, vsrc2),
+            29 => PerfectOp::Vpand(vdst, vsrc1, vsrc2),
+            30 => PerfectOp::Paddq(vdst, vsrc1),
+            31 => PerfectOp::Pxor(vdst, vsrc1),
 
             _ => unreachable!(),
         }
     }
 }
 
+impl PerfectOp {
+    /// Register holding the base address for [`IRMemOperand::Base`] and
+    /// [`IRMemOperand::BaseImm32`] (see [`PerfectProg::emit_arena_ptr`]).
+    pub const ARENA_REG: Gpr = Gpr::R10;
 
+    /// The set of flags read by this operation.
+    pub fn flags_read(&self) -> IRFlagSet {
+        match self {
+            Self::Jcc(cond, _) |
+            Self::Setcc(cond, _) |
+            Self::Cmovcc(cond, _, _, _) => cond.flags_read(),
+            Self::Adc(_, _, _) => IRFlagSet::CF,
+            _ => IRFlagSet::NONE,
+        }
+    }
+
+    /// Given the set of flags which are defined before this operation,
+    /// return the set of flags which are defined afterwards.
+    ///
+    /// Since we don't know which path reaches a label, all flags are
+    /// conservatively treated as undefined after a label.
+    pub fn flags_defined(&self, defined: IRFlagSet) -> IRFlagSet {
+        match self {
+            Self::Label(_) => IRFlagSet::NONE,
+
+            Self::Add(_, _, _) |
+            Self::Xor(_, _, _) |
+            Self::ZeroIdiom(_, _) |
+            Self::Cmp(_, _, _) |
+            Self::Adc(_, _, _) => IRFlagSet::ALL,
+
+            Self::Shl(_, count, _) |
+            Self::Shr(_, count, _) |
+            Self::Sar(_, count, _) => {
+                if *count == 1 {
+                    IRFlagSet::ALL
+                } else {
+                    IRFlagSet::ALL.difference(IRFlagSet::OF)
+                }
+            },
+
+            Self::Imul(_, _, _) |
+            Self::Mul(_, _) => IRFlagSet::CF.union(IRFlagSet::OF),
+
+            Self::Div(_, _) => IRFlagSet::NONE,
+
+            _ => defined,
+        }
+    }
+
+    /// Emit this operation.
+    ///
+    /// `labels` maps each [`IRLabel`] to a [`DynamicLabel`] in the
+    /// assembler (see [`PerfectProg::emit`]).
+    pub fn emit(&self, f: &mut X64Assembler, labels: &[DynamicLabel]) {
+        match self {
+            Self::Nop => {
+                dynasm!(f ; nop);
+            },
 
-impl PerfectOp {
-    const ARENA_REG: Gpr = Gpr::R10;
-    pub fn emit(&self, f: &mut X64Assembler) {
-        match self { 
             Self::MovImm(dst, imm, width) => { match (imm, width) {
                 (IRImmOperand::Imm32(val), PerfectOpWidth::Qword) => {
                     dynasm!(f ; mov Rq(*dst as u8), QWORD *val as i64);
@@ -338,29 +631,496 @@ impl PerfectOp {
                 dynasm!(f ; movzx Rw(*dst as u8), Rb(*src as u8));
             },
 
-            _ => todo!(),
+            Self::Label(lbl) => {
+                dynasm!(f ; =>labels[lbl.0]);
+            },
+            Self::Jmp(lbl) => {
+                dynasm!(f ; jmp =>labels[lbl.0]);
+            },
+            Self::Jcc(cond, lbl) => {
+                let tgt = labels[lbl.0];
+                match cond {
+                    IRCond::O  => dynasm!(f ; jo  =>tgt),
+                    IRCond::No => dynasm!(f ; jno =>tgt),
+                    IRCond::B  => dynasm!(f ; jb  =>tgt),
+                    IRCond::Ae => dynasm!(f ; jae =>tgt),
+                    IRCond::E  => dynasm!(f ; je  =>tgt),
+                    IRCond::Ne => dynasm!(f ; jne =>tgt),
+                    IRCond::Be => dynasm!(f ; jbe =>tgt),
+                    IRCond::A  => dynasm!(f ; ja  =>tgt),
+                    IRCond::S  => dynasm!(f ; js  =>tgt),
+                    IRCond::Ns => dynasm!(f ; jns =>tgt),
+                    IRCond::L  => dynasm!(f ; jl  =>tgt),
+                    IRCond::Ge => dynasm!(f ; jge =>tgt),
+                    IRCond::Le => dynasm!(f ; jle =>tgt),
+                    IRCond::G  => dynasm!(f ; jg  =>tgt),
+                }
+            },
+
+            Self::Cmp(dst, src, width) => { match width {
+                PerfectOpWidth::Qword => {
+                    dynasm!(f ; cmp Rq(*dst as u8), Rq(*src as u8));
+                },
+                PerfectOpWidth::Dword => {
+                    dynasm!(f ; cmp Rd(*dst as u8), Rd(*src as u8));
+                },
+                PerfectOpWidth::Word => {
+                    dynasm!(f ; cmp Rw(*dst as u8), Rw(*src as u8));
+                },
+            }},
+
+            Self::Adc(dst, src, width) => { match width {
+                PerfectOpWidth::Qword => {
+                    dynasm!(f ; adc Rq(*dst as u8), Rq(*src as u8));
+                },
+                PerfectOpWidth::Dword => {
+                    dynasm!(f ; adc Rd(*dst as u8), Rd(*src as u8));
+                },
+                PerfectOpWidth::Word => {
+                    dynasm!(f ; adc Rw(*dst as u8), Rw(*src as u8));
+                },
+            }},
+
+            // NOTE: There are too many SETcc/CMOVcc variants to spell out
+            // with dynasm, so these are encoded by hand. Our registers are
+            // always RAX-RBX, which never need a REX.R/REX.B prefix.
+            Self::Setcc(cond, dst) => {
+                let modrm = 0xc0 | (*dst as u8);
+                f.extend([0x0f, 0x90 | *cond as u8, modrm]);
+            },
+            Self::Cmovcc(cond, dst, src, width) => {
+                let op = [0x0f, 0x40 | *cond as u8];
+                let modrm = 0xc0 | ((*dst as u8) << 3) | (*src as u8);
+                match width {
+                    PerfectOpWidth::Qword => f.extend([0x48, op[0], op[1], modrm]),
+                    PerfectOpWidth::Dword => f.extend([op[0], op[1], modrm]),
+                    PerfectOpWidth::Word => f.extend([0x66, op[0], op[1], modrm]),
+                }
+            },
+
+            Self::Shl(dst, count, width) => {
+                let c = *count as i8;
+                match width {
+                    PerfectOpWidth::Qword => dynasm!(f ; shl Rq(*dst as u8), c),
+                    PerfectOpWidth::Dword => dynasm!(f ; shl Rd(*dst as u8), c),
+                    PerfectOpWidth::Word => dynasm!(f ; shl Rw(*dst as u8), c),
+                }
+            },
+            Self::Shr(dst, count, width) => {
+                let c = *count as i8;
+                match width {
+                    PerfectOpWidth::Qword => dynasm!(f ; shr Rq(*dst as u8), c),
+                    PerfectOpWidth::Dword => dynasm!(f ; shr Rd(*dst as u8), c),
+                    PerfectOpWidth::Word => dynasm!(f ; shr Rw(*dst as u8), c),
+                }
+            },
+            Self::Sar(dst, count, width) => {
+                let c = *count as i8;
+                match width {
+                    PerfectOpWidth::Qword => dynasm!(f ; sar Rq(*dst as u8), c),
+                    PerfectOpWidth::Dword => dynasm!(f ; sar Rd(*dst as u8), c),
+                    PerfectOpWidth::Word => dynasm!(f ; sar Rw(*dst as u8), c),
+                }
+            },
+
+            Self::Imul(dst, src, width) => { match width {
+                PerfectOpWidth::Qword => {
+                    dynasm!(f ; imul Rq(*dst as u8), Rq(*src as u8));
+                },
+                PerfectOpWidth::Dword => {
+                    dynasm!(f ; imul Rd(*dst as u8), Rd(*src as u8));
+                },
+                PerfectOpWidth::Word => {
+                    dynasm!(f ; imul Rw(*dst as u8), Rw(*src as u8));
+                },
+            }},
+            Self::Mul(src, width) => { match width {
+                PerfectOpWidth::Qword => dynasm!(f ; mul Rq(*src as u8)),
+                PerfectOpWidth::Dword => dynasm!(f ; mul Rd(*src as u8)),
+                PerfectOpWidth::Word => dynasm!(f ; mul Rw(*src as u8)),
+            }},
+            Self::Div(src, width) => {
+                assert!(*src != IRRegOperand::Rdx, "div source cannot be RDX");
+                dynasm!(f ; xor edx, edx);
+                match width {
+                    PerfectOpWidth::Qword => dynasm!(f
+                        ; or Rq(*src as u8), 1
+                        ; div Rq(*src as u8)
+                    ),
+                    PerfectOpWidth::Dword => dynasm!(f
+                        ; or Rd(*src as u8), 1
+                        ; div Rd(*src as u8)
+                    ),
+                    PerfectOpWidth::Word => dynasm!(f
+                        ; or Rw(*src as u8), 1
+                        ; div Rw(*src as u8)
+                    ),
+                }
+            },
+
+            Self::VMovqFromGpr(dst, src) => {
+                dynasm!(f ; vmovq Rx(*dst as u8), Rq(*src as u8));
+            },
+            Self::VMovqToGpr(dst, src) => {
+                dynasm!(f ; vmovq Rq(*dst as u8), Rx(*src as u8));
+            },
+            Self::Vpaddq(dst, a, b) => {
+                dynasm!(f ; vpaddq Ry(*dst as u8), Ry(*a as u8), Ry(*b as u8));
+            },
+            Self::Vpxor(dst, a, b) => {
+                dynasm!(f ; vpxor Ry(*dst as u8), Ry(*a as u8), Ry(*b as u8));
+            },
+            Self::Vpand(dst, a, b) => {
+                dynasm!(f ; vpand Ry(*dst as u8), Ry(*a as u8), Ry(*b as u8));
+            },
+            Self::Paddq(dst, src) => {
+                dynasm!(f ; paddq Rx(*dst as u8), Rx(*src as u8));
+            },
+            Self::Pxor(dst, src) => {
+                dynasm!(f ; pxor Rx(*dst as u8), Rx(*src as u8));
+            },
         }
     }
 }
 
+/// A simple interpreter for [`PerfectProg`].
 pub struct PerfectVm {
     pub gpr: [usize; 4],
+    pub vgpr: [[u64; 4]; 4],
+    pub flags: IRFlags,
+    /// Memory (bytes which have never been written are zero)
+    pub mem: BTreeMap<usize, u8>,
+    /// The value of [`PerfectOp::ARENA_REG`]
+    pub arena_base: usize,
 }
 impl PerfectVm {
     pub fn new(init: &[usize; 4]) -> Self {
-        Self { gpr: *init }
+        Self {
+            gpr: *init,
+            vgpr: [[0; 4]; 4],
+            flags: IRFlags::default(),
+            mem: BTreeMap::new(),
+            arena_base: 0,
+        }
+    }
+    pub fn arena_base(mut self, x: usize) -> Self {
+        self.arena_base = x;
+        self
     }
     pub fn clear(&mut self, init: &[usize; 4]) {
         self.gpr = *init;
+        self.vgpr = [[0; 4]; 4];
+        self.flags = IRFlags::default();
+        self.mem.clear();
     }
-    pub fn read_reg(&self, gpr: IRRegOperand) -> usize { 
+    pub fn read_reg(&self, gpr: IRRegOperand) -> usize {
         self.gpr[gpr as usize]
     }
     pub fn write_reg(&mut self, gpr: IRRegOperand, value: usize) {
         self.gpr[gpr as usize] = value;
     }
+
+    /// Read the low bits of a register.
+    pub fn read(&self, gpr: IRRegOperand, width: PerfectOpWidth) -> u64 {
+        self.gpr[gpr as usize] as u64 & width.mask()
+    }
+
+    /// Write the low bits of a register.
+    ///
+    /// Like the hardware, 32-bit writes zero-extend into the upper bits,
+    /// and 16-bit writes preserve the upper bits.
+    pub fn write(&mut self, gpr: IRRegOperand, width: PerfectOpWidth,
+        value: u64)
+    {
+        let old = self.gpr[gpr as usize] as u64;
+        let new = match width {
+            PerfectOpWidth::Qword => value,
+            PerfectOpWidth::Dword => value & 0xffff_ffff,
+            PerfectOpWidth::Word => (old & !0xffff) | (value & 0xffff),
+        };
+        self.gpr[gpr as usize] = new as usize;
+    }
+
+    fn addr(&self, mem: IRMemOperand) -> usize {
+        match mem {
+            IRMemOperand::Base => self.arena_base,
+            IRMemOperand::BaseImm32(disp) => {
+                self.arena_base.wrapping_add(disp as isize as usize)
+            },
+            IRMemOperand::MemImm32(disp) => disp as isize as usize,
+        }
+    }
+    pub fn read_mem(&self, addr: usize, width: PerfectOpWidth) -> u64 {
+        let mut res = 0;
+        for i in 0..(width.bits() / 8) as usize {
+            let byte = *self.mem.get(&(addr + i)).unwrap_or(&0) as u64;
+            res |= byte << (i * 8);
+        }
+        res
+    }
+    pub fn write_mem(&mut self, addr: usize, width: PerfectOpWidth, value: u64) {
+        for i in 0..(width.bits() / 8) as usize {
+            self.mem.insert(addr + i, (value >> (i * 8)) as u8);
+        }
+    }
+
+    fn cond(&self, cond: IRCond) -> bool {
+        cond.eval(&self.flags).unwrap_or_else(|| {
+            panic!("{:?} depends on undefined flags {:?}", cond, self.flags)
+        })
+    }
+
+    fn add(&mut self, a: u64, b: u64, carry: bool, width: PerfectOpWidth)
+        -> u64
+    {
+        let sum = a as u128 + b as u128 + carry as u128;
+        let res = sum as u64 & width.mask();
+        self.flags.cf = Some(sum > width.mask() as u128);
+        self.flags.of = Some((a ^ res) & (b ^ res) & width.msb() != 0);
+        self.flags.set_zs(res, width);
+        res
+    }
+
+    fn sub(&mut self, a: u64, b: u64, width: PerfectOpWidth) -> u64 {
+        let res = a.wrapping_sub(b) & width.mask();
+        self.flags.cf = Some(a < b);
+        self.flags.of = Some((a ^ b) & (a ^ res) & width.msb() != 0);
+        self.flags.set_zs(res, width);
+        res
+    }
+
+    fn logic(&mut self, res: u64, width: PerfectOpWidth) -> u64 {
+        self.flags.cf = Some(false);
+        self.flags.of = Some(false);
+        self.flags.set_zs(res, width);
+        res
+    }
+
+    /// Evaluate a single operation.
+    /// Returns the target label when a branch is taken.
+    pub fn step(&mut self, op: &PerfectOp) -> Option<IRLabel> {
+        use PerfectOpWidth::*;
+        match *op {
+            PerfectOp::Nop | PerfectOp::Label(_) => {},
+
+            PerfectOp::MovImm(dst, imm, width) => {
+                let val = match (imm, width) {
+                    (_, Word) => match imm {
+                        IRImmOperand::Imm32(v) => v as u64 & 0x7fff,
+                        IRImmOperand::Imm64(v) => v as u64 & 0x7fff,
+                    },
+                    (IRImmOperand::Imm32(v), _) => v as i64 as u64,
+                    (IRImmOperand::Imm64(v), _) => v as u64,
+                };
+                self.write(dst, width, val);
+            },
+            PerfectOp::Mov(dst, src, width) => {
+                let val = self.read(src, width);
+                self.write(dst, width, val);
+            },
+            PerfectOp::Add(dst, src, width) => {
+                let (a, b) = (self.read(dst, width), self.read(src, width));
+                let res = self.add(a, b, false, width);
+                self.write(dst, width, res);
+            },
+            PerfectOp::Adc(dst, src, width) => {
+                let carry = self.cond(IRCond::B);
+                let (a, b) = (self.read(dst, width), self.read(src, width));
+                let res = self.add(a, b, carry, width);
+                self.write(dst, width, res);
+            },
+            PerfectOp::Xor(dst, src, width) => {
+                let (a, b) = (self.read(dst, width), self.read(src, width));
+                let res = self.logic(a ^ b, width);
+                self.write(dst, width, res);
+            },
+            PerfectOp::ZeroIdiom(dst, width) => {
+                let res = self.logic(0, width);
+                self.write(dst, width, res);
+            },
+            PerfectOp::Cmp(dst, src, width) => {
+                let (a, b) = (self.read(dst, width), self.read(src, width));
+                self.sub(a, b, width);
+            },
+
+            PerfectOp::Xchg64(dst, src) => {
+                self.gpr.swap(dst as usize, src as usize);
+            },
+            PerfectOp::Xchg32(dst, src) => {
+                let (a, b) = (self.read(dst, Dword), self.read(src, Dword));
+                self.write(dst, Dword, b);
+                self.write(src, Dword, a);
+            },
+
+            PerfectOp::Load(dst, mem, width) => {
+                let val = self.read_mem(self.addr(mem), width);
+                self.write(dst, width, val);
+            },
+            PerfectOp::Store(mem, src, width) => {
+                let val = self.read(src, width);
+                self.write_mem(self.addr(mem), width, val);
+            },
+
+            PerfectOp::Movzx64_16(dst, src) |
+            PerfectOp::Movzx32_16(dst, src) => {
+                let val = self.read(src, Word);
+                self.write(dst, Qword, val);
+            },
+            PerfectOp::Movzx64_8(dst, src) |
+            PerfectOp::Movzx32_8(dst, src) => {
+                let val = self.read(src, Qword) & 0xff;
+                self.write(dst, Qword, val);
+            },
+            PerfectOp::Movzx16_8(dst, src) => {
+                let val = self.read(src, Qword) & 0xff;
+                self.write(dst, Word, val);
+            },
+
+            PerfectOp::Jmp(lbl) => return Some(lbl),
+            PerfectOp::Jcc(cond, lbl) => {
+                if self.cond(cond) {
+                    return Some(lbl);
+                }
+            },
+            PerfectOp::Setcc(cond, dst) => {
+                let old = self.read(dst, Qword);
+                let val = (old & !0xff) | self.cond(cond) as u64;
+                self.write(dst, Qword, val);
+            },
+            PerfectOp::Cmovcc(cond, dst, src, width) => {
+                // NOTE: A 32-bit CMOVcc always zero-extends the destination,
+                // even when the condition is false.
+                let val = if self.cond(cond) {
+                    self.read(src, width)
+                } else {
+                    self.read(dst, width)
+                };
+                self.write(dst, width, val);
+            },
+
+            PerfectOp::Shl(dst, count, width) => {
+                let (a, c) = (self.read(dst, width), count as u32);
+                let res = (a << c) & width.mask();
+                let cf = (a >> (width.bits() - c)) & 1 != 0;
+                self.flags.cf = Some(cf);
+                self.flags.of = if c == 1 {
+                    Some((res & width.msb() != 0) != cf)
+                } else {
+                    None
+                };
+                self.flags.set_zs(res, width);
+                self.write(dst, width, res);
+            },
+            PerfectOp::Shr(dst, count, width) => {
+                let (a, c) = (self.read(dst, width), count as u32);
+                let res = a >> c;
+                self.flags.cf = Some((a >> (c - 1)) & 1 != 0);
+                self.flags.of = if c == 1 {
+                    Some(a & width.msb() != 0)
+                } else {
+                    None
+                };
+                self.flags.set_zs(res, width);
+                self.write(dst, width, res);
+            },
+            PerfectOp::Sar(dst, count, width) => {
+                let (a, c) = (width.sext(self.read(dst, width)), count as u32);
+                let res = (a >> c) as u64 & width.mask();
+                self.flags.cf = Some((a >> (c - 1)) & 1 != 0);
+                self.flags.of = if c == 1 { Some(false) } else { None };
+                self.flags.set_zs(res, width);
+                self.write(dst, width, res);
+            },
+
+            PerfectOp::Imul(dst, src, width) => {
+                let a = width.sext(self.read(dst, width)) as i128;
+                let b = width.sext(self.read(src, width)) as i128;
+                let prod = a * b;
+                let res = prod as u64 & width.mask();
+                let overflow = width.sext(res) as i128 != prod;
+                self.flags = IRFlags {
+                    cf: Some(overflow), of: Some(overflow), zf: None, sf: None
+                };
+                self.write(dst, width, res);
+            },
+            PerfectOp::Mul(src, width) => {
+                let a = self.read(IRRegOperand::Rax, width) as u128;
+                let b = self.read(src, width) as u128;
+                let prod = a * b;
+                let lo = prod as u64 & width.mask();
+                let hi = (prod >> width.bits()) as u64 & width.mask();
+                let overflow = hi != 0;
+                self.flags = IRFlags {
+                    cf: Some(overflow), of: Some(overflow), zf: None, sf: None
+                };
+                self.write(IRRegOperand::Rax, width, lo);
+                self.write(IRRegOperand::Rdx, width, hi);
+            },
+            PerfectOp::Div(src, width) => {
+                assert!(src != IRRegOperand::Rdx, "div source cannot be RDX");
+                self.write(IRRegOperand::Rdx, Dword, 0);
+                let divisor = self.read(src, width) | 1;
+                self.write(src, width, divisor);
+                let dividend = self.read(IRRegOperand::Rax, width);
+                self.write(IRRegOperand::Rax, width, dividend / divisor);
+                self.write(IRRegOperand::Rdx, width, dividend % divisor);
+                self.flags = IRFlags::default();
+            },
+
+            PerfectOp::VMovqFromGpr(dst, src) => {
+                self.vgpr[dst as usize] = [self.read(src, Qword), 0, 0, 0];
+            },
+            PerfectOp::VMovqToGpr(dst, src) => {
+                let val = self.vgpr[src as usize][0];
+                self.write(dst, Qword, val);
+            },
+            PerfectOp::Vpaddq(dst, a, b) => {
+                let (a, b) = (self.vgpr[a as usize], self.vgpr[b as usize]);
+                for i in 0..4 {
+                    self.vgpr[dst as usize][i] = a[i].wrapping_add(b[i]);
+                }
+            },
+            PerfectOp::Vpxor(dst, a, b) => {
+                let (a, b) = (self.vgpr[a as usize], self.vgpr[b as usize]);
+                for i in 0..4 {
+                    self.vgpr[dst as usize][i] = a[i] ^ b[i];
+                }
+            },
+            PerfectOp::Vpand(dst, a, b) => {
+                let (a, b) = (self.vgpr[a as usize], self.vgpr[b as usize]);
+                for i in 0..4 {
+                    self.vgpr[dst as usize][i] = a[i] & b[i];
+                }
+            },
+            PerfectOp::Paddq(dst, src) => {
+                let (a, b) = (self.vgpr[dst as usize], self.vgpr[src as usize]);
+                for i in 0..2 {
+                    self.vgpr[dst as usize][i] = a[i].wrapping_add(b[i]);
+                }
+            },
+            PerfectOp::Pxor(dst, src) => {
+                let (a, b) = (self.vgpr[dst as usize], self.vgpr[src as usize]);
+                for i in 0..2 {
+                    self.vgpr[dst as usize][i] = a[i] ^ b[i];
+                }
+            },
+        }
+        None
+    }
+
     pub fn evaluate_program(&mut self, prog: &PerfectProg) {
-        for op in prog.data.iter() {
+        let targets: BTreeMap<IRLabel, usize> = prog.data.iter().enumerate()
+            .filter_map(|(idx, op)| match op {
+                PerfectOp::Label(lbl) => Some((*lbl, idx)),
+                _ => None,
+            }).collect();
+
+        let mut pc = 0;
+        while pc < prog.len() {
+            pc = match self.step(&prog.data[pc]) {
+                Some(lbl) => targets[&lbl],
+                None => pc + 1,
+            };
         }
     }
 }
@@ -371,17 +1131,85 @@ pub struct PerfectProg {
 }
 impl PerfectProg {
     pub fn len(&self) -> usize { self.data.len() }
-    pub fn gen(len: usize) -> Self { 
+
+    /// Generate a random program with `len` operations (not including
+    /// branches/labels or any comparisons inserted by
+    /// [`PerfectProg::fix_flags`]).
+    pub fn gen(rng: &mut impl Rng, len: usize) -> Self {
+        Self::gen_filtered(rng, len, |_| true)
+    }
+
+    /// Like [`PerfectProg::gen`], but only using operations accepted by
+    /// the provided filter.
+    pub fn gen_filtered(rng: &mut impl Rng, len: usize,
+        filter: impl Fn(&PerfectOp) -> bool) -> Self
+    {
         let mut data = Vec::new();
-        for _ in 0..len {
-            data.push(thread_rng().gen());
+        while data.len() < len {
+            let op: PerfectOp = rng.gen();
+            if filter(&op) {
+                data.push(op);
+            }
+        }
+
+        // Insert some forward branches. The label is inserted first, so
+        // the branch always ends up before its target.
+        for id in 0..(len / 8) {
+            let lbl = IRLabel(id);
+            let src = rng.gen_range(0..=data.len());
+            let tgt = rng.gen_range(src..=data.len());
+            data.insert(tgt, PerfectOp::Label(lbl));
+            if rng.gen_bool(0.25) {
+                data.insert(src, PerfectOp::Jmp(lbl));
+            } else {
+                data.insert(src, PerfectOp::Jcc(rng.gen(), lbl));
+            }
+        }
+
+        let mut res = Self { data };
+        res.fix_flags(rng);
+        res
+    }
+
+    /// Insert a random comparison before any operation that would
+    /// otherwise consume undefined flags.
+    pub fn fix_flags(&mut self, rng: &mut impl Rng) {
+        let mut data = Vec::with_capacity(self.data.len());
+        let mut defined = IRFlagSet::NONE;
+        for op in self.data.drain(..) {
+            if !defined.contains(op.flags_read()) {
+                let cmp = PerfectOp::Cmp(rng.gen(), rng.gen(), rng.gen());
+                defined = cmp.flags_defined(defined);
+                data.push(cmp);
+            }
+            defined = op.flags_defined(defined);
+            data.push(op);
         }
-        Self { data }
+        self.data = data;
+    }
+
+    /// The number of labels used by this program.
+    pub fn num_labels(&self) -> usize {
+        self.data.iter().filter_map(|op| match op {
+            PerfectOp::Label(lbl) |
+            PerfectOp::Jmp(lbl) |
+            PerfectOp::Jcc(_, lbl) => Some(lbl.0 + 1),
+            _ => None,
+        }).max().unwrap_or(0)
+    }
+
+    /// Emit code which points [`PerfectOp::ARENA_REG`] at `arena` (which
+    /// must be called before the program itself is emitted).
+    pub fn emit_arena_ptr(f: &mut X64Assembler, arena: usize) {
+        dynasm!(f ; mov Rq(PerfectOp::ARENA_REG as u8), QWORD arena as i64);
     }
 
     pub fn emit(&self, f: &mut X64Assembler) {
+        let labels: Vec<DynamicLabel> = (0..self.num_labels())
+            .map(|_| f.new_dynamic_label())
+            .collect();
         for irop in &self.data {
-            irop.emit(f);
+            irop.emit(f, &labels);
         }
     }
 
@@ -391,8 +1219,77 @@ impl PerfectProg {
 }
 
 
-pub struct PerfectIR; 
+pub struct PerfectIR;
 impl PerfectIR {
 }
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::harness::*;
+    use crate::util::rng::*;
+
+    #[test]
+    fn ir_flags_fixed() {
+        let prog = PerfectProg::gen(&mut perfect_rng(), 256);
+        let mut defined = IRFlagSet::NONE;
+        for op in prog.data.iter() {
+            assert!(defined.contains(op.flags_read()), "{:?}", op);
+            defined = op.flags_defined(defined);
+        }
+    }
+
+    /// Run random programs with the harness and compare the resulting
+    /// architectural state with [`PerfectVm`].
+    #[test]
+    fn ir_vm_differential() {
+        if !is_x86_feature_detected!("avx2") {
+            return;
+        }
+        let layout = ArenaLayout::new(0x0000_0020_0000_0000)
+            .buffer(ArenaBufferDesc::new("ir", 0x2000));
+        let mut harness = HarnessConfig::default_test(0x1338)
+            .arena(layout)
+            .zero_strategy_fp(ZeroStrategyFp::Vzeroall)
+            .dump_gpr(true)
+            .dump_vgpr(true)
+            .emit();
+
+        let mut rng = perfect_rng();
+        for _ in 0..256 {
+            // The harness arena is the only memory we can use
+            let prog = PerfectProg::gen_filtered(&mut rng, 64, |op| !matches!(op,
+                PerfectOp::Load(_, IRMemOperand::MemImm32(_), _) |
+                PerfectOp::Store(IRMemOperand::MemImm32(_), _, _)
+            ));
+            let init: [usize; 4] = rng.gen();
 
+            let mut f = X64Assembler::new().unwrap();
+            let arena_base = harness.arena_addr("ir");
+            PerfectProg::emit_arena_ptr(&mut f, arena_base);
+            for (idx, val) in init.iter().enumerate() {
+                dynasm!(f ; mov Rq(idx as u8), QWORD *val as i64);
+            }
+            prog.emit(&mut f);
+            f.emit_ret();
+            f.commit().unwrap();
+            let buf = f.finalize().unwrap();
+            let func: MeasuredFn = unsafe {
+                std::mem::transmute(buf.ptr(AssemblyOffset(0)))
+            };
+
+            harness.arena.as_mut().unwrap().slice_mut("ir").fill(0);
+            harness.call(0, 0, func);
+            let arena = harness.arena.as_ref().unwrap().slice("ir");
+
+            let mut vm = PerfectVm::new(&init).arena_base(arena_base);
+            prog.apply_to_vm(&mut vm);
+
+            assert_eq!(&harness.gpr_state.0[0..4], &vm.gpr, "{:#x?}", prog);
+            assert_eq!(&harness.vgpr_state.0[0..4], &vm.vgpr, "{:#x?}", prog);
+            for (addr, byte) in vm.mem.iter() {
+                assert_eq!(arena[addr - arena_base], *byte, "{:#x?}", prog);
+            }
+        }
+    }
+}
@@ -1,3 +1,8 @@
+pub mod func;
+pub mod solve;
+pub use func::*;
+pub use solve::*;
+
 
 /// Interpreter for simple boolean functions. 
 ///
@@ -31,6 +36,19 @@ impl BfOp {
         //println!("{:?} := {}", self, res);
         res
     }
+
+    /// Return the indexes of all input bits used by this operation.
+    pub fn inputs(&self) -> Vec<usize> {
+        let mut res = match self {
+            Self::In(n) => vec![*n],
+            Self::And(ops) | Self::Xor(ops) => {
+                ops.iter().flat_map(|op| op.inputs()).collect()
+            },
+        };
+        res.sort_unstable();
+        res.dedup();
+        res
+    }
 }
 
 /// Describes a simple boolean function. 
@@ -47,6 +65,11 @@ impl <const SZ: usize> BfProg<SZ> {
         }
         res & mask
     }
+
+    /// Return the indexes of the input bits used by each output bit.
+    pub fn inputs(&self) -> [Vec<usize>; SZ] {
+        std::array::from_fn(|idx| self.0[idx].inputs())
+    }
 }
 
 #[cfg(test)]
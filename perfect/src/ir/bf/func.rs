@@ -0,0 +1,468 @@
+//! An owned representation of [`BfProg`].
+//!
+//! [`BfFunc`] can be built at runtime (ie. by [`BfSolver`]), and has a
+//! plain-text format with one output bit per line:
+//!
+//! ```text
+//! out[0] = in[36] ^ in[24]
+//! out[3] = in[39] ^ in[27] ^ (in[15] & in[10])
+//! ```
+//!
+//! As in C, `&` binds more tightly than `^`. Empty lines and lines starting
+//! with `#` are ignored.
+
+use crate::ir::bf::*;
+use std::collections::BTreeSet;
+use std::str::FromStr;
+
+/// An owned version of [`BfOp`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub enum BfExpr {
+    /// Read the value of an input bit
+    In(usize),
+    /// N-ary logical AND
+    And(Vec<Self>),
+    /// N-ary logical XOR
+    Xor(Vec<Self>),
+}
+impl BfExpr {
+    pub fn evaluate(&self, input: usize) -> bool {
+        match self {
+            Self::In(n) => (input & (1 << n)) != 0,
+            Self::And(ops) => ops.iter().all(|op| op.evaluate(input)),
+            Self::Xor(ops) => ops.iter()
+                .fold(false, |res, op| res ^ op.evaluate(input)),
+        }
+    }
+
+    /// Return the indexes of all input bits used by this expression.
+    pub fn inputs(&self) -> Vec<usize> {
+        let mut res = match self {
+            Self::In(n) => vec![*n],
+            Self::And(ops) | Self::Xor(ops) => {
+                ops.iter().flat_map(|op| op.inputs()).collect()
+            },
+        };
+        res.sort_unstable();
+        res.dedup();
+        res
+    }
+
+    /// Return the algebraic normal form of this expression.
+    ///
+    /// Each element is a monomial (the AND of the input bits in the mask),
+    /// and the expression is the XOR of all monomials. The empty mask is
+    /// the constant 1. Two expressions are equivalent exactly when their
+    /// normal forms are equal.
+    pub fn anf(&self) -> BTreeSet<usize> {
+        match self {
+            Self::In(n) => BTreeSet::from([1 << n]),
+            Self::Xor(ops) => ops.iter().fold(BTreeSet::new(), |res, op| {
+                &res ^ &op.anf()
+            }),
+            Self::And(ops) => ops.iter().fold(BTreeSet::from([0]), |res, op| {
+                let mut prod = BTreeSet::new();
+                for x in res.iter() {
+                    for y in op.anf() {
+                        if !prod.insert(x | y) {
+                            prod.remove(&(x | y));
+                        }
+                    }
+                }
+                prod
+            }),
+        }
+    }
+}
+impl From<&BfOp> for BfExpr {
+    fn from(op: &BfOp) -> Self {
+        match op {
+            BfOp::In(n) => Self::In(*n),
+            BfOp::And(ops) => Self::And(ops.iter().map(Self::from).collect()),
+            BfOp::Xor(ops) => Self::Xor(ops.iter().map(Self::from).collect()),
+        }
+    }
+}
+impl std::fmt::Display for BfExpr {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (ops, sep) = match self {
+            Self::In(n) => return write!(f, "in[{}]", n),
+            Self::And(ops) => (ops, " & "),
+            Self::Xor(ops) => (ops, " ^ "),
+        };
+        for (idx, op) in ops.iter().enumerate() {
+            if idx != 0 {
+                write!(f, "{}", sep)?;
+            }
+            match op {
+                Self::In(_) => write!(f, "{}", op)?,
+                _ => write!(f, "({})", op)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Describes a boolean function (see [`BfProg`]).
+///
+/// Each N-th [`BfExpr`] computes the value of output bit 'N'.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct BfFunc(pub Vec<BfExpr>);
+impl BfFunc {
+    pub fn len(&self) -> usize { self.0.len() }
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
+
+    pub fn evaluate(&self, input: usize) -> usize {
+        self.0.iter().enumerate().fold(0, |res, (idx, op)| {
+            res | ((op.evaluate(input) as usize) << idx)
+        })
+    }
+
+    /// Return the indexes of all input bits used by the function.
+    pub fn inputs(&self) -> Vec<usize> {
+        let mut res: Vec<usize> = self.0.iter().flat_map(|op| op.inputs())
+            .collect();
+        res.sort_unstable();
+        res.dedup();
+        res
+    }
+
+    /// Find an input where the two functions disagree (if any).
+    ///
+    /// Returns the output bit and the input. Output bits missing from one
+    /// of the functions are treated as constant 0.
+    pub fn counterexample(&self, other: &Self) -> Option<(usize, usize)> {
+        let empty = BfExpr::Xor(Vec::new());
+        (0..self.len().max(other.len())).find_map(|idx| {
+            let x = self.0.get(idx).unwrap_or(&empty).anf();
+            let y = other.0.get(idx).unwrap_or(&empty).anf();
+
+            // The smallest monomial in the difference is the only one
+            // which is set when exactly its own inputs are set
+            (&x ^ &y).into_iter().min_by_key(|m| m.count_ones())
+                .map(|m| (idx, m))
+        })
+    }
+
+    /// Returns true if the functions are equal for all inputs.
+    pub fn equivalent(&self, other: &Self) -> bool {
+        self.counterexample(other).is_none()
+    }
+
+    /// Draw the function as a matrix of output bits (rows) and input bits
+    /// (columns, from the highest used input bit to the lowest). For the
+    /// first two bits of [`crate::uarch::zen2::ZEN2_L1D_UTAG_FN`]:
+    ///
+    /// ```text
+    ///    27  23  19  15
+    ///    v   v   v   v
+    /// 0  +..............+
+    /// 1  .+............+.
+    /// ```
+    ///
+    /// A `+` is an input to the XOR for the output bit, and a `&` is an
+    /// input to some AND term.
+    pub fn diagram(&self) -> String {
+        let inputs = self.inputs();
+        let (lo, hi) = match (inputs.first(), inputs.last()) {
+            (Some(lo), Some(hi)) => (*lo, *hi),
+            _ => return String::new(),
+        };
+        let width = format!("{}", self.len().saturating_sub(1)).len() + 2;
+        let cols: Vec<usize> = (lo..=hi).rev().collect();
+
+        let mut res = " ".repeat(width);
+        let mut marks = " ".repeat(width);
+        for chunk in cols.chunks(4) {
+            res.push_str(&format!("{:<4}", chunk[0]));
+            marks.push_str("v   ");
+        }
+        res = res.trim_end().to_string() + "\n";
+        res.push_str(marks.trim_end());
+        res.push('\n');
+
+        for (idx, op) in self.0.iter().enumerate() {
+            let terms = match op {
+                BfExpr::Xor(ops) => ops.iter().collect(),
+                op => vec![op],
+            };
+            let mut row = vec!['.'; cols.len()];
+            for term in terms {
+                let mark = if matches!(term, BfExpr::In(_)) { '+' } else { '&' };
+                for bit in term.inputs() {
+                    row[hi - bit] = mark;
+                }
+            }
+            res.push_str(&format!("{:<width$}", idx, width = width));
+            res.extend(row);
+            res.push('\n');
+        }
+        res
+    }
+
+    /// Draw a function where each output bit is the XOR of two input bits
+    /// (in the style of the diagram for
+    /// [`crate::uarch::zen2::ZEN2_L1D_UTAG_FN`]).
+    ///
+    /// Each row is labelled with the lower input bit of a pair, and a `+`
+    /// marks the higher input bit. Columns run from the highest input bit
+    /// down to the highest row label. Returns [`None`] if some output bit
+    /// is not the XOR of two input bits.
+    pub fn pair_diagram(&self) -> Option<String> {
+        let pairs: Vec<(usize, usize)> = self.0.iter().map(|op| match op {
+            BfExpr::Xor(ops) => match ops.as_slice() {
+                [BfExpr::In(x), BfExpr::In(y)] if x != y => {
+                    Some((*x.min(y), *x.max(y)))
+                },
+                _ => None,
+            },
+            _ => None,
+        }).collect::<Option<_>>()?;
+        let hi = pairs.iter().map(|(_, y)| *y).max()?;
+        let lo = pairs.iter().map(|(x, _)| *x).max()?;
+        if pairs.iter().any(|(_, y)| *y < lo) {
+            return None;
+        }
+        let width = format!("{}", lo).len() + 2;
+        let cols: Vec<usize> = (lo..=hi).rev().collect();
+
+        let mut res = " ".repeat(width);
+        let mut marks = " ".repeat(width);
+        for chunk in cols.chunks(4) {
+            res.push_str(&format!("{:<4}", chunk[0]));
+            marks.push_str("v   ");
+        }
+        res = res.trim_end().to_string() + "\n";
+        res.push_str(marks.trim_end());
+        res.push('\n');
+
+        for (x, y) in pairs {
+            let mut row = vec!['.'; cols.len()];
+            row[hi - y] = '+';
+            res.push_str(&format!("{:<width$}", x, width = width));
+            res.extend(row);
+            res.push('\n');
+        }
+        Some(res)
+    }
+}
+impl <const SZ: usize> From<&BfProg<SZ>> for BfFunc {
+    fn from(prog: &BfProg<SZ>) -> Self {
+        Self(prog.0.iter().map(BfExpr::from).collect())
+    }
+}
+impl std::fmt::Display for BfFunc {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for (idx, op) in self.0.iter().enumerate() {
+            writeln!(f, "out[{}] = {}", idx, op)?;
+        }
+        Ok(())
+    }
+}
+
+/// A cursor over the characters in an expression.
+struct Cursor<'a>(std::iter::Peekable<std::str::CharIndices<'a>>, &'a str);
+impl <'a> Cursor<'a> {
+    fn new(s: &'a str) -> Self { Self(s.char_indices().peekable(), s) }
+
+    fn skip_whitespace(&mut self) {
+        while self.0.next_if(|(_, c)| c.is_whitespace()).is_some() {}
+    }
+
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.0.peek().map(|(_, c)| *c)
+    }
+
+    fn expect(&mut self, s: &str) -> Result<(), String> {
+        self.skip_whitespace();
+        for expected in s.chars() {
+            match self.0.next() {
+                Some((_, c)) if c == expected => {},
+                Some((pos, _)) => return Err(format!("expected '{}' at '{}'",
+                    s, &self.1[pos..])),
+                None => return Err(format!("expected '{}'", s)),
+            }
+        }
+        Ok(())
+    }
+
+    /// Parse an index in the form `[N]` (which must be a valid bit
+    /// position in a `usize`).
+    fn index(&mut self) -> Result<usize, String> {
+        self.expect("[")?;
+        self.skip_whitespace();
+        let mut num = String::new();
+        while let Some((_, c)) = self.0.next_if(|(_, c)| c.is_ascii_digit()) {
+            num.push(c);
+        }
+        self.expect("]")?;
+        match num.parse::<usize>() {
+            Ok(n) if n < usize::BITS as usize => Ok(n),
+            Ok(n) => Err(format!("index {} out of range", n)),
+            Err(_) => Err(format!("invalid index '{}'", num)),
+        }
+    }
+
+    fn xor(&mut self) -> Result<BfExpr, String> {
+        let mut ops = vec![self.and()?];
+        while self.peek() == Some('^') {
+            self.expect("^")?;
+            ops.push(self.and()?);
+        }
+        Ok(if ops.len() == 1 { ops.pop().unwrap() } else { BfExpr::Xor(ops) })
+    }
+
+    fn and(&mut self) -> Result<BfExpr, String> {
+        let mut ops = vec![self.atom()?];
+        while self.peek() == Some('&') {
+            self.expect("&")?;
+            ops.push(self.atom()?);
+        }
+        Ok(if ops.len() == 1 { ops.pop().unwrap() } else { BfExpr::And(ops) })
+    }
+
+    fn atom(&mut self) -> Result<BfExpr, String> {
+        match self.peek() {
+            Some('(') => {
+                self.expect("(")?;
+                let op = self.xor()?;
+                self.expect(")")?;
+                Ok(op)
+            },
+            Some(_) => {
+                self.expect("in")?;
+                Ok(BfExpr::In(self.index()?))
+            },
+            None => Err("unexpected end of input".to_string()),
+        }
+    }
+}
+
+impl FromStr for BfExpr {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut cur = Cursor::new(s);
+        let op = cur.xor()?;
+        match cur.0.next() {
+            Some((pos, _)) => Err(format!("unexpected '{}'", &s[pos..])),
+            None => Ok(op),
+        }
+    }
+}
+
+impl FromStr for BfFunc {
+    type Err = String;
+    /// Parse a function (one output bit per line, in any order).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut ops = Vec::new();
+        for (num, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (idx, op) = line.split_once('=')
+                .ok_or_else(|| "expected '='".to_string())
+                .and_then(|(out, op)| {
+                    let mut cur = Cursor::new(out);
+                    cur.expect("out")?;
+                    Ok((cur.index()?, op.parse::<BfExpr>()?))
+                })
+                .map_err(|e| format!("line {}: {}", num + 1, e))?;
+            ops.push((idx, op));
+        }
+        ops.sort_by_key(|(idx, _)| *idx);
+        for (expected, (idx, _)) in ops.iter().enumerate() {
+            if *idx != expected {
+                return Err(format!("missing or duplicate definition for out[{}]",
+                    expected));
+            }
+        }
+        Ok(Self(ops.into_iter().map(|(_, op)| op).collect()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::uarch::zen2::ZEN2_BTB_INDEX_FN;
+    use crate::uarch::zen2::ZEN2_L1D_UTAG_FN;
+    use crate::util::rng::seeded_rng;
+    use rand::Rng;
+
+    #[test]
+    fn bf_func_roundtrip() {
+        let func = BfFunc::from(&ZEN2_BTB_INDEX_FN);
+        let text = func.to_string();
+        assert!(text.contains("out[3] = in[39] ^ in[27] ^ (in[15] & in[10])\n"));
+        assert_eq!(text.parse::<BfFunc>(), Ok(func.clone()));
+
+        let mut rng = seeded_rng(0xbf);
+        for _ in 0..1024 {
+            let x = rng.gen::<usize>();
+            assert_eq!(func.evaluate(x), ZEN2_BTB_INDEX_FN.evaluate(x));
+        }
+
+        let op: BfExpr = "in[1]^in[2] & (in[3] ^ in[4]) & in[5]".parse().unwrap();
+        assert_eq!(op.to_string(), "in[1] ^ (in[2] & (in[3] ^ in[4]) & in[5])");
+        assert!("in[1] ^".parse::<BfExpr>().is_err());
+        assert!("in[1] in[2]".parse::<BfExpr>().is_err());
+        assert!("in[63]".parse::<BfExpr>().is_ok());
+        assert!("in[64]".parse::<BfExpr>().is_err());
+        assert!("in[1] ^ in[99999999999999999999]".parse::<BfExpr>().is_err());
+        assert!("out[1] = in[0]".parse::<BfFunc>().is_err());
+        assert!("out[0] = in[0]\nout[0] = in[1]".parse::<BfFunc>().is_err());
+    }
+
+    #[test]
+    fn bf_func_equivalent() {
+        let func = BfFunc::from(&ZEN2_BTB_INDEX_FN);
+        let mut other = func.clone();
+        other.0.swap(0, 1);
+        assert!(func.equivalent(&func));
+        let (idx, input) = func.counterexample(&other).unwrap();
+        assert_eq!(idx, 0);
+        assert_ne!(func.evaluate(input), other.evaluate(input));
+
+        // Distributing an AND over XOR yields an equivalent function
+        let x: BfFunc = "out[0] = in[0] & (in[1] ^ in[2])".parse().unwrap();
+        let y: BfFunc = "out[0] = (in[0] & in[1]) ^ (in[2] & in[0])".parse().unwrap();
+        let z: BfFunc = "out[0] = (in[0] & in[1]) ^ in[2]".parse().unwrap();
+        assert!(x.equivalent(&y));
+        let (_, input) = x.counterexample(&z).unwrap();
+        assert_ne!(x.evaluate(input), z.evaluate(input));
+        assert!(!x.equivalent(&BfFunc::default()));
+    }
+
+    #[test]
+    fn bf_func_diagram() {
+        let func = BfFunc::from(&ZEN2_L1D_UTAG_FN);
+        let diagram = func.diagram();
+        let lines: Vec<&str> = diagram.lines().collect();
+        assert_eq!(lines[0], "   27  23  19  15");
+        assert_eq!(lines[1], "   v   v   v   v");
+        assert_eq!(lines[2], "0  +..............+");
+        assert_eq!(lines[5], "3  .......+....+...");
+
+        let func = BfFunc::from(&ZEN2_BTB_INDEX_FN);
+        let diagram = func.diagram();
+        assert!(diagram.lines().nth(5).unwrap().ends_with("&....&"));
+        assert_eq!(func.pair_diagram(), None);
+    }
+
+    #[test]
+    fn bf_func_pair_diagram() {
+        // Take the diagram from the doc comment on ZEN2_L1D_UTAG_FN
+        let src = include_str!("../../uarch/zen2.rs");
+        let doc: String = src.lines()
+            .skip_while(|line| !line.starts_with("/// ```"))
+            .skip(1)
+            .take_while(|line| !line.starts_with("/// ```"))
+            .map(|line| line.strip_prefix("/// ").unwrap().to_string() + "\n")
+            .collect();
+        assert!(doc.starts_with("    27  23  19\n"));
+
+        let func = BfFunc::from(&ZEN2_L1D_UTAG_FN);
+        assert_eq!(func.pair_diagram().unwrap(), doc);
+    }
+}
@@ -0,0 +1,355 @@
+//! Recovering a [`BfFunc`] from observed collisions.
+//!
+//! An experiment usually can't observe the output of a hash function
+//! directly. Instead, it tells us whether or not two inputs *collide* (ie.
+//! whether the outputs are equal). If the function is linear over GF(2)
+//! (ie. each output bit is the XOR of some input bits), then two inputs
+//! `a` and `b` collide exactly when `a ^ b` is in the kernel of the function.
+//!
+//! [`BfSolver`] collects the differences between colliding inputs, and
+//! takes the orthogonal complement of the space they span. With enough
+//! observations, this is the space spanned by the output bits. The basis
+//! is reduced to row echelon form, so output bits with disjoint inputs
+//! are recovered exactly (but not necessarily in the original order).
+//!
+//! AND terms are handled by repeating the same process over an extended
+//! set of features: each pair of input bits `(i, j)` is treated as another
+//! input whose value is `in[i] & in[j]`. In that case, the complement also
+//! contains the AND of any two linear output bits, which are discarded.
+
+use crate::ir::bf::*;
+use std::collections::HashMap;
+
+/// A set of bits (one for each feature).
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct Row(Vec<u64>);
+impl Row {
+    fn new(len: usize) -> Self { Self(vec![0; len.div_ceil(64)]) }
+    fn get(&self, idx: usize) -> bool { self.0[idx / 64] & (1 << (idx % 64)) != 0 }
+    fn set(&mut self, idx: usize) { self.0[idx / 64] |= 1 << (idx % 64); }
+    fn flip(&mut self, idx: usize) { self.0[idx / 64] ^= 1 << (idx % 64); }
+    fn is_zero(&self) -> bool { self.0.iter().all(|w| *w == 0) }
+
+    fn xor(&mut self, other: &Self) {
+        self.0.iter_mut().zip(other.0.iter()).for_each(|(x, y)| *x ^= *y);
+    }
+
+    fn or(&mut self, other: &Self) {
+        self.0.iter_mut().zip(other.0.iter()).for_each(|(x, y)| *x |= *y);
+    }
+
+    /// Parity of the bits shared with `other`.
+    fn dot(&self, other: &Self) -> bool {
+        self.0.iter().zip(other.0.iter())
+            .map(|(x, y)| (x & y).count_ones())
+            .sum::<u32>() & 1 != 0
+    }
+
+    /// Index of the lowest set bit.
+    fn pivot(&self) -> Option<usize> {
+        self.0.iter().enumerate().find(|(_, w)| **w != 0)
+            .map(|(idx, w)| idx * 64 + w.trailing_zeros() as usize)
+    }
+
+    fn ones(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().enumerate().flat_map(|(idx, w)| {
+            (0..64).filter(move |bit| w & (1 << bit) != 0)
+                .map(move |bit| idx * 64 + bit)
+        })
+    }
+}
+
+/// A basis in reduced row echelon form.
+///
+/// Each row has a pivot (its lowest set bit) which is clear in all of the
+/// other rows.
+#[derive(Clone, Debug, Default)]
+struct Basis {
+    rows: Vec<Row>,
+    pivots: Vec<usize>,
+}
+impl Basis {
+    /// Add a row to the basis. Returns false if the row was already in
+    /// the space spanned by the basis.
+    fn insert(&mut self, mut row: Row) -> bool {
+        self.reduce(&mut row);
+        let pivot = match row.pivot() {
+            Some(pivot) => pivot,
+            None => return false,
+        };
+        for other in self.rows.iter_mut().filter(|r| r.get(pivot)) {
+            other.xor(&row);
+        }
+        self.rows.push(row);
+        self.pivots.push(pivot);
+        true
+    }
+
+    /// Remove the pivots of the basis from `row`.
+    fn reduce(&self, row: &mut Row) {
+        for (other, pivot) in self.rows.iter().zip(self.pivots.iter()) {
+            if row.get(*pivot) {
+                row.xor(other);
+            }
+        }
+    }
+
+    fn contains(&self, row: &Row) -> bool {
+        let mut row = row.clone();
+        self.reduce(&mut row);
+        row.is_zero()
+    }
+
+    fn len(&self) -> usize { self.rows.len() }
+}
+
+/// An observation from an experiment.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BfSample {
+    pub a: usize,
+    pub b: usize,
+    /// Whether or not the outputs for `a` and `b` are equal
+    pub collides: bool,
+}
+
+/// Recovers a [`BfFunc`] from a list of [`BfSample`].
+///
+/// The solver only considers the input bits given to [`BfSolver::new`].
+/// Bits which are never different between the two inputs of any sample
+/// are assumed to be unused by the function.
+///
+/// NOTE: The number of collisions required grows with the number of
+/// features: for `n` input bits, [`BfSolver::solve`] may need more than
+/// `n * (n + 1) / 2` collisions to find AND terms (although in practice,
+/// the linear case only needs a few more than `n`).
+#[derive(Clone, Debug, Default)]
+pub struct BfSolver {
+    /// Input bits which may be used by the function
+    pub bits: Vec<usize>,
+    pub samples: Vec<BfSample>,
+}
+impl BfSolver {
+    pub fn new(bits: impl IntoIterator<Item = usize>) -> Self {
+        let mut bits: Vec<usize> = bits.into_iter().collect();
+        bits.sort_unstable();
+        bits.dedup();
+        Self { bits, samples: Vec::new() }
+    }
+
+    /// Record whether or not inputs `a` and `b` collide.
+    pub fn observe(&mut self, a: usize, b: usize, collides: bool) {
+        self.samples.push(BfSample { a, b, collides });
+    }
+
+    pub fn collisions(&self) -> usize {
+        self.samples.iter().filter(|s| s.collides).count()
+    }
+
+    /// Recover a function where each output bit is the XOR of input bits.
+    pub fn solve_linear(&self, outputs: usize) -> Result<BfFunc, String> {
+        let terms = self.bits.iter().map(|bit| vec![*bit]).collect();
+        self.solve_terms(outputs, terms)
+    }
+
+    /// Recover a function where each output bit is the XOR of input bits
+    /// and 2-input AND terms.
+    ///
+    /// This first tries to find a linear function. If the samples are not
+    /// consistent with any linear function, all pairs of input bits are
+    /// added as candidate AND terms.
+    pub fn solve(&self, outputs: usize) -> Result<BfFunc, String> {
+        self.solve_linear(outputs).or_else(|_| {
+            let mut terms = Vec::new();
+            for (idx, i) in self.bits.iter().enumerate() {
+                for j in self.bits[idx + 1..].iter() {
+                    terms.push(vec![*i, *j]);
+                }
+            }
+            terms.extend(self.bits.iter().map(|bit| vec![*bit]));
+            self.solve_terms(outputs, terms)
+        })
+    }
+
+    /// Compute the value of each term for some input.
+    fn features(terms: &[Vec<usize>], input: usize) -> Row {
+        let mut row = Row::new(terms.len());
+        for (idx, term) in terms.iter().enumerate() {
+            if term.iter().all(|bit| input & (1 << bit) != 0) {
+                row.set(idx);
+            }
+        }
+        row
+    }
+
+    /// Recover a function from the terms in `terms`.
+    ///
+    /// Any AND terms must come before the single-bit terms: then, rows in 
+    /// the basis with a pivot on a single-bit term are purely linear. 
+    fn solve_terms(&self, len: usize, terms: Vec<Vec<usize>>)
+        -> Result<BfFunc, String>
+    {
+        let diff = |s: &BfSample| {
+            let mut row = Self::features(&terms, s.a);
+            row.xor(&Self::features(&terms, s.b));
+            row
+        };
+
+        // The span of differences between colliding inputs, and the set
+        // of terms which were ever observed to change
+        let mut kernel = Basis::default();
+        let mut observed = Row::new(terms.len());
+        for sample in self.samples.iter() {
+            let row = diff(sample);
+            observed.or(&row);
+            if sample.collides {
+                kernel.insert(row);
+            }
+        }
+
+        // Each observed term that isn't a pivot in the kernel yields a
+        // vector in the orthogonal complement
+        let mut image = Basis::default();
+        for free in observed.ones().filter(|idx| !kernel.pivots.contains(idx)) {
+            let mut row = Row::new(terms.len());
+            row.set(free);
+            for (other, pivot) in kernel.rows.iter().zip(kernel.pivots.iter()) {
+                if other.get(free) {
+                    row.set(*pivot);
+                }
+            }
+            image.insert(row);
+        }
+
+        // The image also contains every function of the output bits that 
+        // can be written with these terms (ie. the AND of two linear 
+        // outputs). Keep the linear rows, and only keep other rows which
+        // can't be obtained from the linear rows.
+        let index: HashMap<&[usize], usize> = terms.iter().enumerate()
+            .map(|(idx, term)| (&term[..], idx)).collect();
+        let (mut outputs, other): (Vec<Row>, Vec<Row>) = image.rows.into_iter()
+            .partition(|row| terms[row.pivot().unwrap()].len() == 1);
+        let mut span = Basis::default();
+        for row in outputs.iter() {
+            span.insert(row.clone());
+        }
+        for (idx, x) in outputs.iter().enumerate() {
+            for y in outputs[idx + 1..].iter() {
+                if let Some(row) = Self::and(&terms, &index, x, y) {
+                    span.insert(row);
+                }
+            }
+        }
+        for mut row in other {
+            span.reduce(&mut row);
+            if !row.is_zero() {
+                span.insert(row.clone());
+                outputs.push(row);
+            }
+        }
+        if outputs.len() != len {
+            return Err(format!("Found {} output bits (expected {}) \
+                with {} collisions", outputs.len(), len, kernel.len()));
+        }
+
+        // Inputs that don't collide must differ in some output bit
+        for sample in self.samples.iter().filter(|s| !s.collides) {
+            let row = diff(sample);
+            if outputs.iter().all(|r| !r.dot(&row)) {
+                return Err(format!("Inputs {:#x} and {:#x} should collide",
+                    sample.a, sample.b));
+            }
+        }
+
+        outputs.sort_by_key(|r| r.ones().map(|idx| terms[idx][0]).min());
+        Ok(BfFunc(outputs.iter().map(|row| {
+            BfExpr::Xor(row.ones().map(|idx| match terms[idx][..] {
+                [bit] => BfExpr::In(bit),
+                _ => BfExpr::And(terms[idx].iter()
+                    .map(|bit| BfExpr::In(*bit)).collect()
+                ),
+            }).collect())
+        }).collect()))
+    }
+
+    /// Return the AND of two linear rows (if it can be written with the 
+    /// terms in `index`).
+    fn and(terms: &[Vec<usize>], index: &HashMap<&[usize], usize>, 
+        x: &Row, y: &Row) -> Option<Row>
+    {
+        let mut row = Row::new(terms.len());
+        for i in x.ones().map(|idx| terms[idx][0]) {
+            for j in y.ones().map(|idx| terms[idx][0]) {
+                let term = [i.min(j), i.max(j)];
+                let term = if i == j { &term[..1] } else { &term[..] };
+                row.flip(*index.get(term)?);
+            }
+        }
+        Some(row)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::uarch::btb::BtbAliases;
+    use crate::uarch::zen2::ZEN2_BTB_INDEX_FN;
+    use crate::uarch::zen2::ZEN2_L1D_UTAG_FN;
+    use crate::util::rng::seeded_rng;
+    use rand::Rng;
+    use std::collections::BTreeSet;
+
+    /// The terms in each output bit (ignoring the order of outputs).
+    fn terms(func: &BfFunc) -> BTreeSet<BTreeSet<Vec<usize>>> {
+        func.0.iter().map(|op| match op {
+            BfExpr::Xor(ops) => ops.iter().map(|op| op.inputs()).collect(),
+            op => [op.inputs()].into_iter().collect(),
+        }).collect()
+    }
+
+    /// Generate samples for a known function (with inputs in `mask`).
+    fn observe<const SZ: usize>(prog: &BfProg<SZ>, mask: usize, iters: usize)
+        -> BfSolver
+    {
+        let mut rng = seeded_rng(0x5017e);
+        let used = prog.inputs().iter().flatten().fold(0, |res, b| res | (1 << b));
+        let mut solver = BfSolver::new((0..64).filter(|b| mask & (1 << b) != 0));
+        for _ in 0..iters {
+            let a = rng.gen::<usize>() & mask;
+            let b = BtbAliases::new(prog, a).random(&mut rng)
+                ^ (rng.gen::<usize>() & mask & !used);
+            solver.observe(a, b, true);
+            let c = rng.gen::<usize>() & mask;
+            solver.observe(a, c, prog.evaluate(a) == prog.evaluate(c));
+        }
+        solver
+    }
+
+    #[test]
+    fn bf_solve_linear() {
+        let solver = observe(&ZEN2_L1D_UTAG_FN, 0x0fff_f000, 64);
+        let func = solver.solve_linear(8).unwrap();
+        assert_eq!(terms(&func), terms(&BfFunc::from(&ZEN2_L1D_UTAG_FN)));
+
+        // Too few samples to find the function
+        let solver = observe(&ZEN2_L1D_UTAG_FN, 0x0fff_f000, 2);
+        assert!(solver.solve_linear(8).is_err());
+    }
+
+    #[test]
+    fn bf_solve_and() {
+        let mask = 0x0000_ffff_ffff_fc00;
+        let solver = observe(&ZEN2_BTB_INDEX_FN, mask, 1536);
+        assert!(solver.solve_linear(12).is_err());
+        let func = solver.solve(12).unwrap();
+        assert_eq!(terms(&func), terms(&BfFunc::from(&ZEN2_BTB_INDEX_FN)));
+
+        let mut rng = seeded_rng(0);
+        for _ in 0..1024 {
+            let (a, b) = (rng.gen::<usize>() & mask, rng.gen::<usize>() & mask);
+            assert_eq!(
+                func.evaluate(a) == func.evaluate(b),
+                ZEN2_BTB_INDEX_FN.evaluate(a) == ZEN2_BTB_INDEX_FN.evaluate(b)
+            );
+        }
+    }
+}
@@ -0,0 +1,186 @@
+//! A plain-text format for [`PerfectProg`].
+//!
+//! Each line is the [`Debug`] representation of a [`PerfectOp`], ie.
+//!
+//! ```text
+//! MovImm(Rax, Imm32(4096), Qword)
+//! Jcc(Ne, IRLabel(0))
+//! Load(Rcx, BaseImm32(-8), Dword)
+//! Label(IRLabel(0))
+//! ```
+//!
+//! Parsing only needs to deal with a flat list of words: parentheses and
+//! commas are treated as whitespace.
+
+use crate::ir::*;
+use std::str::FromStr;
+
+/// A cursor over the words in the text representation of a [`PerfectOp`].
+struct Words<'a>(std::vec::IntoIter<&'a str>);
+impl <'a> Words<'a> {
+    fn new(s: &'a str) -> Self {
+        let words: Vec<&str> = s
+            .split(|c: char| c == '(' || c == ')' || c == ',' || c.is_whitespace())
+            .filter(|w| !w.is_empty())
+            .collect();
+        Self(words.into_iter())
+    }
+
+    fn word(&mut self) -> Result<&'a str, String> {
+        self.0.next().ok_or_else(|| "unexpected end of input".to_string())
+    }
+
+    fn num<T: FromStr>(&mut self) -> Result<T, String> {
+        let w = self.word()?;
+        w.parse().map_err(|_| format!("invalid number '{}'", w))
+    }
+
+    fn reg(&mut self) -> Result<IRRegOperand, String> {
+        match self.word()? {
+            "Rax" => Ok(IRRegOperand::Rax),
+            "Rcx" => Ok(IRRegOperand::Rcx),
+            "Rdx" => Ok(IRRegOperand::Rdx),
+            "Rbx" => Ok(IRRegOperand::Rbx),
+            w => Err(format!("invalid register '{}'", w)),
+        }
+    }
+
+    fn vec(&mut self) -> Result<IRVecOperand, String> {
+        let w = self.word()?;
+        IRVecOperand::ALL.iter().find(|v| format!("{:?}", v) == w).copied()
+            .ok_or_else(|| format!("invalid vector register '{}'", w))
+    }
+
+    fn imm(&mut self) -> Result<IRImmOperand, String> {
+        match self.word()? {
+            "Imm32" => Ok(IRImmOperand::Imm32(self.num()?)),
+            "Imm64" => Ok(IRImmOperand::Imm64(self.num()?)),
+            w => Err(format!("invalid immediate '{}'", w)),
+        }
+    }
+
+    fn mem(&mut self) -> Result<IRMemOperand, String> {
+        match self.word()? {
+            "Base" => Ok(IRMemOperand::Base),
+            "BaseImm32" => Ok(IRMemOperand::BaseImm32(self.num()?)),
+            "MemImm32" => Ok(IRMemOperand::MemImm32(self.num()?)),
+            w => Err(format!("invalid memory operand '{}'", w)),
+        }
+    }
+
+    fn width(&mut self) -> Result<PerfectOpWidth, String> {
+        match self.word()? {
+            "Qword" => Ok(PerfectOpWidth::Qword),
+            "Dword" => Ok(PerfectOpWidth::Dword),
+            "Word" => Ok(PerfectOpWidth::Word),
+            w => Err(format!("invalid width '{}'", w)),
+        }
+    }
+
+    fn cond(&mut self) -> Result<IRCond, String> {
+        let w = self.word()?;
+        IRCond::ALL.iter().find(|c| format!("{:?}", c) == w).copied()
+            .ok_or_else(|| format!("invalid condition '{}'", w))
+    }
+
+    fn label(&mut self) -> Result<IRLabel, String> {
+        match self.word()? {
+            "IRLabel" => Ok(IRLabel(self.num()?)),
+            w => Err(format!("invalid label '{}'", w)),
+        }
+    }
+}
+
+impl FromStr for PerfectOp {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use PerfectOp::*;
+        let mut w = Words::new(s);
+        let op = match w.word()? {
+            "Nop" => Nop,
+            "MovImm" => MovImm(w.reg()?, w.imm()?, w.width()?),
+            "Mov" => Mov(w.reg()?, w.reg()?, w.width()?),
+            "Add" => Add(w.reg()?, w.reg()?, w.width()?),
+            "Xor" => Xor(w.reg()?, w.reg()?, w.width()?),
+            "ZeroIdiom" => ZeroIdiom(w.reg()?, w.width()?),
+            "Xchg64" => Xchg64(w.reg()?, w.reg()?),
+            "Xchg32" => Xchg32(w.reg()?, w.reg()?),
+            "Load" => Load(w.reg()?, w.mem()?, w.width()?),
+            "Store" => Store(w.mem()?, w.reg()?, w.width()?),
+            "Movzx64_16" => Movzx64_16(w.reg()?, w.reg()?),
+            "Movzx64_8" => Movzx64_8(w.reg()?, w.reg()?),
+            "Movzx32_16" => Movzx32_16(w.reg()?, w.reg()?),
+            "Movzx32_8" => Movzx32_8(w.reg()?, w.reg()?),
+            "Movzx16_8" => Movzx16_8(w.reg()?, w.reg()?),
+            "Label" => Label(w.label()?),
+            "Jcc" => Jcc(w.cond()?, w.label()?),
+            "Jmp" => Jmp(w.label()?),
+            "Cmp" => Cmp(w.reg()?, w.reg()?, w.width()?),
+            "Adc" => Adc(w.reg()?, w.reg()?, w.width()?),
+            "Setcc" => Setcc(w.cond()?, w.reg()?),
+            "Cmovcc" => Cmovcc(w.cond()?, w.reg()?, w.reg()?, w.width()?),
+            "Shl" => Shl(w.reg()?, w.num()?, w.width()?),
+            "Shr" => Shr(w.reg()?, w.num()?, w.width()?),
+            "Sar" => Sar(w.reg()?, w.num()?, w.width()?),
+            "Imul" => Imul(w.reg()?, w.reg()?, w.width()?),
+            "Mul" => Mul(w.reg()?, w.width()?),
+            "Div" => Div(w.reg()?, w.width()?),
+            "VMovqFromGpr" => VMovqFromGpr(w.vec()?, w.reg()?),
+            "VMovqToGpr" => VMovqToGpr(w.reg()?, w.vec()?),
+            "Vpaddq" => Vpaddq(w.vec()?, w.vec()?, w.vec()?),
+            "Vpxor" => Vpxor(w.vec()?, w.vec()?, w.vec()?),
+            "Vpand" => Vpand(w.vec()?, w.vec()?, w.vec()?),
+            "Paddq" => Paddq(w.vec()?, w.vec()?),
+            "Pxor" => Pxor(w.vec()?, w.vec()?),
+            other => return Err(format!("unknown operation '{}'", other)),
+        };
+        if let Ok(extra) = w.word() {
+            return Err(format!("unexpected '{}' after {:?}", extra, op));
+        }
+        Ok(op)
+    }
+}
+
+impl std::fmt::Display for PerfectProg {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for op in self.data.iter() {
+            writeln!(f, "{:?}", op)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for PerfectProg {
+    type Err = String;
+    /// Parse a program (one operation per line). Empty lines are ignored.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut data = Vec::new();
+        for (num, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            data.push(line.parse().map_err(|e| format!("line {}: {}", num + 1, e))?);
+        }
+        Ok(Self { data })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::rng::*;
+
+    #[test]
+    fn parse_prog_roundtrip() {
+        let mut rng = perfect_rng();
+        for _ in 0..64 {
+            let prog = PerfectProg::gen(&mut rng, 128);
+            let text = prog.to_string();
+            let parsed: PerfectProg = text.parse().unwrap();
+            assert_eq!(prog.data, parsed.data);
+        }
+        assert!("Add(Rax, Rsp, Qword)".parse::<PerfectOp>().is_err());
+        assert!("Nop(Rax)".parse::<PerfectOp>().is_err());
+    }
+}
@@ -15,6 +15,8 @@ pub mod stats;
 pub mod util;
 pub mod events;
 pub mod uarch; 
+pub mod sidechannel;
+pub mod timer;
 
 pub use rand::Rng;
 pub use rand::rngs::ThreadRng;
@@ -37,6 +39,7 @@ pub use dynasmrt::{
 pub use crate::asm::*;
 pub use crate::harness::*;
 pub use crate::util::*;
+pub use crate::timer::{ Timer, TimerSource, FencePolicy };
 pub use crate::experiments::{ 
     Experiment,
     ExperimentArgs,
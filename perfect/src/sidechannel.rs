@@ -0,0 +1,26 @@
+//! Cache side-channel primitives.
+//!
+//! This module provides emitted probe gadgets and a few of the usual
+//! cache timing attacks built on top of them:
+//!
+//! - [`FlushReload`] (timed reload of a shared line, followed by CLFLUSH)
+//! - [`PrimeProbe`] (timed traversal of an [`EvictionSet`])
+//! - [`EvictTime`] (timed call to a victim after evicting a cache set)
+//! - [`CollideProbe`] (timed load, relative to the timer overhead)
+//!
+//! Gadgets are emitted into dynamically-allocated memory and called
+//! directly from Rust code (ie. not through [`crate::PerfectHarness`]).
+//! Each primitive can be calibrated to obtain a [`Threshold`] for
+//! distinguishing between fast and slow probes. Classification is entirely
+//! separate from the gadgets, so a [`Threshold`] can also be computed from
+//! recorded latency distributions.
+
+pub mod threshold;
+pub mod gadget;
+pub mod evset;
+pub mod probe;
+
+pub use threshold::*;
+pub use gadget::*;
+pub use evset::*;
+pub use probe::*;
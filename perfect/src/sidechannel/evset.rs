@@ -0,0 +1,498 @@
+//! Cache geometry and eviction sets.
+
+use crate::harness::{ Arena, PageSize };
+use crate::util::pagemap::PageMap;
+use crate::sidechannel::{ Access, Gadget, Threshold };
+use crate::timer::Timer;
+use std::collections::HashMap;
+
+/// How a cache selects a set.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheIndexing {
+    /// Set index bits are taken from the virtual address.
+    Virtual,
+    /// Set index bits are taken from the physical address.
+    Physical,
+}
+
+/// The shape of a set-associative cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CacheGeometry {
+    pub name: &'static str,
+    /// Size of a cache line (in bytes)
+    pub line_size: usize,
+    /// Number of sets (in each slice)
+    pub sets: usize,
+    /// Number of ways in each set
+    pub ways: usize,
+    /// Number of slices (selected by an undocumented hash of the physical
+    /// address)
+    pub slices: usize,
+    pub indexing: CacheIndexing,
+}
+impl CacheGeometry {
+    /// Zen 2 L1D cache (32KiB, 8-way)
+    pub const ZEN2_L1D: Self = Self {
+        name: "L1D", line_size: 64, sets: 64, ways: 8, slices: 1,
+        indexing: CacheIndexing::Virtual,
+    };
+
+    /// Zen 2 L2 cache (512KiB, 8-way)
+    pub const ZEN2_L2: Self = Self {
+        name: "L2", line_size: 64, sets: 1024, ways: 8, slices: 1,
+        indexing: CacheIndexing::Physical,
+    };
+
+    /// Zen 2 L3 cache (16MiB per CCX, 16-way, 4 slices)
+    pub const ZEN2_LLC: Self = Self {
+        name: "LLC", line_size: 64, sets: 4096, ways: 16, slices: 4,
+        indexing: CacheIndexing::Physical,
+    };
+
+    /// Zen 3 L1D cache (32KiB, 8-way)
+    pub const ZEN3_L1D: Self = Self::ZEN2_L1D;
+
+    /// Zen 3 L2 cache (512KiB, 8-way)
+    pub const ZEN3_L2: Self = Self::ZEN2_L2;
+
+    /// Zen 3 L3 cache (32MiB per CCD, 16-way, 8 slices)
+    pub const ZEN3_LLC: Self = Self {
+        name: "LLC", line_size: 64, sets: 4096, ways: 16, slices: 8,
+        indexing: CacheIndexing::Physical,
+    };
+
+    /// Total capacity (in bytes).
+    pub fn size(&self) -> usize {
+        self.line_size * self.sets * self.ways * self.slices
+    }
+
+    /// Distance between two consecutive addresses in the same set.
+    pub fn set_stride(&self) -> usize { self.line_size * self.sets }
+
+    /// Return the set index for an address.
+    pub fn set_index(&self, addr: usize) -> usize {
+        (addr / self.line_size) % self.sets
+    }
+}
+
+/// A list of addresses which map to the same cache set.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct EvictionSet {
+    pub geometry: CacheGeometry,
+    /// The set index shared by all addresses, or [`None`] when it is
+    /// unknown (ie. when the addresses were only found to be congruent with
+    /// each other by timing)
+    pub set: Option<usize>,
+    pub addrs: Vec<usize>,
+}
+impl EvictionSet {
+    /// Build an eviction set with `len` addresses in the buffer at `base`
+    /// (with length `buf_len`), all mapping to the requested `set`.
+    ///
+    /// Addresses are spaced apart by [`CacheGeometry::set_stride`].
+    /// For a physically-indexed cache, this is only correct when the
+    /// buffer is physically contiguous over each stride (ie. when the
+    /// stride is smaller than the page size; see
+    /// [`PageSize::Huge2M`](crate::harness::PageSize)).
+    pub fn congruent(geometry: CacheGeometry, base: usize, buf_len: usize,
+        set: usize, len: usize) -> Result<Self, String>
+    {
+        if set >= geometry.sets {
+            return Err(format!("Set {} is out of bounds for {} ({} sets)",
+                set, geometry.name, geometry.sets
+            ));
+        }
+        let stride = geometry.set_stride();
+        let first = (base.next_multiple_of(stride)) + set * geometry.line_size;
+        let addrs: Vec<usize> = (0..len).map(|i| first + i * stride)
+            .collect();
+        if let Some(last) = addrs.last() {
+            if last + geometry.line_size > base + buf_len {
+                return Err(format!(
+                    "Buffer {:#x} (len {:#x}) is too small for {} addresses \
+                    in {} set {}", base, buf_len, len, geometry.name, set
+                ));
+            }
+        }
+        Ok(Self { geometry, set: Some(set), addrs })
+    }
+
+    pub fn len(&self) -> usize { self.addrs.len() }
+    pub fn is_empty(&self) -> bool { self.addrs.is_empty() }
+
+    /// Returns true if the address maps to the same set.
+    ///
+    /// For a physically-indexed cache, the set index is taken from the
+    /// physical address (see [`PageMap::resolve_paddr`]). Returns [`None`]
+    /// when the set index is unknown, or when the physical address cannot
+    /// be resolved.
+    pub fn is_congruent(&self, addr: usize) -> Option<bool> {
+        let set = self.set?;
+        let addr = match self.geometry.indexing {
+            CacheIndexing::Virtual => addr,
+            CacheIndexing::Physical => PageMap::resolve_paddr(addr).ok()?,
+        };
+        Some(self.geometry.set_index(addr) == set)
+    }
+}
+
+/// Builds an [`EvictionSet`] for a particular set in a buffer.
+///
+/// Candidates are the lines in the buffer whose set index bits are known to
+/// match the requested set:
+///
+/// - For a virtually-indexed cache, all of the set index bits are known,
+///   and the first candidates are used directly
+/// - For a physically-indexed cache, only the set index bits within a page
+///   are known. When [`PageMap::resolve_paddr`] is usable (this usually
+///   requires root), candidates are filtered by their physical address
+/// - Otherwise (or when the cache is sliced), candidates are reduced to a
+///   minimal eviction set by timing (see [`EvictionSetBuilder::reduce`]).
+///   Without physical addresses, the candidates may belong to any set that
+///   agrees on the bits within a page, so [`EvictionSet::set`] is unknown
+///
+/// NOTE: Building an eviction set overwrites the first quadword of each
+/// candidate line in the buffer.
+#[derive(Clone, Copy, Debug)]
+pub struct EvictionSetBuilder {
+    pub geometry: CacheGeometry,
+    pub timer: Timer,
+    /// Threshold for deciding when a line has been evicted (calibrated
+    /// automatically if not provided)
+    pub threshold: Option<Threshold>,
+    /// Number of addresses in the eviction set (ignored for sets found by
+    /// timing, which always have one address per way)
+    pub len: usize,
+    /// Use [`PageMap`] to resolve physical addresses (if possible)
+    pub use_pagemap: bool,
+    /// Number of measurements used to decide whether a set evicts a line
+    pub votes: usize,
+    /// Number of times group testing is allowed to fail before giving up
+    pub max_retries: usize,
+}
+impl EvictionSetBuilder {
+    /// Number of samples used to calibrate the threshold.
+    const CALIBRATE_ITERS: usize = 64;
+
+    pub fn new(geometry: CacheGeometry) -> Self {
+        Self {
+            geometry,
+            timer: Timer::RDTSC,
+            threshold: None,
+            len: geometry.ways,
+            use_pagemap: true,
+            votes: 7,
+            max_retries: 16,
+        }
+    }
+
+    pub fn timer(mut self, x: Timer) -> Self {
+        self.timer = x;
+        self
+    }
+    pub fn threshold(mut self, x: Threshold) -> Self {
+        self.threshold = Some(x);
+        self
+    }
+    pub fn len(mut self, x: usize) -> Self {
+        self.len = x;
+        self
+    }
+    pub fn use_pagemap(mut self, x: bool) -> Self {
+        self.use_pagemap = x;
+        self
+    }
+    pub fn votes(mut self, x: usize) -> Self {
+        self.votes = x;
+        self
+    }
+    pub fn max_retries(mut self, x: usize) -> Self {
+        self.max_retries = x;
+        self
+    }
+}
+
+impl EvictionSetBuilder {
+    /// Build an eviction set for `set` from a buffer in the arena.
+    pub fn build_in(&self, arena: &Arena, name: &str, set: usize)
+        -> Result<EvictionSet, String>
+    {
+        let buf = arena.get(name)
+            .ok_or_else(|| format!("No arena buffer named '{}'", name))?;
+        self.build(buf.addr, buf.len(), buf.desc.page_size, set)
+    }
+
+    /// Build an eviction set for `set` from the buffer at `base` (with
+    /// length `buf_len`, backed by pages of size `page_size`).
+    pub fn build(&self, base: usize, buf_len: usize, page_size: PageSize,
+        set: usize) -> Result<EvictionSet, String>
+    {
+        let g = self.geometry;
+        if set >= g.sets {
+            return Err(format!("Set {} is out of bounds for {} ({} sets)",
+                set, g.name, g.sets
+            ));
+        }
+
+        // Only the set index bits within a page are known to match
+        let page = match (g.indexing, page_size) {
+            (CacheIndexing::Virtual, _) => usize::MAX,
+            (_, PageSize::Huge2M) => PageSize::Huge2M.bytes(),
+            (_, _) => PageSize::Base4K.bytes(),
+        };
+        let stride = g.set_stride().min(page);
+        let mut known = g.set_stride() <= page;
+        let first = base.next_multiple_of(stride) + (set * g.line_size) % stride;
+        let end = base + buf_len;
+        let mut candidates: Vec<usize> = (first..end).step_by(stride)
+            .filter(|addr| addr + g.line_size <= end)
+            .collect();
+
+        // Make sure each page is actually backed by memory
+        for addr in (base..end).step_by(PageSize::Base4K.bytes()) {
+            unsafe {
+                let ptr = addr as *mut u8;
+                ptr.write_volatile(ptr.read_volatile());
+            }
+        }
+
+        if g.indexing == CacheIndexing::Virtual {
+            return self.take(candidates, set);
+        }
+        if self.use_pagemap {
+            if let Ok(paddrs) = Self::resolve(&candidates) {
+                candidates = candidates.iter().zip(paddrs.iter())
+                    .filter(|(_, paddr)| g.set_index(**paddr) == set)
+                    .map(|(vaddr, _)| *vaddr)
+                    .collect();
+                known = true;
+                if g.slices == 1 {
+                    return self.take(candidates, set);
+                }
+            }
+        }
+        self.build_by_timing(candidates, base, buf_len, set, known)
+    }
+
+    fn take(&self, mut candidates: Vec<usize>, set: usize)
+        -> Result<EvictionSet, String>
+    {
+        if candidates.len() < self.len {
+            return Err(format!("Only found {} of {} addresses for {} set {}",
+                candidates.len(), self.len, self.geometry.name, set
+            ));
+        }
+        candidates.truncate(self.len);
+        Ok(EvictionSet {
+            geometry: self.geometry, set: Some(set), addrs: candidates
+        })
+    }
+
+    /// Resolve the physical address for each virtual address.
+    fn resolve(vaddrs: &[usize]) -> Result<Vec<usize>, &'static str> {
+        let mut pages: HashMap<usize, usize> = HashMap::new();
+        vaddrs.iter().map(|vaddr| {
+            let vpage = vaddr & !0xfff;
+            let ppage = match pages.get(&vpage) {
+                Some(ppage) => *ppage,
+                None => {
+                    let ppage = PageMap::resolve_paddr(vpage)?;
+                    pages.insert(vpage, ppage);
+                    ppage
+                },
+            };
+            Ok(ppage | (vaddr & 0xfff))
+        }).collect()
+    }
+
+    /// Link a list of lines together (for use with [`Gadget::chase`]).
+    fn link(addrs: &[usize]) {
+        for (idx, addr) in addrs.iter().enumerate() {
+            let next = addrs.get(idx + 1).copied().unwrap_or(0);
+            unsafe { (*addr as *mut usize).write_volatile(next) };
+        }
+    }
+
+    /// Compute a threshold between hits and evictions for `target`.
+    ///
+    /// Evictions are caused by traversing the buffer (when it's at least
+    /// twice the size of the cache), or by CLFLUSH otherwise.
+    fn calibrate(&self, target: usize, base: usize, buf_len: usize)
+        -> Result<Threshold, String>
+    {
+        let g = self.geometry;
+        let touch = Gadget::touch();
+        let flush = Gadget::flush();
+        let reload = Gadget::reload(self.timer);
+        let chase = Gadget::chase();
+
+        let target_line = target & !(g.line_size - 1);
+        let thrash: Vec<usize> = if buf_len >= 2 * g.size() {
+            (base..base + 2 * g.size()).step_by(g.line_size)
+                .filter(|addr| *addr != target_line)
+                .collect()
+        } else {
+            Vec::new()
+        };
+        Self::link(&thrash);
+
+        let mut hits = Vec::with_capacity(Self::CALIBRATE_ITERS);
+        let mut misses = Vec::with_capacity(Self::CALIBRATE_ITERS);
+        for _ in 0..Self::CALIBRATE_ITERS {
+            touch.call(target, 0);
+            hits.push(reload.call(target, 0));
+            if let Some(head) = thrash.first() {
+                chase.call(*head, 0);
+            } else {
+                flush.call(target, 0);
+            }
+            misses.push(reload.call(target, 0));
+        }
+        Threshold::calibrate(&hits, &misses)
+    }
+
+    /// Find an eviction set for the first candidate by timing. When `known`
+    /// is false, the candidates don't necessarily map to `set`.
+    fn build_by_timing(&self, mut candidates: Vec<usize>, base: usize,
+        buf_len: usize, set: usize, known: bool)
+        -> Result<EvictionSet, String>
+    {
+        let g = self.geometry;
+        if candidates.len() <= g.ways {
+            return Err(format!("Not enough candidates for {} set {} ({})",
+                g.name, set, candidates.len()
+            ));
+        }
+        let target = candidates.remove(0);
+        let threshold = match self.threshold {
+            Some(t) => t,
+            None => self.calibrate(target, base, buf_len)?,
+        };
+
+        let touch = Gadget::touch();
+        let reload = Gadget::reload(self.timer);
+        let chase = Gadget::chase();
+        let evicts = |addrs: &[usize]| {
+            let Some(head) = addrs.first() else { return false };
+            Self::link(addrs);
+            let mut evicted = 0;
+            for _ in 0..self.votes {
+                touch.call(target, 0);
+                chase.call(*head, 0);
+                chase.call(*head, 0);
+                if threshold.classify(reload.call(target, 0)) == Access::Miss {
+                    evicted += 1;
+                }
+            }
+            evicted > self.votes / 2
+        };
+        let addrs = Self::reduce(candidates, g.ways, self.max_retries, evicts)?;
+        Ok(EvictionSet { geometry: g, set: known.then_some(set), addrs })
+    }
+
+    /// Reduce a list of candidates (which must evict some target line) to
+    /// a minimal eviction set with `ways` addresses, where `evicts` tests
+    /// whether a list of addresses evicts the target.
+    ///
+    /// This is the group-testing reduction from "Theory and Practice of
+    /// Finding Eviction Sets" (Vila et al., 2019): split the candidates into
+    /// `ways + 1` groups, and discard the first group whose removal still
+    /// leaves an eviction set. When no group can be removed (ie. because
+    /// of a noisy measurement), the round is retried.
+    pub fn reduce(mut candidates: Vec<usize>, ways: usize, max_retries: usize,
+        mut evicts: impl FnMut(&[usize]) -> bool) -> Result<Vec<usize>, String>
+    {
+        if !evicts(&candidates) {
+            return Err(format!("{} candidates do not evict the target",
+                candidates.len()
+            ));
+        }
+        let mut retries = 0;
+        while candidates.len() > ways {
+            let chunk = candidates.len().div_ceil(ways + 1);
+            let mut removed = false;
+            for lo in (0..candidates.len()).step_by(chunk) {
+                let hi = (lo + chunk).min(candidates.len());
+                let rest: Vec<usize> = candidates[..lo].iter()
+                    .chain(candidates[hi..].iter())
+                    .copied().collect();
+                if evicts(&rest) {
+                    candidates = rest;
+                    removed = true;
+                    break;
+                }
+            }
+            if !removed {
+                retries += 1;
+                if retries > max_retries {
+                    return Err(format!("Group testing failed with {} \
+                        candidates remaining", candidates.len()
+                    ));
+                }
+            }
+        }
+        Ok(candidates)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::harness::*;
+
+    #[test]
+    fn evset_reduce() {
+        // A sliced cache with 16 sets and 4 ways, where the slice is
+        // selected by a hash of the upper bits
+        let congruent = |addr: usize| {
+            let slice = ((addr >> 12) ^ (addr >> 14) ^ (addr >> 17)) & 1;
+            (addr >> 6) % 16 == 3 && slice == 0
+        };
+        let candidates: Vec<usize> = (0..0x40_0000).step_by(64)
+            .filter(|addr| (addr >> 6) % 16 == 3)
+            .collect();
+        let mut tests = 0;
+        let evset = EvictionSetBuilder::reduce(candidates, 4, 0, |addrs| {
+            tests += 1;
+            addrs.iter().filter(|a| congruent(**a)).count() >= 4
+        }).unwrap();
+        assert_eq!(evset.len(), 4);
+        assert!(evset.iter().all(|a| congruent(*a)));
+        assert!(tests < 256, "{} tests", tests);
+
+        assert!(EvictionSetBuilder::reduce(vec![0x40, 0x80], 4, 0,
+            |addrs| addrs.len() >= 4).is_err()
+        );
+    }
+
+    #[test]
+    fn evset_build() {
+        let layout = ArenaLayout::new(0x0000_0033_0000_0000)
+            .buffer(ArenaBufferDesc::new("evset", 0x10_0000));
+        let arena = layout.map().unwrap();
+
+        let g = CacheGeometry::ZEN2_L1D;
+        let evset = EvictionSetBuilder::new(g).len(12)
+            .build_in(&arena, "evset", 17).unwrap();
+        assert_eq!(evset.len(), 12);
+        assert_eq!(evset.set, Some(17));
+        assert!(evset.addrs.iter().all(|a| g.set_index(*a) == 17));
+        assert!(EvictionSetBuilder::new(g).build_in(&arena, "evset", 64)
+            .is_err()
+        );
+
+        // Physical addresses are only available with permission
+        let g = CacheGeometry::ZEN2_L2;
+        if arena.paddr("evset", 0).is_ok() {
+            let evset = EvictionSetBuilder::new(g)
+                .build_in(&arena, "evset", 0x123).unwrap();
+            assert_eq!(evset.len(), g.ways);
+            assert_eq!(evset.set, Some(0x123));
+            for addr in evset.addrs.iter() {
+                let paddr = PageMap::resolve_paddr(*addr).unwrap();
+                assert_eq!(g.set_index(paddr), 0x123);
+                assert_eq!(evset.is_congruent(*addr), Some(true));
+            }
+        }
+    }
+}
@@ -0,0 +1,135 @@
+//! Emitted probe gadgets.
+
+use crate::asm::*;
+use crate::harness::MeasuredFn;
+use crate::timer::Timer;
+use dynasmrt::{
+    dynasm, DynasmApi, DynasmLabelApi, AssemblyOffset, ExecutableBuffer
+};
+
+/// A block of emitted code with the [`MeasuredFn`] calling convention.
+///
+/// Gadgets only use caller-saved registers, and can be called directly
+/// from Rust code.
+pub struct Gadget {
+    buf: ExecutableBuffer,
+    func: MeasuredFn,
+}
+impl Gadget {
+    /// Assemble a new gadget (a RET is emitted after `emit`).
+    pub fn assemble(emit: impl FnOnce(&mut X64Assembler)) -> Self {
+        let mut f = X64Assembler::new().unwrap();
+        emit(&mut f);
+        f.emit_ret();
+        f.commit().unwrap();
+        let buf = f.finalize().unwrap();
+        let func: MeasuredFn = unsafe {
+            std::mem::transmute(buf.ptr(AssemblyOffset(0)))
+        };
+        Self { buf, func }
+    }
+
+    pub fn as_fn(&self) -> MeasuredFn { self.func }
+
+    pub fn addr(&self) -> usize { self.func as usize }
+
+    #[inline(always)]
+    pub fn call(&self, rdi: usize, rsi: usize) -> usize {
+        (self.func)(rdi, rsi)
+    }
+}
+
+impl Gadget {
+    /// Scratch register used to hold the start time.
+    const SCRATCH: u8 = Gpr::R8 as u8;
+
+    /// Load from the address in RDI.
+    pub fn touch() -> Self {
+        Self::assemble(|f| {
+            dynasm!(f
+                ; mov rax, [rdi]
+                ; lfence
+            );
+        })
+    }
+
+    /// Flush the line containing the address in RDI.
+    pub fn flush() -> Self {
+        Self::assemble(|f| {
+            dynasm!(f
+                ; clflush [rdi]
+                ; mfence
+                ; lfence
+            );
+        })
+    }
+
+    /// Measure a load from the address in RDI.
+    pub fn reload(timer: Timer) -> Self {
+        Self::assemble(|f| {
+            f.emit_timer_start(timer, Self::SCRATCH);
+            dynasm!(f ; mov r9, [rdi]);
+            f.emit_timer_end(timer, Self::SCRATCH, Gpr::Rax as u8);
+        })
+    }
+
+    /// Load from each address in `addrs`.
+    pub fn traverse(addrs: &[usize]) -> Self {
+        Self::assemble(|f| {
+            Self::emit_loads(f, addrs);
+            dynasm!(f ; lfence);
+        })
+    }
+
+    /// Follow a linked list of pointers starting at the address in RDI
+    /// (until reaching a null pointer).
+    pub fn chase() -> Self {
+        Self::assemble(|f| {
+            dynasm!(f
+                ; ->chase:
+                ; test rdi, rdi
+                ; jz ->done
+                ; mov rdi, [rdi]
+                ; jmp ->chase
+                ; ->done:
+                ; lfence
+            );
+        })
+    }
+
+    /// Measure a load from each address in `addrs`.
+    pub fn timed_traverse(timer: Timer, addrs: &[usize]) -> Self {
+        Self::assemble(|f| {
+            f.emit_timer_start(timer, Self::SCRATCH);
+            Self::emit_loads(f, addrs);
+            f.emit_timer_end(timer, Self::SCRATCH, Gpr::Rax as u8);
+        })
+    }
+
+    /// Measure a call to the function in RDI (with RSI as its first
+    /// argument).
+    pub fn timed_call(timer: Timer) -> Self {
+        Self::assemble(|f| {
+            dynasm!(f
+                ; mov r10, rdi
+                ; mov rdi, rsi
+            );
+            f.emit_timer_start(timer, Self::SCRATCH);
+            dynasm!(f
+                ; push r8
+                ; call r10
+                ; pop r8
+            );
+            f.emit_timer_end(timer, Self::SCRATCH, Gpr::Rax as u8);
+        })
+    }
+
+    fn emit_loads(f: &mut X64Assembler, addrs: &[usize]) {
+        for addr in addrs {
+            dynasm!(f
+                ; mov r9, QWORD *addr as _
+                ; mov r9, [r9]
+            );
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Cache timing primitives.
+
+use crate::harness::MeasuredFn;
+use crate::sidechannel::*;
+use crate::timer::Timer;
+
+/// Flush+Reload on a shared line.
+///
+/// A probe is a timed load from the target address, followed by CLFLUSH.
+/// A [`Access::Hit`] means that the line was accessed since the last probe.
+pub struct FlushReload {
+    pub timer: Timer,
+    pub threshold: Option<Threshold>,
+    touch: Gadget,
+    flush: Gadget,
+    reload: Gadget,
+}
+impl FlushReload {
+    pub fn new(timer: Timer) -> Self {
+        Self {
+            timer,
+            threshold: None,
+            touch: Gadget::touch(),
+            flush: Gadget::flush(),
+            reload: Gadget::reload(timer),
+        }
+    }
+
+    pub fn touch(&self, addr: usize) { self.touch.call(addr, 0); }
+    pub fn flush(&self, addr: usize) { self.flush.call(addr, 0); }
+
+    /// Measure a load from `addr` (without flushing it).
+    pub fn reload(&self, addr: usize) -> usize { self.reload.call(addr, 0) }
+
+    /// Measure a load from `addr`, and then flush it.
+    pub fn probe(&self, addr: usize) -> ProbeResult {
+        let threshold = self.threshold.expect("FlushReload is not calibrated");
+        let latency = self.reload(addr);
+        self.flush(addr);
+        ProbeResult::new(latency, &threshold)
+    }
+
+    /// Collect hit/miss latencies for `addr` and compute a threshold.
+    pub fn calibrate(&mut self, addr: usize, iters: usize)
+        -> Result<Threshold, String>
+    {
+        let mut hits = Vec::with_capacity(iters);
+        let mut misses = Vec::with_capacity(iters);
+        for _ in 0..iters {
+            self.touch(addr);
+            hits.push(self.reload(addr));
+            self.flush(addr);
+            misses.push(self.reload(addr));
+        }
+        let threshold = Threshold::calibrate(&hits, &misses)?;
+        self.threshold = Some(threshold);
+        Ok(threshold)
+    }
+}
+
+/// Prime+Probe on a single cache set.
+///
+/// A probe is a timed traversal of the eviction set (which also primes the
+/// set for the next probe). A [`Access::Miss`] means that some line in the
+/// set was evicted since the last probe.
+pub struct PrimeProbe {
+    pub timer: Timer,
+    pub threshold: Option<Threshold>,
+    pub evset: EvictionSet,
+    touch: Gadget,
+    prime: Gadget,
+    probe: Gadget,
+}
+impl PrimeProbe {
+    pub fn new(timer: Timer, evset: EvictionSet) -> Self {
+        Self {
+            timer,
+            threshold: None,
+            touch: Gadget::touch(),
+            prime: Gadget::traverse(&evset.addrs),
+            probe: Gadget::timed_traverse(timer, &evset.addrs),
+            evset,
+        }
+    }
+
+    /// Fill the set with lines from the eviction set.
+    pub fn prime(&self) { self.prime.call(0, 0); }
+
+    /// Measure a traversal of the eviction set.
+    pub fn measure(&self) -> usize { self.probe.call(0, 0) }
+
+    pub fn probe(&self) -> ProbeResult {
+        let threshold = self.threshold.expect("PrimeProbe is not calibrated");
+        ProbeResult::new(self.measure(), &threshold)
+    }
+
+    /// Collect latencies for a primed set (hits) and for a primed set after
+    /// a load from `evictor` (misses), and compute a threshold.
+    ///
+    /// The `evictor` address must be congruent with (but not a member of)
+    /// the eviction set. When congruence cannot be checked (ie. for a
+    /// physically-indexed cache without access to physical addresses),
+    /// the caller is trusted.
+    pub fn calibrate(&mut self, evictor: usize, iters: usize)
+        -> Result<Threshold, String>
+    {
+        if self.evset.is_congruent(evictor) == Some(false)
+            || self.evset.addrs.contains(&evictor)
+        {
+            return Err(format!("Address {:#x} cannot be used as an evictor \
+                for the {} eviction set", evictor, self.evset.geometry.name
+            ));
+        }
+        let mut hits = Vec::with_capacity(iters);
+        let mut misses = Vec::with_capacity(iters);
+        for _ in 0..iters {
+            self.prime();
+            hits.push(self.measure());
+            self.prime();
+            self.touch.call(evictor, 0);
+            misses.push(self.measure());
+        }
+        let threshold = Threshold::calibrate(&hits, &misses)?;
+        self.threshold = Some(threshold);
+        Ok(threshold)
+    }
+}
+
+/// Evict+Time on a single cache set.
+///
+/// A probe evicts the set and then measures a call to the victim.
+/// A [`Access::Miss`] means that the victim accessed the evicted set.
+pub struct EvictTime {
+    pub timer: Timer,
+    pub threshold: Option<Threshold>,
+    pub evset: EvictionSet,
+    evict: Gadget,
+    time: Gadget,
+}
+impl EvictTime {
+    pub fn new(timer: Timer, evset: EvictionSet) -> Self {
+        Self {
+            timer,
+            threshold: None,
+            evict: Gadget::traverse(&evset.addrs),
+            time: Gadget::timed_call(timer),
+            evset,
+        }
+    }
+
+    /// Evict the set.
+    pub fn evict(&self) { self.evict.call(0, 0); }
+
+    /// Measure a call to `victim(arg, _)`.
+    pub fn time(&self, victim: MeasuredFn, arg: usize) -> usize {
+        self.time.call(victim as usize, arg)
+    }
+
+    pub fn probe(&self, victim: MeasuredFn, arg: usize) -> ProbeResult {
+        let threshold = self.threshold.expect("EvictTime is not calibrated");
+        self.evict();
+        ProbeResult::new(self.time(victim, arg), &threshold)
+    }
+
+    /// Collect latencies for a warm victim (hits) and for the victim after
+    /// evicting the set (misses), and compute a threshold.
+    pub fn calibrate(&mut self, victim: MeasuredFn, arg: usize, iters: usize)
+        -> Result<Threshold, String>
+    {
+        let mut hits = Vec::with_capacity(iters);
+        let mut misses = Vec::with_capacity(iters);
+        for _ in 0..iters {
+            self.time(victim, arg);
+            hits.push(self.time(victim, arg));
+            self.evict();
+            misses.push(self.time(victim, arg));
+        }
+        let threshold = Threshold::calibrate(&hits, &misses)?;
+        self.threshold = Some(threshold);
+        Ok(threshold)
+    }
+}
+
+/// Collide+Probe on a single load.
+///
+/// A probe is a timed load (minus the overhead of the timer). Unlike the
+/// other primitives, the conditions for a collision depend on the
+/// experiment, so the threshold must be computed by the caller (for
+/// instance, with [`Threshold::calibrate`]).
+pub struct CollideProbe {
+    pub timer: Timer,
+    pub threshold: Option<Threshold>,
+    /// Minimum observed overhead of the timer
+    /// (see [`CollideProbe::calibrate_floor`])
+    pub floor: usize,
+    reload: Gadget,
+}
+impl CollideProbe {
+    /// Number of measurements used to determine the timer overhead.
+    const FLOOR_ITERS: usize = 128;
+
+    pub fn new(timer: Timer) -> Self {
+        Self {
+            timer,
+            threshold: None,
+            floor: timer.overhead(Self::FLOOR_ITERS),
+            reload: Gadget::reload(timer),
+        }
+    }
+
+    /// Measure the overhead of the timer again, ie. before each test,
+    /// since it may drift between tests.
+    pub fn calibrate_floor(&mut self) -> usize {
+        self.floor = self.timer.overhead(Self::FLOOR_ITERS);
+        self.floor
+    }
+
+    /// Measure a load from `addr`.
+    pub fn measure(&self, addr: usize) -> usize {
+        self.reload.call(addr, 0).saturating_sub(self.floor)
+    }
+
+    pub fn probe(&self, addr: usize) -> ProbeResult {
+        let threshold = self.threshold.expect("CollideProbe is not calibrated");
+        ProbeResult::new(self.measure(addr), &threshold)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sidechannel_gadgets() {
+        let buf = vec![0u8; 0x4_0000];
+        let base = buf.as_ptr() as usize;
+
+        let mut fr = FlushReload::new(Timer::RDTSC);
+        let t = fr.calibrate(base, 256).unwrap();
+        assert!(t.hit_median < t.miss_median);
+        fr.touch(base);
+        fr.probe(base);
+
+        let evset = EvictionSet::congruent(CacheGeometry::ZEN2_L1D,
+            base, buf.len(), 5, 8
+        ).unwrap();
+        assert!(evset.addrs.iter().all(|a| evset.is_congruent(*a) == Some(true)));
+        let pp = PrimeProbe::new(Timer::RDTSC, evset.clone());
+        pp.prime();
+        pp.measure();
+        assert!(EvictionSet::congruent(CacheGeometry::ZEN2_L1D,
+            base, buf.len(), 5, 1024).is_err()
+        );
+
+        let victim = Gadget::touch();
+        let et = EvictTime::new(Timer::RDTSC, evset);
+        et.evict();
+        et.time(victim.as_fn(), base);
+
+        let mut cp = CollideProbe::new(Timer::RDTSC);
+        cp.calibrate_floor();
+        cp.measure(base);
+    }
+}
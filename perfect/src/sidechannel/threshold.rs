@@ -0,0 +1,226 @@
+//! Classifying probe latencies.
+
+use crate::stats::RawResults;
+
+/// The outcome of a single probe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Access {
+    /// The probe was fast (below the threshold).
+    Hit,
+    /// The probe was slow (above the threshold).
+    Miss,
+}
+
+/// A threshold for sorting probe latencies into hits and misses.
+///
+/// Latencies less than or equal to `cut` are classified as [`Access::Hit`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Threshold {
+    /// The largest latency classified as a hit.
+    pub cut: usize,
+    /// Median latency of the hit distribution used for calibration.
+    pub hit_median: usize,
+    /// Median latency of the miss distribution used for calibration.
+    pub miss_median: usize,
+    /// Fraction of the calibration samples which were misclassified.
+    pub error: f32,
+}
+impl Threshold {
+    /// Create a threshold with a fixed cut (ie. without calibration).
+    pub fn new(cut: usize) -> Self {
+        Self { cut, hit_median: 0, miss_median: 0, error: 0.0 }
+    }
+
+    /// Compute a threshold from distributions of latencies which are
+    /// known to be hits and misses.
+    ///
+    /// The cut is placed where the number of misclassified samples is
+    /// smallest. When more than one cut is equally good, this picks the
+    /// midpoint of the gap between the hit and miss latencies around it.
+    pub fn calibrate(hits: &[usize], misses: &[usize]) -> Result<Self, String> {
+        if hits.is_empty() || misses.is_empty() {
+            return Err("Calibration requires both hit and miss samples".to_string());
+        }
+        let mut hits = hits.to_vec();
+        let mut misses = misses.to_vec();
+        hits.sort_unstable();
+        misses.sort_unstable();
+
+        let hit_median = hits[hits.len() / 2];
+        let miss_median = misses[misses.len() / 2];
+        if hit_median >= miss_median {
+            return Err(format!(
+                "Hits are not faster than misses (median {} vs. {})",
+                hit_median, miss_median
+            ));
+        }
+
+        // Number of misclassified samples when 'cut' is the threshold
+        let errors = |cut: usize| {
+            let slow_hits = hits.len() - hits.partition_point(|x| *x <= cut);
+            let fast_misses = misses.partition_point(|x| *x <= cut);
+            slow_hits + fast_misses
+        };
+
+        // Candidate cuts are all of the observed latencies
+        let mut candidates: Vec<usize> = hits.iter().chain(misses.iter())
+            .copied().collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+
+        let (idx, min_err) = candidates.iter().enumerate()
+            .map(|(idx, cut)| (idx, errors(*cut)))
+            .min_by_key(|(_, err)| *err)
+            .unwrap();
+
+        // Move the cut into the middle of the gap before the next sample
+        let lo = candidates[idx];
+        let cut = match candidates.get(idx + 1) {
+            Some(hi) => lo + (hi - lo - 1) / 2,
+            None => lo,
+        };
+
+        Ok(Self {
+            cut,
+            hit_median,
+            miss_median,
+            error: min_err as f32 / (hits.len() + misses.len()) as f32,
+        })
+    }
+
+    /// Like [`Threshold::calibrate`], but for [`RawResults`].
+    pub fn calibrate_results(hits: &RawResults, misses: &RawResults)
+        -> Result<Self, String>
+    {
+        Self::calibrate(&hits.0, &misses.0)
+    }
+
+    /// Classify a single latency.
+    pub fn classify(&self, latency: usize) -> Access {
+        if latency <= self.cut { Access::Hit } else { Access::Miss }
+    }
+
+    /// Returns the fraction of samples misclassified by this threshold.
+    pub fn error_rate(&self, hits: &[usize], misses: &[usize]) -> f32 {
+        let total = hits.len() + misses.len();
+        if total == 0 {
+            return 0.0;
+        }
+        let wrong = hits.iter().filter(|x| self.classify(**x) == Access::Miss)
+            .count()
+            + misses.iter().filter(|x| self.classify(**x) == Access::Hit)
+            .count();
+        wrong as f32 / total as f32
+    }
+}
+
+/// The result of a single probe.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ProbeResult {
+    /// The measured latency.
+    pub latency: usize,
+    /// The classified outcome.
+    pub access: Access,
+}
+impl ProbeResult {
+    pub fn new(latency: usize, threshold: &Threshold) -> Self {
+        Self { latency, access: threshold.classify(latency) }
+    }
+    pub fn is_hit(&self) -> bool { self.access == Access::Hit }
+    pub fn is_miss(&self) -> bool { self.access == Access::Miss }
+}
+
+/// A list of results from repeated probes.
+#[derive(Clone, Debug, Default)]
+pub struct ProbeResults(pub Vec<ProbeResult>);
+impl ProbeResults {
+    /// Classify a list of latencies.
+    pub fn classify(latencies: &[usize], threshold: &Threshold) -> Self {
+        Self(latencies.iter().map(|x| ProbeResult::new(*x, threshold)).collect())
+    }
+
+    pub fn len(&self) -> usize { self.0.len() }
+    pub fn is_empty(&self) -> bool { self.0.is_empty() }
+    pub fn push(&mut self, res: ProbeResult) { self.0.push(res) }
+    pub fn iter(&self) -> impl Iterator<Item = &ProbeResult> { self.0.iter() }
+
+    pub fn hits(&self) -> usize {
+        self.0.iter().filter(|r| r.is_hit()).count()
+    }
+    pub fn misses(&self) -> usize {
+        self.0.iter().filter(|r| r.is_miss()).count()
+    }
+
+    /// Fraction of probes which were hits.
+    pub fn hit_rate(&self) -> f32 {
+        if self.0.is_empty() {
+            return 0.0;
+        }
+        self.hits() as f32 / self.0.len() as f32
+    }
+
+    /// Return the measured latencies.
+    pub fn latencies(&self) -> RawResults {
+        RawResults(self.0.iter().map(|r| r.latency).collect())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::rng::seeded_rng;
+    use rand::Rng;
+
+    /// Flush+Reload latencies (RDTSC) recorded on an Intel Xeon machine
+    /// (in a virtual machine).
+    const RECORDED_HITS: [usize; 32] = [
+        100, 98, 100, 96, 96, 76, 80, 80, 102, 102, 98, 102, 102, 100, 96, 78,
+        80, 94, 106, 100, 98, 88, 76, 96, 96, 94, 100, 98, 100, 100, 102, 80,
+    ];
+    const RECORDED_MISSES: [usize; 32] = [
+        332, 324, 310, 294, 324, 606, 306, 330, 316, 306, 320, 398, 304, 308,
+        312, 320, 312, 454, 306, 314, 318, 348, 298, 296, 372, 566, 306, 316,
+        352, 446, 314, 306,
+    ];
+
+    #[test]
+    fn threshold_recorded() {
+        let t = Threshold::calibrate(&RECORDED_HITS, &RECORDED_MISSES).unwrap();
+        assert!(t.cut >= 106 && t.cut < 294, "{:?}", t);
+        assert_eq!(t.hit_median, 98);
+        assert_eq!(t.miss_median, 316);
+        assert_eq!(t.error, 0.0);
+        assert_eq!(t.error, t.error_rate(&RECORDED_HITS, &RECORDED_MISSES));
+
+        let res = ProbeResults::classify(&RECORDED_HITS, &t);
+        assert_eq!(res.hit_rate(), 1.0);
+        assert_eq!(res.latencies().0, RECORDED_HITS.to_vec());
+        let res = ProbeResults::classify(&RECORDED_MISSES, &t);
+        assert_eq!(res.misses(), RECORDED_MISSES.len());
+
+        // A fixed threshold misclassifies part of the distribution
+        let t = Threshold::new(96);
+        assert_eq!(t.classify(96), Access::Hit);
+        assert_eq!(t.classify(98), Access::Miss);
+        assert!(t.error_rate(&RECORDED_HITS, &RECORDED_MISSES) > 0.0);
+    }
+
+    #[test]
+    fn threshold_overlapping() {
+        // Two overlapping distributions with noise
+        let mut rng = seeded_rng(0x5ca1ab1e);
+        let hits: Vec<usize> = (0..4096)
+            .map(|_| 40 + rng.gen_range(0..20) + rng.gen_range(0..20))
+            .collect();
+        let misses: Vec<usize> = (0..4096)
+            .map(|_| 70 + rng.gen_range(0..20) + rng.gen_range(0..20))
+            .collect();
+        let t = Threshold::calibrate(&hits, &misses).unwrap();
+        assert!(t.cut >= 70 && t.cut <= 80, "{:?}", t);
+        assert!(t.error < 0.05, "{:?}", t);
+
+        // Swapped distributions are rejected
+        assert!(Threshold::calibrate(&misses, &hits).is_err());
+        assert!(Threshold::calibrate(&[], &misses).is_err());
+    }
+}
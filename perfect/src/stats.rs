@@ -140,14 +140,38 @@ pub struct MeasureResults {
     /// Set of recorded [vector] GPR states across all test iterations
     pub vgpr_dumps: Option<Vec<VectorGprState>>,
 
+    /// Set of recorded extended states (RFLAGS, x87, MXCSR, AVX-512) 
+    /// across all test iterations
+    pub xstate_dumps: Option<Vec<ExtState>>,
+
     /// Set of inputs (from RDI and RSI) across all test iterations
     pub inputs: Option<Vec<(usize, usize)>>,
+
+    /// Seed for the RNG passed to [`InputMethod`](crate::harness::InputMethod)
+    /// while generating inputs. Passing `seeded_rng(seed)` to
+    /// [`InputMethod::args`](crate::harness::InputMethod::args) reproduces
+    /// the same inputs.
+    pub seed: u64,
+
+    /// Set of faults (and the index of the associated test iteration) that 
+    /// occurred while running measured code. The result for a faulted 
+    /// iteration is always zero. 
+    pub faults: Vec<(usize, FaultRecord)>,
 }
 impl MeasureResults {
     /// Return the PMC event ID associated with these results.
     pub fn event_id(&self) -> u16 { self.event.id() }
     /// Return the PMC event mask associated with these results.
     pub fn event_mask(&self) -> u8 { self.event.mask() }
+    /// Return the [`FaultRecord`] for a particular test iteration (if the 
+    /// iteration faulted).
+    pub fn fault(&self, iter: usize) -> Option<&FaultRecord> {
+        self.faults.iter().find(|(idx, _)| *idx == iter).map(|(_, f)| f)
+    }
+    /// Returns true if a particular test iteration faulted.
+    pub fn is_faulted(&self, iter: usize) -> bool {
+        self.fault(iter).is_some()
+    }
 }
 
 /// Implemented on suitable "input" variable types associated with a particular 
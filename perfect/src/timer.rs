@@ -0,0 +1,270 @@
+//! Sources of timing information.
+//!
+//! A [`Timer`] describes how a measurement is taken: the instruction used to
+//! read a counter ([`TimerSource`]) and the barriers emitted around each read
+//! ([`FencePolicy`]). The same [`Timer`] can be used to emit measurements
+//! (see [`Emitter::emit_timer_start`] and [`Emitter::emit_timer_end`]), or to
+//! read the counter directly from Rust code (see [`Timer::read`]).
+//!
+//! Timers can also be parsed from strings in the form `<source>[:<fence>]`
+//! (ie. `rdtsc`, `aperf:mfence`, or `rdpmc0:none`), which makes it easy for
+//! an experiment to select a timer with a command-line flag.
+
+use crate::asm::*;
+use crate::harness::MeasuredFn;
+use crate::util::cpuid::HostCpuid;
+use dynasmrt::AssemblyOffset;
+use iced_x86::CpuidFeature;
+use std::str::FromStr;
+
+/// An instruction used to read a counter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TimerSource {
+    /// Time-stamp counter (RDTSC)
+    Rdtsc,
+    /// Time-stamp counter (RDTSCP, which waits for older instructions)
+    Rdtscp,
+    /// APERF via RDPRU (AMD Zen 2 and later)
+    Aperf,
+    /// MPERF via RDPRU (AMD Zen 2 and later)
+    Mperf,
+    /// A performance counter via RDPMC (with the given counter index)
+    Rdpmc(u32),
+}
+impl TimerSource {
+    /// Returns true if the host can execute the instruction for this source.
+    ///
+    /// NOTE: RDPMC is always reported as supported here, although it only
+    /// works when userspace access to counters has been enabled (and when
+    /// some counter has actually been programmed).
+    pub fn supported(&self) -> bool {
+        let cpuid = HostCpuid::read();
+        match self {
+            Self::Rdtsc => cpuid.supports(CpuidFeature::TSC),
+            Self::Rdtscp => cpuid.supports(CpuidFeature::RDTSCP),
+            Self::Aperf | Self::Mperf => cpuid.supports(CpuidFeature::RDPRU),
+            Self::Rdpmc(_) => cpuid.supports(CpuidFeature::RDPMC),
+        }
+    }
+
+    /// Read the counter (without any fences).
+    #[inline(always)]
+    pub fn read(&self) -> usize {
+        use core::arch::x86_64::{ _rdtsc, __rdtscp };
+        let rdpru = |ecx: u32| unsafe {
+            let (lo, hi): (u32, u32);
+            core::arch::asm!("rdpru",
+                in("ecx") ecx, out("eax") lo, out("edx") hi,
+                options(nomem, nostack),
+            );
+            (hi as usize) << 32 | lo as usize
+        };
+        match self {
+            Self::Rdtsc => unsafe { _rdtsc() as usize },
+            Self::Rdtscp => unsafe {
+                let mut aux = 0;
+                __rdtscp(&mut aux) as usize
+            },
+            Self::Aperf => rdpru(1),
+            Self::Mperf => rdpru(0),
+            Self::Rdpmc(ctr) => unsafe {
+                let (lo, hi): (u32, u32);
+                core::arch::asm!("rdpmc",
+                    in("ecx") *ctr, out("eax") lo, out("edx") hi,
+                    options(nomem, nostack),
+                );
+                (hi as usize) << 32 | lo as usize
+            },
+        }
+    }
+}
+impl FromStr for TimerSource {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rdtsc" => Ok(Self::Rdtsc),
+            "rdtscp" => Ok(Self::Rdtscp),
+            "aperf" => Ok(Self::Aperf),
+            "mperf" => Ok(Self::Mperf),
+            _ => match s.strip_prefix("rdpmc") {
+                Some(ctr) => ctr.parse().map(Self::Rdpmc)
+                    .map_err(|_| format!("Invalid RDPMC counter '{}'", ctr)),
+                None => Err(format!("Unknown timer source '{}'", s)),
+            },
+        }
+    }
+}
+
+/// Barriers emitted before and after reading a counter.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FencePolicy {
+    /// No barriers
+    None,
+    /// LFENCE (wait for older instructions to complete)
+    Lfence,
+    /// MFENCE followed by LFENCE (also wait for older stores)
+    Mfence,
+    /// CPUID (fully serializing, but relatively slow)
+    Cpuid,
+}
+impl FencePolicy {
+    /// Execute the barrier.
+    #[inline(always)]
+    pub fn fence(&self) {
+        use core::arch::x86_64::{ _mm_lfence, _mm_mfence, __cpuid };
+        unsafe {
+            match self {
+                Self::None => {},
+                Self::Lfence => _mm_lfence(),
+                Self::Mfence => {
+                    _mm_mfence();
+                    _mm_lfence();
+                },
+                Self::Cpuid => { __cpuid(0); },
+            }
+        }
+    }
+}
+impl FromStr for FencePolicy {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "lfence" => Ok(Self::Lfence),
+            "mfence" => Ok(Self::Mfence),
+            "cpuid" => Ok(Self::Cpuid),
+            _ => Err(format!("Unknown fence policy '{}'", s)),
+        }
+    }
+}
+
+/// A [`TimerSource`] with a [`FencePolicy`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct Timer {
+    pub source: TimerSource,
+    pub fence: FencePolicy,
+}
+impl Timer {
+    pub const RDTSC: Self = Self::new(TimerSource::Rdtsc);
+    pub const RDTSCP: Self = Self::new(TimerSource::Rdtscp);
+    pub const APERF: Self = Self::new(TimerSource::Aperf);
+    pub const MPERF: Self = Self::new(TimerSource::Mperf);
+
+    /// Create a timer (with [`FencePolicy::Lfence`]).
+    pub const fn new(source: TimerSource) -> Self {
+        Self { source, fence: FencePolicy::Lfence }
+    }
+
+    pub const fn fence(mut self, fence: FencePolicy) -> Self {
+        self.fence = fence;
+        self
+    }
+
+    pub fn supported(&self) -> bool { self.source.supported() }
+
+    /// Read the counter from Rust code (with the same barriers that would
+    /// be emitted for a measurement).
+    #[inline(always)]
+    pub fn read(&self) -> usize {
+        self.fence.fence();
+        let res = self.source.read();
+        self.fence.fence();
+        res
+    }
+
+    /// Return the minimum value observed for an empty measurement
+    /// (over `iters` attempts).
+    pub fn overhead(&self, iters: usize) -> usize {
+        let mut f = X64Assembler::new().unwrap();
+        f.emit_timer_start(*self, Gpr::R8 as u8);
+        f.emit_timer_end(*self, Gpr::R8 as u8, Gpr::Rax as u8);
+        f.emit_ret();
+        f.commit().unwrap();
+        let buf = f.finalize().unwrap();
+        let func: MeasuredFn = unsafe {
+            std::mem::transmute(buf.ptr(AssemblyOffset(0)))
+        };
+        (0..iters).map(|_| func(0, 0)).min().unwrap_or(0)
+    }
+}
+impl Default for Timer {
+    fn default() -> Self { Self::RDTSC }
+}
+impl FromStr for Timer {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            Some((source, fence)) => {
+                Ok(Self::new(source.parse()?).fence(fence.parse()?))
+            },
+            None => Ok(Self::new(s.parse()?)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use dynasmrt::{ dynasm, DynasmApi, DynasmLabelApi };
+
+    #[test]
+    fn timer_parse() {
+        assert_eq!("rdtsc".parse(), Ok(Timer::RDTSC));
+        assert_eq!("aperf:mfence".parse(),
+            Ok(Timer::APERF.fence(FencePolicy::Mfence))
+        );
+        assert_eq!("rdpmc3:none".parse(),
+            Ok(Timer::new(TimerSource::Rdpmc(3)).fence(FencePolicy::None))
+        );
+        assert!("rdpmcx".parse::<Timer>().is_err());
+        assert!("rdtsc:sfence".parse::<Timer>().is_err());
+    }
+
+    #[test]
+    fn timer_measure() {
+        let fences = [
+            FencePolicy::None, FencePolicy::Lfence,
+            FencePolicy::Mfence, FencePolicy::Cpuid,
+        ];
+        let sources = [TimerSource::Rdtsc, TimerSource::Rdtscp];
+        for source in sources.iter().filter(|s| s.supported()) {
+            for fence in fences {
+                let timer = Timer::new(*source).fence(fence);
+                let overhead = timer.overhead(64);
+
+                // Measure a long-running loop (with the result in R9, and
+                // with RBX preserved across CPUID)
+                let mut f = X64Assembler::new().unwrap();
+                dynasm!(f
+                    ; push rbx
+                    ; mov rbx, 0x1234
+                );
+                f.emit_timer_start(timer, Gpr::R8 as u8);
+                dynasm!(f
+                    ; mov rdi, 0x1_0000
+                    ; loop_top:
+                    ; dec rdi
+                    ; jnz <loop_top
+                );
+                f.emit_timer_end(timer, Gpr::R8 as u8, Gpr::R9 as u8);
+                dynasm!(f
+                    ; cmp rbx, 0x1234
+                    ; mov rax, 0
+                    ; cmove rax, r9
+                    ; pop rbx
+                    ; ret
+                );
+                f.commit().unwrap();
+                let buf = f.finalize().unwrap();
+                let func: MeasuredFn = unsafe {
+                    std::mem::transmute(buf.ptr(AssemblyOffset(0)))
+                };
+                let res = (0..16).map(|_| func(0, 0)).min().unwrap();
+                assert!(res > overhead, "{:?}: {} <= {}", timer, res, overhead);
+
+                let (t0, t1) = (timer.read(), timer.read());
+                assert!(t1 > t0);
+            }
+        }
+    }
+}
@@ -1,4 +1,65 @@
+//! Models of microarchitectural structures.
+//!
+//! Definitions for each platform live in their own module (ie. [`zen2`]
+//! and [`zen3`]). Each platform is a type implementing [`L1dGeometry`]
+//! and/or [`BtbGeometry`], which can be used to parameterize code shared
+//! between platforms (see [`vaddr::VirtualAddress`]).
+//!
+//! NOTE: The hash functions have only been recovered for Zen 2. There are
+//! no definitions for Tremont yet.
+
+use crate::ir::bf::BfProg;
 
 pub mod btb; 
+pub mod direction;
 pub mod l1d;
+pub mod ras;
 pub mod vaddr; 
+pub mod zen2;
+pub mod zen3;
+
+pub use zen2::*;
+pub use zen3::*;
+
+/// Parameters for the L1 data cache on some platform.
+///
+/// Lines are always 64 bytes. The set index is taken from the bits
+/// immediately above the offset.
+///
+/// Platforms are unit types, so this also requires the traits derived 
+/// for types parameterized by a platform. 
+pub trait L1dGeometry: Copy + Ord + std::fmt::Debug {
+    /// Number of sets
+    const SETS: usize;
+    /// Number of ways in each set
+    const WAYS: usize;
+    /// Virtual address bits used as input to [`L1dGeometry::UTAG_FN`]
+    const UTAG_MASK: usize;
+    /// The function used to compute the utag for a virtual address
+    const UTAG_FN: BfProg<8>;
+
+    /// Return the set index for an address.
+    fn set(vaddr: usize) -> usize { (vaddr >> 6) & (Self::SETS - 1) }
+
+    /// Return the utag for a virtual address.
+    fn utag(vaddr: usize) -> usize { Self::UTAG_FN.evaluate(vaddr) }
+}
+
+/// Parameters for the BTB on some platform.
+///
+/// The set is selected with the low bits of the BTB index, and the
+/// remaining bits of the index are part of the tag.
+pub trait BtbGeometry {
+    /// Number of sets
+    const SETS: usize;
+    /// Number of ways in each set
+    const WAYS: usize;
+    /// The function used to compute the BTB index for a virtual address
+    const INDEX_FN: BfProg<12>;
+
+    /// Return the BTB index for an address.
+    fn index(vaddr: usize) -> usize { Self::INDEX_FN.evaluate(vaddr) }
+
+    /// Return the set for an address.
+    fn set(vaddr: usize) -> usize { Self::index(vaddr) & (Self::SETS - 1) }
+}
@@ -1,30 +1,12 @@
-
 use crate::ir::bf::*;
+use crate::uarch::*;
 use std::collections::*;
 use bitvec::prelude::*;
 use itertools::*;
 
-/// The function used to compute the BTB index on Zen 2. 
-///
-/// This is reproduced from the description in the RETBLEED paper[^1].
-/// [^1]: [RETBLEED: Arbitrary Speculative Code Execution with Return Instructions](https://comsec.ethz.ch/wp-content/files/retbleed_sec22.pdf)
-pub const ZEN2_BTB_INDEX_FN: BfProg<12> = {  
-    use BfOp::*;
-    BfProg([ 
-        Xor(&[In(36), In(24)]),
-        Xor(&[In(37), In(25)]),
-        Xor(&[In(38), In(26)]),
-        Xor(&[In(39), In(27), And(&[In(15), In(10)])]),
-        Xor(&[In(40), In(28), And(&[In(16), In(11)])]),
-        Xor(&[In(41), In(29), In(17)]),
-        Xor(&[In(42), In(30), In(18)]),
-        Xor(&[In(43), In(31), In(19)]),
-        Xor(&[In(44), In(32), In(20)]),
-        Xor(&[In(45), In(33), In(21)]),
-        Xor(&[In(46), In(34), In(22)]),
-        Xor(&[In(47), In(35), In(23)]),
-    ])
-};
+/// Moved to [`crate::uarch::zen2`] (re-exported for compatibility).
+pub use crate::uarch::zen2::ZEN2_BTB_INDEX_FN;
+
 
 /// Operations used to create BTB collisions
 pub enum BTBCollideOp { 
@@ -32,9 +14,8 @@ pub enum BTBCollideOp {
     Flip(usize, usize),
     /// Set the value of three bits
     Set3((usize, usize, usize), (bool, bool, bool)),
-
-    ///// Set the value of five bits
-    //Set5((usize, usize, usize), (bool, bool, bool), (usize, usize), (bool, bool)),
+    /// Set the value of four bits
+    Set4((usize, usize, usize, usize), (bool, bool, bool, bool)),
 }
 
 /// Compute the allowed operations on the given address that do not affect 
@@ -49,11 +30,12 @@ pub enum BTBCollideOp {
 /// 2. For the 3-input XOR gates, the parity (whether or not the number
 ///    of set bits is even or odd) must be the same. 
 ///
-/// Evaluating the powerset of the resulting list would yield the set of *all* 
-/// possible addresses whose BTB index is aliasing with `vaddr`. 
+/// 3. For the XOR gates with an AND gate as one of the inputs, the parity
+///    must be the same after taking the output of the AND gate. 
 ///
-/// FIXME: We're just skipping the bits with AND gates here because the 
-/// cases with only XOR gates are easiest. 
+/// Evaluating the powerset of the resulting list would yield the set of *all* 
+/// possible addresses whose BTB index is aliasing with `vaddr` (see
+/// [`BtbAliases`] for enumerating them directly). 
 ///
 pub fn zen2_btb_valid_ops(vaddr: usize) -> Vec<BTBCollideOp> { 
 
@@ -84,7 +66,7 @@ pub fn zen2_btb_valid_ops(vaddr: usize) -> Vec<BTBCollideOp> {
     /// of a 2-input AND gate)
     const ANDXOR: [((usize, usize), (usize, usize)); 2] = [
         ( (10, 15), (27, 39) ),
-        ( (11, 16), (28, 30) ),
+        ( (11, 16), (28, 40) ),
     ];
 
     /// Bit indexes for 3-input XOR gates
@@ -107,29 +89,15 @@ pub fn zen2_btb_valid_ops(vaddr: usize) -> Vec<BTBCollideOp> {
 
     let bits = vaddr.view_bits::<Lsb0>();
 
-    //for ((x0, x1), (y, z)) in ANDXOR { 
-    //    let mut cnt = 0;
-    //    let x = bits[x0] & bits[x1];
-
-    //    if x { cnt += 1; }
-    //    if bits[y] { cnt += 1; }
-    //    if bits[z] { cnt += 1; }
-
-    //    if cnt & 1 == 1 { 
-    //        if x { 
-    //        } else { 
-    //        }
-    //        ops.push(BTBCollideOp::Set3((x, y, z), ODD[0]));
-    //        ops.push(BTBCollideOp::Set3((x, y, z), ODD[1]));
-    //        ops.push(BTBCollideOp::Set3((x, y, z), ODD[2]));
-    //        ops.push(BTBCollideOp::Set3((x, y, z), ODD[3]));
-    //    } else { 
-    //        ops.push(BTBCollideOp::Set3((x, y, z), EVEN[0]));
-    //        ops.push(BTBCollideOp::Set3((x, y, z), EVEN[1]));
-    //        ops.push(BTBCollideOp::Set3((x, y, z), EVEN[2]));
-    //        ops.push(BTBCollideOp::Set3((x, y, z), EVEN[3]));
-    //    }
-    //}
+    for ((x0, x1), (y, z)) in ANDXOR { 
+        let parity = (bits[x0] & bits[x1]) ^ bits[y] ^ bits[z];
+        for n in 0..16usize { 
+            let (a, b, c, d) = (n & 1 != 0, n & 2 != 0, n & 4 != 0, n & 8 != 0);
+            if (a & b) ^ c ^ d == parity { 
+                ops.push(BTBCollideOp::Set4((x0, x1, y, z), (a, b, c, d)));
+            }
+        }
+    }
 
     for (x,y,z) in XOR3 { 
         let mut cnt = 0;
@@ -180,6 +148,12 @@ pub fn zen2_btb_collisions(vaddr: usize, combinations: usize)
                     bits.set(*y, *b);
                     bits.set(*z, *c);
                 },
+                BTBCollideOp::Set4((w,x,y,z), (a,b,c,d)) => {
+                    bits.set(*w, *a);
+                    bits.set(*x, *b);
+                    bits.set(*y, *c);
+                    bits.set(*z, *d);
+                },
             }
         }
         let new_index = ZEN2_BTB_INDEX_FN.evaluate(res);
@@ -192,6 +166,244 @@ pub fn zen2_btb_collisions(vaddr: usize, combinations: usize)
     collisions
 }
 
+/// The set of all addresses whose BTB index is aliasing with some address.
+///
+/// The inputs to each output bit of the index function must be disjoint
+/// (which is the case for [`ZEN2_BTB_INDEX_FN`]). Then, each output bit can
+/// be considered separately: for each gate, we keep the list of assignments
+/// to its input bits that leave the output unchanged. Every combination of 
+/// these assignments (one for each gate) is a distinct aliasing address,
+/// and each combination is identified by a number in `0..len()`. 
+///
+/// Bits which are not used by the index function are never changed. 
+/// Note that the set also includes the original address. 
+pub struct BtbAliases {
+    /// The original address
+    pub vaddr: usize,
+    /// The input bits for each gate, and the valid assignments to them
+    gates: Vec<(Vec<usize>, Vec<usize>)>,
+}
+impl BtbAliases {
+    pub fn new<const SZ: usize>(func: &BfProg<SZ>, vaddr: usize) -> Self {
+        let inputs = func.inputs();
+        let mut used = BTreeSet::new();
+        for bit in inputs.iter().flatten() {
+            assert!(used.insert(*bit), 
+                "Input bit {} is used by more than one gate", bit);
+        }
+
+        let gates = func.0.iter().zip(inputs).map(|(op, bits)| {
+            let output = op.evaluate(vaddr);
+            let valid = (0..(1usize << bits.len()))
+                .filter(|n| op.evaluate(Self::deposit(vaddr, &bits, *n)) == output)
+                .collect();
+            (bits, valid)
+        }).collect();
+        Self { vaddr, gates }
+    }
+
+    /// Enumerate aliases with the Zen 2 BTB index function.
+    pub fn zen2(vaddr: usize) -> Self {
+        Self::new(&ZEN2_BTB_INDEX_FN, vaddr)
+    }
+
+    /// Replace the bits `bits` in `vaddr` with the bits in `value`. 
+    fn deposit(vaddr: usize, bits: &[usize], value: usize) -> usize {
+        bits.iter().enumerate().fold(vaddr, |res, (idx, bit)| {
+            (res & !(1 << bit)) | (((value >> idx) & 1) << bit)
+        })
+    }
+
+    /// The number of aliasing addresses.
+    pub fn len(&self) -> usize {
+        self.gates.iter().map(|(_, valid)| valid.len()).product()
+    }
+
+    pub fn is_empty(&self) -> bool { self.len() == 0 }
+
+    /// Return the aliasing address identified by `n`.
+    pub fn get(&self, mut n: usize) -> Option<usize> {
+        if n >= self.len() {
+            return None;
+        }
+        let mut res = self.vaddr;
+        for (bits, valid) in self.gates.iter() {
+            res = Self::deposit(res, bits, valid[n % valid.len()]);
+            n /= valid.len();
+        }
+        Some(res)
+    }
+
+    /// Iterate over all aliasing addresses.
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        (0..self.len()).map(|n| self.get(n).unwrap())
+    }
+
+    /// Pick a random aliasing address.
+    pub fn random(&self, rng: &mut impl rand::Rng) -> usize {
+        self.get(rng.gen_range(0..self.len())).unwrap()
+    }
+}
+
+/// A branch in a trace for [`BtbModel`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BtbBranch {
+    /// Address of the branch
+    pub vaddr: usize,
+    /// Address of the branch target
+    pub target: usize,
+}
+impl BtbBranch {
+    pub fn new(vaddr: usize, target: usize) -> Self { Self { vaddr, target } }
+
+    /// BTB index for the branch address (see [`ZEN2_BTB_INDEX_FN`])
+    pub fn index(&self) -> usize { Zen2::index(self.vaddr) }
+
+    /// Set in [`BtbModel`]
+    pub fn set(&self) -> usize { self.index() & (BtbModel::SETS - 1) }
+
+    /// Tag in [`BtbModel`]
+    pub fn tag(&self) -> usize {
+        let upper = self.index() >> BtbModel::SETS.trailing_zeros();
+        (upper << 6) | (self.vaddr & 0x3f)
+    }
+}
+
+/// The result of a branch in [`BtbModel`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BtbPrediction {
+    /// No entry matched the branch.
+    Miss,
+    /// An entry created by the same branch matched.
+    Hit,
+    /// An entry created by a different (aliasing) branch matched.
+    Alias(BtbBranch),
+}
+impl BtbPrediction {
+    /// Returns true if the branch was predicted from an entry created by 
+    /// some other branch.
+    pub fn is_alias(&self) -> bool { matches!(self, Self::Alias(_)) }
+}
+
+/// An entry in [`BtbModel`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BtbEntry {
+    pub tag: usize,
+    /// The branch which created (or last updated) this entry
+    pub branch: BtbBranch,
+    pub stamp: usize,
+}
+
+/// The outcome of a branch in [`BtbModel`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BtbOutcome {
+    pub kind: BtbPrediction,
+    /// The predicted target (if any)
+    pub predicted: Option<usize>,
+    pub set: usize,
+    /// The way holding the entry after the branch
+    pub way: usize,
+    /// The branch whose entry was evicted (if any)
+    pub evicted: Option<BtbBranch>,
+}
+impl BtbOutcome {
+    /// Returns true if the branch was predicted with the wrong target.
+    pub fn mispredicted(&self, branch: &BtbBranch) -> bool {
+        self.predicted.is_some_and(|tgt| tgt != branch.target)
+    }
+}
+
+/// A hypothetical model of the Zen 2 L2 BTB (1024 sets, 4 ways). 
+///
+/// - The set is selected with the lower 10 bits of the BTB index 
+///   (see [`ZEN2_BTB_INDEX_FN`])
+/// - The tag is the upper 2 bits of the BTB index, and the offset of the 
+///   branch within its cacheline
+/// - Branches with the same index and offset alias with each other
+///
+/// NOTE: The 4096 entries here follow from the 12-bit BTB index, and match
+/// the L2 BTB in the SOG for Family 17h Models 00h-0Fh (Zen 1). The SOG for
+/// Family 17h Models 30h and later (Zen 2) gives 7K L2 BTB entries, so the
+/// real structure is larger than this model. The split between sets and
+/// ways, the tag, and the replacement policy (true LRU here) are guesses;
+/// the L0/L1 BTBs and the ability to hold two branches in a single entry
+/// are not modeled. 
+#[derive(Clone, Debug)]
+pub struct BtbModel {
+    pub sets: Vec<[Option<BtbEntry>; Self::WAYS]>,
+    /// Number of branches so far
+    pub clock: usize,
+}
+impl BtbModel {
+    pub const SETS: usize = <Zen2 as BtbGeometry>::SETS;
+    pub const WAYS: usize = <Zen2 as BtbGeometry>::WAYS;
+
+    pub fn new() -> Self {
+        Self { sets: vec![[None; Self::WAYS]; Self::SETS], clock: 0 }
+    }
+
+    /// Return the entry used to predict a branch (if any).
+    pub fn lookup(&self, vaddr: usize) -> Option<&BtbEntry> {
+        let brn = BtbBranch::new(vaddr, 0);
+        let tag = brn.tag();
+        self.sets[brn.set()].iter().flatten().find(|e| e.tag == tag)
+    }
+
+    /// Simulate a taken branch.
+    pub fn access(&mut self, branch: BtbBranch) -> BtbOutcome {
+        self.clock += 1;
+        let (set, tag) = (branch.set(), branch.tag());
+        let stamp = self.clock;
+        let ways = &mut self.sets[set];
+
+        let hit_way = ways.iter()
+            .position(|e| e.is_some_and(|e| e.tag == tag));
+        let (kind, predicted, way, evicted) = match hit_way {
+            Some(way) => {
+                let entry = ways[way].unwrap();
+                let kind = if entry.branch.vaddr == branch.vaddr {
+                    BtbPrediction::Hit
+                } else {
+                    BtbPrediction::Alias(entry.branch)
+                };
+                (kind, Some(entry.branch.target), way, None)
+            },
+            None => {
+                let way = ways.iter().position(|e| e.is_none())
+                    .unwrap_or_else(|| {
+                        ways.iter().enumerate()
+                            .min_by_key(|(_, e)| e.unwrap().stamp)
+                            .unwrap().0
+                    });
+                let evicted = ways[way].map(|e| e.branch);
+                (BtbPrediction::Miss, None, way, evicted)
+            },
+        };
+        ways[way] = Some(BtbEntry { tag, branch, stamp });
+        BtbOutcome { kind, predicted, set, way, evicted }
+    }
+
+    /// Simulate a list of taken branches.
+    pub fn run(&mut self, trace: &[BtbBranch]) -> Vec<BtbOutcome> {
+        trace.iter().map(|brn| self.access(*brn)).collect()
+    }
+
+    /// Invalidate all entries (ie. with IBPB).
+    pub fn invalidate(&mut self) {
+        self.sets.iter_mut().for_each(|s| *s = [None; Self::WAYS]);
+    }
+
+    /// Returns true if branches at `a` and `b` are predicted from the same 
+    /// entry in this model.
+    pub fn aliasing(a: usize, b: usize) -> bool {
+        let (a, b) = (BtbBranch::new(a, 0), BtbBranch::new(b, 0));
+        a.set() == b.set() && a.tag() == b.tag()
+    }
+}
+impl Default for BtbModel {
+    fn default() -> Self { Self::new() }
+}
+
 
 
 ///// A hypothetical BTB addressing scheme. 
@@ -251,4 +463,75 @@ pub fn zen2_btb_collisions(vaddr: usize, combinations: usize)
 //    }
 //}
 
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::util::rng::seeded_rng;
+
+    #[test]
+    fn btb_valid_ops() {
+        // All of the operations preserve the index (this is also asserted 
+        // inside zen2_btb_collisions())
+        let vaddr = 0x0000_5555_dead_beefusize;
+        let coll = zen2_btb_collisions(vaddr, 2);
+        assert!(coll.iter().all(|a| BtbModel::aliasing(vaddr, *a)));
+
+        // Some collisions must come from changing the AND gate inputs
+        assert!(coll.iter().any(|a| (a ^ vaddr) & (1 << 10 | 1 << 15) != 0));
+    }
+
+    #[test]
+    fn btb_aliases() {
+        let vaddr = 0x0000_0000_4002_0c4dusize;
+        let aliases = BtbAliases::zen2(vaddr);
+        assert_eq!(aliases.len(), 8 * 8 * 8 * 4usize.pow(7));
+
+        let index = ZEN2_BTB_INDEX_FN.evaluate(vaddr);
+        let mut rng = seeded_rng(0x0b7b_a11a5);
+        let sample: BTreeSet<usize> = (0..4096)
+            .map(|_| aliases.random(&mut rng))
+            .collect();
+        for alias in sample.iter() {
+            assert_eq!(ZEN2_BTB_INDEX_FN.evaluate(*alias), index);
+            assert_eq!(alias & !0x0000_ffff_ffff_fc00, vaddr & 0x3ff);
+        }
+        assert!(sample.iter().any(|a| (a ^ vaddr) & (1 << 10) != 0));
+        assert!(sample.iter().any(|a| (a ^ vaddr) & (1 << 16) != 0));
+        assert!(aliases.iter().take(4096).all_unique());
+        assert_eq!(aliases.get(aliases.len()), None);
+    }
 
+    #[test]
+    fn btb_model() {
+        let mut model = BtbModel::new();
+        let victim = BtbBranch::new(0x0000_0000_4002_0c4d, 0x4002_1000);
+        let alias = BtbBranch::new(victim.vaddr | 0x0000_1001_0000_0000, 0x1337_0000);
+        assert!(BtbModel::aliasing(victim.vaddr, alias.vaddr));
+
+        // The attacker branch is predicted with the victim's target, and
+        // then the victim is predicted with the attacker's target
+        let res = model.run(&[victim, victim, alias, victim]);
+        assert_eq!(res[0].kind, BtbPrediction::Miss);
+        assert_eq!(res[1].kind, BtbPrediction::Hit);
+        assert_eq!(res[2].kind, BtbPrediction::Alias(victim));
+        assert_eq!(res[3].kind, BtbPrediction::Alias(alias));
+        assert!(res[3].mispredicted(&victim));
+        assert_eq!(model.lookup(alias.vaddr).unwrap().branch, victim);
+
+        // Branches in the same set (with different tags) evict each other
+        let aliases = BtbAliases::zen2(victim.vaddr);
+        let mut rng = seeded_rng(0x0b7b_a11a5);
+        let mut trace = vec![victim];
+        for tag in 1..=BtbModel::WAYS {
+            let vaddr = (aliases.random(&mut rng) & !0x3f) | tag;
+            assert!(!BtbModel::aliasing(victim.vaddr, vaddr));
+            trace.push(BtbBranch::new(vaddr, 0));
+        }
+        model.invalidate();
+        let res = model.run(&trace);
+        assert!(res.iter().all(|o| o.kind == BtbPrediction::Miss));
+        assert!(res.iter().all(|o| o.set == victim.set()));
+        assert_eq!(res[BtbModel::WAYS].evicted, Some(victim));
+        assert!(model.lookup(victim.vaddr).is_none());
+    }
+}
@@ -0,0 +1,457 @@
+//! A model of a conditional branch direction predictor.
+//!
+//! [`DirectionModel`] is a configurable TAGE-like predictor: a table of
+//! bimodal counters (indexed only by the branch address), and some number
+//! of tagged tables indexed by the branch address hashed with increasingly
+//! long slices of global history. The model keeps two kinds of history:
+//!
+//! - Global history, where each conditional branch shifts in its outcome
+//! - Path history, where each taken branch shifts in some of the bits of
+//!   its address (and optionally, its target)
+//!
+//! None of this is known to match the actual predictor on any platform.
+//! The point is to replay the same [`BranchOutcomes`] used in experiments
+//! (see `bp.rs` and `bp-pattern.rs`), and compare the predicted number of
+//! mispredictions with measurements for different configurations.
+//!
+//! NOTE: The periodic reset of the "useful" counters and the
+//! "use alternate on newly-allocated" heuristic from TAGE are not modeled.
+
+use crate::experiments::branch::BranchOutcomes;
+
+/// Configuration for a single tagged table.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TaggedTableConfig {
+    /// Number of global history bits used to index this table
+    pub hist_len: usize,
+    /// Number of bits in the index (the table has `1 << index_bits` entries)
+    pub index_bits: usize,
+    /// Number of bits in each tag
+    pub tag_bits: usize,
+}
+impl TaggedTableConfig {
+    pub const fn new(hist_len: usize, index_bits: usize, tag_bits: usize) -> Self {
+        Self { hist_len, index_bits, tag_bits }
+    }
+}
+
+/// Configuration for a [`DirectionModel`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DirectionConfig {
+    /// Number of bits in the index for the bimodal table
+    pub base_index_bits: usize,
+    /// Number of low branch address bits ignored when computing indexes
+    pub addr_shift: usize,
+    /// Number of path history bits used when computing indexes
+    pub path_len: usize,
+    /// Number of branch address bits shifted into the path history for
+    /// each taken branch
+    pub path_bits: usize,
+    /// Fold branch target bits into the path history
+    pub path_target: bool,
+    /// Only taken branches are recorded in the global history
+    pub taken_only: bool,
+    /// Tagged tables, ordered from shortest to longest history
+    pub tables: Vec<TaggedTableConfig>,
+}
+impl DirectionConfig {
+    /// A bimodal predictor (without any history).
+    pub fn bimodal(index_bits: usize) -> Self {
+        Self {
+            base_index_bits: index_bits,
+            addr_shift: 0,
+            path_len: 0,
+            path_bits: 0,
+            path_target: false,
+            taken_only: false,
+            tables: Vec::new(),
+        }
+    }
+
+    /// A TAGE-like predictor with `num_tables` tagged tables whose history
+    /// lengths form a geometric series from `min_hist` to `max_hist`.
+    pub fn tage(num_tables: usize, min_hist: usize, max_hist: usize) -> Self {
+        let tables = (0..num_tables).map(|idx| {
+            let ratio = if num_tables > 1 {
+                (max_hist as f64 / min_hist as f64)
+                    .powf(idx as f64 / (num_tables - 1) as f64)
+            } else {
+                1.0
+            };
+            let hist_len = (min_hist as f64 * ratio).round() as usize;
+            TaggedTableConfig::new(hist_len, 10, 8 + idx.min(4))
+        }).collect();
+        Self::bimodal(12).path(16, 2).tables(tables)
+    }
+
+    pub fn addr_shift(mut self, shift: usize) -> Self {
+        self.addr_shift = shift;
+        self
+    }
+
+    pub fn path(mut self, path_len: usize, path_bits: usize) -> Self {
+        self.path_len = path_len;
+        self.path_bits = path_bits;
+        self
+    }
+
+    pub fn path_target(mut self, path_target: bool) -> Self {
+        self.path_target = path_target;
+        self
+    }
+
+    pub fn taken_only(mut self, taken_only: bool) -> Self {
+        self.taken_only = taken_only;
+        self
+    }
+
+    pub fn tables(mut self, tables: Vec<TaggedTableConfig>) -> Self {
+        self.tables = tables;
+        self
+    }
+
+    /// The longest global history used by any table.
+    pub fn max_hist_len(&self) -> usize {
+        self.tables.iter().map(|t| t.hist_len).max().unwrap_or(0)
+    }
+}
+impl Default for DirectionConfig {
+    fn default() -> Self { Self::tage(4, 4, 64) }
+}
+
+/// A branch history register.
+///
+/// The most recent bit is `bits[0]`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct History {
+    bits: Vec<bool>,
+    len: usize,
+}
+impl History {
+    pub fn new(len: usize) -> Self { Self { bits: vec![false; len], len } }
+
+    /// Shift a bit into the history.
+    pub fn push(&mut self, bit: bool) {
+        if self.len != 0 {
+            self.bits.pop();
+            self.bits.insert(0, bit);
+        }
+    }
+
+    pub fn clear(&mut self) { self.bits.iter_mut().for_each(|b| *b = false); }
+
+    pub fn bits(&self) -> &[bool] { &self.bits }
+
+    /// XOR the most recent `len` bits into a value with `width` bits.
+    pub fn fold(&self, len: usize, width: usize) -> usize {
+        if width == 0 {
+            return 0;
+        }
+        self.bits.iter().take(len).enumerate()
+            .filter(|(_, bit)| **bit)
+            .fold(0, |res, (idx, _)| res ^ (1 << (idx % width)))
+    }
+}
+
+/// An entry in a tagged table.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct TaggedEntry {
+    tag: usize,
+    /// 3-bit signed counter (taken when non-negative)
+    ctr: i8,
+    /// 2-bit "useful" counter
+    useful: u8,
+    valid: bool,
+}
+
+/// Where a prediction came from.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Provider {
+    /// The bimodal table
+    Base,
+    /// A tagged table (by index in [`DirectionConfig::tables`])
+    Tagged(usize),
+}
+
+/// The result of a conditional branch in [`DirectionModel`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DirectionOutcome {
+    pub predicted: bool,
+    pub taken: bool,
+    pub provider: Provider,
+}
+impl DirectionOutcome {
+    pub fn mispredicted(&self) -> bool { self.predicted != self.taken }
+}
+
+/// The results of [`DirectionModel::replay`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ReplayResults {
+    pub outcomes: Vec<DirectionOutcome>,
+}
+impl ReplayResults {
+    pub fn predictions(&self) -> Vec<bool> {
+        self.outcomes.iter().map(|o| o.predicted).collect()
+    }
+
+    pub fn misses(&self) -> Vec<bool> {
+        self.outcomes.iter().map(|o| o.mispredicted()).collect()
+    }
+
+    /// Total number of mispredictions.
+    pub fn mispredicts(&self) -> usize {
+        self.outcomes.iter().filter(|o| o.mispredicted()).count()
+    }
+
+    /// Number of branches where the model disagrees with measured
+    /// mispredictions (ie. from `ExRetMsprdBrnchInstrDirMsmtch`).
+    pub fn distance(&self, measured: &[bool]) -> usize {
+        self.outcomes.iter().zip(measured.iter())
+            .filter(|(o, m)| o.mispredicted() != **m)
+            .count()
+    }
+}
+
+/// A TAGE-like model of a branch direction predictor (see the module
+/// documentation).
+#[derive(Clone, Debug)]
+pub struct DirectionModel {
+    pub cfg: DirectionConfig,
+    pub ghist: History,
+    pub path: History,
+    /// 2-bit saturating counters (taken when greater than 1)
+    base: Vec<u8>,
+    tables: Vec<Vec<TaggedEntry>>,
+}
+impl DirectionModel {
+    pub fn new(cfg: DirectionConfig) -> Self {
+        let tables = cfg.tables.iter()
+            .map(|t| vec![TaggedEntry::default(); 1 << t.index_bits])
+            .collect();
+        Self {
+            ghist: History::new(cfg.max_hist_len()),
+            path: History::new(cfg.path_len),
+            base: vec![1; 1 << cfg.base_index_bits],
+            tables,
+            cfg,
+        }
+    }
+
+    fn base_index(&self, addr: usize) -> usize {
+        (addr >> self.cfg.addr_shift) & ((1 << self.cfg.base_index_bits) - 1)
+    }
+
+    fn tagged_index(&self, table: usize, addr: usize) -> usize {
+        let t = &self.cfg.tables[table];
+        let pc = addr >> self.cfg.addr_shift;
+        let mask = (1 << t.index_bits) - 1;
+        (pc ^ (pc >> t.index_bits)
+            ^ self.ghist.fold(t.hist_len, t.index_bits)
+            ^ self.path.fold(self.cfg.path_len.min(t.hist_len), t.index_bits)
+        ) & mask
+    }
+
+    fn tagged_tag(&self, table: usize, addr: usize) -> usize {
+        let t = &self.cfg.tables[table];
+        let pc = addr >> self.cfg.addr_shift;
+        let mask = (1 << t.tag_bits) - 1;
+        (pc ^ self.ghist.fold(t.hist_len, t.tag_bits)
+            ^ (self.ghist.fold(t.hist_len, t.tag_bits.saturating_sub(1)) << 1)
+        ) & mask
+    }
+
+    /// Return the tables with a matching entry (from longest to shortest
+    /// history) and their indexes.
+    fn matches(&self, addr: usize) -> Vec<(usize, usize)> {
+        (0..self.tables.len()).rev().filter_map(|table| {
+            let idx = self.tagged_index(table, addr);
+            let e = &self.tables[table][idx];
+            (e.valid && e.tag == self.tagged_tag(table, addr))
+                .then_some((table, idx))
+        }).collect()
+    }
+
+    /// Predict the direction of a conditional branch at `addr` (without
+    /// updating the model).
+    pub fn predict(&self, addr: usize) -> (bool, Provider) {
+        match self.matches(addr).first() {
+            Some((table, idx)) => (self.tables[*table][*idx].ctr >= 0,
+                Provider::Tagged(*table)),
+            None => (self.base[self.base_index(addr)] > 1, Provider::Base),
+        }
+    }
+
+    /// Simulate a conditional branch at `addr`.
+    pub fn branch(&mut self, addr: usize, target: usize, taken: bool)
+        -> DirectionOutcome
+    {
+        let matches = self.matches(addr);
+        let (predicted, provider) = self.predict(addr);
+        let alt = match matches.get(1) {
+            Some((table, idx)) => self.tables[*table][*idx].ctr >= 0,
+            None => self.base[self.base_index(addr)] > 1,
+        };
+
+        // Update the provider
+        match provider {
+            Provider::Base => {
+                let idx = self.base_index(addr);
+                let ctr = &mut self.base[idx];
+                *ctr = if taken { (*ctr + 1).min(3) } else { ctr.saturating_sub(1) };
+            },
+            Provider::Tagged(table) => {
+                let (_, idx) = matches[0];
+                let e = &mut self.tables[table][idx];
+                e.ctr = if taken { (e.ctr + 1).min(3) } else { (e.ctr - 1).max(-4) };
+                if predicted != alt {
+                    e.useful = if predicted == taken {
+                        (e.useful + 1).min(3)
+                    } else {
+                        e.useful.saturating_sub(1)
+                    };
+                }
+            },
+        }
+
+        // Allocate an entry in a table with longer history
+        if predicted != taken {
+            let start = match provider {
+                Provider::Base => 0,
+                Provider::Tagged(table) => table + 1,
+            };
+            let free = (start..self.tables.len()).find(|table| {
+                let idx = self.tagged_index(*table, addr);
+                self.tables[*table][idx].useful == 0
+            });
+            match free {
+                Some(table) => {
+                    let idx = self.tagged_index(table, addr);
+                    let tag = self.tagged_tag(table, addr);
+                    self.tables[table][idx] = TaggedEntry {
+                        tag, ctr: if taken { 0 } else { -1 }, useful: 0, valid: true,
+                    };
+                },
+                None => {
+                    for table in start..self.tables.len() {
+                        let idx = self.tagged_index(table, addr);
+                        let e = &mut self.tables[table][idx];
+                        e.useful = e.useful.saturating_sub(1);
+                    }
+                },
+            }
+        }
+
+        if taken || !self.cfg.taken_only {
+            self.ghist.push(taken);
+        }
+        if taken {
+            self.push_path(addr, target);
+        }
+        DirectionOutcome { predicted, taken, provider }
+    }
+
+    /// Simulate an unconditional jump (which only affects history).
+    ///
+    /// NOTE: [`crate::util::clear_ghist_dir`] and
+    /// [`crate::util::clear_ghist_indir`] are a sequence of jumps.
+    pub fn jump(&mut self, addr: usize, target: usize) {
+        self.ghist.push(true);
+        self.push_path(addr, target);
+    }
+
+    fn push_path(&mut self, addr: usize, target: usize) {
+        let mut val = addr >> self.cfg.addr_shift;
+        if self.cfg.path_target {
+            val ^= target >> self.cfg.addr_shift;
+        }
+        for bit in (0..self.cfg.path_bits).rev() {
+            self.path.push(val & (1 << bit) != 0);
+        }
+    }
+
+    /// Clear all history.
+    pub fn clear_history(&mut self) {
+        self.ghist.clear();
+        self.path.clear();
+    }
+
+    /// Replay a list of outcomes for a single conditional branch at `addr`
+    /// (where any non-zero outcome is taken, as with `cmp rdi, 1; je`).
+    ///
+    /// Before each branch, the unconditional jumps in `padding` are
+    /// simulated (ie. to account for branches in the harness, or for
+    /// padding emitted before the branch).
+    pub fn replay(&mut self, addr: usize, target: usize,
+        outcomes: &BranchOutcomes, padding: &[(usize, usize)])
+        -> ReplayResults
+    {
+        let outcomes = outcomes.as_slice().iter().map(|o| {
+            for (pad_addr, pad_tgt) in padding {
+                self.jump(*pad_addr, *pad_tgt);
+            }
+            self.branch(addr, target, *o != 0)
+        }).collect();
+        ReplayResults { outcomes }
+    }
+}
+impl Default for DirectionModel {
+    fn default() -> Self { Self::new(DirectionConfig::default()) }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ADDR: usize = 0x0000_1000_0040;
+
+    #[test]
+    fn direction_history() {
+        let mut h = History::new(8);
+        for bit in [true, false, true, true] {
+            h.push(bit);
+        }
+        assert_eq!(h.bits()[..4], [true, true, false, true]);
+        assert_eq!(h.fold(4, 8), 0b1011);
+        assert_eq!(h.fold(4, 2), 0b11 ^ 0b10);
+        assert_eq!(h.fold(2, 8), 0b11);
+    }
+
+    #[test]
+    fn direction_bimodal() {
+        // A bimodal predictor learns a biased branch, but always
+        // mispredicts an alternating branch once it is trained
+        let mut model = DirectionModel::new(DirectionConfig::bimodal(8));
+        let res = model.replay(ADDR, ADDR + 2,
+            &BranchOutcomes::from_pattern(64, &[1usize]), &[]);
+        assert_eq!(res.mispredicts(), 1);
+
+        let res = model.replay(ADDR, ADDR + 2,
+            &BranchOutcomes::from_pattern(64, &[0usize, 1]), &[]);
+        assert!(res.mispredicts() >= 32);
+    }
+
+    #[test]
+    fn direction_tage() {
+        // With global history, repeating patterns shorter than the longest
+        // history are learned
+        let pattern = BranchOutcomes::from_pattern(256, &[1usize, 1, 0, 1, 0, 0, 0]);
+        let mut model = DirectionModel::default();
+        let res = model.replay(ADDR, ADDR + 2, &pattern, &[]);
+        let tail = &res.outcomes[128..];
+        assert!(tail.iter().all(|o| !o.mispredicted()), "{:?}", res.misses());
+
+        // Padding jumps between each branch displace the useful history.
+        // Without history, the counters settle on the majority direction
+        // (not-taken), and every taken branch is mispredicted
+        let mut model = DirectionModel::new(DirectionConfig::tage(4, 4, 16));
+        let padding: Vec<(usize, usize)> = (0..32)
+            .map(|i| (0x2000_0000 + i * 2, 0x2000_0000 + i * 2 + 2))
+            .collect();
+        let res = model.replay(ADDR, ADDR + 2, &pattern, &padding);
+        assert!(res.mispredicts() > 64);
+        let expected: Vec<bool> = pattern.as_slice().iter()
+            .map(|o| *o != 0)
+            .collect();
+        assert_eq!(res.misses()[128..], expected[128..]);
+    }
+}
@@ -1,39 +1,249 @@
+use crate::uarch::*;
 
-use crate::ir::bf::*;
+/// Moved to [`crate::uarch::zen2`] (re-exported for compatibility).
+pub use crate::uarch::zen2::ZEN2_L1D_UTAG_FN;
 
-/// The function used to compute the L1D micro-tag (utag) used to implement
-/// the L1D way predictor. 
-///
-/// ```
-///     27  23  19
-///     v   v   v
-/// 12  +........
-/// 13  .+.......
-/// 14  ..+......
-/// 15  .......+.
-/// 16  ......+..
-/// 17  .....+...
-/// 18  ....+....
-/// 19  ...+.....
-/// ```
-///
-/// This is reproduced from the description in the "Take A Way" paper[^1]. 
+/// The result of an access in [`L1dModel`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WayPrediction {
+    /// The utag matched a way holding the requested line.
+    Hit,
+    /// The utag didn't match any way, and the line was not present.
+    /// The line is filled into the least-recently-used way.
+    Miss,
+    /// The utag matched a way holding some *other* line.
+    /// The predicted way is evicted and refilled with the requested line.
+    UtagConflict,
+    /// The utag didn't match any way, but the line was present (accessed
+    /// through some other virtual address). The utag for the line is updated.
+    UtagAlias,
+}
+impl WayPrediction {
+    /// Returns true if the access is expected to have L1D hit latency.
+    pub fn is_hit(&self) -> bool { matches!(self, Self::Hit) }
+}
+
+/// A memory access in [`L1dModel`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct L1dRequest {
+    pub vaddr: usize,
+    pub paddr: usize,
+}
+impl L1dRequest {
+    pub fn new(vaddr: usize, paddr: usize) -> Self { Self { vaddr, paddr } }
+
+    /// An access where the virtual and physical addresses are the same.
+    pub fn identity(addr: usize) -> Self { Self { vaddr: addr, paddr: addr } }
+
+    /// Set index bits (which are the same in both addresses)
+    pub fn set(&self) -> usize { <Zen2 as L1dGeometry>::set(self.vaddr) }
+
+    /// Micro-tag for the virtual address
+    pub fn utag(&self) -> usize { Zen2::utag(self.vaddr) }
+
+    /// Physical tag (the physical address of the line)
+    pub fn ptag(&self) -> usize { self.paddr >> 6 }
+}
+
+/// A line in [`L1dModel`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct L1dLine {
+    pub utag: usize,
+    pub ptag: usize,
+    /// Time of the most-recent access (for LRU replacement)
+    pub stamp: usize,
+}
+
+/// The outcome of an access in [`L1dModel`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct L1dOutcome {
+    pub kind: WayPrediction,
+    pub set: usize,
+    /// The way holding the line after the access
+    pub way: usize,
+    /// The line evicted by this access (if any)
+    pub evicted: Option<L1dLine>,
+}
+
+/// A model of the Zen 2 L1D cache (64 sets, 8 ways) with utag-based way
+/// prediction, as described in the "Take A Way" paper.
 ///
-/// [^1]: [Take A Way: Exploring the Security Implications of AMD's Cache Way Predictors](https://dl.acm.org/doi/10.1145/3320269.3384746)
+/// - Each way in a set holds a utag (see [`ZEN2_L1D_UTAG_FN`]) computed
+///   from the virtual address used to fill the line
+/// - Only one way in a set can hold a particular utag
+/// - The utag does not depend on the address space, so accesses from
+///   different address spaces (ie. kernel and userspace) can collide
 ///
-pub const ZEN2_L1D_UTAG_FN: BfProg<8> = {
-    use BfOp::*;
-    BfProg([ 
-        Xor(&[In(12), In(27)]),
-        Xor(&[In(13), In(26)]),
-        Xor(&[In(14), In(25)]),
-
-        Xor(&[In(15), In(20)]),
-        Xor(&[In(16), In(21)]),
-        Xor(&[In(17), In(22)]),
-        Xor(&[In(18), In(23)]),
-        Xor(&[In(19), In(24)]),
-    ])
-};
+/// NOTE: Replacement is modeled as true LRU, and prefetching is ignored.
+#[derive(Clone, Debug)]
+pub struct L1dModel {
+    pub sets: [[Option<L1dLine>; Self::WAYS]; Self::SETS],
+    /// Number of accesses so far
+    pub clock: usize,
+}
+impl L1dModel {
+    pub const SETS: usize = <Zen2 as L1dGeometry>::SETS;
+    pub const WAYS: usize = <Zen2 as L1dGeometry>::WAYS;
+
+    pub fn new() -> Self {
+        Self { sets: [[None; Self::WAYS]; Self::SETS], clock: 0 }
+    }
+
+    /// Simulate a load (or store).
+    pub fn access(&mut self, req: L1dRequest) -> L1dOutcome {
+        self.clock += 1;
+        let (set, utag, ptag) = (req.set(), req.utag(), req.ptag());
+        let stamp = self.clock;
+        let ways = &mut self.sets[set];
+
+        let utag_way = ways.iter()
+            .position(|l| l.is_some_and(|l| l.utag == utag));
+        let ptag_way = ways.iter()
+            .position(|l| l.is_some_and(|l| l.ptag == ptag));
+
+        let new_line = Some(L1dLine { utag, ptag, stamp });
+        let (kind, way, evicted) = match (utag_way, ptag_way) {
+            (Some(u), Some(p)) if u == p => {
+                (WayPrediction::Hit, u, None)
+            },
+            (Some(u), p) => {
+                // Only a single copy of the line can be present
+                if let Some(p) = p {
+                    ways[p] = None;
+                }
+                (WayPrediction::UtagConflict, u, ways[u])
+            },
+            (None, Some(p)) => {
+                (WayPrediction::UtagAlias, p, None)
+            },
+            (None, None) => {
+                let way = ways.iter().position(|l| l.is_none())
+                    .unwrap_or_else(|| {
+                        ways.iter().enumerate()
+                            .min_by_key(|(_, l)| l.unwrap().stamp)
+                            .unwrap().0
+                    });
+                (WayPrediction::Miss, way, ways[way])
+            },
+        };
+        ways[way] = new_line;
+        L1dOutcome { kind, set, way, evicted }
+    }
+
+    /// Simulate a list of accesses.
+    pub fn run(&mut self, trace: &[L1dRequest]) -> Vec<L1dOutcome> {
+        trace.iter().map(|req| self.access(*req)).collect()
+    }
+
+    /// Invalidate the line containing a physical address (ie. CLFLUSH).
+    pub fn flush(&mut self, paddr: usize) {
+        let ptag = paddr >> 6;
+        let set = (paddr >> 6) & (Self::SETS - 1);
+        for line in self.sets[set].iter_mut() {
+            if line.is_some_and(|l| l.ptag == ptag) {
+                *line = None;
+            }
+        }
+    }
+
+    /// Invalidate all lines.
+    pub fn invalidate(&mut self) {
+        self.sets = [[None; Self::WAYS]; Self::SETS];
+    }
+
+    /// Returns true if the line containing a physical address is present.
+    pub fn contains(&self, paddr: usize) -> bool {
+        let set = (paddr >> 6) & (Self::SETS - 1);
+        self.sets[set].iter().any(|l| l.is_some_and(|l| l.ptag == paddr >> 6))
+    }
+}
+impl Default for L1dModel {
+    fn default() -> Self { Self::new() }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::uarch::vaddr::VirtualAddress;
+    use WayPrediction::*;
+
+    fn kinds(model: &mut L1dModel, trace: &[L1dRequest]) -> Vec<WayPrediction> {
+        model.run(trace).iter().map(|o| o.kind).collect()
+    }
+
+    #[test]
+    fn l1d_model_fill_and_evict() {
+        let mut model = L1dModel::new();
+        let a = L1dRequest::identity(0x1234_0040);
+        assert_eq!(kinds(&mut model, &[a, a]), [Miss, Hit]);
+        model.flush(a.paddr);
+        assert_eq!(kinds(&mut model, &[a]), [Miss]);
+
+        // Nine lines with distinct utags in the same set: the least-recently
+        // used line is evicted
+        let lines: Vec<L1dRequest> = (0..9)
+            .map(|i| L1dRequest::identity(0x1000_0040 + (i << 12)))
+            .collect();
+        let mut model = L1dModel::new();
+        let out = model.run(&lines);
+        assert!(out.iter().all(|o| o.kind == Miss));
+        assert_eq!(out[8].evicted.unwrap().ptag, lines[0].ptag());
+        assert!(!model.contains(lines[0].paddr));
+        assert_eq!(kinds(&mut model, &lines[1..]), [Hit; 8]);
+    }
+
+    #[test]
+    fn l1d_model_utag() {
+        // Two different lines in the same set with colliding utags
+        // cannot be cached at the same time
+        let a = VirtualAddress::<Zen2>::from(0x1dea_0000_0000).with_set(5);
+        let b = a.random_collision(&mut crate::util::seeded_rng(0))
+            .with_hibits(0x2).with_set(5);
+        assert_eq!(a.utag(), b.utag());
+        assert_ne!(a.value(), b.value());
+        let ra = L1dRequest::identity(a.value());
+        let rb = L1dRequest::identity(b.value());
+        let mut model = L1dModel::new();
+        assert_eq!(kinds(&mut model, &[ra, rb, ra, ra]),
+            [Miss, UtagConflict, UtagConflict, Hit]
+        );
+
+        // The same physical line through two virtual addresses with
+        // different utags
+        let va = L1dRequest::new(0x0000_1000_0080, 0x8000_0080);
+        let vb = L1dRequest::new(0x0000_5555_0080, 0x8000_0080);
+        assert_ne!(va.utag(), vb.utag());
+        let mut model = L1dModel::new();
+        assert_eq!(kinds(&mut model, &[va, vb, va, va]),
+            [Miss, UtagAlias, UtagAlias, Hit]
+        );
+    }
 
+    /// Expected outcomes for the cases in 'collide-probe.rs', where the
+    /// kernel accesses some line between two accesses in userspace.
+    #[test]
+    fn l1d_model_collide_probe() {
+        let kernel = VirtualAddress::<Zen2>::from(0xffff_8880_1234_5000);
+        let alias = kernel.random_collision(&mut crate::util::seeded_rng(1))
+            .with_hibits(0x1dea);
+        let other = VirtualAddress::<Zen2>::from(0x1dea_0000_0000)
+            .with_utag_input(kernel.utag_input() ^ 1);
+        assert_ne!(other.utag(), kernel.utag());
 
+        let cases = [
+            (alias, 7, 7, UtagConflict),
+            (alias, 7, 7 ^ 0b111111, Hit),
+            (other, 7, 7, Hit),
+            (other, 7, 7 ^ 0b111111, Hit),
+        ];
+        for (user, user_set, kernel_set, expected) in cases {
+            let u = L1dRequest::new(user.with_set(user_set).value(), 0x1000_0000
+                | (user_set << 6));
+            let k = L1dRequest::new(kernel.with_set(kernel_set).value(),
+                0x2000_0000 | (kernel_set << 6));
+            let mut model = L1dModel::new();
+            let out = kinds(&mut model, &[u, k, u]);
+            assert_eq!(out[2], expected, "{:x?} {:x?}", u, k);
+        }
+    }
+}
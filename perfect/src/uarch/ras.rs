@@ -0,0 +1,288 @@
+//! A model of a return address stack (RAS).
+//!
+//! [`RasModel`] is a circular buffer of return addresses: calls push the
+//! address of the next instruction, and returns pop a predicted target.
+//! The parts we want to measure (see [`crate::experiments::ras`]) are
+//! configurable:
+//!
+//! - The number of entries
+//! - What happens when a return is predicted while the stack is empty
+//! - How the stack is repaired after calls/returns on a mispredicted path
+//!
+//! Older entries are always silently overwritten when the stack is full.
+
+use std::collections::HashMap;
+
+/// Behavior when a return is predicted with no valid entries.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RasUnderflow {
+    /// No prediction (the return is always mispredicted)
+    Empty,
+    /// The pointer wraps around and the stale entry is used
+    Wrap,
+    /// Use the last target observed for the same return (ie. from the BTB)
+    Fallback,
+}
+
+/// Behavior when recovering from a misprediction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RasRecovery {
+    /// Nothing is repaired
+    None,
+    /// Only the top-of-stack pointer is repaired; entries overwritten on
+    /// the wrong path are lost
+    Pointer,
+    /// The whole stack is repaired
+    Full,
+}
+
+/// An operation on a [`RasModel`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RasOp {
+    /// A call (with the address of the next instruction)
+    Call { site: usize },
+    /// A return at `addr` with the (architectural) target `target`
+    Ret { addr: usize, target: usize },
+    /// Start executing down a mispredicted path. Returns until the
+    /// matching [`RasOp::Resolve`] are not counted.
+    Speculate,
+    /// Recover from the misprediction
+    Resolve,
+}
+
+/// The result of predicting a return.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RasOutcome {
+    pub predicted: Option<usize>,
+    pub target: usize,
+}
+impl RasOutcome {
+    pub fn mispredicted(&self) -> bool { self.predicted != Some(self.target) }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct RasCheckpoint {
+    entries: Vec<usize>,
+    tos: usize,
+    count: usize,
+}
+
+/// A model of a return address stack (see the module documentation).
+#[derive(Clone, Debug)]
+pub struct RasModel {
+    pub underflow: RasUnderflow,
+    pub recovery: RasRecovery,
+    entries: Vec<usize>,
+    /// Index of the most-recently pushed entry
+    tos: usize,
+    /// Number of valid entries
+    count: usize,
+    /// Last observed target for each return (for [`RasUnderflow::Fallback`])
+    fallback: HashMap<usize, usize>,
+    checkpoint: Option<RasCheckpoint>,
+}
+impl RasModel {
+    pub fn new(depth: usize) -> Self {
+        assert!(depth != 0);
+        Self {
+            underflow: RasUnderflow::Empty,
+            recovery: RasRecovery::Full,
+            entries: vec![0; depth],
+            tos: 0,
+            count: 0,
+            fallback: HashMap::new(),
+            checkpoint: None,
+        }
+    }
+
+    pub fn underflow(mut self, underflow: RasUnderflow) -> Self {
+        self.underflow = underflow;
+        self
+    }
+
+    pub fn recovery(mut self, recovery: RasRecovery) -> Self {
+        self.recovery = recovery;
+        self
+    }
+
+    pub fn depth(&self) -> usize { self.entries.len() }
+
+    /// Number of valid entries.
+    pub fn len(&self) -> usize { self.count }
+    pub fn is_empty(&self) -> bool { self.count == 0 }
+
+    /// Invalidate all entries (without clearing them).
+    pub fn clear(&mut self) {
+        self.count = 0;
+        self.checkpoint = None;
+    }
+
+    /// Push a return address.
+    pub fn call(&mut self, site: usize) {
+        self.tos = (self.tos + 1) % self.depth();
+        self.entries[self.tos] = site;
+        self.count = (self.count + 1).min(self.depth());
+    }
+
+    /// Pop a predicted target for the return at `addr`.
+    pub fn pop(&mut self, addr: usize) -> Option<usize> {
+        let pred = if self.count != 0 {
+            self.count -= 1;
+            Some(self.entries[self.tos])
+        } else {
+            match self.underflow {
+                RasUnderflow::Empty => return None,
+                RasUnderflow::Wrap => Some(self.entries[self.tos]),
+                RasUnderflow::Fallback => {
+                    return self.fallback.get(&addr).copied();
+                },
+            }
+        };
+        self.tos = (self.tos + self.depth() - 1) % self.depth();
+        pred
+    }
+
+    /// Predict the return at `addr`, and then train with the actual target.
+    pub fn ret(&mut self, addr: usize, target: usize) -> RasOutcome {
+        let predicted = self.pop(addr);
+        self.fallback.insert(addr, target);
+        RasOutcome { predicted, target }
+    }
+
+    /// Save the state of the stack before following a mispredicted path.
+    pub fn speculate(&mut self) {
+        self.checkpoint = Some(RasCheckpoint {
+            entries: self.entries.clone(),
+            tos: self.tos,
+            count: self.count,
+        });
+    }
+
+    /// Repair the stack (according to [`RasModel::recovery`]) after
+    /// following a mispredicted path.
+    pub fn resolve(&mut self) {
+        let cp = match self.checkpoint.take() {
+            Some(cp) => cp,
+            None => return,
+        };
+        match self.recovery {
+            RasRecovery::None => {},
+            RasRecovery::Pointer => {
+                self.tos = cp.tos;
+                self.count = cp.count;
+            },
+            RasRecovery::Full => {
+                self.entries = cp.entries;
+                self.tos = cp.tos;
+                self.count = cp.count;
+            },
+        }
+    }
+
+    /// Run a list of operations, returning the outcome for each return
+    /// that was not on a mispredicted path.
+    pub fn run(&mut self, ops: &[RasOp]) -> Vec<RasOutcome> {
+        let mut res = Vec::new();
+        let mut spec = false;
+        for op in ops {
+            match *op {
+                RasOp::Call { site } => self.call(site),
+                RasOp::Ret { addr, target } => {
+                    if spec {
+                        self.pop(addr);
+                    } else {
+                        res.push(self.ret(addr, target));
+                    }
+                },
+                RasOp::Speculate => {
+                    self.speculate();
+                    spec = true;
+                },
+                RasOp::Resolve => {
+                    self.resolve();
+                    spec = false;
+                },
+            }
+        }
+        res
+    }
+
+    /// Run a list of operations, returning the number of mispredictions.
+    pub fn mispredicts(&mut self, ops: &[RasOp]) -> usize {
+        self.run(ops).iter().filter(|o| o.mispredicted()).count()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chain(n: usize) -> Vec<RasOp> {
+        let mut ops: Vec<RasOp> = (0..n)
+            .map(|i| RasOp::Call { site: 0x100 + i })
+            .collect();
+        ops.extend((0..n).rev().map(|i| RasOp::Ret { addr: 0x200 + i, target: 0x100 + i }));
+        ops
+    }
+
+    #[test]
+    fn ras_depth() {
+        for n in 0..=32 {
+            let mut ras = RasModel::new(16);
+            assert_eq!(ras.mispredicts(&chain(n)), n.saturating_sub(16));
+        }
+    }
+
+    #[test]
+    fn ras_underflow() {
+        let ops = [
+            RasOp::Call { site: 0x10 },
+            RasOp::Ret { addr: 0x20, target: 0x10 },
+            RasOp::Ret { addr: 0x30, target: 0x10 },
+        ];
+        let mut ras = RasModel::new(4);
+        assert_eq!(ras.mispredicts(&ops), 1);
+        let mut ras = RasModel::new(4).underflow(RasUnderflow::Wrap);
+        assert_eq!(ras.mispredicts(&ops), 1);
+        let mut ras = RasModel::new(1).underflow(RasUnderflow::Wrap);
+        assert_eq!(ras.mispredicts(&ops), 0);
+
+        // The fallback is trained by the first run
+        let mut ras = RasModel::new(4).underflow(RasUnderflow::Fallback);
+        assert_eq!(ras.mispredicts(&ops), 1);
+        assert_eq!(ras.mispredicts(&ops), 0);
+    }
+
+    #[test]
+    fn ras_recovery() {
+        // Calls on the wrong path
+        let ops = [
+            RasOp::Call { site: 0x10 },
+            RasOp::Speculate,
+            RasOp::Call { site: 0x20 },
+            RasOp::Call { site: 0x30 },
+            RasOp::Resolve,
+            RasOp::Ret { addr: 0x40, target: 0x10 },
+        ];
+        // A return pops on the wrong path, and a call overwrites the entry
+        let overwrite = [
+            RasOp::Call { site: 0x10 },
+            RasOp::Call { site: 0x20 },
+            RasOp::Ret { addr: 0x30, target: 0x20 },
+            RasOp::Speculate,
+            RasOp::Ret { addr: 0x40, target: 0 },
+            RasOp::Call { site: 0x50 },
+            RasOp::Resolve,
+            RasOp::Ret { addr: 0x60, target: 0x10 },
+        ];
+        for (recovery, expected) in [
+            (RasRecovery::None, (1, 1)),
+            (RasRecovery::Pointer, (0, 1)),
+            (RasRecovery::Full, (0, 0)),
+        ] {
+            let mut ras = RasModel::new(8).recovery(recovery);
+            let res = (ras.mispredicts(&ops), ras.mispredicts(&overwrite));
+            assert_eq!(res, expected, "{:?}", recovery);
+        }
+    }
+}
@@ -1,48 +1,54 @@
 
 use std::collections::*;
-use crate::uarch::l1d::ZEN2_L1D_UTAG_FN;
+use std::marker::PhantomData;
+use crate::uarch::*;
 
-/// Wrapper around a virtual address. 
+/// Wrapper around a virtual address (on the platform `P`).
 ///
 /// NOTE: This is only relevant to experiments for Family 17h/19h parts. 
 ///
 #[derive(Copy, Clone, Debug, PartialOrd, Ord, PartialEq, Eq)]
-pub struct VirtualAddress(pub usize);
-impl VirtualAddress {
+pub struct VirtualAddress<P: L1dGeometry = Zen2>(pub usize, pub PhantomData<P>);
+impl <P: L1dGeometry> VirtualAddress<P> {
     /// Bits [5:0] map to an offset within the cache line
     const OFFSET_MASK: usize = 0x0000_0000_0000_003f;
-    /// Bits [11:6] map to a set in the L1 data cache
-    const SET_MASK: usize    = 0x0000_0000_0000_0fc0;
-    /// According to the "Take A Way" paper, bits [27:12] constitute input to the utag
-    const UTAG_MASK: usize   = 0x0000_0000_0fff_f000;
-    /// High bits
-    const HI_MASK : usize    = 0x0000_ffff_f000_0000;
+    /// Bits above the offset map to a set in the L1 data cache
+    const SET_MASK: usize    = (P::SETS - 1) << 6;
+    /// Bits used as input to the utag function
+    const UTAG_SHIFT: usize  = P::UTAG_MASK.trailing_zeros() as usize;
+    /// High bits (above the utag input bits)
+    const HI_SHIFT: usize    = 64 - P::UTAG_MASK.leading_zeros() as usize;
+    const HI_MASK : usize    = 0x0000_ffff_ffff_ffff & !((1 << Self::HI_SHIFT) - 1);
 
     /// Return a new address with the requested offset bits.
     pub const fn with_offset(self, off: usize) -> Self { 
         Self(
-            (self.0 & !Self::OFFSET_MASK) | ((off & 0b111111))
+            (self.0 & !Self::OFFSET_MASK) | (off & Self::OFFSET_MASK),
+            PhantomData
         )
     }
 
     /// Return a new address with the requested cache set bits.
     pub const fn with_set(self, set: usize) -> Self { 
         Self(
-            (self.0 & !Self::SET_MASK) | ((set & 0b111111) << 6)
+            (self.0 & !Self::SET_MASK) | ((set << 6) & Self::SET_MASK),
+            PhantomData
         )
     }
 
     /// Return a new address with the requested utag input bits.
     pub const fn with_utag_input(self, input: usize) -> Self { 
         Self(
-            (self.0 & !Self::UTAG_MASK) | ((input & 0xffff) << 12)
+            (self.0 & !P::UTAG_MASK) | ((input << Self::UTAG_SHIFT) & P::UTAG_MASK),
+            PhantomData
         )
     }
 
-    /// Return a new address with the requested offset bits.
+    /// Return a new address with the requested high bits.
     pub const fn with_hibits(self, hibits: usize) -> Self { 
         Self(
-            (self.0 & !Self::HI_MASK) | ((hibits & 0xfffff) << 28)
+            (self.0 & !Self::HI_MASK) | ((hibits << Self::HI_SHIFT) & Self::HI_MASK),
+            PhantomData
         )
     }
 
@@ -59,18 +65,18 @@ impl VirtualAddress {
 
     /// Return the micro-tag input bits
     pub fn utag_input(&self) -> usize {
-        (self.0 & Self::UTAG_MASK) >> 12
+        (self.0 & P::UTAG_MASK) >> Self::UTAG_SHIFT
     }
 
-    /// Return the micro-tag input bits
+    /// Return the high bits
     pub fn hibits(&self) -> usize {
-        (self.0 & Self::HI_MASK) >> 28
+        (self.0 & Self::HI_MASK) >> Self::HI_SHIFT
     }
 
 
     /// Compute and return the micro-tag for this address.
     pub fn utag(&self) -> usize { 
-        ZEN2_L1D_UTAG_FN.evaluate(self.0)
+        P::utag(self.0)
     }
 
     /// Return the 64-bit virtual address as a [`usize`].
@@ -81,31 +87,30 @@ impl VirtualAddress {
     /// Create a new virtual address from the given offset, set index, 
     /// micro-tag input bits, and high bits. 
     pub fn new(offset: usize, set: usize, utag_input: usize, hi_bits: usize) -> Self {
-        let offset = offset & 0x3f;
-        let set = set & 0b0011_1111;
-        let utag_input = utag_input & 0xffff;
-        Self(offset | set << 6 | utag_input << 12 | hi_bits << 28)
+        Self(0, PhantomData)
+            .with_offset(offset)
+            .with_set(set)
+            .with_utag_input(utag_input)
+            .with_hibits(hi_bits)
     }
 
-    // Generate the set of all 256 addresses whose micro-tags are colliding
+    // Generate the set of all addresses whose micro-tags are colliding
     // with the micro-tag for this address.
     pub fn generate_collisions(&self) -> Vec<Self> {
         let mut res = Vec::new();
         let map = Self::compute_utag_map();
         let inputs = map.get(&self.utag()).unwrap();
         for input in inputs {
-            res.push(VirtualAddress::new(0b000000, 0b000000, *input, 0));
+            res.push(Self::new(0b000000, 0b000000, *input, 0));
         }
         res
     }
 
     // Generate a random address whose micro-tag is colliding with the 
     // micro-tag for this address. 
-    pub fn random_collision(&self) -> Self { 
-        use rand::Rng;
-        let mut rng = rand::thread_rng();
+    pub fn random_collision(&self, rng: &mut impl rand::Rng) -> Self { 
         let colls = self.generate_collisions();
-        let x = rng.gen_range(0..256);
+        let x = rng.gen_range(0..colls.len());
         colls[x]
     }
 
@@ -113,8 +118,8 @@ impl VirtualAddress {
     pub fn compute_utag_map() -> BTreeMap<usize, BTreeSet<usize>> {
         let mut map: BTreeMap<usize, BTreeSet<usize>> = BTreeMap::new();
 
-        for input in (0x0000..=0xffffusize) {
-            let utag = ZEN2_L1D_UTAG_FN.evaluate(input << 12);
+        for input in 0..=(P::UTAG_MASK >> Self::UTAG_SHIFT) {
+            let utag = P::utag(input << Self::UTAG_SHIFT);
             if let Some(inputs) = map.get_mut(&utag) {
                 inputs.insert(input);
             } else { 
@@ -126,7 +131,29 @@ impl VirtualAddress {
         map
     }
 }
+impl <P: L1dGeometry> From<usize> for VirtualAddress<P> {
+    fn from(vaddr: usize) -> Self { Self(vaddr, PhantomData) }
+}
 
-
-
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn vaddr_fields() {
+        let a = VirtualAddress::<Zen3>::new(0x3f, 0x2a, 0x1234, 0x1dea);
+        assert_eq!(a.value(), 0x0000_01de_a123_4abf);
+        assert_eq!((a.offset(), a.set(), a.utag_input(), a.hibits()),
+            (0x3f, 0x2a, 0x1234, 0x1dea)
+        );
+        assert_eq!(a.utag(), Zen3::UTAG_FN.evaluate(a.value()));
+
+        let map = VirtualAddress::<Zen2>::compute_utag_map();
+        assert_eq!(map.len(), 256);
+        assert!(map.values().all(|inputs| inputs.len() == 256));
+        let b = VirtualAddress::<Zen2>::from(a.value());
+        let c = b.random_collision(&mut crate::util::seeded_rng(2));
+        assert_eq!(c.utag(), b.utag());
+    }
+}
 
@@ -0,0 +1,76 @@
+//! Definitions for Zen 2 (Family 17h) parts.
+
+use crate::ir::bf::*;
+use crate::uarch::*;
+
+/// The function used to compute the L1D micro-tag (utag) used to implement
+/// the L1D way predictor. 
+///
+/// ```
+///     27  23  19
+///     v   v   v
+/// 12  +........
+/// 13  .+.......
+/// 14  ..+......
+/// 15  .......+.
+/// 16  ......+..
+/// 17  .....+...
+/// 18  ....+....
+/// 19  ...+.....
+/// ```
+///
+/// This is reproduced from the description in the "Take A Way" paper[^1]. 
+///
+/// [^1]: [Take A Way: Exploring the Security Implications of AMD's Cache Way Predictors](https://dl.acm.org/doi/10.1145/3320269.3384746)
+///
+pub const ZEN2_L1D_UTAG_FN: BfProg<8> = {
+    use BfOp::*;
+    BfProg([ 
+        Xor(&[In(12), In(27)]),
+        Xor(&[In(13), In(26)]),
+        Xor(&[In(14), In(25)]),
+
+        Xor(&[In(15), In(20)]),
+        Xor(&[In(16), In(21)]),
+        Xor(&[In(17), In(22)]),
+        Xor(&[In(18), In(23)]),
+        Xor(&[In(19), In(24)]),
+    ])
+};
+
+/// The function used to compute the BTB index on Zen 2. 
+///
+/// This is reproduced from the description in the RETBLEED paper[^1].
+/// [^1]: [RETBLEED: Arbitrary Speculative Code Execution with Return Instructions](https://comsec.ethz.ch/wp-content/files/retbleed_sec22.pdf)
+pub const ZEN2_BTB_INDEX_FN: BfProg<12> = {  
+    use BfOp::*;
+    BfProg([ 
+        Xor(&[In(36), In(24)]),
+        Xor(&[In(37), In(25)]),
+        Xor(&[In(38), In(26)]),
+        Xor(&[In(39), In(27), And(&[In(15), In(10)])]),
+        Xor(&[In(40), In(28), And(&[In(16), In(11)])]),
+        Xor(&[In(41), In(29), In(17)]),
+        Xor(&[In(42), In(30), In(18)]),
+        Xor(&[In(43), In(31), In(19)]),
+        Xor(&[In(44), In(32), In(20)]),
+        Xor(&[In(45), In(33), In(21)]),
+        Xor(&[In(46), In(34), In(22)]),
+        Xor(&[In(47), In(35), In(23)]),
+    ])
+};
+
+/// AMD Zen 2 (Family 17h).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Zen2;
+impl L1dGeometry for Zen2 {
+    const SETS: usize = 64;
+    const WAYS: usize = 8;
+    const UTAG_MASK: usize = 0x0000_0000_0fff_f000;
+    const UTAG_FN: BfProg<8> = ZEN2_L1D_UTAG_FN;
+}
+impl BtbGeometry for Zen2 {
+    const SETS: usize = 1024;
+    const WAYS: usize = 4;
+    const INDEX_FN: BfProg<12> = ZEN2_BTB_INDEX_FN;
+}
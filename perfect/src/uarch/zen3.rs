@@ -0,0 +1,32 @@
+//! Definitions for Zen 3 (Family 19h) parts.
+//!
+//! NOTE: This only describes the geometry of structures on Zen 3. The utag
+//! and BTB index functions haven't been recovered for Zen 3 (ie. with
+//! [`crate::ir::bf::BfSolver`]), so [`Zen3`] uses the functions for Zen 2.
+
+use crate::ir::bf::*;
+use crate::uarch::*;
+
+/// AMD Zen 3 (Family 19h).
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Zen3;
+
+/// The L1D on Zen 3 has the same geometry as on Zen 2 (32KiB, 8-way).
+///
+/// NOTE: The utag function is [`ZEN2_L1D_UTAG_FN`] (which the
+/// 'collide-probe' experiment has been using on Zen 3).
+impl L1dGeometry for Zen3 {
+    const SETS: usize = 64;
+    const WAYS: usize = 8;
+    const UTAG_MASK: usize = 0x0000_0000_0fff_f000;
+    const UTAG_FN: BfProg<8> = ZEN2_L1D_UTAG_FN;
+}
+
+/// NOTE: The L2 BTB on Zen 3 has 6.5K entries, but the organization is
+/// unknown. This uses the same sets, ways, and index function
+/// ([`ZEN2_BTB_INDEX_FN`]) as [`Zen2`].
+impl BtbGeometry for Zen3 {
+    const SETS: usize = 1024;
+    const WAYS: usize = 4;
+    const INDEX_FN: BfProg<12> = ZEN2_BTB_INDEX_FN;
+}
@@ -2,6 +2,10 @@
 pub mod msr;
 pub mod pagemap;
 pub mod maps;
+pub mod cpuid;
+pub mod rng;
+
+pub use rng::{ PerfectRng, perfect_rng, seeded_rng };
 
 use std::io::Read;
 use dynasmrt::{
@@ -249,6 +253,13 @@ impl PerfectEnv {
         Ok(())
     }
 
+    /// Returns whether floating-point/vector move elimination is enabled
+    /// (see [`PerfectEnv::toggle_fp_mov_elim`]).
+    pub fn fp_mov_elim(cpu: usize) -> Result<bool, String> {
+        let val = Msr::rdmsr(0xc001_1029, cpu)?;
+        Ok(val & (1 << 9) == 0)
+    }
+
     /// Toggle branch predictions for non-branch instructions.
     /// Documented as "SuppressBPOnNonBr" in the mitigations for BTC.
     /// Known valid on Zen 2 parts.
@@ -596,22 +607,10 @@ pub fn build_pmc_counter(p: TargetPlatform, desc: &EventDesc) -> Counter {
         ctr
 }
 
+/// Read APERF with RDPRU (see [`crate::timer::Timer`]).
 #[inline(always)]
 pub fn rdpru() -> usize {
-    unsafe { 
-        let l: u32;
-        let h: u32;
-        core::arch::asm!(r#"
-            lfence
-            rdpru
-            lfence
-        "#,
-            out("edx") h, 
-            out("eax") l,
-            in("ecx") 1,
-        );
-        (h as usize) << 32 | l as usize
-    }
+    crate::timer::Timer::APERF.read()
 }
 
 
@@ -0,0 +1,257 @@
+//! Querying CPUID feature flags on the host.
+
+use core::arch::x86_64::{ __cpuid_count, _xgetbv };
+use iced_x86::CpuidFeature;
+
+/// A snapshot of the CPUID feature flags reported by the host.
+///
+/// This is mainly used to decide whether or not some instruction (as
+/// described by [`iced_x86::Code::cpuid_features`]) can actually be
+/// executed on this machine.
+#[derive(Clone, Copy, Debug)]
+pub struct HostCpuid {
+    /// Vendor string (ie. "AuthenticAMD")
+    pub vendor: [u8; 12],
+    /// Leaf 0x0000_0001, ECX
+    pub leaf1_ecx: u32,
+    /// Leaf 0x0000_0001, EDX
+    pub leaf1_edx: u32,
+    /// Leaf 0x0000_0007, subleaf 0, EBX
+    pub leaf7_ebx: u32,
+    /// Leaf 0x0000_0007, subleaf 0, ECX
+    pub leaf7_ecx: u32,
+    /// Leaf 0x0000_0007, subleaf 0, EDX
+    pub leaf7_edx: u32,
+    /// Leaf 0x0000_000d, subleaf 1, EAX
+    pub leafd_eax: u32,
+    /// Leaf 0x8000_0001, ECX
+    pub ext1_ecx: u32,
+    /// Leaf 0x8000_0001, EDX
+    pub ext1_edx: u32,
+    /// Leaf 0x8000_0008, EBX
+    pub ext8_ebx: u32,
+    /// The value of XCR0 (or zero when XGETBV is not enabled by the OS)
+    pub xcr0: u64,
+}
+impl HostCpuid {
+    /// Read the feature flags for the current core.
+    pub fn read() -> Self {
+        let leaf = |eax: u32, ecx: u32| __cpuid_count(eax, ecx);
+
+        let l0 = leaf(0x0000_0000, 0);
+        let mut vendor = [0u8; 12];
+        vendor[0..4].copy_from_slice(&l0.ebx.to_le_bytes());
+        vendor[4..8].copy_from_slice(&l0.edx.to_le_bytes());
+        vendor[8..12].copy_from_slice(&l0.ecx.to_le_bytes());
+
+        let l1 = leaf(0x0000_0001, 0);
+        let (leaf7_ebx, leaf7_ecx, leaf7_edx) = if l0.eax >= 0x7 {
+            let l7 = leaf(0x0000_0007, 0);
+            (l7.ebx, l7.ecx, l7.edx)
+        } else {
+            (0, 0, 0)
+        };
+        let leafd_eax = if l0.eax >= 0xd {
+            leaf(0x0000_000d, 1).eax
+        } else {
+            0
+        };
+
+        let e0 = leaf(0x8000_0000, 0);
+        let (ext1_ecx, ext1_edx) = if e0.eax >= 0x8000_0001 {
+            let e1 = leaf(0x8000_0001, 0);
+            (e1.ecx, e1.edx)
+        } else {
+            (0, 0)
+        };
+        let ext8_ebx = if e0.eax >= 0x8000_0008 {
+            leaf(0x8000_0008, 0).ebx
+        } else {
+            0
+        };
+
+        // XGETBV is only usable when CR4.OSXSAVE is set
+        let xcr0 = if (l1.ecx & (1 << 27)) != 0 {
+            unsafe { _xgetbv(0) }
+        } else {
+            0
+        };
+
+        Self {
+            vendor,
+            leaf1_ecx: l1.ecx,
+            leaf1_edx: l1.edx,
+            leaf7_ebx,
+            leaf7_ecx,
+            leaf7_edx,
+            leafd_eax,
+            ext1_ecx,
+            ext1_edx,
+            ext8_ebx,
+            xcr0,
+        }
+    }
+
+    /// Return the vendor string.
+    pub fn vendor(&self) -> &str {
+        std::str::from_utf8(&self.vendor).unwrap_or("unknown")
+    }
+
+    /// Returns true if the OS has enabled saving the AVX state (XMM/YMM).
+    pub fn os_avx(&self) -> bool {
+        (self.xcr0 & 0b110) == 0b110
+    }
+
+    /// Returns true if the OS has enabled saving the AVX-512 state
+    /// (opmask registers, ZMM0-15 upper halves, and ZMM16-31).
+    pub fn os_avx512(&self) -> bool {
+        self.os_avx() && (self.xcr0 & 0b1110_0000) == 0b1110_0000
+    }
+
+    /// Returns true if the host supports a particular [`CpuidFeature`].
+    ///
+    /// Features that we don't know how to detect are always reported as
+    /// being unsupported.
+    pub fn supports(&self, feature: CpuidFeature) -> bool {
+        use CpuidFeature::*;
+        let bit = |reg: u32, n: u32| (reg & (1 << n)) != 0;
+        let avx = self.os_avx();
+        let avx512 = self.os_avx512();
+        let avx512f = avx512 && bit(self.leaf7_ebx, 16);
+
+        match feature {
+            INTEL8086 | INTEL186 | INTEL286 | INTEL386 | INTEL486 |
+            X64 | CPUID | PAUSE | MULTIBYTENOP | RDPMC |
+            FPU287 | FPU387 => true,
+
+            FPU         => bit(self.leaf1_edx, 0),
+            TSC         => bit(self.leaf1_edx, 4),
+            MSR         => bit(self.leaf1_edx, 5),
+            CX8         => bit(self.leaf1_edx, 8),
+            SEP         => bit(self.leaf1_edx, 11),
+            CMOV        => bit(self.leaf1_edx, 15),
+            CLFSH       => bit(self.leaf1_edx, 19),
+            MMX         => bit(self.leaf1_edx, 23),
+            FXSR        => bit(self.leaf1_edx, 24),
+            SSE         => bit(self.leaf1_edx, 25),
+            SSE2        => bit(self.leaf1_edx, 26),
+
+            SSE3        => bit(self.leaf1_ecx, 0),
+            PCLMULQDQ   => bit(self.leaf1_ecx, 1),
+            MONITOR     => bit(self.leaf1_ecx, 3),
+            VMX         => bit(self.leaf1_ecx, 5),
+            SMX         => bit(self.leaf1_ecx, 6),
+            SSSE3       => bit(self.leaf1_ecx, 9),
+            FMA         => avx && bit(self.leaf1_ecx, 12),
+            CMPXCHG16B  => bit(self.leaf1_ecx, 13),
+            SSE4_1      => bit(self.leaf1_ecx, 19),
+            SSE4_2      => bit(self.leaf1_ecx, 20),
+            MOVBE       => bit(self.leaf1_ecx, 22),
+            POPCNT      => bit(self.leaf1_ecx, 23),
+            AES         => bit(self.leaf1_ecx, 25),
+            XSAVE       => bit(self.leaf1_ecx, 26),
+            AVX         => avx && bit(self.leaf1_ecx, 28),
+            F16C        => avx && bit(self.leaf1_ecx, 29),
+            RDRAND      => bit(self.leaf1_ecx, 30),
+
+            FSGSBASE    => bit(self.leaf7_ebx, 0),
+            SGX1        => bit(self.leaf7_ebx, 2),
+            BMI1        => bit(self.leaf7_ebx, 3),
+            HLE         => bit(self.leaf7_ebx, 4),
+            AVX2        => avx && bit(self.leaf7_ebx, 5),
+            BMI2        => bit(self.leaf7_ebx, 8),
+            INVPCID     => bit(self.leaf7_ebx, 10),
+            RTM         => bit(self.leaf7_ebx, 11),
+            MPX         => bit(self.leaf7_ebx, 14),
+            AVX512F     => avx512f,
+            AVX512DQ    => avx512f && bit(self.leaf7_ebx, 17),
+            RDSEED      => bit(self.leaf7_ebx, 18),
+            ADX         => bit(self.leaf7_ebx, 19),
+            SMAP        => bit(self.leaf7_ebx, 20),
+            AVX512_IFMA => avx512f && bit(self.leaf7_ebx, 21),
+            CLFLUSHOPT  => bit(self.leaf7_ebx, 23),
+            CLWB        => bit(self.leaf7_ebx, 24),
+            AVX512PF    => avx512f && bit(self.leaf7_ebx, 26),
+            AVX512ER    => avx512f && bit(self.leaf7_ebx, 27),
+            AVX512CD    => avx512f && bit(self.leaf7_ebx, 28),
+            SHA         => bit(self.leaf7_ebx, 29),
+            AVX512BW    => avx512f && bit(self.leaf7_ebx, 30),
+            AVX512VL    => avx512f && bit(self.leaf7_ebx, 31),
+
+            PREFETCHWT1 => bit(self.leaf7_ecx, 0),
+            AVX512_VBMI => avx512f && bit(self.leaf7_ecx, 1),
+            PKU         => bit(self.leaf7_ecx, 3),
+            WAITPKG     => bit(self.leaf7_ecx, 5),
+            AVX512_VBMI2 => avx512f && bit(self.leaf7_ecx, 6),
+            CET_SS      => bit(self.leaf7_ecx, 7),
+            GFNI        => bit(self.leaf7_ecx, 8),
+            VAES        => avx && bit(self.leaf7_ecx, 9),
+            VPCLMULQDQ  => avx && bit(self.leaf7_ecx, 10),
+            AVX512_VNNI => avx512f && bit(self.leaf7_ecx, 11),
+            AVX512_BITALG => avx512f && bit(self.leaf7_ecx, 12),
+            AVX512_VPOPCNTDQ => avx512f && bit(self.leaf7_ecx, 14),
+            RDPID       => bit(self.leaf7_ecx, 22),
+            CLDEMOTE    => bit(self.leaf7_ecx, 25),
+            MOVDIRI     => bit(self.leaf7_ecx, 27),
+            MOVDIR64B   => bit(self.leaf7_ecx, 28),
+
+            AVX512_4VNNIW => avx512f && bit(self.leaf7_edx, 2),
+            AVX512_4FMAPS => avx512f && bit(self.leaf7_edx, 3),
+            AVX512_VP2INTERSECT => avx512f && bit(self.leaf7_edx, 8),
+            SERIALIZE   => bit(self.leaf7_edx, 14),
+            TSXLDTRK    => bit(self.leaf7_edx, 16),
+            CET_IBT     => bit(self.leaf7_edx, 20),
+
+            HLE_or_RTM  => bit(self.leaf7_ebx, 4) || bit(self.leaf7_ebx, 11),
+
+            XSAVEOPT    => bit(self.leafd_eax, 0),
+            XSAVEC      => bit(self.leafd_eax, 1),
+            XSAVES      => bit(self.leafd_eax, 3),
+
+            SVM         => bit(self.ext1_ecx, 2),
+            LZCNT       => bit(self.ext1_ecx, 5),
+            SSE4A       => bit(self.ext1_ecx, 6),
+            PREFETCHW   => bit(self.ext1_ecx, 8),
+            XOP         => avx && bit(self.ext1_ecx, 11),
+            SKINIT      => bit(self.ext1_ecx, 12),
+            LWP         => bit(self.ext1_ecx, 15),
+            FMA4        => avx && bit(self.ext1_ecx, 16),
+            TBM         => bit(self.ext1_ecx, 21),
+            MONITORX    => bit(self.ext1_ecx, 29),
+            SKINIT_or_SVM => bit(self.ext1_ecx, 2) || bit(self.ext1_ecx, 12),
+
+            SYSCALL     => bit(self.ext1_edx, 11),
+            RDTSCP      => bit(self.ext1_edx, 27),
+            D3NOWEXT    => bit(self.ext1_edx, 30),
+            D3NOW       => bit(self.ext1_edx, 31),
+
+            CLZERO      => bit(self.ext8_ebx, 0),
+            RDPRU       => bit(self.ext8_ebx, 4),
+            MCOMMIT     => bit(self.ext8_ebx, 8),
+            WBNOINVD    => bit(self.ext8_ebx, 9),
+
+            _ => false,
+        }
+    }
+
+    /// Returns true if the host supports *all* of the given features.
+    pub fn supports_all(&self, features: &[CpuidFeature]) -> bool {
+        features.iter().all(|f| self.supports(*f))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn cpuid_baseline() {
+        let cpuid = HostCpuid::read();
+        // Every x86_64 machine has SSE2
+        assert!(cpuid.supports_all(&[
+            CpuidFeature::X64, CpuidFeature::SSE, CpuidFeature::SSE2
+        ]));
+        assert_eq!(cpuid.supports(CpuidFeature::AVX2),
+            is_x86_feature_detected!("avx2"));
+    }
+}
@@ -0,0 +1,138 @@
+//! Deterministic random number generation.
+//!
+//! All randomness in this library (and in the experiment binaries) should
+//! come from a [`PerfectRng`], which is always derived from a single 64-bit
+//! seed. The seed is chosen once per process:
+//!
+//! - If the `PERFECT_SEED` environment variable is set, it's used as the
+//!   seed (in decimal, or hexadecimal with a `0x` prefix)
+//! - Otherwise, a random seed is chosen
+//!
+//! Either way, the seed is printed when it's first used. Running an
+//! experiment again with the same value in `PERFECT_SEED` should replay the
+//! same programs, encodings, and inputs.
+//!
+//! NOTE: This only holds when RNGs are created in the same order. Each
+//! thread has its own sequence of RNGs derived from the seed.
+
+use rand::{ Rng, SeedableRng };
+use std::cell::RefCell;
+use std::sync::OnceLock;
+
+/// The type of RNG used throughout this library.
+pub type PerfectRng = rand::rngs::StdRng;
+
+/// Environment variable used to set the seed for [`perfect_rng`].
+pub const SEED_VAR: &str = "PERFECT_SEED";
+
+/// Return the process-wide seed (see the module documentation).
+pub fn global_seed() -> u64 {
+    static SEED: OnceLock<u64> = OnceLock::new();
+    *SEED.get_or_init(|| {
+        let seed = match std::env::var(SEED_VAR) {
+            Ok(s) => parse_seed(&s)
+                .unwrap_or_else(|| panic!("Invalid {}='{}'", SEED_VAR, s)),
+            Err(_) => rand::thread_rng().gen(),
+        };
+        println!("[*] {}={:#018x}", SEED_VAR, seed);
+        seed
+    })
+}
+
+/// Parse a seed (in decimal, or in hexadecimal with a `0x` prefix).
+pub fn parse_seed(s: &str) -> Option<u64> {
+    let s = s.trim();
+    match s.strip_prefix("0x") {
+        Some(hex) => u64::from_str_radix(&hex.replace('_', ""), 16).ok(),
+        None => s.parse().ok(),
+    }
+}
+
+/// Create an RNG from a particular seed.
+pub fn seeded_rng(seed: u64) -> PerfectRng {
+    PerfectRng::seed_from_u64(seed)
+}
+
+/// Create a new RNG derived from the process-wide seed.
+///
+/// This is a replacement for [`rand::thread_rng`]: each call returns an RNG
+/// seeded with the next value from a per-thread RNG (which is seeded with
+/// [`global_seed`]).
+pub fn perfect_rng() -> PerfectRng {
+    thread_local! {
+        static RNG: RefCell<Option<PerfectRng>> = const { RefCell::new(None) };
+    }
+    RNG.with(|rng| {
+        let mut rng = rng.borrow_mut();
+        let rng = rng.get_or_insert_with(|| seeded_rng(global_seed()));
+        seeded_rng(rng.gen())
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ir::*;
+    use crate::harness::*;
+    use crate::experiments::decoder::*;
+    use crate::experiments::fuzz::*;
+    use crate::events::{ EventDesc, MaskDesc };
+    use crate::asm::*;
+
+    #[test]
+    fn rng_same_seed_same_output() {
+        let (mut a, mut b) = (seeded_rng(0x1234), seeded_rng(0x1234));
+
+        // Programs
+        let prog_a = PerfectProg::gen(&mut a, 128);
+        let prog_b = PerfectProg::gen(&mut b, 128);
+        assert_eq!(prog_a.data, prog_b.data);
+
+        // Encodings
+        for _ in 0..256 {
+            let enc_a: RandomEncoding<15> = a.gen();
+            let enc_b: RandomEncoding<15> = b.gen();
+            assert_eq!(enc_a.0, enc_b.0);
+        }
+        assert_eq!(EncodingSeq::gen(&mut a, 16), EncodingSeq::gen(&mut b, 16));
+
+        // Inputs
+        let method = InputMethod::Random(&|rng, _| (rng.gen(), rng.gen()));
+        assert_eq!(method.args(&mut a, 64), method.args(&mut b, 64));
+
+        // A different seed gives different output
+        let mut c = seeded_rng(0x1235);
+        assert_ne!(PerfectProg::gen(&mut c, 128).data, prog_a.data);
+
+        assert_eq!(parse_seed("0x1234"), Some(0x1234));
+        assert_eq!(parse_seed("4660"), Some(0x1234));
+        assert_eq!(parse_seed("zzz"), None);
+    }
+
+    /// Harnesses with the same seed generate the same inputs.
+    #[test]
+    fn rng_harness_seed() {
+        let mut f = X64Assembler::new().unwrap();
+        f.emit_ret();
+        f.commit().unwrap();
+        let buf = f.finalize().unwrap();
+        let func: MeasuredFn = unsafe {
+            std::mem::transmute(buf.ptr(dynasmrt::AssemblyOffset(0)))
+        };
+        let event = EventDesc::new_unk(0x0c0, MaskDesc::new_unk(0x00));
+
+        let mut results = Vec::new();
+        for _ in 0..2 {
+            let mut harness = HarnessConfig::default_test(0x133b)
+                .seed(0xdead_beef)
+                .emit();
+            assert_eq!(harness.seed, 0xdead_beef);
+            let res = harness.measure(func, &event, 64,
+                InputMethod::Random(&|rng, _| (rng.gen(), rng.gen()))
+            ).unwrap();
+            results.push(res);
+        }
+        assert_eq!(results[0].seed, results[1].seed);
+        assert_eq!(results[0].inputs, results[1].inputs);
+    }
+}